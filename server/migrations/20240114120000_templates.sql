@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS templates (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    description TEXT,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT user_ownership FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS template_exercises (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL,
+    template_id uuid NOT NULL,
+    exercise_id uuid NOT NULL,
+    position integer NOT NULL CHECK (position >= 0),
+    sets integer NOT NULL CHECK (sets > 0),
+    reps integer CHECK (reps >= 0),
+    weight REAL,
+    CONSTRAINT user_ownership FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT exercise_type FOREIGN KEY(exercise_id) REFERENCES exercises(id) ON DELETE RESTRICT,
+    CONSTRAINT parent_template FOREIGN KEY(template_id) REFERENCES templates(id) ON DELETE CASCADE,
+    CONSTRAINT unique_position UNIQUE (template_id, position)
+);
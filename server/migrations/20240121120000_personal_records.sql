@@ -0,0 +1,23 @@
+CREATE TYPE record_kind AS ENUM (
+    'HEAVIEST_WEIGHT',
+    'MOST_REPS',
+    'ESTIMATED_ONE_REP_MAX',
+    'SESSION_VOLUME'
+);
+CREATE TABLE IF NOT EXISTS personal_records (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL,
+    exercise_id uuid NOT NULL,
+    kind record_kind NOT NULL,
+    value REAL NOT NULL,
+    weight REAL,
+    reps integer,
+    set_id uuid,
+    session_id uuid NOT NULL,
+    achieved TIMESTAMP WITH TIME ZONE NOT NULL,
+    CONSTRAINT user_ownership FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT record_exercise FOREIGN KEY(exercise_id) REFERENCES exercises(id) ON DELETE CASCADE,
+    CONSTRAINT record_set FOREIGN KEY(set_id) REFERENCES sets(id) ON DELETE CASCADE,
+    CONSTRAINT record_session FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS personal_records_exercise ON personal_records (exercise_id);
@@ -0,0 +1,9 @@
+CREATE TYPE session_state AS ENUM (
+    'PLANNED',
+    'IN_PROGRESS',
+    'FINISHED'
+);
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS state session_state NOT NULL DEFAULT 'IN_PROGRESS';
+UPDATE sessions SET state = 'FINISHED' WHERE finished IS NOT NULL;
+ALTER TABLE sessions ADD CONSTRAINT finished_state CHECK ((state = 'FINISHED') = (finished IS NOT NULL));
+ALTER TABLE sessions ADD CONSTRAINT finished_after_started CHECK (finished >= started);
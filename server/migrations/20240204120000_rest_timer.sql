@@ -0,0 +1,4 @@
+ALTER TABLE exercises ADD COLUMN IF NOT EXISTS rest_seconds integer NOT NULL DEFAULT 120 CHECK (rest_seconds >= 0);
+ALTER TABLE exercise_instances ADD COLUMN IF NOT EXISTS rest_seconds integer CHECK (rest_seconds >= 0);
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE sets ADD CONSTRAINT completed_at_only_when_completed CHECK (completed OR completed_at IS NULL);
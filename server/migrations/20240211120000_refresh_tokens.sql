@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token VARCHAR(100) PRIMARY KEY,
+    -- Tokens rotated from the same login share the family
+    family_id uuid NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- Set when rotated, using it again revokes the family
+    used TIMESTAMP WITH TIME ZONE,
+    user_id uuid NOT NULL,
+    CONSTRAINT token_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_family ON refresh_tokens (family_id);
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS family_id uuid;
+CREATE INDEX IF NOT EXISTS access_tokens_family ON access_tokens (family_id);
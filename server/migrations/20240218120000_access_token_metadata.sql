@@ -0,0 +1,6 @@
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS id uuid NOT NULL UNIQUE DEFAULT uuid_generate_v4();
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS device_name VARCHAR(50);
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS user_agent TEXT;
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS ip TEXT;
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS last_used TIMESTAMP WITH TIME ZONE;
+CREATE INDEX IF NOT EXISTS access_tokens_user ON access_tokens (user_id);
@@ -0,0 +1,33 @@
+-- Tokens are stored as keyed hashes, found by their non-secret prefix.
+-- Existing tokens are hashed with the key the server sets on the connection
+-- running its migrations, so with any tokens this has to be run by the server.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+DO $$
+BEGIN
+    IF COALESCE(current_setting('liftlog.token_key', true), '') = ''
+        AND (EXISTS (SELECT 1 FROM access_tokens) OR EXISTS (SELECT 1 FROM refresh_tokens)) THEN
+        RAISE EXCEPTION 'Existing tokens can only be hashed when the server runs the migrations';
+    END IF;
+END $$;
+
+ALTER TABLE access_tokens DROP CONSTRAINT access_tokens_pkey;
+ALTER TABLE access_tokens ADD PRIMARY KEY (id);
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS token_prefix VARCHAR(8);
+ALTER TABLE access_tokens ADD COLUMN IF NOT EXISTS token_hash BYTEA;
+UPDATE access_tokens SET token_prefix = LEFT(token, 8), token_hash = hmac(token, current_setting('liftlog.token_key', true), 'sha256');
+ALTER TABLE access_tokens ALTER COLUMN token_prefix SET NOT NULL;
+ALTER TABLE access_tokens ALTER COLUMN token_hash SET NOT NULL;
+ALTER TABLE access_tokens DROP COLUMN token;
+CREATE INDEX IF NOT EXISTS access_tokens_prefix ON access_tokens (token_prefix);
+
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS id uuid NOT NULL DEFAULT uuid_generate_v4();
+ALTER TABLE refresh_tokens DROP CONSTRAINT refresh_tokens_pkey;
+ALTER TABLE refresh_tokens ADD PRIMARY KEY (id);
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS token_prefix VARCHAR(8);
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS token_hash BYTEA;
+UPDATE refresh_tokens SET token_prefix = LEFT(token, 8), token_hash = hmac(token, current_setting('liftlog.token_key', true), 'sha256');
+ALTER TABLE refresh_tokens ALTER COLUMN token_prefix SET NOT NULL;
+ALTER TABLE refresh_tokens ALTER COLUMN token_hash SET NOT NULL;
+ALTER TABLE refresh_tokens DROP COLUMN token;
+CREATE INDEX IF NOT EXISTS refresh_tokens_prefix ON refresh_tokens (token_prefix);
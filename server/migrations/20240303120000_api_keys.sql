@@ -0,0 +1,25 @@
+CREATE TYPE api_scope AS ENUM (
+    'EXERCISES_READ',
+    'EXERCISES_WRITE',
+    'SESSIONS_READ',
+    'SESSIONS_WRITE',
+    'SETS_READ',
+    'SETS_WRITE',
+    'TEMPLATES_READ',
+    'TEMPLATES_WRITE',
+    'EXPORT'
+);
+CREATE TABLE IF NOT EXISTS api_keys (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name VARCHAR(50) NOT NULL,
+    scopes api_scope[] NOT NULL,
+    -- Stored hashed like access tokens
+    token_prefix VARCHAR(8) NOT NULL,
+    token_hash BYTEA NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used TIMESTAMP WITH TIME ZONE,
+    user_id uuid NOT NULL,
+    CONSTRAINT api_key_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS api_keys_prefix ON api_keys (token_prefix);
+CREATE INDEX IF NOT EXISTS api_keys_user ON api_keys (user_id);
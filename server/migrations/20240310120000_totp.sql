@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS totp_credentials (
+    user_id uuid PRIMARY KEY,
+    secret BYTEA NOT NULL,
+    -- False until the first code is verified
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Time step of the latest accepted code, which can't be used again
+    last_used_step BIGINT,
+    -- Invalid codes since the last valid one, locked for a while after too many
+    failed_attempts INT NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP WITH TIME ZONE,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT totp_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL,
+    -- Hashed like access tokens
+    code_hash BYTEA NOT NULL,
+    used TIMESTAMP WITH TIME ZONE,
+    CONSTRAINT recovery_code_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS recovery_codes_user ON recovery_codes (user_id);
@@ -0,0 +1,18 @@
+-- External identities linked to users, a provider's subject is unique
+CREATE TABLE IF NOT EXISTS oidc_identities (
+    provider VARCHAR(50) NOT NULL,
+    subject VARCHAR(255) NOT NULL,
+    user_id uuid NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (provider, subject),
+    CONSTRAINT oidc_identity_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS oidc_identities_user ON oidc_identities (user_id);
+-- Started authorization code flows, each can be finished once
+CREATE TABLE IF NOT EXISTS oidc_login_states (
+    state VARCHAR(100) PRIMARY KEY,
+    provider VARCHAR(50) NOT NULL,
+    code_verifier VARCHAR(128) NOT NULL,
+    nonce VARCHAR(100) NOT NULL,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,19 @@
+CREATE TYPE user_role AS ENUM (
+    'ADMIN',
+    'USER'
+);
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role user_role NOT NULL DEFAULT 'USER';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+-- Existing instances get their oldest user as the admin
+UPDATE users SET role = 'ADMIN' WHERE id = (SELECT id FROM users ORDER BY created LIMIT 1);
+CREATE TABLE IF NOT EXISTS invite_codes (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    -- Stored hashed like access tokens
+    code_hash BYTEA NOT NULL UNIQUE,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by uuid,
+    used TIMESTAMP WITH TIME ZONE,
+    used_by uuid,
+    CONSTRAINT invite_code_creator FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL,
+    CONSTRAINT invite_code_user FOREIGN KEY(used_by) REFERENCES users(id) ON DELETE SET NULL
+);
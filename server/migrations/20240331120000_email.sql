@@ -0,0 +1,20 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email VARCHAR(254) UNIQUE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TYPE email_token_purpose AS ENUM (
+    'VERIFY_EMAIL',
+    'RESET_PASSWORD'
+);
+-- Single use tokens sent by email
+CREATE TABLE IF NOT EXISTS email_tokens (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    purpose email_token_purpose NOT NULL,
+    -- Stored hashed like access tokens
+    token_hash BYTEA NOT NULL UNIQUE,
+    -- Address the token was sent to, verifying fails if the user's email has changed since
+    email VARCHAR(254) NOT NULL,
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires TIMESTAMP WITH TIME ZONE NOT NULL,
+    user_id uuid NOT NULL,
+    CONSTRAINT email_token_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS email_tokens_user ON email_tokens (user_id);
@@ -0,0 +1,10 @@
+-- Users are deleted after a grace period, logging in cancels it
+CREATE TABLE IF NOT EXISTS account_deletions (
+    user_id uuid PRIMARY KEY,
+    requested TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    purge_after TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- Allows downloading the export without logging in, stored hashed like access tokens
+    export_token_hash BYTEA NOT NULL UNIQUE,
+    CONSTRAINT account_deletion_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS account_deletions_purge_after ON account_deletions (purge_after);
@@ -0,0 +1,13 @@
+CREATE TYPE set_kind AS ENUM (
+    'WARM_UP',
+    'WORKING',
+    'DROP',
+    'FAILURE',
+    'AMRAP'
+);
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS kind set_kind NOT NULL DEFAULT 'WORKING';
+-- Set a drop set continues from, kept when the parent is deleted without the link
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS parent_set_id uuid REFERENCES sets(id) ON DELETE SET NULL;
+ALTER TABLE sets ADD CONSTRAINT only_drop_sets_have_parents CHECK (parent_set_id IS NULL OR kind = 'DROP');
+ALTER TABLE sets ADD CONSTRAINT not_own_parent CHECK (parent_set_id != id);
+CREATE INDEX IF NOT EXISTS sets_parent_set_id ON sets (parent_set_id);
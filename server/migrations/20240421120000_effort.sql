@@ -0,0 +1,7 @@
+-- Effort of a set as either RPE in half steps or reps in reserve
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS rpe REAL CHECK (rpe >= 6 AND rpe <= 10 AND rpe * 2 = FLOOR(rpe * 2));
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS rir integer CHECK (rir >= 0);
+ALTER TABLE sets ADD CONSTRAINT rpe_or_rir CHECK (rpe IS NULL OR rir IS NULL);
+-- Perceived exertion of the whole session and readiness before it
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS exertion integer CHECK (exertion >= 1 AND exertion <= 10);
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS readiness integer CHECK (readiness >= 1 AND readiness <= 10);
@@ -0,0 +1,20 @@
+CREATE TYPE exercise_group_kind AS ENUM (
+    'SUPERSET',
+    'CIRCUIT',
+    'GIANT_SET'
+);
+-- Exercise instances of a session done in rounds, one set of each in turn
+CREATE TABLE IF NOT EXISTS exercise_groups (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL,
+    session_id uuid NOT NULL,
+    kind exercise_group_kind NOT NULL,
+    -- Rest after each round, the rest times of the exercises are used if not set
+    rest_seconds integer CHECK (rest_seconds >= 0),
+    created TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT user_ownership FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+    CONSTRAINT parent_session FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS exercise_groups_session_id ON exercise_groups (session_id);
+ALTER TABLE exercise_instances ADD COLUMN IF NOT EXISTS group_id uuid REFERENCES exercise_groups(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS exercise_instances_group_id ON exercise_instances (group_id);
@@ -0,0 +1,19 @@
+-- Explicit order of exercise instances in a session and sets in an instance, starting
+-- from the created times. Positions go from 0 without gaps and the uniqueness is checked
+-- after each statement, so a single update can shift them.
+ALTER TABLE exercise_instances ADD COLUMN IF NOT EXISTS position integer;
+UPDATE exercise_instances SET position = ordered.position FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY session_id ORDER BY created, id) - 1 AS position
+    FROM exercise_instances
+) AS ordered WHERE exercise_instances.id = ordered.id;
+ALTER TABLE exercise_instances ALTER COLUMN position SET NOT NULL;
+ALTER TABLE exercise_instances ADD CONSTRAINT exercise_instance_position_positive CHECK (position >= 0);
+ALTER TABLE exercise_instances ADD CONSTRAINT exercise_instance_position_unique UNIQUE (session_id, position) DEFERRABLE INITIALLY IMMEDIATE;
+ALTER TABLE sets ADD COLUMN IF NOT EXISTS position integer;
+UPDATE sets SET position = ordered.position FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY exercise_instance_id ORDER BY created, id) - 1 AS position
+    FROM sets
+) AS ordered WHERE sets.id = ordered.id;
+ALTER TABLE sets ALTER COLUMN position SET NOT NULL;
+ALTER TABLE sets ADD CONSTRAINT set_position_positive CHECK (position >= 0);
+ALTER TABLE sets ADD CONSTRAINT set_position_unique UNIQUE (exercise_instance_id, position) DEFERRABLE INITIALLY IMMEDIATE;
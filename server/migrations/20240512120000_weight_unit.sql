@@ -0,0 +1,6 @@
+CREATE TYPE weight_unit AS ENUM (
+    'KG',
+    'LB'
+);
+-- Weights are always stored in kilograms, this is the unit they are shown and given in
+ALTER TABLE users ADD COLUMN IF NOT EXISTS weight_unit weight_unit NOT NULL DEFAULT 'KG';
@@ -0,0 +1,30 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+    RequestPartsExt,
+};
+use sqlx::PgPool;
+
+use crate::{api::response::RouteError, models::user::User};
+
+// User from the access token, who has to be an admin.
+// API keys can't be used for admin routes.
+pub struct Admin(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RouteError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts.extract_with_state::<User, S>(state).await?;
+
+        user.require_admin()?;
+
+        Ok(Admin(user))
+    }
+}
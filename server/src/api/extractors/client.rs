@@ -0,0 +1,125 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+
+// Longer user agents are cut, they are only shown to the user
+const MAX_USER_AGENT_LENGTH: usize = 500;
+
+// Reverse proxies whose X-Forwarded-For header is believed,
+// shared with the extractor as an extension
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(pub Arc<Vec<IpAddr>>);
+
+// Who made the request, stored with new access tokens.
+// Both are missing if the client or the proxy doesn't provide them.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        let forwarded_for = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<&str>>()
+            .join(",");
+
+        let trusted_proxies = parts
+            .extensions
+            .get::<TrustedProxies>()
+            .map(|TrustedProxies(proxies)| proxies.as_slice())
+            .unwrap_or_default();
+
+        let ip = client_ip(peer, &forwarded_for, trusted_proxies).map(|ip| ip.to_string());
+
+        Ok(ClientInfo { user_agent, ip })
+    }
+}
+
+// The socket address is the client, unless it's a trusted proxy.
+// Then the forwarded addresses are read from the closest one,
+// and the first one which isn't a trusted proxy is the client.
+fn client_ip(peer: Option<IpAddr>, forwarded_for: &str, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let mut client = peer?;
+
+    if !trusted.contains(&client) {
+        return Some(client);
+    }
+
+    for forwarded in forwarded_for.rsplit(',').map(str::trim) {
+        // Anything the proxy didn't add can't be believed
+        let Ok(address) = forwarded.parse::<IpAddr>() else {
+            break;
+        };
+
+        client = address;
+
+        if !trusted.contains(&client) {
+            break;
+        }
+    }
+
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_only_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        // Without a trusted proxy the header is ignored
+        assert_eq!(client_ip(Some(client), "198.51.100.1", &[]), Some(client));
+        assert_eq!(client_ip(Some(proxy), "198.51.100.1", &[]), Some(proxy));
+
+        // Addresses the client made up are left of the real one
+        assert_eq!(
+            client_ip(Some(proxy), "198.51.100.1, 203.0.113.7", &[proxy]),
+            Some(client)
+        );
+
+        // Chained proxies are skipped
+        let inner_proxy: IpAddr = "10.0.0.3".parse().unwrap();
+        assert_eq!(
+            client_ip(Some(proxy), "203.0.113.7, 10.0.0.3", &[proxy, inner_proxy]),
+            Some(client)
+        );
+
+        // Missing or garbled headers fall back to the last trusted address
+        assert_eq!(client_ip(Some(proxy), "", &[proxy]), Some(proxy));
+        assert_eq!(client_ip(Some(proxy), "unknown", &[proxy]), Some(proxy));
+        assert_eq!(client_ip(None, "203.0.113.7", &[proxy]), None);
+    }
+}
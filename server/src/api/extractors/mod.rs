@@ -1,2 +1,6 @@
+pub mod admin;
+pub mod client;
 pub mod json;
 pub mod path;
+pub mod query;
+pub mod scope;
@@ -0,0 +1,51 @@
+use axum::{
+    async_trait,
+    extract::{rejection::QueryRejection, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use serde::de::DeserializeOwned;
+use tracing::error;
+use validator::Validate;
+
+use crate::api::response::RouteError;
+
+// Wrapper to get custom error responses from invalid query strings
+pub struct ValidatedQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = RouteError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Query::<T>::from_request_parts(parts, state).await {
+            Ok(value) => {
+                value.0.validate()?;
+
+                Ok(Self(value.0))
+            }
+            Err(rejection) => Err(match rejection {
+                QueryRejection::FailedToDeserializeQueryString(error) => RouteError::new(
+                    format!("Invalid query string: {}.", error.body_text()),
+                    None::<&str>,
+                    error.status(),
+                ),
+                rejection => {
+                    error!(
+                        "Unimplemented query string error encountered: {}",
+                        rejection
+                    );
+
+                    RouteError::new(
+                        "Unimplemented query string error occurred. Check logs for details.",
+                        None::<&str>,
+                        StatusCode::BAD_REQUEST,
+                    )
+                }
+            }),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::marker::PhantomData;
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+    RequestPartsExt,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use sqlx::PgPool;
+
+use crate::{
+    api::response::RouteError,
+    models::{
+        api_key::{ApiKey, ApiScope},
+        user::User,
+    },
+};
+
+// Scope a route requires from API keys
+pub trait RequiredScope {
+    const SCOPE: ApiScope;
+}
+
+macro_rules! required_scope {
+    ($($name:ident),*) => {
+        $(
+            pub struct $name;
+
+            impl RequiredScope for $name {
+                const SCOPE: ApiScope = ApiScope::$name;
+            }
+        )*
+    };
+}
+
+required_scope!(
+    ExercisesRead,
+    ExercisesWrite,
+    SessionsRead,
+    SessionsWrite,
+    SetsRead,
+    SetsWrite,
+    TemplatesRead,
+    TemplatesWrite,
+    Export
+);
+
+// User authenticated with an access token, or with an API key which has the
+// required scope. Routes which only take User can't be used with API keys.
+pub struct Scoped<S: RequiredScope>(pub User, pub PhantomData<S>);
+
+#[async_trait]
+impl<S, T> FromRequestParts<S> for Scoped<T>
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+    T: RequiredScope,
+{
+    type Rejection = RouteError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user_error = match parts.extract_with_state::<User, S>(state).await {
+            Ok(user) => return Ok(Scoped(user, PhantomData)),
+            Err(user_error) => user_error,
+        };
+
+        // Missing header is reported like without API keys
+        let Ok(TypedHeader(Authorization(bearer_token))) =
+            parts.extract::<TypedHeader<Authorization<Bearer>>>().await
+        else {
+            return Err(user_error);
+        };
+
+        let pool = &PgPool::from_ref(state);
+
+        let api_key = ApiKey::from_key(bearer_token.token(), pool)
+            .await
+            .map_err(|_| user_error)?;
+
+        api_key.require_scope(T::SCOPE)?;
+
+        let user = User::from_id(api_key.user_id, pool).await?;
+
+        Ok(Scoped(user, PhantomData))
+    }
+}
@@ -1,3 +1,4 @@
 pub mod extractors;
+pub mod rate_limit;
 pub mod response;
 pub mod routes;
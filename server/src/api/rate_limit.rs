@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Request, State},
+    http::{header, request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::{info, instrument, warn};
+
+use super::{extractors::client::ClientInfo, response::RouteError};
+
+// Failed logins allowed before the backoff starts
+const FREE_ATTEMPTS_PER_USERNAME: u32 = 5;
+// Higher, because many users can share an address
+const FREE_ATTEMPTS_PER_IP: u32 = 20;
+// First lockout, doubled after every further failure
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+// Password reset emails for one address before the backoff starts
+const FREE_EMAILS_PER_ADDRESS: u32 = 3;
+const FREE_EMAILS_PER_IP: u32 = 10;
+// Failures are forgotten after this long without new ones
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(60 * 60);
+// Old entries are pruned when there are more than this many
+const MAX_TRACKED: usize = 10_000;
+// Login bodies are small, anything bigger is rejected by the route anyway
+const MAX_LOGIN_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Tracks failed logins per username and per IP address in memory
+#[derive(Debug, Default)]
+pub struct LoginLimiter {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginLimiter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(LoginLimiter::default())
+    }
+
+    // Counts the attempt as failed before it's made, so concurrent requests
+    // can't all get in before the first failure is recorded. Returns how long
+    // any of the keys is still locked out instead, without counting anything.
+    fn reserve(&self, keys: &[(String, u32)], now: Instant) -> Result<(), Duration> {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        let locked_for = keys
+            .iter()
+            .filter_map(|(key, _)| attempts.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max();
+
+        if let Some(locked_for) = locked_for {
+            return Err(locked_for);
+        }
+
+        if attempts.len() > MAX_TRACKED {
+            attempts.retain(|_, attempts| now - attempts.last_failure < ATTEMPT_WINDOW);
+        }
+
+        for (key, free_attempts) in keys {
+            let entry = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+
+            if now - entry.last_failure >= ATTEMPT_WINDOW {
+                entry.failures = 0;
+            }
+
+            entry.failures += 1;
+            entry.last_failure = now;
+            entry.locked_until =
+                lockout(entry.failures, *free_attempts).map(|lockout| now + lockout);
+        }
+
+        Ok(())
+    }
+
+    // Takes back a reserved attempt which didn't fail
+    fn release(&self, key: &str, free_attempts: u32) {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        if let Some(entry) = attempts.get_mut(key) {
+            entry.failures = entry.failures.saturating_sub(1);
+            entry.locked_until =
+                lockout(entry.failures, free_attempts).map(|lockout| entry.last_failure + lockout);
+        }
+    }
+
+    fn clear(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+// Exponential backoff once the free attempts are used
+fn lockout(failures: u32, free_attempts: u32) -> Option<Duration> {
+    if failures <= free_attempts {
+        return None;
+    }
+
+    let exponent = (failures - free_attempts - 1).min(31);
+
+    Some(
+        BASE_LOCKOUT
+            .checked_mul(2u32.pow(exponent))
+            .map_or(MAX_LOCKOUT, |lockout| lockout.min(MAX_LOCKOUT)),
+    )
+}
+
+// Middleware for routes which check a password, a code or a token.
+// Every attempt is counted by the username in the body, if any, and the client IP
+// before it's made, and taken back if it didn't fail. Locked out requests are
+// rejected without reaching the route.
+#[instrument(skip_all)]
+pub async fn limit_logins(
+    State(limiter): State<Arc<LoginLimiter>>,
+    client: ClientInfo,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, bytes) = match read_body(request).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    let mut keys = Vec::new();
+
+    if let Some(username) = body_field(&bytes, "username") {
+        keys.push((format!("username:{}", username), FREE_ATTEMPTS_PER_USERNAME));
+    }
+    if let Some(ip) = client.ip {
+        keys.push((format!("ip:{}", ip), FREE_ATTEMPTS_PER_IP));
+    }
+
+    if let Err(locked_for) = limiter.reserve(&keys, Instant::now()) {
+        return too_many_requests(locked_for, "Too many failed login attempts");
+    }
+
+    let response = next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await;
+
+    match response.status() {
+        // Wrong password, code or token, or unknown username
+        StatusCode::UNAUTHORIZED | StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => {
+            warn!("Failed login attempt");
+        }
+        status => {
+            for (key, free_attempts) in &keys {
+                // Only the username is cleared, so an attacker can't reset the IP
+                // counter with an account of their own
+                if status.is_success() && key.starts_with("username:") {
+                    limiter.clear(key);
+                } else {
+                    limiter.release(key, *free_attempts);
+                }
+            }
+        }
+    }
+
+    response
+}
+
+// Middleware for routes which send emails to the address in the body.
+// Every request is counted by the address and the client IP, whatever the result,
+// because the response doesn't tell whether an email was sent.
+#[instrument(skip_all)]
+pub async fn limit_emails(
+    State(limiter): State<Arc<LoginLimiter>>,
+    client: ClientInfo,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, bytes) = match read_body(request).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    let mut keys = Vec::new();
+
+    if let Some(email) = body_field(&bytes, "email") {
+        keys.push((format!("email:{}", email.trim()), FREE_EMAILS_PER_ADDRESS));
+    }
+    if let Some(ip) = client.ip {
+        keys.push((format!("ip:{}", ip), FREE_EMAILS_PER_IP));
+    }
+
+    if let Err(locked_for) = limiter.reserve(&keys, Instant::now()) {
+        return too_many_requests(locked_for, "Too many emails requested");
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+async fn read_body(request: Request) -> Result<(Parts, Bytes), Response> {
+    let (parts, body) = request.into_parts();
+
+    match to_bytes(body, MAX_LOGIN_BODY).await {
+        Ok(bytes) => Ok((parts, bytes)),
+        Err(_) => Err(RouteError::new(
+            "Request body is too large.",
+            None::<&str>,
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )
+        .into_response()),
+    }
+}
+
+// Lowercased string field of a JSON body. Invalid bodies are rejected by the route itself.
+fn body_field(bytes: &[u8], field: &str) -> Option<String> {
+    serde_json::from_slice::<serde_json::Value>(bytes)
+        .ok()
+        .and_then(|body| body.get(field)?.as_str().map(str::to_lowercase))
+}
+
+fn too_many_requests(locked_for: Duration, reason: &str) -> Response {
+    // Rounded up, so retrying after it is always allowed
+    let retry_after = locked_for.as_secs() + u64::from(locked_for.subsec_nanos() > 0);
+
+    info!("Request rejected for {} seconds", retry_after);
+
+    (
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        RouteError::new(
+            format!("{}, try again in {} seconds.", reason, retry_after),
+            None::<&str>,
+            StatusCode::TOO_MANY_REQUESTS,
+        ),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::test_utils::api::create_test_app;
+
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        assert_eq!(lockout(5, 5), None);
+        assert_eq!(lockout(6, 5), Some(Duration::from_secs(1)));
+        assert_eq!(lockout(8, 5), Some(Duration::from_secs(4)));
+        assert_eq!(lockout(100, 5), Some(MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn window_resets_failures() {
+        let limiter = LoginLimiter::default();
+        let start = Instant::now();
+        let keys = [("username:test".to_string(), 5)];
+
+        for _ in 0..6 {
+            assert!(limiter.reserve(&keys, start).is_ok());
+        }
+
+        assert_eq!(limiter.reserve(&keys, start), Err(Duration::from_secs(1)));
+
+        // A failure long after the previous ones starts over
+        assert!(limiter.reserve(&keys, start + ATTEMPT_WINDOW).is_ok());
+        assert!(limiter.reserve(&keys, start + ATTEMPT_WINDOW).is_ok());
+    }
+
+    #[test]
+    fn attempts_counted_before_they_finish() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+        let keys = [("username:test".to_string(), 5)];
+
+        // Concurrent attempts are locked out before any of them has failed
+        for _ in 0..6 {
+            assert!(limiter.reserve(&keys, now).is_ok());
+        }
+
+        assert!(limiter.reserve(&keys, now).is_err());
+
+        // The last one didn't fail after all
+        limiter.release("username:test", 5);
+
+        assert!(limiter.reserve(&keys, now).is_ok());
+    }
+
+    #[sqlx::test]
+    async fn lockout_after_failures(pool: PgPool) {
+        let (server, _, _) = create_test_app(&pool).await;
+
+        let wrong_login = json!({
+            "username": "test",
+            "password": "wrong_password",
+            "validity_in_seconds": 60
+        });
+
+        for _ in 0..FREE_ATTEMPTS_PER_USERNAME + 1 {
+            server
+                .post("/api/access_token")
+                .json(&wrong_login)
+                .await
+                .assert_status(StatusCode::UNAUTHORIZED);
+        }
+
+        // Even the right password is rejected during the lockout
+        let response = server
+            .post("/api/access_token/pair")
+            .json(&json!({ "username": "TEST", "password": "testuserpassword" }))
+            .await;
+
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header(header::RETRY_AFTER), "1");
+
+        // Other usernames are not affected
+        server
+            .post("/api/access_token")
+            .json(&json!({
+                "username": "someone_else",
+                "password": "wrong_password",
+                "validity_in_seconds": 60
+            }))
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+}
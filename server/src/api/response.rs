@@ -9,8 +9,25 @@ use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::models::{
-    access_token::AccessToken, exercise::Exercise, exercise_instance::ExerciseInstance,
-    session::Session, set::Set, user::User,
+    access_token::{AccessToken, AccessTokenInfo},
+    account_deletion::AccountDeletion,
+    admin::UsageStats,
+    analytics::ExerciseAnalytics,
+    api_key::ApiKey,
+    exercise::Exercise,
+    exercise_group::ExerciseGroup,
+    exercise_instance::ExerciseInstance,
+    import::ImportSummary,
+    invite_code::InviteCode,
+    oidc::{OidcAuthorization, OidcIdentity},
+    personal_record::ExerciseRecords,
+    refresh_token::TokenPair,
+    rest_timer::RestTimer,
+    session::{Session, SessionPage},
+    set::Set,
+    template::Template,
+    totp::TotpEnrollment,
+    user::User,
 };
 
 // Reponse to a successful API request
@@ -18,7 +35,7 @@ use crate::models::{
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
 // All different generics appering in API documentation have
 // to be defined manually because some utoipa limitation...
-#[aliases(RouteSuccessUuid = RouteSuccess<Uuid>, RouteSuccessString = RouteSuccess<String>, RouteSuccessAccessToken = RouteSuccess<AccessToken>, RouteSuccessUser = RouteSuccess<User>, RouteSuccessExercise = RouteSuccess<Exercise>, RouteSuccessExerciseVec = RouteSuccess<Vec<Exercise>>, RouteSuccessSession = RouteSuccess<Session>, RouteSuccessSessionVec = RouteSuccess<Vec<Session>>, RouteSuccessExerciseInstance = RouteSuccess<ExerciseInstance>, RouteSuccessExerciseInstanceVec = RouteSuccess<Vec<ExerciseInstance>>, RouteSuccessUsize = RouteSuccess<usize>, RouteSuccessSet = RouteSuccess<Set>)]
+#[aliases(RouteSuccessUuid = RouteSuccess<Uuid>, RouteSuccessString = RouteSuccess<String>, RouteSuccessAccessToken = RouteSuccess<AccessToken>, RouteSuccessAccessTokenInfoVec = RouteSuccess<Vec<AccessTokenInfo>>, RouteSuccessUser = RouteSuccess<User>, RouteSuccessExercise = RouteSuccess<Exercise>, RouteSuccessExerciseVec = RouteSuccess<Vec<Exercise>>, RouteSuccessSession = RouteSuccess<Session>, RouteSuccessSessionVec = RouteSuccess<Vec<Session>>, RouteSuccessSessionPage = RouteSuccess<SessionPage>, RouteSuccessExerciseInstance = RouteSuccess<ExerciseInstance>, RouteSuccessExerciseInstanceVec = RouteSuccess<Vec<ExerciseInstance>>, RouteSuccessExerciseGroup = RouteSuccess<ExerciseGroup>, RouteSuccessUsize = RouteSuccess<usize>, RouteSuccessSet = RouteSuccess<Set>, RouteSuccessTemplate = RouteSuccess<Template>, RouteSuccessTemplateVec = RouteSuccess<Vec<Template>>, RouteSuccessExerciseRecords = RouteSuccess<ExerciseRecords>, RouteSuccessExerciseAnalytics = RouteSuccess<ExerciseAnalytics>, RouteSuccessImportSummary = RouteSuccess<ImportSummary>, RouteSuccessRestTimer = RouteSuccess<RestTimer>, RouteSuccessTokenPair = RouteSuccess<TokenPair>, RouteSuccessApiKey = RouteSuccess<ApiKey>, RouteSuccessApiKeyVec = RouteSuccess<Vec<ApiKey>>, RouteSuccessTotpEnrollment = RouteSuccess<TotpEnrollment>, RouteSuccessStringVec = RouteSuccess<Vec<String>>, RouteSuccessOidcAuthorization = RouteSuccess<OidcAuthorization>, RouteSuccessOidcIdentity = RouteSuccess<OidcIdentity>, RouteSuccessUserVec = RouteSuccess<Vec<User>>, RouteSuccessUsageStats = RouteSuccess<UsageStats>, RouteSuccessInviteCode = RouteSuccess<InviteCode>, RouteSuccessInviteCodeVec = RouteSuccess<Vec<InviteCode>>, RouteSuccessAccountDeletion = RouteSuccess<AccountDeletion>)]
 pub struct RouteSuccess<D>
 where
     D: Serialize + Debug,
@@ -110,10 +127,7 @@ impl RouteError {
         RouteError {
             errors: vec![SingleRouteError {
                 msg: msg.to_string(),
-                field: match field {
-                    Some(field) => Some(field.to_string()),
-                    None => None,
-                },
+                field: field.map(|field| field.to_string()),
             }],
             status,
         }
@@ -126,10 +140,7 @@ impl RouteError {
 
         self.errors.push(SingleRouteError {
             msg: msg.to_string(),
-            field: match field {
-                Some(field) => Some(field.to_string()),
-                None => None,
-            },
+            field: field.map(|field| field.to_string()),
         });
     }
 
@@ -242,7 +253,7 @@ impl From<validator::ValidationErrors> for RouteError {
                         Some(message) => {
                             if i == errors.len() - 1 {
                                 // Last error
-                                formatted_messages = formatted_messages + message.as_ref();
+                                formatted_messages += message.as_ref();
                             } else {
                                 // Still other errors left
                                 formatted_messages = formatted_messages + &format!("{}, ", message);
@@ -251,21 +262,21 @@ impl From<validator::ValidationErrors> for RouteError {
                         None => {
                             warn!("Encountered a validation error without an error message");
 
-                            formatted_messages = formatted_messages + "invalid input";
+                            formatted_messages += "invalid input";
                         }
                     }
                 }
             } else if errors.len() == 1 {
                 // Just one validation error
-                match errors.get(0) {
+                match errors.first() {
                     Some(error) => match error.message.to_owned() {
                         Some(message) => {
-                            formatted_messages = formatted_messages + message.as_ref();
+                            formatted_messages += message.as_ref();
                         }
                         None => {
                             warn!("Encountered a validation error without an error message");
 
-                            formatted_messages = formatted_messages + "invalid input";
+                            formatted_messages += "invalid input";
                         }
                     },
                     None => {
@@ -332,8 +343,8 @@ mod tests {
         let response = RouteError::new(msg, field.clone(), status);
 
         assert_eq!(response.status, status);
-        assert_eq!(response.errors.get(0).unwrap().field, field);
-        assert_eq!(response.errors.get(0).unwrap().msg, msg);
+        assert_eq!(response.errors.first().unwrap().field, field);
+        assert_eq!(response.errors.first().unwrap().msg, msg);
     }
 
     // RouteSuccess should be able to be converted into an axum HTTP response
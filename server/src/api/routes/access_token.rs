@@ -3,21 +3,28 @@ use chrono::Duration;
 use serde::Deserialize;
 use sqlx::PgPool;
 use utoipa::ToSchema;
+use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
     api::{
-        extractors::{json::ValidatedJson, path::Path},
+        extractors::{client::ClientInfo, json::ValidatedJson, path::Path},
         response::{RouteResponse, RouteSuccess},
         routes::user::REGEX_USERNAME,
     },
-    models::{access_token::AccessToken, user::User},
+    models::{
+        access_token::{self, AccessToken, AccessTokenInfo, TokenMetadata},
+        account_deletion,
+        refresh_token::{TokenPair, ACCESS_TOKEN_VALIDITY_SECONDS},
+        totp,
+        user::User,
+    },
 };
 
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct CreateAccessTokenInput {
     #[validate(regex(
-        path = "REGEX_USERNAME",
+        path = *REGEX_USERNAME,
         message = "only letters a-z, A-Z, numbers, - and _ are allowed"
     ))]
     #[schema(example = "some_username")]
@@ -32,6 +39,14 @@ pub struct CreateAccessTokenInput {
     ))]
     #[schema(example = "600")]
     validity_in_seconds: i64,
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    #[schema(example = "Phone")]
+    device_name: Option<String>,
+    // Required when two-factor authentication is enabled, either from
+    // the authenticator app or a recovery code
+    #[validate(length(min = 1, max = 20, message = "must be between 1 and 20 characters"))]
+    #[schema(example = "123456")]
+    code: Option<String>,
 }
 
 #[utoipa::path(
@@ -40,22 +55,128 @@ pub struct CreateAccessTokenInput {
     request_body = CreateAccessTokenInput,
     responses(
         (status = OK, description = "New token created", body = RouteSuccessAccessToken),
-        (status = UNAUTHORIZED, description = "Wrong password", body = RouteError),
+        (status = UNAUTHORIZED, description = "Wrong password or two-factor code", body = RouteError),
+        (status = FORBIDDEN, description = "Two-factor code required", body = RouteError),
         (status = NOT_FOUND, description = "Username not found", body = RouteError),
+        (status = TOO_MANY_REQUESTS, description = "Too many failed attempts, see the Retry-After header", body = RouteError),
         (status = INTERNAL_SERVER_ERROR, description = "Password hashing failed", body = RouteError)
     )
 )]
 pub async fn create_access_token(
     State(pool): State<PgPool>,
+    client: ClientInfo,
     ValidatedJson(body): ValidatedJson<CreateAccessTokenInput>,
 ) -> RouteResponse<AccessToken> {
     let user = User::from_credentials(body.username, body.password, &pool).await?;
 
-    let duration = Duration::seconds(body.validity_in_seconds.into());
+    totp::verify_login(user.id, body.code.as_deref(), &pool).await?;
+    account_deletion::cancel(user.id, &pool).await?;
+
+    let duration = Duration::seconds(body.validity_in_seconds);
+
+    let metadata = TokenMetadata {
+        device_name: body.device_name,
+        user_agent: client.user_agent,
+        ip: client.ip,
+    };
 
     Ok(RouteSuccess::new(
         "New access token created.",
-        AccessToken::new(user.id, duration, &pool).await?,
+        AccessToken::new(user.id, duration, metadata, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct CreateTokenPairInput {
+    #[validate(regex(
+        path = *REGEX_USERNAME,
+        message = "only letters a-z, A-Z, numbers, - and _ are allowed"
+    ))]
+    #[schema(example = "some_username")]
+    username: String,
+    #[validate(length(min = 10, max = 200, message = "must be between 10 and 200 characters"))]
+    #[schema(example = "strong_password_with_at_least_10_characters")]
+    password: String,
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    #[schema(example = "Phone")]
+    device_name: Option<String>,
+    // Required when two-factor authentication is enabled, either from
+    // the authenticator app or a recovery code
+    #[validate(length(min = 1, max = 20, message = "must be between 1 and 20 characters"))]
+    #[schema(example = "123456")]
+    code: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/access_token/pair",
+    request_body = CreateTokenPairInput,
+    responses(
+        (status = OK, description = "New access and refresh token created", body = RouteSuccessTokenPair),
+        (status = UNAUTHORIZED, description = "Wrong password or two-factor code", body = RouteError),
+        (status = FORBIDDEN, description = "Two-factor code required", body = RouteError),
+        (status = NOT_FOUND, description = "Username not found", body = RouteError),
+        (status = TOO_MANY_REQUESTS, description = "Too many failed attempts, see the Retry-After header", body = RouteError),
+        (status = INTERNAL_SERVER_ERROR, description = "Password hashing failed", body = RouteError)
+    )
+)]
+pub async fn create_token_pair(
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    ValidatedJson(body): ValidatedJson<CreateTokenPairInput>,
+) -> RouteResponse<TokenPair> {
+    let user = User::from_credentials(body.username, body.password, &pool).await?;
+
+    totp::verify_login(user.id, body.code.as_deref(), &pool).await?;
+    account_deletion::cancel(user.id, &pool).await?;
+
+    let metadata = TokenMetadata {
+        device_name: body.device_name,
+        user_agent: client.user_agent,
+        ip: client.ip,
+    };
+
+    Ok(RouteSuccess::new(
+        format!(
+            "New access token valid for {} seconds and refresh token created.",
+            ACCESS_TOKEN_VALIDITY_SECONDS
+        ),
+        TokenPair::new(user.id, metadata, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct RefreshTokenInput {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    refresh_token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/access_token/refresh",
+    request_body = RefreshTokenInput,
+    responses(
+        (status = OK, description = "Tokens rotated", body = RouteSuccessTokenPair),
+        (status = FORBIDDEN, description = "Invalid, expired or already used refresh token", body = RouteError),
+        (status = BAD_REQUEST, description = "Refresh token missing or malformed", body = RouteError)
+    )
+)]
+pub async fn refresh_token_pair(
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    ValidatedJson(body): ValidatedJson<RefreshTokenInput>,
+) -> RouteResponse<TokenPair> {
+    let metadata = TokenMetadata {
+        device_name: None,
+        user_agent: client.user_agent,
+        ip: client.ip,
+    };
+
+    Ok(RouteSuccess::new(
+        "Access and refresh token rotated.",
+        TokenPair::refresh(body.refresh_token, metadata, &pool).await?,
         StatusCode::OK,
     ))
 }
@@ -88,16 +209,91 @@ pub async fn delete_token(
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/access_token/all",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Unexpired tokens of the user", body = RouteSuccessAccessTokenInfoVec),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
+    )
+)]
+pub async fn get_user_tokens(
+    access_token: AccessToken,
+    State(pool): State<PgPool>,
+) -> RouteResponse<Vec<AccessTokenInfo>> {
+    Ok(RouteSuccess::new(
+        "Returned the user's access tokens.",
+        access_token::all_user_tokens(access_token.user_id, access_token.id, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/access_token/id/{token_id}",
+    params(
+        ("token_id" = Uuid, Path, description = "The ID of the token being revoked")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Token revoked", body = RouteSuccessUuid),
+        (status = NOT_FOUND, description = "Token not found", body = RouteError),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
+    )
+)]
+pub async fn revoke_token_by_id(
+    user: User,
+    State(pool): State<PgPool>,
+    Path(token_id): Path<Uuid>,
+) -> RouteResponse<Uuid> {
+    Ok(RouteSuccess::new(
+        "Access token revoked.",
+        access_token::revoke_by_id(user.id, token_id, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/access_token/all",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Logged out everywhere, returns the amount of revoked tokens", body = RouteSuccessUsize),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
+    )
+)]
+pub async fn revoke_all_tokens(user: User, State(pool): State<PgPool>) -> RouteResponse<usize> {
+    Ok(RouteSuccess::new(
+        "Logged out everywhere.",
+        access_token::revoke_all(user.id, None, &pool).await? as usize,
+        StatusCode::OK,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
-    use axum::http::{HeaderName, HeaderValue};
+    use axum::http::{HeaderName, HeaderValue, StatusCode};
     use axum_test::TestServer;
     use serde_json::json;
     use sqlx::PgPool;
 
     use crate::{
         api::response::RouteSuccess,
-        models::{access_token::AccessToken, user::User},
+        models::{
+            access_token::{AccessToken, AccessTokenInfo},
+            refresh_token::TokenPair,
+            user::User,
+        },
         test_utils::api::create_test_app,
     };
 
@@ -194,6 +390,111 @@ mod tests {
         invalid_token_response.assert_status_failure();
     }
 
+    #[sqlx::test]
+    async fn refresh_and_reuse(pool: PgPool) {
+        let (mut server, _, _) = create_test_app(&pool).await;
+        server.clear_headers();
+
+        let first = server
+            .post("/api/access_token/pair")
+            .json(&json!({
+                "username": "test",
+                "password": "testuserpassword"
+            }))
+            .await
+            .json::<RouteSuccess<TokenPair>>()
+            .data;
+
+        let second = server
+            .post("/api/access_token/refresh")
+            .json(&json!({ "refresh_token": first.refresh_token.token }))
+            .await
+            .json::<RouteSuccess<TokenPair>>()
+            .data;
+
+        server
+            .get("/api/user")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_bytes(format!("Bearer {}", second.access_token.token).as_bytes())
+                    .unwrap(),
+            )
+            .await
+            .assert_status_success();
+
+        // Replaying the first refresh token revokes the second pair too
+        server
+            .post("/api/access_token/refresh")
+            .json(&json!({ "refresh_token": first.refresh_token.token }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .get("/api/user")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_bytes(format!("Bearer {}", second.access_token.token).as_bytes())
+                    .unwrap(),
+            )
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn list_and_revoke(pool: PgPool) {
+        let (server, _, current_token) = create_test_app(&pool).await;
+
+        let phone_token = server
+            .post("/api/access_token")
+            .add_header(
+                HeaderName::from_static("user-agent"),
+                HeaderValue::from_static("LiftLog Android"),
+            )
+            .json(&json!({
+                "username": "test",
+                "password": "testuserpassword",
+                "validity_in_seconds": 60,
+                "device_name": "Phone"
+            }))
+            .await
+            .json::<RouteSuccess<AccessToken>>()
+            .data;
+
+        let tokens = server
+            .get("/api/access_token/all")
+            .await
+            .json::<RouteSuccess<Vec<AccessTokenInfo>>>()
+            .data;
+
+        assert_eq!(tokens.len(), 2);
+
+        let phone = tokens.iter().find(|t| t.id == phone_token.id).unwrap();
+
+        assert_eq!(phone.device_name.as_deref(), Some("Phone"));
+        assert_eq!(phone.user_agent.as_deref(), Some("LiftLog Android"));
+        assert!(!phone.current);
+        assert!(tokens.iter().any(|t| t.id == current_token.id && t.current));
+
+        server
+            .delete(&format!("/api/access_token/id/{}", phone_token.id))
+            .await
+            .assert_status_success();
+
+        // Already revoked
+        server
+            .delete(&format!("/api/access_token/id/{}", phone_token.id))
+            .await
+            .assert_status_not_found();
+
+        // Log out everywhere, including the current token
+        server
+            .delete("/api/access_token/all")
+            .await
+            .assert_status_success();
+
+        server.get("/api/user").await.assert_status_failure();
+    }
+
     #[sqlx::test]
     async fn too_long_validity(pool: PgPool) {
         let (mut server, _, _) = create_test_app(&pool).await;
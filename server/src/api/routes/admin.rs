@@ -0,0 +1,419 @@
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::{admin::Admin, json::ValidatedJson, path::Path},
+        response::{RouteResponse, RouteSuccess},
+    },
+    models::{
+        admin::{self, UsageStats},
+        invite_code::{self, InviteCode},
+        user::{User, UserRole},
+    },
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/user/all",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Every user of the instance", body = RouteSuccessUserVec),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin", body = RouteError),
+    )
+)]
+pub async fn get_users(_admin: Admin, State(pool): State<PgPool>) -> RouteResponse<Vec<User>> {
+    Ok(RouteSuccess::new(
+        "Returned all users.",
+        admin::all_users(&pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct UpdateUserInput {
+    #[schema(example = "admin")]
+    role: Option<UserRole>,
+    #[schema(example = true)]
+    disabled: Option<bool>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/admin/user/{user_id}",
+    request_body = UpdateUserInput,
+    params(
+        ("user_id" = Uuid, Path, description = "The ID of the user being updated")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "User updated, disabled users are logged out", body = RouteSuccessUser),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin, or tried to update self", body = RouteError),
+        (status = NOT_FOUND, description = "User not found", body = RouteError),
+    )
+)]
+pub async fn update_user(
+    Admin(admin_user): Admin,
+    State(pool): State<PgPool>,
+    Path(user_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<UpdateUserInput>,
+) -> RouteResponse<User> {
+    Ok(RouteSuccess::new(
+        "User updated.",
+        admin::update_user(admin_user.id, user_id, body.role, body.disabled, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct ResetPasswordInput {
+    #[validate(length(min = 10, max = 200, message = "must be between 10 and 200 characters"))]
+    #[schema(example = "strong_password_with_at_least_10_characters")]
+    new_password: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/user/{user_id}/password",
+    request_body = ResetPasswordInput,
+    params(
+        ("user_id" = Uuid, Path, description = "The ID of the user whose password is reset")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Password reset and the user logged out", body = RouteSuccessUser),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin", body = RouteError),
+        (status = NOT_FOUND, description = "User not found", body = RouteError),
+    )
+)]
+pub async fn reset_password(
+    _admin: Admin,
+    State(pool): State<PgPool>,
+    Path(user_id): Path<Uuid>,
+    ValidatedJson(body): ValidatedJson<ResetPasswordInput>,
+) -> RouteResponse<User> {
+    Ok(RouteSuccess::new(
+        "Password reset.",
+        admin::reset_password(user_id, body.new_password, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/stats",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Usage of the instance", body = RouteSuccessUsageStats),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin", body = RouteError),
+    )
+)]
+pub async fn get_stats(_admin: Admin, State(pool): State<PgPool>) -> RouteResponse<UsageStats> {
+    Ok(RouteSuccess::new(
+        "Returned the usage statistics.",
+        admin::usage_stats(&pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/invite_code",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "New invite code, the code is only returned now", body = RouteSuccessInviteCode),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin", body = RouteError),
+    )
+)]
+pub async fn create_invite_code(
+    Admin(admin_user): Admin,
+    State(pool): State<PgPool>,
+) -> RouteResponse<InviteCode> {
+    Ok(RouteSuccess::new(
+        "New invite code created.",
+        InviteCode::new(admin_user.id, &pool).await?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/invite_code/all",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Used and unused invite codes", body = RouteSuccessInviteCodeVec),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = FORBIDDEN, description = "Not an admin", body = RouteError),
+    )
+)]
+pub async fn get_invite_codes(
+    _admin: Admin,
+    State(pool): State<PgPool>,
+) -> RouteResponse<Vec<InviteCode>> {
+    Ok(RouteSuccess::new(
+        "Returned the invite codes.",
+        invite_code::all_invite_codes(&pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        api::{
+            response::RouteSuccess,
+            routes::{build_router, RouterOptions},
+        },
+        models::{
+            access_token::AccessToken,
+            admin::UsageStats,
+            api_key::{ApiKey, ApiScope},
+            invite_code::InviteCode,
+            user::{User, UserRole},
+        },
+        settings::Registration,
+        test_utils::api::{create_test_app, get_auth_header, test_server},
+    };
+
+    fn login(username: &str) -> serde_json::Value {
+        json!({
+            "username": username,
+            "password": "otheruserpassword",
+            "validity_in_seconds": 60
+        })
+    }
+
+    #[sqlx::test]
+    async fn manage_users(pool: PgPool) {
+        // First user is the admin
+        let (server, admin_user, _) = create_test_app(&pool).await;
+        let other_server = test_server(&pool);
+
+        assert_eq!(admin_user.role, UserRole::Admin);
+
+        let other_user = other_server
+            .post("/api/user")
+            .json(&json!({ "username": "other", "password": "otheruserpassword" }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert_eq!(other_user.role, UserRole::User);
+
+        let access_token = other_server
+            .post("/api/access_token")
+            .json(&login("other"))
+            .await
+            .json::<RouteSuccess<AccessToken>>()
+            .data;
+        let (auth_name, auth_value) = get_auth_header(&access_token);
+
+        // Normal users can't use admin routes
+        other_server
+            .get("/api/admin/stats")
+            .add_header(auth_name.clone(), auth_value.clone())
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let users = server
+            .get("/api/admin/user/all")
+            .await
+            .json::<RouteSuccess<Vec<User>>>()
+            .data;
+
+        assert_eq!(users.len(), 2);
+
+        let stats = server
+            .get("/api/admin/stats")
+            .await
+            .json::<RouteSuccess<UsageStats>>()
+            .data;
+
+        assert_eq!(stats.users, 2);
+        assert_eq!(stats.admins, 1);
+
+        // Can't lock themselves out
+        server
+            .patch(&format!("/api/admin/user/{}", admin_user.id))
+            .json(&json!({ "disabled": true }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let disabled = server
+            .patch(&format!("/api/admin/user/{}", other_user.id))
+            .json(&json!({ "disabled": true }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert!(disabled.disabled);
+
+        // Logged out and can't log in again
+        other_server
+            .get("/api/user")
+            .add_header(auth_name.clone(), auth_value.clone())
+            .await
+            .assert_status_not_ok();
+        other_server
+            .post("/api/access_token")
+            .json(&login("other"))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .patch(&format!("/api/admin/user/{}", other_user.id))
+            .json(&json!({ "disabled": false }))
+            .await
+            .assert_status_ok();
+
+        let api_key = ApiKey::new(
+            other_user.id,
+            "Script",
+            vec![ApiScope::ExercisesRead],
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        server
+            .post(&format!("/api/admin/user/{}/password", other_user.id))
+            .json(&json!({ "new_password": "resetuserpassword" }))
+            .await
+            .assert_status_ok();
+
+        other_server
+            .post("/api/access_token")
+            .json(&login("other"))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        assert!(ApiKey::from_key(api_key.key.unwrap(), &pool).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn invite_only(pool: PgPool) {
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    registration: Registration::InviteOnly,
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        // The first user doesn't need a code
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "admin", "password": "otheruserpassword" }))
+            .await
+            .assert_status_ok();
+
+        let access_token = server
+            .post("/api/access_token")
+            .json(&login("admin"))
+            .await
+            .json::<RouteSuccess<AccessToken>>()
+            .data;
+        let (auth_name, auth_value) = get_auth_header(&access_token);
+
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "other", "password": "otheruserpassword" }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let invite_code = server
+            .post("/api/admin/invite_code")
+            .add_header(auth_name.clone(), auth_value.clone())
+            .await
+            .json::<RouteSuccess<InviteCode>>()
+            .data;
+
+        let code = invite_code.code.unwrap();
+
+        // Failing to create the user doesn't use the code
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "admin", "password": "otheruserpassword", "invite_code": code }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        let other_user = server
+            .post("/api/user")
+            .json(&json!({ "username": "other", "password": "otheruserpassword", "invite_code": code }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "third", "password": "otheruserpassword", "invite_code": code }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let invite_codes = server
+            .get("/api/admin/invite_code/all")
+            .add_header(auth_name.clone(), auth_value.clone())
+            .await
+            .json::<RouteSuccess<Vec<InviteCode>>>()
+            .data;
+
+        assert_eq!(invite_codes[0].used_by, Some(other_user.id));
+        assert!(invite_codes[0].code.is_none());
+    }
+
+    #[sqlx::test]
+    async fn closed(pool: PgPool) {
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    registration: Registration::Closed,
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "admin", "password": "otheruserpassword" }))
+            .await
+            .assert_status_ok();
+
+        server
+            .post("/api/user")
+            .json(&json!({ "username": "other", "password": "otheruserpassword" }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
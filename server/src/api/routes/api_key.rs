@@ -0,0 +1,196 @@
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::{json::ValidatedJson, path::Path},
+        response::{RouteResponse, RouteSuccess},
+    },
+    models::{
+        api_key::{self, ApiKey, ApiScope},
+        user::User,
+    },
+};
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct CreateApiKeyInput {
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    #[schema(example = "Home dashboard")]
+    name: String,
+    #[validate(length(min = 1, message = "at least one scope is required"))]
+    #[schema(example = json!(["sessions:read", "sets:read"]))]
+    scopes: Vec<ApiScope>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/api_key",
+    request_body = CreateApiKeyInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "New API key created, the key is only returned now", body = RouteSuccessApiKey),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid input for API key", body = RouteError),
+    )
+)]
+pub async fn create_api_key(
+    user: User,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<CreateApiKeyInput>,
+) -> RouteResponse<ApiKey> {
+    Ok(RouteSuccess::new(
+        "New API key created.",
+        ApiKey::new(user.id, body.name, body.scopes, &pool).await?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/api_key/all",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "API keys of the user", body = RouteSuccessApiKeyVec),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+    )
+)]
+pub async fn get_user_api_keys(
+    user: User,
+    State(pool): State<PgPool>,
+) -> RouteResponse<Vec<ApiKey>> {
+    Ok(RouteSuccess::new(
+        "Returned the user's API keys.",
+        api_key::all_user_api_keys(user.id, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/api_key/{api_key_id}",
+    params(
+        ("api_key_id" = Uuid, Path, description = "The ID of the API key being revoked")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "API key revoked", body = RouteSuccessUuid),
+        (status = NOT_FOUND, description = "API key not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+    )
+)]
+pub async fn revoke_api_key(
+    user: User,
+    State(pool): State<PgPool>,
+    Path(api_key_id): Path<Uuid>,
+) -> RouteResponse<Uuid> {
+    Ok(RouteSuccess::new(
+        "API key revoked.",
+        api_key::revoke_api_key(user.id, api_key_id, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderName, HeaderValue, StatusCode};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        api::response::RouteSuccess,
+        models::{api_key::ApiKey, session::Session},
+        test_utils::api::create_test_scenario,
+    };
+
+    #[sqlx::test]
+    async fn scopes_enforced(pool: PgPool) {
+        let (mut server, _, _, _, session, _, _) = create_test_scenario(&pool).await;
+
+        let response = server
+            .post("/api/api_key")
+            .json(&json!({ "name": "Dashboard", "scopes": ["sessions:read"] }))
+            .await;
+
+        response.assert_status(StatusCode::CREATED);
+
+        let api_key = response.json::<RouteSuccess<ApiKey>>().data;
+        let key = api_key.key.unwrap();
+
+        server.clear_headers();
+        server.add_header(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_bytes(format!("Bearer {}", key).as_bytes()).unwrap(),
+        );
+
+        // Allowed by the scope
+        let response = server.get(&format!("/api/session/{}", session.id)).await;
+
+        response.assert_status(StatusCode::FOUND);
+        assert_eq!(response.json::<RouteSuccess<Session>>().data.id, session.id);
+
+        // Missing the scope
+        server
+            .delete(&format!("/api/session/{}", session.id))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        // Only for access tokens
+        server.delete("/api/user").await.assert_status_failure();
+        server.get("/api/api_key/all").await.assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn list_and_revoke(pool: PgPool) {
+        let (mut server, _, _, _, session, _, _) = create_test_scenario(&pool).await;
+
+        server
+            .post("/api/api_key")
+            .json(&json!({ "name": "Nothing", "scopes": [] }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        let api_key = server
+            .post("/api/api_key")
+            .json(&json!({ "name": "Script", "scopes": ["sessions:read", "export"] }))
+            .await
+            .json::<RouteSuccess<ApiKey>>()
+            .data;
+
+        let response = server.get("/api/api_key/all").await;
+
+        response.assert_status_success();
+
+        let listed = response.json::<RouteSuccess<Vec<ApiKey>>>().data;
+
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, api_key.id);
+        // The key itself isn't shown again
+        assert!(listed[0].key.is_none());
+
+        server
+            .delete(&format!("/api/api_key/{}", api_key.id))
+            .await
+            .assert_status_success();
+
+        server.clear_headers();
+        server.add_header(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_bytes(format!("Bearer {}", api_key.key.unwrap()).as_bytes()).unwrap(),
+        );
+
+        server
+            .get(&format!("/api/session/{}", session.id))
+            .await
+            .assert_status_failure();
+    }
+}
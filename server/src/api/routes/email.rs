@@ -0,0 +1,437 @@
+use axum::{extract::State, http::StatusCode, Extension};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::json::ValidatedJson,
+        response::{RouteResponse, RouteSuccess},
+    },
+    mailer::SharedMailer,
+    models::{email, user::User},
+};
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct ChangeEmailInput {
+    // Null removes the email
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = 254, message = "must be at most 254 characters")
+    )]
+    #[schema(example = "someone@example.com")]
+    email: Option<String>,
+    // Current password, so a stolen access token can't take over the account
+    #[validate(length(min = 1, max = 200, message = "must be between 1 and 200 characters"))]
+    #[schema(example = "current_password")]
+    password: String,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/user/email",
+    request_body = ChangeEmailInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Email changed, a verification code was sent to it", body = RouteSuccessUser),
+        (status = UNAUTHORIZED, description = "Invalid authorization token or wrong password", body = RouteError),
+        (status = CONFLICT, description = "Email used by another user", body = RouteError),
+        (status = INTERNAL_SERVER_ERROR, description = "Sending the email failed", body = RouteError),
+    )
+)]
+pub async fn change_email(
+    mut user: User,
+    Extension(mailer): Extension<SharedMailer>,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<ChangeEmailInput>,
+) -> RouteResponse<User> {
+    email::change_email(
+        &mut user,
+        body.email,
+        &body.password,
+        mailer.as_ref(),
+        &pool,
+    )
+    .await?;
+
+    Ok(RouteSuccess::new("Email changed.", user, StatusCode::OK))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct EmailTokenInput {
+    // Code from the email
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/email/verify",
+    request_body = EmailTokenInput,
+    responses(
+        (status = OK, description = "Email verified", body = RouteSuccessUser),
+        (status = BAD_REQUEST, description = "Invalid, expired or used code", body = RouteError),
+    )
+)]
+// Doesn't need an access token, so the code works on any device
+pub async fn verify_email(
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<EmailTokenInput>,
+) -> RouteResponse<User> {
+    Ok(RouteSuccess::new(
+        "Email verified.",
+        email::verify_email(&body.token, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct RequestPasswordResetInput {
+    #[validate(
+        email(message = "must be a valid email address"),
+        length(max = 254, message = "must be at most 254 characters")
+    )]
+    #[schema(example = "someone@example.com")]
+    email: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/password_reset",
+    request_body = RequestPasswordResetInput,
+    responses(
+        (status = OK, description = "Reset code sent if an user has the verified email", body = RouteSuccessString),
+        (status = TOO_MANY_REQUESTS, description = "Too many resets requested for the email or from the address", body = RouteError),
+    )
+)]
+pub async fn request_password_reset(
+    Extension(mailer): Extension<SharedMailer>,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<RequestPasswordResetInput>,
+) -> RouteResponse<String> {
+    let email = body.email.clone();
+
+    // Sent in the background, so neither the response nor its timing tells
+    // whether an user has the email
+    tokio::spawn(async move {
+        if let Err(reset_error) =
+            email::request_password_reset(&email, mailer.as_ref(), &pool).await
+        {
+            error!("Failed to send a password reset code: {:?}", reset_error);
+        }
+    });
+
+    Ok(RouteSuccess::new(
+        "If an user has the verified email, a reset code was sent to it.",
+        body.email,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct ResetPasswordWithTokenInput {
+    // Code from the email
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    token: String,
+    #[validate(length(min = 10, max = 200, message = "must be between 10 and 200 characters"))]
+    #[schema(example = "strong_password_with_at_least_10_characters")]
+    new_password: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/password_reset/confirm",
+    request_body = ResetPasswordWithTokenInput,
+    responses(
+        (status = OK, description = "Password changed and logged out everywhere", body = RouteSuccessUuid),
+        (status = BAD_REQUEST, description = "Invalid, expired or used code", body = RouteError),
+        (status = FORBIDDEN, description = "Account disabled", body = RouteError),
+    )
+)]
+pub async fn reset_password(
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<ResetPasswordWithTokenInput>,
+) -> RouteResponse<Uuid> {
+    let user = email::reset_password(&body.token, body.new_password, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Password changed, log in with the new password.",
+        user.id,
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::{
+        api::{
+            response::RouteSuccess,
+            routes::{build_router, RouterOptions},
+        },
+        mailer::LogMailer,
+        models::{
+            access_token::AccessToken,
+            api_key::{ApiKey, ApiScope},
+            user::User,
+        },
+        test_utils::api::{create_test_access_token, get_auth_header},
+    };
+
+    // Server which writes emails to a new file
+    fn server_with_mail_file(pool: &PgPool) -> (TestServer, PathBuf) {
+        let file = env::temp_dir().join(format!("liftlog-mail-{}.txt", Uuid::new_v4()));
+        let mailer = Arc::new(LogMailer {
+            file: Some(file.clone()),
+        });
+
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    mailer,
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        (server, file)
+    }
+
+    // Reset codes are sent in the background, so wait until the file has this many emails
+    async fn wait_for_emails(file: &PathBuf, count: usize) {
+        for _ in 0..100 {
+            let sent = fs::read_to_string(file)
+                .map(|mail| mail.matches("To: ").count())
+                .unwrap_or(0);
+
+            if sent >= count {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("Expected {} emails", count);
+    }
+
+    // The code is on its own line after the line ending with a colon
+    fn last_code(file: &PathBuf) -> String {
+        let mail = fs::read_to_string(file).unwrap();
+        let lines: Vec<&str> = mail.lines().collect();
+
+        let position = lines.iter().rposition(|line| line.ends_with(':')).unwrap();
+
+        lines[position + 2].to_string()
+    }
+
+    #[sqlx::test]
+    async fn verify_and_reset(pool: PgPool) {
+        let (mut server, file) = server_with_mail_file(&pool);
+        let (user, access_token) = create_test_access_token(&pool).await;
+        let (header_name, header_value) = get_auth_header(&access_token);
+        let api_key = ApiKey::new(user.id, "Script", vec![ApiScope::ExercisesRead], &pool)
+            .await
+            .unwrap();
+
+        server.add_header(header_name, header_value);
+
+        let user = server
+            .patch("/api/user/email")
+            .json(&json!({ "email": "Test@Example.com", "password": "testuserpassword" }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert_eq!(user.email.as_deref(), Some("test@example.com"));
+        assert!(!user.email_verified);
+
+        server.clear_headers();
+
+        // Unverified emails can't be used for resetting
+        server
+            .post("/api/user/password_reset")
+            .json(&json!({ "email": "test@example.com" }))
+            .await
+            .assert_status_ok();
+
+        let verify_code = last_code(&file);
+
+        let user = server
+            .post("/api/user/email/verify")
+            .json(&json!({ "token": verify_code }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert!(user.email_verified);
+
+        server
+            .post("/api/user/email/verify")
+            .json(&json!({ "token": verify_code }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        server
+            .post("/api/user/password_reset")
+            .json(&json!({ "email": "test@example.com" }))
+            .await
+            .assert_status_ok();
+
+        wait_for_emails(&file, 2).await;
+        let reset_code = last_code(&file);
+
+        assert_ne!(reset_code, verify_code);
+
+        let reset = json!({ "token": reset_code, "new_password": "new_test_password" });
+
+        server
+            .post("/api/user/password_reset/confirm")
+            .json(&reset)
+            .await
+            .assert_status_ok();
+
+        server
+            .post("/api/user/password_reset/confirm")
+            .json(&reset)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // Old tokens were revoked
+        let (header_name, header_value) = get_auth_header(&access_token);
+        server
+            .get("/api/user")
+            .add_header(header_name, header_value)
+            .await
+            .assert_status_not_ok();
+
+        assert!(ApiKey::from_key(api_key.key.unwrap(), &pool).await.is_err());
+
+        server
+            .post("/api/access_token")
+            .json(&json!({
+                "username": "test",
+                "password": "new_test_password",
+                "validity_in_seconds": 60
+            }))
+            .await
+            .assert_status_ok();
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn email_conflict(pool: PgPool) {
+        let (mut server, file) = server_with_mail_file(&pool);
+        let (_, access_token) = create_test_access_token(&pool).await;
+        let (header_name, header_value) = get_auth_header(&access_token);
+
+        server.add_header(header_name, header_value);
+
+        server
+            .patch("/api/user/email")
+            .json(&json!({ "email": "test@example.com", "password": "testuserpassword" }))
+            .await
+            .assert_status_ok();
+
+        let other_user = User::new("other", "otheruserpassword", &pool)
+            .await
+            .unwrap();
+        let other_token = AccessToken::new(
+            other_user.id,
+            chrono::Duration::days(1),
+            Default::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
+        let (header_name, header_value) = get_auth_header(&other_token);
+        let mut other_server = server_with_mail_file(&pool).0;
+
+        other_server.add_header(header_name, header_value);
+
+        other_server
+            .patch("/api/user/email")
+            .json(&json!({ "email": "TEST@example.com", "password": "otheruserpassword" }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        server
+            .patch("/api/user/email")
+            .json(&json!({ "email": "not an email", "password": "testuserpassword" }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // Removing works too
+        let user = server
+            .patch("/api/user/email")
+            .json(&json!({ "email": null, "password": "testuserpassword" }))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert!(user.email.is_none());
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[sqlx::test]
+    async fn change_needs_password(pool: PgPool) {
+        let (mut server, _) = server_with_mail_file(&pool);
+        let (_, access_token) = create_test_access_token(&pool).await;
+        let (header_name, header_value) = get_auth_header(&access_token);
+
+        server.add_header(header_name, header_value);
+
+        let response = server
+            .patch("/api/user/email")
+            .json(&json!({ "email": "test@example.com", "password": "wrong_password" }))
+            .await;
+
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let user = User::from_id(access_token.user_id, &pool).await.unwrap();
+
+        assert!(user.email.is_none());
+    }
+
+    #[sqlx::test]
+    async fn reset_requests_limited(pool: PgPool) {
+        let (server, _) = server_with_mail_file(&pool);
+
+        // Unknown emails look the same as known ones
+        for _ in 0..4 {
+            server
+                .post("/api/user/password_reset")
+                .json(&json!({ "email": "nobody@example.com" }))
+                .await
+                .assert_status_ok();
+        }
+
+        server
+            .post("/api/user/password_reset")
+            .json(&json!({ "email": "Nobody@example.com" }))
+            .await
+            .assert_status(StatusCode::TOO_MANY_REQUESTS);
+
+        server
+            .post("/api/user/password_reset")
+            .json(&json!({ "email": "other@example.com" }))
+            .await
+            .assert_status_ok();
+    }
+}
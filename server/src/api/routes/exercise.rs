@@ -1,22 +1,32 @@
 use axum::{extract::State, http::StatusCode};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use sqlx::PgPool;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
     api::{
-        extractors::{json::ValidatedJson, path::Path},
-        response::{RouteResponse, RouteSuccess},
+        extractors::{
+            json::ValidatedJson,
+            path::Path,
+            query::ValidatedQuery,
+            scope::{ExercisesRead, ExercisesWrite, Scoped},
+        },
+        response::{RouteError, RouteResponse, RouteSuccess},
     },
     models::{
+        analytics::{AnalyticsFilter, ExerciseAnalytics, Grouping, OneRepMaxFormula},
         exercise::{all_user_exercises, Exercise, ExerciseKind},
-        user::User,
+        personal_record::{self, ExerciseRecords},
+        set::{self, ConvertWeights, SetKind},
     },
 };
 
-use super::{default_as_false, deserialize_optional_option};
+use super::{
+    default_as_false, deserialize_comma_separated, deserialize_optional_option, UnitQuery,
+};
 
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct CreateExerciseInput {
@@ -27,11 +37,6 @@ pub struct CreateExerciseInput {
         max = 10000,
         message = "must be between 1 and 10000 characters"
     ))]
-    #[validate(length(
-        min = 1,
-        max = 10000,
-        message = "must be between 1 and 10000 characters"
-    ))]
     description: Option<String>,
     #[serde(default = "default_as_false")]
     favourite: bool,
@@ -42,6 +47,9 @@ pub struct CreateExerciseInput {
     ))]
     notes: Option<String>,
     kind: ExerciseKind,
+    // Rest between sets, 120 seconds by default
+    #[validate(range(min = 0, max = 3600, message = "must be between 0 and 3600"))]
+    rest_seconds: Option<i32>,
 }
 
 #[utoipa::path(
@@ -58,11 +66,11 @@ pub struct CreateExerciseInput {
     )
 )]
 pub async fn create_exercise(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesWrite>,
     State(pool): State<PgPool>,
     ValidatedJson(body): ValidatedJson<CreateExerciseInput>,
 ) -> RouteResponse<Exercise> {
-    let new_exercise = Exercise::new(
+    let mut new_exercise = Exercise::new(
         user.id,
         body.name,
         body.description,
@@ -73,6 +81,10 @@ pub async fn create_exercise(
     )
     .await?;
 
+    if let Some(rest_seconds) = body.rest_seconds {
+        new_exercise.set_rest_seconds(rest_seconds, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         format!("New exercise '{}' created.", &new_exercise.name),
         new_exercise,
@@ -86,11 +98,6 @@ pub async fn create_exercise(
 pub struct EditExerciseInput {
     #[validate(length(min = 1, max = 30, message = "must be between 1 and 30 characters"))]
     name: Option<String>,
-    #[validate(length(
-        min = 1,
-        max = 10000,
-        message = "must be between 1 and 10000 characters"
-    ))]
     #[serde(default, deserialize_with = "deserialize_optional_option")]
     #[validate(length(
         min = 1,
@@ -107,6 +114,8 @@ pub struct EditExerciseInput {
     #[serde(default, deserialize_with = "deserialize_optional_option")]
     notes: Option<Option<String>>,
     kind: Option<ExerciseKind>,
+    #[validate(range(min = 0, max = 3600, message = "must be between 0 and 3600"))]
+    rest_seconds: Option<i32>,
 }
 
 #[utoipa::path(
@@ -128,7 +137,7 @@ pub struct EditExerciseInput {
     )
 )]
 pub async fn edit_exercise(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesWrite>,
     State(pool): State<PgPool>,
     Path(exercise_id): Path<Uuid>,
     ValidatedJson(body): ValidatedJson<EditExerciseInput>,
@@ -160,6 +169,10 @@ pub async fn edit_exercise(
         exercise.set_kind(new_kind, &pool).await?;
     }
 
+    if let Some(rest_seconds) = body.rest_seconds {
+        exercise.set_rest_seconds(rest_seconds, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         "Updated fields.",
         exercise,
@@ -184,7 +197,7 @@ pub async fn edit_exercise(
     )
 )]
 pub async fn get_exercise_by_id(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesRead>,
     State(pool): State<PgPool>,
     Path(exercise_id): Path<Uuid>,
 ) -> RouteResponse<Exercise> {
@@ -212,7 +225,7 @@ pub async fn get_exercise_by_id(
     )
 )]
 pub async fn delete_exercise_by_id(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesWrite>,
     State(pool): State<PgPool>,
     Path(exercise_id): Path<Uuid>,
 ) -> RouteResponse<Uuid> {
@@ -238,7 +251,7 @@ pub async fn delete_exercise_by_id(
     )
 )]
 pub async fn get_user_exercises(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesRead>,
     State(pool): State<PgPool>,
 ) -> RouteResponse<Vec<Exercise>> {
     Ok(RouteSuccess::new(
@@ -264,7 +277,7 @@ pub async fn get_user_exercises(
     )
 )]
 pub async fn get_user_exercises_by_kind(
-    user: User,
+    Scoped(user, _): Scoped<ExercisesRead>,
     State(pool): State<PgPool>,
     Path(exercise_kind): Path<ExerciseKind>,
 ) -> RouteResponse<Vec<Exercise>> {
@@ -275,17 +288,170 @@ pub async fn get_user_exercises_by_kind(
     ))
 }
 
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExerciseRecordsQuery {
+    // Only count these kinds of sets, comma separated
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "working,drop")]
+    kinds: Option<Vec<SetKind>>,
+    // Leave out these kinds of sets, comma separated. Warm-ups by default.
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "warm_up")]
+    exclude_kinds: Option<Vec<SetKind>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/exercise/{exercise_id}/records",
+    params(
+        ("exercise_id" = Uuid, Path, description = "The ID of the exercise"),
+        ExerciseRecordsQuery,
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Personal records of the exercise returned", body = RouteSuccessExerciseRecords),
+        (status = NOT_FOUND, description = "Invalid exercise ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format or query parameters", body = RouteError),
+    )
+)]
+pub async fn get_exercise_records(
+    Scoped(user, _): Scoped<ExercisesRead>,
+    State(pool): State<PgPool>,
+    Path(exercise_id): Path<Uuid>,
+    ValidatedQuery(query): ValidatedQuery<ExerciseRecordsQuery>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<ExerciseRecords> {
+    // Makes sure the exercise exists and is owned by the user
+    let exercise = Exercise::from_id(user.id, exercise_id, &pool).await?;
+
+    let set_kinds = set::included_kinds(
+        query.kinds.as_deref(),
+        query.exclude_kinds.as_deref(),
+        &personal_record::EXCLUDED_SET_KINDS,
+    );
+
+    Ok(RouteSuccess::new(
+        "Returned personal records of the exercise.",
+        ExerciseRecords::from_set_kinds(user.id, exercise.id, &set_kinds, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExerciseAnalyticsQuery {
+    // Formula used for the estimated one-rep-max, Epley by default
+    #[serde(default)]
+    formula: OneRepMaxFormula,
+    // Length of the periods, a day by default
+    #[serde(default)]
+    group_by: Grouping,
+    // Count the reps in reserve from the RPE or RIR of the sets in the estimates
+    #[serde(default = "default_as_false")]
+    use_rpe: bool,
+    // Only include sessions started at or after this
+    from: Option<DateTime<Utc>>,
+    // Only include sessions started at or before this
+    to: Option<DateTime<Utc>>,
+    // Only include these kinds of sets, comma separated
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "working,drop")]
+    kinds: Option<Vec<SetKind>>,
+    // Leave out these kinds of sets, comma separated. Warm-ups by default.
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "warm_up")]
+    exclude_kinds: Option<Vec<SetKind>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/exercise/{exercise_id}/analytics",
+    params(
+        ("exercise_id" = Uuid, Path, description = "The ID of the exercise"),
+        ExerciseAnalyticsQuery,
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Analytics of the exercise returned", body = RouteSuccessExerciseAnalytics),
+        (status = NOT_FOUND, description = "Invalid exercise ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format or query parameters", body = RouteError),
+    )
+)]
+pub async fn get_exercise_analytics(
+    Scoped(user, _): Scoped<ExercisesRead>,
+    State(pool): State<PgPool>,
+    Path(exercise_id): Path<Uuid>,
+    ValidatedQuery(query): ValidatedQuery<ExerciseAnalyticsQuery>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<ExerciseAnalytics> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(RouteError::new(
+                "Invalid input in from field: must not be after to",
+                Some("from"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    }
+
+    // Makes sure the exercise exists and is owned by the user
+    let exercise = Exercise::from_id(user.id, exercise_id, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Returned analytics of the exercise.",
+        ExerciseAnalytics::from_exercise_id(
+            user.id,
+            exercise.id,
+            query.formula,
+            query.group_by,
+            query.use_rpe,
+            AnalyticsFilter {
+                from: query.from,
+                to: query.to,
+                set_kinds: set::included_kinds(
+                    query.kinds.as_deref(),
+                    query.exclude_kinds.as_deref(),
+                    &[SetKind::WarmUp],
+                ),
+            },
+            &pool,
+        )
+        .await?
+        .in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use axum::http::{HeaderName, HeaderValue};
     use axum_test::TestServer;
+    use chrono::{Duration, SecondsFormat};
     use serde_json::json;
     use sqlx::PgPool;
 
     use crate::{
         api::response::RouteSuccess,
-        models::exercise::{Exercise, ExerciseKind},
-        test_utils::api::{create_test_app, get_auth_header},
+        models::{
+            analytics::{ExerciseAnalytics, Grouping, OneRepMaxFormula},
+            exercise::{Exercise, ExerciseKind},
+            personal_record::ExerciseRecords,
+            set::SetKind,
+        },
+        test_utils::api::{
+            create_test_app, create_test_scenario, create_test_set, get_auth_header,
+        },
     };
 
     async fn create_test_exercise(
@@ -371,7 +537,8 @@ mod tests {
                     "description": new_description,
                     "notes": new_notes,
                     "favourite": false,
-                    "kind": new_kind
+                    "kind": new_kind,
+                    "rest_seconds": 90
                 }
             ))
             .await;
@@ -387,6 +554,7 @@ mod tests {
         assert_eq!(updated_test_exercise.name, new_name);
         assert_eq!(updated_test_exercise.description, new_description);
         assert_eq!(updated_test_exercise.notes, new_notes);
+        assert_eq!(updated_test_exercise.rest_seconds, 90);
         assert!(!updated_test_exercise.favourite);
         assert_eq!(updated_test_exercise.kind, new_kind);
     }
@@ -508,6 +676,212 @@ mod tests {
 
         // There should be just the one
         assert_eq!(users_exercises.len(), 1);
-        assert_eq!(users_exercises.get(0).unwrap().id, barbell_exercise.id);
+        assert_eq!(users_exercises.first().unwrap().id, barbell_exercise.id);
+    }
+
+    #[sqlx::test]
+    async fn personal_records(pool: PgPool) {
+        let (server, _, _, exercise, _, exercise_instance, set) = create_test_scenario(&pool).await;
+
+        let another_set = create_test_set(&server, exercise_instance.id).await;
+
+        // Complete both sets, the second one is heavier
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"weight": 100.0, "reps": 5, "completed": true}))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/set/{}", another_set.id))
+            .json(&json!({"weight": 110.0, "reps": 2, "completed": true}))
+            .await
+            .assert_status_success();
+
+        let get_records = || async {
+            server
+                .get(&format!("/api/exercise/{}/records", exercise.id))
+                .await
+                .json::<RouteSuccess<ExerciseRecords>>()
+                .data
+        };
+
+        let records = get_records().await;
+
+        assert_eq!(records.heaviest_weight.unwrap().value, 110.0);
+        assert_eq!(records.session_volume.unwrap().value, 720.0);
+        assert_eq!(records.most_reps.len(), 2);
+
+        // Editing a completed set recomputes the records
+        server
+            .patch(&format!("/api/set/{}", another_set.id))
+            .json(&json!({"weight": 90.0}))
+            .await
+            .assert_status_success();
+
+        let records = get_records().await;
+
+        assert_eq!(records.heaviest_weight.unwrap().value, 100.0);
+        assert_eq!(records.session_volume.unwrap().value, 680.0);
+
+        // Deleting removes it from the history
+        server
+            .delete(&format!("/api/set/{}", another_set.id))
+            .await
+            .assert_status_success();
+
+        let records = get_records().await;
+
+        assert_eq!(records.most_reps.len(), 1);
+        assert!(records
+            .history
+            .iter()
+            .all(|record| record.set_id != Some(another_set.id)));
+
+        // Only the asked kinds of sets count
+        let warm_up_records = server
+            .get(&format!("/api/exercise/{}/records", exercise.id))
+            .add_query_param("kinds", "warm_up")
+            .await
+            .json::<RouteSuccess<ExerciseRecords>>()
+            .data;
+
+        assert!(warm_up_records.heaviest_weight.is_none());
+        assert!(warm_up_records.history.is_empty());
+
+        server
+            .get(&format!("/api/exercise/{}/records", exercise.id))
+            .add_query_param("kinds", "heavy")
+            .await
+            .assert_status_failure();
+
+        // Not owned or non existent exercise
+        server
+            .get(&format!("/api/exercise/{}/records", uuid::Uuid::new_v4()))
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn analytics(pool: PgPool) {
+        let (server, _, _, exercise, session, exercise_instance, set) =
+            create_test_scenario(&pool).await;
+
+        let another_set = create_test_set(&server, exercise_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"weight": 100.0, "reps": 10, "completed": true}))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/set/{}", another_set.id))
+            .json(&json!({"weight": 120.0, "reps": 1, "completed": true}))
+            .await
+            .assert_status_success();
+
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("formula", "brzycki")
+            .add_query_param("group_by", "week")
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert_eq!(analytics.formula, OneRepMaxFormula::Brzycki);
+        assert_eq!(analytics.grouping, Grouping::Week);
+        assert_eq!(analytics.points.len(), 1);
+        assert_eq!(analytics.points[0].volume, 1120.0);
+        assert_eq!(analytics.points[0].sessions, 1);
+        assert_eq!(analytics.points[0].top_set.set_id, another_set.id);
+        assert_eq!(
+            analytics.points[0].estimated_one_rep_max,
+            Some(100.0 * 36.0 / 27.0)
+        );
+
+        // Warm-ups are left out unless asked for
+        let warm_up = create_test_set(&server, exercise_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}", warm_up.id))
+            .json(&json!({"weight": 60.0, "reps": 10, "completed": true, "kind": "warm_up"}))
+            .await
+            .assert_status_success();
+
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert_eq!(analytics.points[0].sets, 2);
+        assert!(!analytics.set_kinds.contains(&SetKind::WarmUp));
+
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("exclude_kinds", "")
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert_eq!(analytics.points[0].sets, 3);
+        assert_eq!(analytics.points[0].volume, 1720.0);
+
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("kinds", "warm_up")
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert_eq!(analytics.points[0].top_set.set_id, warm_up.id);
+
+        // RPE 8 is two reps in reserve, so 10 reps are estimated like 12
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"rpe": 8}))
+            .await
+            .assert_status_success();
+
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("use_rpe", true)
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert!(analytics.use_rpe);
+        assert_eq!(
+            analytics.points[0].estimated_one_rep_max,
+            Some(100.0 * (1.0 + 12.0 / 30.0))
+        );
+
+        // Range after the session started
+        let analytics = server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param(
+                "from",
+                (session.started + Duration::days(1)).to_rfc3339_opts(SecondsFormat::Secs, true),
+            )
+            .await
+            .json::<RouteSuccess<ExerciseAnalytics>>()
+            .data;
+
+        assert!(analytics.points.is_empty());
+
+        // Invalid formula and range
+        server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("formula", "unknown")
+            .await
+            .assert_status_bad_request();
+
+        server
+            .get(&format!("/api/exercise/{}/analytics", exercise.id))
+            .add_query_param("from", "2024-02-01T00:00:00Z")
+            .add_query_param("to", "2024-01-01T00:00:00Z")
+            .await
+            .assert_status_bad_request();
     }
 }
@@ -0,0 +1,296 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::{
+            path::Path,
+            scope::{Scoped, SessionsRead, SessionsWrite},
+        },
+        response::{RouteResponse, RouteSuccess},
+    },
+    models::exercise_group::{ExerciseGroup, ExerciseGroupKind},
+};
+
+use super::deserialize_optional_option;
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct CreateExerciseGroupInput {
+    session_id: Uuid,
+    #[serde(default)]
+    kind: ExerciseGroupKind,
+    // In the order of the session, at least two
+    #[validate(length(min = 2, max = 20, message = "must have between 2 and 20 instances"))]
+    exercise_instance_ids: Vec<Uuid>,
+    // Rest after each round, the rest times of the instances are used if not set
+    #[validate(range(min = 0, max = 3600, message = "must be between 0 and 3600"))]
+    rest_seconds: Option<i32>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/exercise_group",
+    request_body = CreateExerciseGroupInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "New exercise group created", body = RouteSuccessExerciseGroup),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid input for exercise group", body = RouteError),
+        (status = CONFLICT, description = "Session is finished", body = RouteError),
+    )
+)]
+pub async fn create_exercise_group(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Json(body): Json<CreateExerciseGroupInput>,
+) -> RouteResponse<ExerciseGroup> {
+    body.validate()?;
+
+    Ok(RouteSuccess::new(
+        "New exercise group created.",
+        ExerciseGroup::new(
+            user.id,
+            body.session_id,
+            body.kind,
+            body.rest_seconds,
+            &body.exercise_instance_ids,
+            &pool,
+        )
+        .await?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/exercise_group/{exercise_group_id}",
+    params(
+        ("exercise_group_id" = Uuid, Path, description = "The ID of the exercise group")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = FOUND, description = "Exercise group found", body = RouteSuccessExerciseGroup),
+        (status = NOT_FOUND, description = "Exercise group not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+    )
+)]
+pub async fn get_exercise_group_by_id(
+    Scoped(user, _): Scoped<SessionsRead>,
+    State(pool): State<PgPool>,
+    Path(exercise_group_id): Path<Uuid>,
+) -> RouteResponse<ExerciseGroup> {
+    Ok(RouteSuccess::new(
+        "Found exercise group.",
+        ExerciseGroup::from_id(user.id, exercise_group_id, &pool).await?,
+        StatusCode::FOUND,
+    ))
+}
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct EditExerciseGroupInput {
+    kind: Option<ExerciseGroupKind>,
+    // Null to use the rest times of the instances
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(range(min = 0, max = 3600, message = "must be between 0 and 3600"))]
+    rest_seconds: Option<Option<i32>>,
+    // Replaces the instances of the group, removed ones are ungrouped
+    #[validate(length(min = 2, max = 20, message = "must have between 2 and 20 instances"))]
+    exercise_instance_ids: Option<Vec<Uuid>>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/exercise_group/{exercise_group_id}",
+    params(
+        ("exercise_group_id" = Uuid, Path, description = "The ID of the exercise group being edited"),
+    ),
+    request_body = EditExerciseGroupInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Changes made successfully", body = RouteSuccessExerciseGroup),
+        (status = NOT_FOUND, description = "Exercise group not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid input for edits", body = RouteError),
+        (status = CONFLICT, description = "Session is finished", body = RouteError),
+    )
+)]
+pub async fn edit_exercise_group(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(exercise_group_id): Path<Uuid>,
+    Json(body): Json<EditExerciseGroupInput>,
+) -> RouteResponse<ExerciseGroup> {
+    body.validate()?;
+
+    let mut exercise_group = ExerciseGroup::from_id(user.id, exercise_group_id, &pool).await?;
+
+    if let Some(exercise_instance_ids) = body.exercise_instance_ids {
+        exercise_group
+            .set_exercise_instances(&exercise_instance_ids, &pool)
+            .await?;
+    }
+
+    if let Some(kind) = body.kind {
+        exercise_group.set_kind(kind, &pool).await?;
+    }
+
+    if let Some(rest_seconds) = body.rest_seconds {
+        exercise_group.set_rest_seconds(rest_seconds, &pool).await?;
+    }
+
+    Ok(RouteSuccess::new(
+        "Requested changes made.",
+        exercise_group,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/exercise_group/{exercise_group_id}",
+    params(
+        ("exercise_group_id" = Uuid, Path, description = "The ID of the exercise group being dissolved")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Exercise group dissolved", body = RouteSuccessUuid),
+        (status = NOT_FOUND, description = "Exercise group not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+        (status = CONFLICT, description = "Session is finished", body = RouteError),
+    )
+)]
+pub async fn delete_exercise_group_by_id(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(exercise_group_id): Path<Uuid>,
+) -> RouteResponse<Uuid> {
+    Ok(RouteSuccess::new(
+        "Exercise group dissolved, its exercise instances are kept.",
+        ExerciseGroup::from_id(user.id, exercise_group_id, &pool)
+            .await?
+            .dissolve(&pool)
+            .await?,
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        api::response::RouteSuccess,
+        models::{
+            exercise_group::{ExerciseGroup, ExerciseGroupKind},
+            exercise_instance::ExerciseInstance,
+            session::Session,
+        },
+        test_utils::api::create_test_scenario,
+    };
+
+    #[sqlx::test]
+    async fn create_edit_and_dissolve(pool: PgPool) {
+        let (server, _, _, exercise, session, first, _) = create_test_scenario(&pool).await;
+
+        let mut instances = vec![first];
+
+        for _ in 0..2 {
+            instances.push(
+                server
+                    .post("/api/exercise_instance")
+                    .json(&json!({"session_id": session.id, "exercise_id": exercise.id}))
+                    .await
+                    .json::<RouteSuccess<ExerciseInstance>>()
+                    .data,
+            );
+        }
+
+        // A single instance isn't a group
+        server
+            .post("/api/exercise_group")
+            .json(&json!({"session_id": session.id, "exercise_instance_ids": [instances[0].id]}))
+            .await
+            .assert_status_bad_request();
+
+        let group = server
+            .post("/api/exercise_group")
+            .json(&json!({
+                "session_id": session.id,
+                "exercise_instance_ids": [instances[0].id, instances[1].id],
+                "rest_seconds": 90
+            }))
+            .await
+            .json::<RouteSuccess<ExerciseGroup>>()
+            .data;
+
+        assert_eq!(group.kind, ExerciseGroupKind::Superset);
+        assert_eq!(group.rest_seconds, Some(90));
+
+        // Instances can only be in one group
+        server
+            .post("/api/exercise_group")
+            .json(&json!({
+                "session_id": session.id,
+                "exercise_instance_ids": [instances[1].id, instances[2].id]
+            }))
+            .await
+            .assert_status_bad_request();
+
+        let edited = server
+            .patch(&format!("/api/exercise_group/{}", group.id))
+            .json(&json!({
+                "kind": "giant_set",
+                "rest_seconds": null,
+                "exercise_instance_ids": [instances[0].id, instances[1].id, instances[2].id]
+            }))
+            .await
+            .json::<RouteSuccess<ExerciseGroup>>()
+            .data;
+
+        assert_eq!(edited.kind, ExerciseGroupKind::GiantSet);
+        assert_eq!(edited.rest_seconds, None);
+        assert_eq!(edited.exercise_instance_ids.len(), 3);
+
+        let queried = server
+            .get(&format!("/api/session/{}", session.id))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        assert_eq!(queried.exercise_groups, vec![edited]);
+
+        server
+            .delete(&format!("/api/exercise_group/{}", group.id))
+            .await
+            .assert_status_ok();
+
+        let queried = server
+            .get(&format!("/api/session/{}", session.id))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        // The instances stay without the group
+        assert!(queried.exercise_groups.is_empty());
+        assert_eq!(queried.exercise_instances.len(), 3);
+        assert!(queried
+            .exercise_instances
+            .iter()
+            .all(|instance| instance.group_id.is_none()));
+    }
+}
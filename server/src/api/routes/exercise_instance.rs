@@ -7,16 +7,25 @@ use validator::Validate;
 
 use crate::{
     api::{
-        extractors::path::Path,
+        extractors::{
+            path::Path,
+            query::ValidatedQuery,
+            scope::{Scoped, SessionsRead, SessionsWrite},
+        },
         response::{RouteResponse, RouteSuccess},
     },
-    models::{exercise_instance::ExerciseInstance, user::User},
+    models::{exercise_instance::ExerciseInstance, set::ConvertWeights},
 };
 
-#[derive(Debug, Deserialize, ToSchema)]
+use super::{deserialize_optional_option, UnitQuery};
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
 pub struct CreateExerciseInstanceInput {
     session_id: Uuid,
     exercise_id: Uuid,
+    // Inserted before the instance at the position, after the last one if not given
+    #[validate(range(min = 0, message = "can't be negative"))]
+    position: Option<i32>,
 }
 
 #[utoipa::path(
@@ -34,13 +43,22 @@ pub struct CreateExerciseInstanceInput {
     )
 )]
 pub async fn create_exercise_instance(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Json(body): Json<CreateExerciseInstanceInput>,
 ) -> RouteResponse<ExerciseInstance> {
+    body.validate()?;
+
     Ok(RouteSuccess::new(
         "New exercise instance created.",
-        ExerciseInstance::new(user.id, body.session_id, body.exercise_id, &pool).await?,
+        ExerciseInstance::new(
+            user.id,
+            body.session_id,
+            body.exercise_id,
+            body.position,
+            &pool,
+        )
+        .await?,
         StatusCode::CREATED,
     ))
 }
@@ -49,7 +67,8 @@ pub async fn create_exercise_instance(
     get,
     path = "/api/exercise_instance/{exercise_instance_id}",
     params(
-        ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance")
+        ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance"),
+        UnitQuery
     ),
     security(
         ("access_token"= [])
@@ -62,13 +81,16 @@ pub async fn create_exercise_instance(
     )
 )]
 pub async fn get_exercise_instance_by_id(
-    user: User,
+    Scoped(user, _): Scoped<SessionsRead>,
     State(pool): State<PgPool>,
     Path(exercise_instance_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
 ) -> RouteResponse<ExerciseInstance> {
     Ok(RouteSuccess::new(
         "Found exercise instance.",
-        ExerciseInstance::from_id(user.id, exercise_instance_id, &pool).await?,
+        ExerciseInstance::from_id(user.id, exercise_instance_id, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
         StatusCode::FOUND,
     ))
 }
@@ -90,7 +112,7 @@ pub async fn get_exercise_instance_by_id(
     )
 )]
 pub async fn delete_exercise_instance_by_id(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(exercise_instance_id): Path<Uuid>,
 ) -> RouteResponse<Uuid> {
@@ -114,7 +136,8 @@ pub struct CreateExerciseInstanceCommentInput {
     post,
     path = "/api/exercise_instance/{exercise_instance_id}/comment",
     params(
-        ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance being deleted")
+        ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance being deleted"),
+        UnitQuery
     ),
     request_body = CreateExerciseInstanceCommentInput,
     security(
@@ -128,9 +151,10 @@ pub struct CreateExerciseInstanceCommentInput {
     )
 )]
 pub async fn add_exercise_instance_comment(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(exercise_instance_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     Json(body): Json<CreateExerciseInstanceCommentInput>,
 ) -> RouteResponse<ExerciseInstance> {
     body.validate()?;
@@ -144,7 +168,7 @@ pub async fn add_exercise_instance_comment(
 
     Ok(RouteSuccess::new(
         "Appended a new comment.",
-        exercise_instance,
+        exercise_instance.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -160,7 +184,8 @@ pub struct SetExerciseInstanceCommentInput {
     path = "/api/exercise_instance/{exercise_instance_id}/comment/{comment_index}",
     params(
         ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance being edited"),
-        ("comment_index" = i32, Path, description = "Index of the comment in exercise instance")
+        ("comment_index" = i32, Path, description = "Index of the comment in exercise instance"),
+        UnitQuery
     ),
     request_body = SetExerciseInstanceCommentInput,
     security(
@@ -174,9 +199,10 @@ pub struct SetExerciseInstanceCommentInput {
     )
 )]
 pub async fn set_exercise_instance_comment(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(path_args): Path<(Uuid, i32)>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     Json(body): Json<SetExerciseInstanceCommentInput>,
 ) -> RouteResponse<ExerciseInstance> {
     body.validate()?;
@@ -189,7 +215,7 @@ pub async fn set_exercise_instance_comment(
 
     Ok(RouteSuccess::new(
         "Comment updated.",
-        exercise_instance,
+        exercise_instance.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -212,7 +238,7 @@ pub async fn set_exercise_instance_comment(
     )
 )]
 pub async fn delete_exercise_instance_comment(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(path_args): Path<(Uuid, usize)>,
 ) -> RouteResponse<usize> {
@@ -229,7 +255,13 @@ pub async fn delete_exercise_instance_comment(
 #[derive(Debug, Deserialize, Validate, ToSchema)]
 pub struct EditExerciseInstanceInput {
     exercise_id: Option<Uuid>,
-    // Could have more editable fields here if added
+    // Null to use the rest time of the exercise
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(range(min = 0, max = 3600, message = "must be between 0 and 3600"))]
+    rest_seconds: Option<Option<i32>>,
+    // Moves the instance, the ones in between move by one
+    #[validate(range(min = 0, message = "can't be negative"))]
+    position: Option<i32>,
 }
 
 #[utoipa::path(
@@ -237,6 +269,7 @@ pub struct EditExerciseInstanceInput {
     path = "/api/exercise_instance/{exercise_instance_id}",
     params(
         ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance being edited"),
+        UnitQuery
     ),
     request_body = EditExerciseInstanceInput,
     security(
@@ -250,12 +283,12 @@ pub struct EditExerciseInstanceInput {
     )
 )]
 pub async fn edit_exercise_instance(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(exercise_instance_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     Json(body): Json<EditExerciseInstanceInput>,
 ) -> RouteResponse<ExerciseInstance> {
-    // Not for anything yet but maybe will have more fields in the future
     body.validate()?;
 
     let mut exercise_instance =
@@ -265,9 +298,54 @@ pub async fn edit_exercise_instance(
         exercise_instance.set_exercise(id, &pool).await?;
     }
 
+    if let Some(rest_seconds) = body.rest_seconds {
+        exercise_instance
+            .set_rest_seconds(rest_seconds, &pool)
+            .await?;
+    }
+
+    if let Some(position) = body.position {
+        exercise_instance.set_position(position, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         "Requested changes made.",
-        exercise_instance,
+        exercise_instance.in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/exercise_instance/{exercise_instance_id}/swap/{other_exercise_instance_id}",
+    params(
+        ("exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance being moved"),
+        ("other_exercise_instance_id" = Uuid, Path, description = "The ID of the exercise instance it swaps places with"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Exercise instances swapped", body = RouteSuccessExerciseInstance),
+        (status = NOT_FOUND, description = "Exercise instance not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Exercise instances aren't in the same session", body = RouteError),
+    )
+)]
+pub async fn swap_exercise_instances(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(path_args): Path<(Uuid, Uuid)>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<ExerciseInstance> {
+    let mut exercise_instance = ExerciseInstance::from_id(user.id, path_args.0, &pool).await?;
+
+    exercise_instance.swap_position(path_args.1, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Exercise instances swapped.",
+        exercise_instance.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -280,7 +358,7 @@ mod tests {
 
     use crate::{
         api::response::RouteSuccess,
-        models::{exercise_instance::ExerciseInstance, set::Set},
+        models::{exercise_instance::ExerciseInstance, session::Session, set::Set},
         test_utils::api::create_test_scenario,
     };
 
@@ -351,7 +429,7 @@ mod tests {
         // 1 should be 2 now
         assert_eq!(query.comments.get(1).unwrap(), "Comment 2");
         // 0 edited
-        assert_eq!(query.comments.get(0).unwrap(), "Comment");
+        assert_eq!(query.comments.first().unwrap(), "Comment");
     }
 
     // Try to change to an invalid exercise
@@ -410,4 +488,87 @@ mod tests {
             .await
             .assert_status_failure();
     }
+
+    #[sqlx::test]
+    async fn reorder(pool: PgPool) {
+        let (server, _, _, exercise, session, first, _) = create_test_scenario(&pool).await;
+
+        let create = |position: Option<i32>| {
+            server.post("/api/exercise_instance").json(&json!({
+                "session_id": session.id,
+                "exercise_id": exercise.id,
+                "position": position
+            }))
+        };
+
+        let last = create(None)
+            .await
+            .json::<RouteSuccess<ExerciseInstance>>()
+            .data;
+        let inserted = create(Some(1))
+            .await
+            .json::<RouteSuccess<ExerciseInstance>>()
+            .data;
+
+        assert_eq!(last.position, 1);
+        assert_eq!(inserted.position, 1);
+
+        create(Some(-1)).await.assert_status_bad_request();
+
+        let query_order = || async {
+            server
+                .get(&format!("/api/session/{}", session.id))
+                .await
+                .json::<RouteSuccess<Session>>()
+                .data
+                .exercise_instances
+                .iter()
+                .map(|instance| instance.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(query_order().await, vec![first.id, inserted.id, last.id]);
+
+        let moved = server
+            .patch(&format!("/api/exercise_instance/{}", first.id))
+            .json(&json!({"position": 2}))
+            .await
+            .json::<RouteSuccess<ExerciseInstance>>()
+            .data;
+
+        assert_eq!(moved.position, 2);
+        assert_eq!(query_order().await, vec![inserted.id, last.id, first.id]);
+
+        server
+            .patch(&format!("/api/exercise_instance/{}", first.id))
+            .json(&json!({"position": 3}))
+            .await
+            .assert_status_bad_request();
+
+        server
+            .patch(&format!(
+                "/api/exercise_instance/{}/swap/{}",
+                inserted.id, first.id
+            ))
+            .await
+            .assert_status_ok();
+
+        assert_eq!(query_order().await, vec![first.id, last.id, inserted.id]);
+
+        // The later ones move forward
+        server
+            .delete(&format!("/api/exercise_instance/{}", first.id))
+            .await
+            .assert_status_ok();
+
+        let query = server
+            .get(&format!("/api/session/{}", session.id))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        assert_eq!(query.exercise_instances[0].id, last.id);
+        assert_eq!(query.exercise_instances[0].position, 0);
+        assert_eq!(query.exercise_instances[1].position, 1);
+    }
 }
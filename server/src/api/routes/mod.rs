@@ -1,33 +1,56 @@
 mod access_token;
+mod admin;
+mod api_key;
+mod email;
 mod exercise;
+mod exercise_group;
 mod exercise_instance;
 mod fallback;
+mod oidc;
 mod ping;
 mod session;
 mod set;
+mod template;
+mod two_factor;
 mod user;
 
 use crate::{
     api::{
+        extractors::client::TrustedProxies,
+        rate_limit::{limit_emails, limit_logins, LoginLimiter},
         response::*,
         routes::{self, fallback::fallback404},
     },
-    models,
+    mailer::{LogMailer, SharedMailer},
+    models::{self, account_deletion::DeletionGracePeriod, set::WeightUnit, user::User},
+    settings::{OidcProvider, Registration},
 };
 use axum::{
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    middleware,
     routing::{delete, get, patch, post},
-    Router,
+    Extension, Router,
+};
+use chrono::Duration;
+use serde::{
+    de::{value::Error as ValueError, DeserializeOwned, Error, IntoDeserializer},
+    Deserialize, Deserializer,
 };
-use serde::{Deserialize, Deserializer};
 use sqlx::PgPool;
+use std::{net::IpAddr, sync::Arc};
 use tracing::{info, instrument};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
-    Modify, OpenApi,
+    IntoParams, Modify, OpenApi,
 };
 use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
+use validator::Validate;
+
+// Exports of other apps can be larger than the default limit of 2MB
+const IMPORT_BODY_LIMIT: usize = 50 * 1024 * 1024;
 
 // For serde...
 pub fn default_as_false() -> bool {
@@ -47,11 +70,69 @@ where
     Option::<T>::deserialize(deserializer).map(Some)
 }
 
+// Lists in query strings are comma separated, like `?kinds=working,drop`.
+// An empty value is an empty list.
+pub fn deserialize_comma_separated<'de, T, D>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    T: DeserializeOwned,
+    D: Deserializer<'de>,
+{
+    let Some(list) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| T::deserialize(IntoDeserializer::<ValueError>::into_deserializer(item)))
+        .collect::<Result<Vec<T>, ValueError>>()
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+// Weights are in the unit preferred by the user, unless another one is asked for
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UnitQuery {
+    // Unit of the weights in the request and the response, kg or lb
+    unit: Option<WeightUnit>,
+}
+
+impl UnitQuery {
+    pub fn or_preference(&self, user: &User) -> WeightUnit {
+        self.unit.unwrap_or(user.weight_unit)
+    }
+}
+
+// What the routes need from the settings
+#[derive(Debug, Clone)]
+pub struct RouterOptions {
+    pub registration: Registration,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub oidc_providers: Vec<OidcProvider>,
+    pub mailer: SharedMailer,
+    // How long deleted users can still cancel by logging in
+    pub deletion_grace_period: Duration,
+}
+
+// Used in tests, emails are only logged
+impl Default for RouterOptions {
+    fn default() -> Self {
+        RouterOptions {
+            registration: Registration::Open,
+            trusted_proxies: Vec::new(),
+            oidc_providers: Vec::new(),
+            mailer: Arc::new(LogMailer::default()),
+            deletion_grace_period: Duration::days(30),
+        }
+    }
+}
+
 // Centralized builder for all API routes.
 // Actual routes are also under this module,
 // and not public the rest of the crate.
-#[instrument]
-pub fn build_router(pool: PgPool) -> Router {
+#[instrument(skip(options))]
+pub fn build_router(pool: PgPool, options: RouterOptions) -> Router {
     info!("Building axum routes and API documentation");
 
     #[derive(OpenApi)]
@@ -59,23 +140,38 @@ pub fn build_router(pool: PgPool) -> Router {
         paths(
             ping::handle,
             access_token::create_access_token,
+            access_token::create_token_pair,
+            access_token::refresh_token_pair,
             access_token::delete_token,
+            access_token::get_user_tokens,
+            access_token::revoke_token_by_id,
+            access_token::revoke_all_tokens,
             user::create_user,
             user::get_self,
             user::delete_user,
             user::change_username,
+            user::change_weight_unit,
             user::change_password,
+            user::export_user_data,
+            user::export_deleted_user_data,
+            user::import_csv,
+            user::import_export,
             exercise::create_exercise,
             exercise::edit_exercise,
             exercise::get_exercise_by_id,
             exercise::delete_exercise_by_id,
             exercise::get_user_exercises,
             exercise::get_user_exercises_by_kind,
+            exercise::get_exercise_records,
+            exercise::get_exercise_analytics,
             session::create_session,
             session::edit_session,
             session::delete_session_by_id,
             session::get_session_by_id,
             session::finish_session,
+            session::start_session,
+            session::reopen_session,
+            session::get_rest_timer,
             session::get_all_user_sessions,
             exercise_instance::create_exercise_instance,
             exercise_instance::get_exercise_instance_by_id,
@@ -84,10 +180,42 @@ pub fn build_router(pool: PgPool) -> Router {
             exercise_instance::add_exercise_instance_comment,
             exercise_instance::set_exercise_instance_comment,
             exercise_instance::delete_exercise_instance_comment,
+            exercise_instance::swap_exercise_instances,
+            exercise_group::create_exercise_group,
+            exercise_group::get_exercise_group_by_id,
+            exercise_group::edit_exercise_group,
+            exercise_group::delete_exercise_group_by_id,
             set::create_set,
             set::get_set_by_id,
             set::delete_set,
             set::edit_set,
+            set::swap_sets,
+            template::create_template,
+            template::edit_template,
+            template::get_template_by_id,
+            template::delete_template_by_id,
+            template::get_all_user_templates,
+            template::create_session_from_template,
+            api_key::create_api_key,
+            api_key::get_user_api_keys,
+            api_key::revoke_api_key,
+            two_factor::start_two_factor,
+            two_factor::verify_two_factor,
+            two_factor::disable_two_factor,
+            oidc::get_providers,
+            oidc::authorize,
+            oidc::callback,
+            oidc::link,
+            admin::get_users,
+            admin::update_user,
+            admin::reset_password,
+            admin::get_stats,
+            admin::create_invite_code,
+            admin::get_invite_codes,
+            email::change_email,
+            email::verify_email,
+            email::request_password_reset,
+            email::reset_password,
         ),
         modifiers(&SecurityAddon),
         tags(
@@ -98,16 +226,64 @@ pub fn build_router(pool: PgPool) -> Router {
             SingleRouteError,
             RouteError,
             models::access_token::AccessToken,
+            models::access_token::AccessTokenInfo,
+            models::refresh_token::RefreshToken,
+            models::refresh_token::TokenPair,
+            models::api_key::ApiKey,
+            models::api_key::ApiScope,
+            models::totp::TotpEnrollment,
+            models::oidc::OidcAuthorization,
+            models::oidc::OidcIdentity,
+            models::account_deletion::AccountDeletion,
+            models::user::UserRole,
+            models::admin::UsageStats,
+            models::invite_code::InviteCode,
             models::user::User,
+            models::export::UserExport,
+            models::export::ExportFormat,
+            models::import::CsvSource,
+            models::import::ImportSummary,
+            models::set::WeightUnit,
             models::exercise::Exercise,
             models::exercise::ExerciseKind,
+            models::personal_record::PersonalRecord,
+            models::personal_record::RecordKind,
+            models::personal_record::ExerciseRecords,
+            models::analytics::OneRepMaxFormula,
+            models::analytics::Grouping,
+            models::analytics::TopSet,
+            models::analytics::AnalyticsPoint,
+            models::analytics::ExerciseAnalytics,
             models::session::Session,
+            models::session::SessionOrder,
+            models::session::SessionState,
+            models::session::SessionPage,
+            models::rest_timer::RestTimer,
             models::exercise_instance::ExerciseInstance,
+            models::exercise_group::ExerciseGroup,
+            models::exercise_group::ExerciseGroupKind,
             models::set::Set,
+            models::set::SetKind,
+            models::template::Template,
+            models::template::TemplateExercise,
+            models::template::PlannedExercise,
             routes::user::CreateUserInput,
             routes::user::ChangeUsernameInput,
+            routes::user::ChangeWeightUnitInput,
             routes::user::ChangePasswordInput,
             routes::access_token::CreateAccessTokenInput,
+            routes::access_token::CreateTokenPairInput,
+            routes::access_token::RefreshTokenInput,
+            routes::api_key::CreateApiKeyInput,
+            routes::two_factor::TwoFactorCodeInput,
+            routes::oidc::OidcCallbackInput,
+            routes::oidc::OidcLinkInput,
+            routes::admin::UpdateUserInput,
+            routes::admin::ResetPasswordInput,
+            routes::email::ChangeEmailInput,
+            routes::email::EmailTokenInput,
+            routes::email::RequestPasswordResetInput,
+            routes::email::ResetPasswordWithTokenInput,
             routes::exercise::CreateExerciseInput,
             routes::exercise::EditExerciseInput,
             routes::session::CreateSessionInput,
@@ -116,8 +292,12 @@ pub fn build_router(pool: PgPool) -> Router {
             routes::exercise_instance::CreateExerciseInstanceCommentInput,
             routes::exercise_instance::SetExerciseInstanceCommentInput,
             routes::exercise_instance::EditExerciseInstanceInput,
+            routes::exercise_group::CreateExerciseGroupInput,
+            routes::exercise_group::EditExerciseGroupInput,
             routes::set::CreateSetInput,
             routes::set::EditSetInput,
+            routes::template::CreateTemplateInput,
+            routes::template::EditTemplateInput,
         ))
     )]
     struct ApiDoc;
@@ -135,6 +315,9 @@ pub fn build_router(pool: PgPool) -> Router {
         }
     }
 
+    // Shared by every route which checks a password, a code or a token
+    let login_limit = middleware::from_fn_with_state(LoginLimiter::new(), limit_logins);
+
     let user_router = Router::new()
         .route(
             "/",
@@ -143,12 +326,88 @@ pub fn build_router(pool: PgPool) -> Router {
                 .delete(user::delete_user),
         )
         .route("/username", patch(user::change_username))
-        .route("/password", patch(user::change_password));
+        .route("/weight_unit", patch(user::change_weight_unit))
+        .route("/password", patch(user::change_password))
+        .route(
+            "/2fa",
+            post(two_factor::start_two_factor)
+                .delete(two_factor::disable_two_factor.layer(login_limit.clone())),
+        )
+        .route(
+            "/2fa/verify",
+            post(two_factor::verify_two_factor).layer(login_limit.clone()),
+        )
+        .route(
+            "/email",
+            patch(email::change_email).layer(login_limit.clone()),
+        )
+        .route("/email/verify", post(email::verify_email))
+        .route(
+            "/password_reset",
+            post(email::request_password_reset).layer(middleware::from_fn_with_state(
+                LoginLimiter::new(),
+                limit_emails,
+            )),
+        )
+        .route(
+            "/password_reset/confirm",
+            post(email::reset_password).layer(login_limit.clone()),
+        )
+        .route("/export", get(user::export_user_data))
+        .route("/deletion/export", get(user::export_deleted_user_data))
+        .route(
+            "/import",
+            post(user::import_export).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .route(
+            "/import/csv",
+            post(user::import_csv).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .layer(Extension(options.registration))
+        .layer(Extension(options.mailer))
+        .layer(Extension(DeletionGracePeriod(
+            options.deletion_grace_period,
+        )));
 
     let access_token_router = Router::new()
-        .route("/", post(access_token::create_access_token))
+        .route(
+            "/",
+            post(access_token::create_access_token).layer(login_limit.clone()),
+        )
+        .route(
+            "/pair",
+            post(access_token::create_token_pair).layer(login_limit.clone()),
+        )
+        .route("/refresh", post(access_token::refresh_token_pair))
+        .route("/all", get(access_token::get_user_tokens))
+        .route("/all", delete(access_token::revoke_all_tokens))
+        .route("/id/:token_id", delete(access_token::revoke_token_by_id))
         .route("/:token", delete(access_token::delete_token));
 
+    let api_key_router = Router::new()
+        .route("/", post(api_key::create_api_key))
+        .route("/all", get(api_key::get_user_api_keys))
+        .route("/:api_key_id", delete(api_key::revoke_api_key));
+
+    let admin_router = Router::new()
+        .route("/user/all", get(admin::get_users))
+        .route("/user/:user_id", patch(admin::update_user))
+        .route("/user/:user_id/password", post(admin::reset_password))
+        .route("/stats", get(admin::get_stats))
+        .route("/invite_code", post(admin::create_invite_code))
+        .route("/invite_code/all", get(admin::get_invite_codes));
+
+    let oidc_router = Router::new()
+        .route("/providers", get(oidc::get_providers))
+        .route("/:provider/authorize", get(oidc::authorize))
+        .route(
+            "/:provider/callback",
+            post(oidc::callback).layer(login_limit.clone()),
+        )
+        .route("/:provider/link", post(oidc::link))
+        .layer(Extension(oidc::OidcProviders::new(options.oidc_providers)))
+        .layer(Extension(options.registration));
+
     let exercise_router = Router::new()
         .route("/", post(exercise::create_exercise))
         .route("/all", get(exercise::get_user_exercises))
@@ -158,15 +417,23 @@ pub fn build_router(pool: PgPool) -> Router {
         )
         .route("/:exercise_id", patch(exercise::edit_exercise))
         .route("/:exercise_id", get(exercise::get_exercise_by_id))
-        .route("/:exercise_id", delete(exercise::delete_exercise_by_id));
+        .route("/:exercise_id", delete(exercise::delete_exercise_by_id))
+        .route("/:exercise_id/records", get(exercise::get_exercise_records))
+        .route(
+            "/:exercise_id/analytics",
+            get(exercise::get_exercise_analytics),
+        );
 
     let session_router = Router::new()
         .route("/", post(session::create_session))
         .route("/", get(session::get_all_user_sessions))
+        .route("/rest", get(session::get_rest_timer))
         .route("/:session_id", patch(session::edit_session))
         .route("/:session_id", delete(session::delete_session_by_id))
         .route("/:session_id", get(session::get_session_by_id))
-        .route("/:session_id/finish", patch(session::finish_session));
+        .route("/:session_id/finish", patch(session::finish_session))
+        .route("/:session_id/start", patch(session::start_session))
+        .route("/:session_id/reopen", patch(session::reopen_session));
 
     let exercise_instance_router = Router::new()
         .route("/", post(exercise_instance::create_exercise_instance))
@@ -193,28 +460,65 @@ pub fn build_router(pool: PgPool) -> Router {
         .route(
             "/:exercise_instance_id/comment/:comment_index",
             delete(exercise_instance::delete_exercise_instance_comment),
+        )
+        .route(
+            "/:exercise_instance_id/swap/:other_exercise_instance_id",
+            patch(exercise_instance::swap_exercise_instances),
+        );
+
+    let exercise_group_router = Router::new()
+        .route("/", post(exercise_group::create_exercise_group))
+        .route(
+            "/:exercise_group_id",
+            get(exercise_group::get_exercise_group_by_id),
+        )
+        .route(
+            "/:exercise_group_id",
+            patch(exercise_group::edit_exercise_group),
+        )
+        .route(
+            "/:exercise_group_id",
+            delete(exercise_group::delete_exercise_group_by_id),
         );
 
     let set_router = Router::new()
         .route("/", post(set::create_set))
         .route("/:set_id", get(set::get_set_by_id))
         .route("/:set_id", delete(set::delete_set))
-        .route("/:set_id", patch(set::edit_set));
+        .route("/:set_id", patch(set::edit_set))
+        .route("/:set_id/swap/:other_set_id", patch(set::swap_sets));
+
+    let template_router = Router::new()
+        .route("/", post(template::create_template))
+        .route("/", get(template::get_all_user_templates))
+        .route("/:template_id", patch(template::edit_template))
+        .route("/:template_id", get(template::get_template_by_id))
+        .route("/:template_id", delete(template::delete_template_by_id))
+        .route(
+            "/:template_id/session",
+            post(template::create_session_from_template),
+        );
 
     let api_router = Router::new()
         .route("/ping", get(ping::handle))
         .nest("/user", user_router)
         .nest("/access_token", access_token_router)
+        .nest("/api_key", api_key_router)
+        .nest("/oidc", oidc_router)
+        .nest("/admin", admin_router)
         .nest("/exercise", exercise_router)
         .nest("/session", session_router)
         .nest("/set", set_router)
-        .nest("/exercise_instance", exercise_instance_router);
+        .nest("/exercise_instance", exercise_instance_router)
+        .nest("/exercise_group", exercise_group_router)
+        .nest("/template", template_router);
 
     Router::new()
         .merge(SwaggerUi::new("/docs/swagger_ui").url("/docs/spec/openapi.json", ApiDoc::openapi()))
         .merge(Redoc::with_url("/docs/redoc", ApiDoc::openapi()))
         .merge(RapiDoc::new("/docs/spec/openapi.json").path("/docs/rapidoc"))
         .nest("/api", api_router)
+        .layer(Extension(TrustedProxies(Arc::new(options.trusted_proxies))))
         .with_state(pool)
         .fallback(fallback404)
 }
@@ -0,0 +1,493 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, Extension};
+use chrono::Duration;
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::{client::ClientInfo, json::ValidatedJson, path::Path},
+        response::{RouteError, RouteResponse, RouteSuccess},
+    },
+    models::{
+        access_token::{AccessToken, TokenMetadata},
+        account_deletion,
+        oidc::{self, OidcAuthorization, OidcIdentity},
+        user::User,
+    },
+    settings::{OidcProvider, Registration},
+};
+
+// Configured identity providers, shared with the routes as an extension
+pub type OidcProviders = Arc<Vec<OidcProvider>>;
+
+fn find_provider<'a>(
+    providers: &'a OidcProviders,
+    name: &str,
+) -> Result<&'a OidcProvider, RouteError> {
+    providers
+        .iter()
+        .find(|provider| provider.name == name)
+        .ok_or_else(|| {
+            RouteError::new(
+                "Identity provider not found.",
+                Some("provider"),
+                StatusCode::NOT_FOUND,
+            )
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/oidc/providers",
+    responses(
+        (status = OK, description = "Names of the identity providers users can log in with", body = RouteSuccessStringVec),
+    )
+)]
+pub async fn get_providers(
+    Extension(providers): Extension<OidcProviders>,
+) -> RouteResponse<Vec<String>> {
+    Ok(RouteSuccess::new(
+        "Returned the identity providers.",
+        providers
+            .iter()
+            .map(|provider| provider.name.clone())
+            .collect(),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/oidc/{provider}/authorize",
+    params(
+        ("provider" = String, Path, description = "Name of the identity provider")
+    ),
+    responses(
+        (status = OK, description = "Where to send the user to log in", body = RouteSuccessOidcAuthorization),
+        (status = NOT_FOUND, description = "Identity provider not found", body = RouteError),
+    )
+)]
+pub async fn authorize(
+    Extension(providers): Extension<OidcProviders>,
+    State(pool): State<PgPool>,
+    Path(provider): Path<String>,
+) -> RouteResponse<OidcAuthorization> {
+    let provider = find_provider(&providers, &provider)?;
+
+    Ok(RouteSuccess::new(
+        "Log in at the identity provider.",
+        oidc::start_login(provider, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct OidcCallbackInput {
+    #[validate(length(min = 1, max = 2000, message = "must be between 1 and 2000 characters"))]
+    code: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    state: String,
+    #[validate(range(
+        min = 1,
+        max = 2592000,
+        message = "validity is limited to 30 days (2592000 seconds)"
+    ))]
+    #[schema(example = "600")]
+    validity_in_seconds: i64,
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    #[schema(example = "Phone")]
+    device_name: Option<String>,
+    // Only needed to sign up when registration is invite only
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    invite_code: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/oidc/{provider}/callback",
+    request_body = OidcCallbackInput,
+    params(
+        ("provider" = String, Path, description = "Name of the identity provider")
+    ),
+    responses(
+        (status = OK, description = "Logged in, new access token created", body = RouteSuccessAccessToken),
+        (status = BAD_REQUEST, description = "Login expired or already finished", body = RouteError),
+        (status = UNAUTHORIZED, description = "Code or ID token rejected", body = RouteError),
+        (status = FORBIDDEN, description = "Identity not linked and signing up is not allowed or the invite code is invalid", body = RouteError),
+        (status = NOT_FOUND, description = "Identity provider not found", body = RouteError),
+        (status = BAD_GATEWAY, description = "Identity provider could not be reached", body = RouteError),
+    )
+)]
+// Two-factor authentication of LiftLog isn't required, the provider handles it
+pub async fn callback(
+    Extension(providers): Extension<OidcProviders>,
+    Extension(registration): Extension<Registration>,
+    State(pool): State<PgPool>,
+    client: ClientInfo,
+    Path(provider): Path<String>,
+    ValidatedJson(body): ValidatedJson<OidcCallbackInput>,
+) -> RouteResponse<AccessToken> {
+    let provider = find_provider(&providers, &provider)?;
+
+    let user = oidc::finish_login(
+        provider,
+        &body.code,
+        &body.state,
+        registration,
+        body.invite_code,
+        &pool,
+    )
+    .await?;
+    account_deletion::cancel(user.id, &pool).await?;
+
+    let metadata = TokenMetadata {
+        device_name: body.device_name,
+        user_agent: client.user_agent,
+        ip: client.ip,
+    };
+
+    Ok(RouteSuccess::new(
+        "New access token created.",
+        AccessToken::new(
+            user.id,
+            Duration::seconds(body.validity_in_seconds),
+            metadata,
+            &pool,
+        )
+        .await?,
+        StatusCode::OK,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct OidcLinkInput {
+    #[validate(length(min = 1, max = 2000, message = "must be between 1 and 2000 characters"))]
+    code: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    state: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/oidc/{provider}/link",
+    request_body = OidcLinkInput,
+    params(
+        ("provider" = String, Path, description = "Name of the identity provider")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "Identity linked, it can be used to log in", body = RouteSuccessOidcIdentity),
+        (status = BAD_REQUEST, description = "Login expired or already finished", body = RouteError),
+        (status = UNAUTHORIZED, description = "Code, ID token or authorization token rejected", body = RouteError),
+        (status = CONFLICT, description = "Identity already linked", body = RouteError),
+        (status = NOT_FOUND, description = "Identity provider not found", body = RouteError),
+    )
+)]
+pub async fn link(
+    user: User,
+    Extension(providers): Extension<OidcProviders>,
+    State(pool): State<PgPool>,
+    Path(provider): Path<String>,
+    ValidatedJson(body): ValidatedJson<OidcLinkInput>,
+) -> RouteResponse<OidcIdentity> {
+    let provider = find_provider(&providers, &provider)?;
+
+    Ok(RouteSuccess::new(
+        "Identity linked.",
+        oidc::finish_link(provider, &body.code, &body.state, user.id, &pool).await?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{http::StatusCode, routing::post, Form, Json, Router};
+    use axum_test::TestServer;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use chrono::Utc;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use url::Url;
+
+    use crate::{
+        api::{
+            response::RouteSuccess,
+            routes::{build_router, RouterOptions},
+        },
+        models::{
+            access_token::AccessToken,
+            oidc::{self, OidcAuthorization},
+        },
+        settings::{OidcProvider, Registration},
+        test_utils::api::create_test_app,
+    };
+
+    // Token endpoint of a local provider. The code is "subject:nonce",
+    // so the test decides who logs in.
+    async fn mock_token_endpoint(
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        if form.get("grant_type").map(String::as_str) != Some("authorization_code")
+            || !form.contains_key("code_verifier")
+        {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let (subject, nonce) = form["code"]
+            .split_once(':')
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let claims = json!({
+            "iss": "http://mock-provider",
+            "sub": subject,
+            "aud": "liftlog",
+            "exp": Utc::now().timestamp() + 60,
+            "nonce": nonce,
+            "preferred_username": "oidc.user",
+        });
+
+        let id_token = format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    async fn start_mock_provider(allow_signup: bool) -> OidcProvider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                Router::new().route("/token", post(mock_token_endpoint)),
+            )
+            .await
+            .unwrap();
+        });
+
+        OidcProvider {
+            name: "mock".to_string(),
+            issuer: "http://mock-provider".to_string(),
+            client_id: "liftlog".to_string(),
+            client_secret: Some("secret".to_string()),
+            authorization_endpoint: format!("http://{}/authorize", address),
+            token_endpoint: format!("http://{}/token", address),
+            redirect_uri: "http://localhost/callback".to_string(),
+            scopes: "openid profile".to_string(),
+            allow_signup,
+        }
+    }
+
+    // Start a login and return the state and the code the provider would give
+    async fn authorize(server: &TestServer, subject: &str) -> (String, String) {
+        let authorization = server
+            .get("/api/oidc/mock/authorize")
+            .await
+            .json::<RouteSuccess<OidcAuthorization>>()
+            .data;
+
+        let url = Url::parse(&authorization.authorization_url).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+        assert_eq!(query["state"], authorization.state);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        (
+            authorization.state,
+            format!("{}:{}", subject, query["nonce"]),
+        )
+    }
+
+    #[sqlx::test]
+    async fn signup_and_login(pool: PgPool) {
+        let provider = start_mock_provider(true).await;
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    oidc_providers: vec![provider],
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        let (state, code) = authorize(&server, "first").await;
+        let login = json!({ "code": code, "state": state, "validity_in_seconds": 60 });
+
+        let response = server.post("/api/oidc/mock/callback").json(&login).await;
+
+        response.assert_status_success();
+
+        let first_token = response.json::<RouteSuccess<AccessToken>>().data;
+
+        // The state can't be used again
+        server
+            .post("/api/oidc/mock/callback")
+            .json(&login)
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        // Same identity logs in to the same user
+        let (state, code) = authorize(&server, "first").await;
+        let second_token = server
+            .post("/api/oidc/mock/callback")
+            .json(&json!({ "code": code, "state": state, "validity_in_seconds": 60 }))
+            .await
+            .json::<RouteSuccess<AccessToken>>()
+            .data;
+
+        assert_eq!(first_token.user_id, second_token.user_id);
+
+        let username = sqlx::query_scalar!(
+            "SELECT username FROM users WHERE id = $1",
+            first_token.user_id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(username, "oidcuser");
+
+        server
+            .get("/api/oidc/other/authorize")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn concurrent_signup(pool: PgPool) {
+        let provider = start_mock_provider(true).await;
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    oidc_providers: vec![provider.clone()],
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        let (first_state, first_code) = authorize(&server, "same").await;
+        let (second_state, second_code) = authorize(&server, "same").await;
+
+        // Both find no linked user, only one of them creates it
+        let (first, second) = tokio::join!(
+            oidc::finish_login(
+                &provider,
+                &first_code,
+                &first_state,
+                Registration::Open,
+                None,
+                &pool
+            ),
+            oidc::finish_login(
+                &provider,
+                &second_code,
+                &second_state,
+                Registration::Open,
+                None,
+                &pool
+            )
+        );
+
+        assert_eq!(first.unwrap().id, second.unwrap().id);
+
+        let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(users, Some(1));
+    }
+
+    #[sqlx::test]
+    async fn link_existing_user(pool: PgPool) {
+        let provider = start_mock_provider(false).await;
+        let (_, user, access_token) = create_test_app(&pool).await;
+        let mut server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    oidc_providers: vec![provider],
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        // Signing up is not allowed
+        let (state, code) = authorize(&server, "existing").await;
+        server
+            .post("/api/oidc/mock/callback")
+            .json(&json!({ "code": code, "state": state, "validity_in_seconds": 60 }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let (state, code) = authorize(&server, "existing").await;
+        server.add_header(
+            axum::http::header::AUTHORIZATION,
+            format!("Bearer {}", access_token.token).parse().unwrap(),
+        );
+        server
+            .post("/api/oidc/mock/link")
+            .json(&json!({ "code": code, "state": state }))
+            .await
+            .assert_status(StatusCode::CREATED);
+        server.clear_headers();
+
+        let (state, code) = authorize(&server, "existing").await;
+        let logged_in = server
+            .post("/api/oidc/mock/callback")
+            .json(&json!({ "code": code, "state": state, "validity_in_seconds": 60 }))
+            .await
+            .json::<RouteSuccess<AccessToken>>()
+            .data;
+
+        assert_eq!(logged_in.user_id, user.id);
+    }
+
+    #[sqlx::test]
+    async fn signup_follows_registration(pool: PgPool) {
+        // Not the first user, so only the setting decides
+        create_test_app(&pool).await;
+
+        let provider = start_mock_provider(true).await;
+        let server = TestServer::new(
+            build_router(
+                pool.clone(),
+                RouterOptions {
+                    registration: Registration::Closed,
+                    oidc_providers: vec![provider],
+                    ..Default::default()
+                },
+            )
+            .into_make_service(),
+        )
+        .unwrap();
+
+        let (state, code) = authorize(&server, "new").await;
+        server
+            .post("/api/oidc/mock/callback")
+            .json(&json!({ "code": code, "state": state, "validity_in_seconds": 60 }))
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+    }
+}
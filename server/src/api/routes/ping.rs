@@ -18,11 +18,13 @@ mod tests {
     use axum_test::TestServer;
     use sqlx::PgPool;
 
-    use crate::api::routes::build_router;
+    use crate::api::routes::{build_router, RouterOptions};
 
     #[sqlx::test]
     async fn ping(pool: PgPool) {
-        let server = TestServer::new(build_router(pool).into_make_service()).unwrap();
+        let server =
+            TestServer::new(build_router(pool, RouterOptions::default()).into_make_service())
+                .unwrap();
 
         let response = server.get("/api/ping").await;
 
@@ -1,22 +1,29 @@
 use axum::{extract::State, http::StatusCode};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use sqlx::PgPool;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
     api::{
-        extractors::{json::ValidatedJson, path::Path},
-        response::{RouteResponse, RouteSuccess},
+        extractors::{
+            json::ValidatedJson,
+            path::Path,
+            query::ValidatedQuery,
+            scope::{Scoped, SessionsRead, SessionsWrite},
+        },
+        response::{RouteError, RouteResponse, RouteSuccess},
     },
     models::{
-        session::{self, Session},
-        user::User,
+        rest_timer::RestTimer,
+        session::{self, Session, SessionCursor, SessionFilter, SessionOrder, SessionPage},
+        set::ConvertWeights,
     },
 };
 
-use super::deserialize_optional_option;
+use super::{default_as_false, deserialize_optional_option, UnitQuery};
 
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct CreateSessionInput {
@@ -28,6 +35,11 @@ pub struct CreateSessionInput {
         message = "must be between 1 and 10000 characters"
     ))]
     description: Option<String>,
+    // Creates a planned session which is started later
+    planned_start: Option<DateTime<Utc>>,
+    // How ready for training the user feels, 1-10
+    #[validate(range(min = 1, max = 10, message = "must be between 1 and 10"))]
+    readiness: Option<i32>,
 }
 
 #[utoipa::path(
@@ -44,15 +56,26 @@ pub struct CreateSessionInput {
     )
 )]
 pub async fn create_session(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     ValidatedJson(body): ValidatedJson<CreateSessionInput>,
 ) -> RouteResponse<Session> {
     body.validate()?;
 
+    let mut session = match body.planned_start {
+        Some(planned_start) => {
+            session::new_planned(user.id, body.name, body.description, planned_start, &pool).await?
+        }
+        None => Session::new(user.id, body.name, body.description, &pool).await?,
+    };
+
+    if body.readiness.is_some() {
+        session.set_readiness(body.readiness, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         "New session created.",
-        Session::new(user.id, body.name, body.description, &pool).await?,
+        session,
         StatusCode::CREATED,
     ))
 }
@@ -61,11 +84,6 @@ pub async fn create_session(
 pub struct EditSessionInput {
     #[validate(length(min = 1, max = 30, message = "must be between 1 and 30 characters"))]
     name: Option<String>,
-    #[validate(length(
-        min = 1,
-        max = 10000,
-        message = "must be between 1 and 10000 characters"
-    ))]
     #[serde(default, deserialize_with = "deserialize_optional_option")]
     #[validate(length(
         min = 1,
@@ -73,13 +91,25 @@ pub struct EditSessionInput {
         message = "must be between 1 and 10000 characters"
     ))]
     description: Option<Option<String>>,
+    // Corrections to when the session was started or finished
+    started: Option<DateTime<Utc>>,
+    finished: Option<DateTime<Utc>>,
+    // Perceived exertion of the whole session, 1-10
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(range(min = 1, max = 10, message = "must be between 1 and 10"))]
+    exertion: Option<Option<i32>>,
+    // How ready for training the user felt, 1-10
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(range(min = 1, max = 10, message = "must be between 1 and 10"))]
+    readiness: Option<Option<i32>>,
 }
 
 #[utoipa::path(
     patch,
     path = "/api/session/{session_id}",
     params(
-        ("session_id" = Uuid, Path, description = "The ID of the session edited")
+        ("session_id" = Uuid, Path, description = "The ID of the session edited"),
+        UnitQuery
     ),
     request_body = EditSessionInput,
     security(
@@ -89,12 +119,14 @@ pub struct EditSessionInput {
         (status = OK, description = "Session modified", body = RouteSuccessSession),
         (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
         (status = BAD_REQUEST, description = "Invalid input for session", body = RouteError),
+        (status = CONFLICT, description = "Finish time given for an unfinished session", body = RouteError),
     )
 )]
 pub async fn edit_session(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(session_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     ValidatedJson(body): ValidatedJson<EditSessionInput>,
 ) -> RouteResponse<Session> {
     let mut session = Session::from_id(user.id, session_id, &pool).await?;
@@ -107,9 +139,24 @@ pub async fn edit_session(
         session.set_description(new_descripstion, &pool).await?;
     }
 
+    if let Some(exertion) = body.exertion {
+        session.set_exertion(exertion, &pool).await?;
+    }
+
+    if let Some(readiness) = body.readiness {
+        session.set_readiness(readiness, &pool).await?;
+    }
+
+    if body.started.is_some() || body.finished.is_some() {
+        let started = body.started.unwrap_or(session.started);
+        let finished = body.finished.or(session.finished);
+
+        session.set_times(started, finished, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         "Session modified if changes were requested.",
-        session,
+        session.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -131,7 +178,7 @@ pub async fn edit_session(
     )
 )]
 pub async fn delete_session_by_id(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(session_id): Path<Uuid>,
 ) -> RouteResponse<Uuid> {
@@ -149,7 +196,8 @@ pub async fn delete_session_by_id(
     get,
     path = "/api/session/{session_id}",
     params(
-        ("session_id" = Uuid, Path, description = "The ID of the session requested")
+        ("session_id" = Uuid, Path, description = "The ID of the session requested"),
+        UnitQuery
     ),
     security(
         ("access_token"= [])
@@ -162,13 +210,16 @@ pub async fn delete_session_by_id(
     )
 )]
 pub async fn get_session_by_id(
-    user: User,
+    Scoped(user, _): Scoped<SessionsRead>,
     State(pool): State<PgPool>,
     Path(session_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
 ) -> RouteResponse<Session> {
     Ok(RouteSuccess::new(
         "Session found.",
-        Session::from_id(user.id, session_id, &pool).await?,
+        Session::from_id(user.id, session_id, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
         StatusCode::FOUND,
     ))
 }
@@ -177,7 +228,8 @@ pub async fn get_session_by_id(
     patch,
     path = "/api/session/{session_id}/finish",
     params(
-        ("session_id" = Uuid, Path, description = "The ID of the finished session")
+        ("session_id" = Uuid, Path, description = "The ID of the finished session"),
+        UnitQuery
     ),
     security(
         ("access_token"= [])
@@ -187,12 +239,14 @@ pub async fn get_session_by_id(
         (status = NOT_FOUND, description = "Invalid session ID", body = RouteError),
         (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
         (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+        (status = CONFLICT, description = "Session is not in progress", body = RouteError),
     )
 )]
 pub async fn finish_session(
-    user: User,
+    Scoped(user, _): Scoped<SessionsWrite>,
     State(pool): State<PgPool>,
     Path(session_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
 ) -> RouteResponse<Session> {
     let mut session = Session::from_id(user.id, session_id, &pool).await?;
 
@@ -200,44 +254,206 @@ pub async fn finish_session(
 
     Ok(RouteSuccess::new(
         "Session set as finished.",
-        session,
+        session.in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/session/{session_id}/start",
+    params(
+        ("session_id" = Uuid, Path, description = "The ID of the planned session"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Planned session started", body = RouteSuccessSession),
+        (status = NOT_FOUND, description = "Invalid session ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+        (status = CONFLICT, description = "Session is not planned", body = RouteError),
+    )
+)]
+pub async fn start_session(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(session_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Session> {
+    let mut session = Session::from_id(user.id, session_id, &pool).await?;
+
+    session.start(&pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Planned session started.",
+        session.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
 
+#[utoipa::path(
+    patch,
+    path = "/api/session/{session_id}/reopen",
+    params(
+        ("session_id" = Uuid, Path, description = "The ID of the finished session"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Session set back in progress", body = RouteSuccessSession),
+        (status = NOT_FOUND, description = "Invalid session ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+        (status = CONFLICT, description = "Session is not finished", body = RouteError),
+    )
+)]
+pub async fn reopen_session(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(session_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Session> {
+    let mut session = Session::from_id(user.id, session_id, &pool).await?;
+
+    session.reopen(&pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Session set back in progress.",
+        session.in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/session/rest",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Rest after the latest completed set of the current session", body = RouteSuccessRestTimer),
+        (status = NOT_FOUND, description = "No session in progress with completed sets", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+    )
+)]
+pub async fn get_rest_timer(
+    Scoped(user, _): Scoped<SessionsRead>,
+    State(pool): State<PgPool>,
+) -> RouteResponse<RestTimer> {
+    Ok(RouteSuccess::new(
+        "Rest timer found.",
+        RestTimer::current(user.id, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+fn default_session_limit() -> i64 {
+    20
+}
+
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SessionListQuery {
+    // Amount of sessions per page, 20 by default
+    #[serde(default = "default_session_limit")]
+    #[validate(range(min = 1, max = 100, message = "must be between 1 and 100"))]
+    limit: i64,
+    // Next cursor from the previous page
+    cursor: Option<String>,
+    // Newest first by default
+    #[serde(default)]
+    order: SessionOrder,
+    // Only include sessions started at or after this
+    from: Option<DateTime<Utc>>,
+    // Only include sessions started at or before this
+    to: Option<DateTime<Utc>>,
+    // Only include finished or unfinished sessions
+    finished: Option<bool>,
+    // Only include sessions with this in their name
+    #[validate(length(min = 1, max = 30, message = "must be between 1 and 30 characters"))]
+    name: Option<String>,
+    // Only include sessions with an instance of this exercise
+    exercise_id: Option<Uuid>,
+    // Leave out exercise instances and their sets
+    #[serde(default = "default_as_false")]
+    summary: bool,
+}
+
 #[utoipa::path(
     get,
     path = "/api/session",
+    params(SessionListQuery, UnitQuery),
     security(
         ("access_token"= [])
     ),
     responses(
-        (status = FOUND, description = "Returned all user's sessions (zero or more)", body = RouteSuccessSessionVec),
+        (status = FOUND, description = "Returned a page of user's sessions (zero or more)", body = RouteSuccessSessionPage),
         (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
-        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid query parameters", body = RouteError),
     )
 )]
 pub async fn get_all_user_sessions(
-    user: User,
+    Scoped(user, _): Scoped<SessionsRead>,
     State(pool): State<PgPool>,
-) -> RouteResponse<Vec<Session>> {
+    ValidatedQuery(query): ValidatedQuery<SessionListQuery>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<SessionPage> {
+    let cursor = match query.cursor {
+        Some(cursor) => Some(cursor.parse::<SessionCursor>().map_err(|_| {
+            RouteError::new(
+                "Invalid input in cursor field: malformed cursor",
+                Some("cursor"),
+                StatusCode::BAD_REQUEST,
+            )
+        })?),
+        None => None,
+    };
+
+    let filter = SessionFilter {
+        from: query.from,
+        to: query.to,
+        finished: query.finished,
+        name: query.name,
+        exercise_id: query.exercise_id,
+    };
+
     Ok(RouteSuccess::new(
-        "Returned all user's sessions.",
-        session::all_user_sessions(user.id, &pool).await?,
+        "Returned a page of user's sessions.",
+        session::user_sessions_page(
+            user.id,
+            &filter,
+            query.order,
+            cursor,
+            query.limit,
+            !query.summary,
+            &pool,
+        )
+        .await?
+        .in_unit(unit_query.or_preference(&user)),
         StatusCode::FOUND,
     ))
 }
 
 #[cfg(test)]
 mod tests {
+    use axum::http::StatusCode;
     use axum_test::TestServer;
+    use chrono::{Duration, Utc};
     use serde_json::json;
     use sqlx::PgPool;
     use uuid::Uuid;
 
     use crate::{
         api::response::RouteSuccess,
-        models::session::Session,
+        models::{
+            rest_timer::RestTimer,
+            session::{Session, SessionPage, SessionState},
+        },
         test_utils::api::{
             create_test_exercise, create_test_exercise_instance, create_test_scenario,
             create_test_session,
@@ -341,6 +557,55 @@ mod tests {
         assert_eq!(session.name, query.name);
     }
 
+    #[sqlx::test]
+    async fn exertion_and_readiness(pool: PgPool) {
+        let (server, _, _, _, session, _, _) = create_test_scenario(&pool).await;
+
+        assert!(session.exertion.is_none());
+
+        let created = server
+            .post("/api/session")
+            .json(&json!({"name": "Rated", "readiness": 7}))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        assert_eq!(created.readiness, Some(7));
+
+        // Rated after finishing
+        server
+            .patch(&format!("/api/session/{}/finish", session.id))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/session/{}", session.id))
+            .json(&json!({"exertion": 8, "readiness": 6}))
+            .await
+            .assert_status_success();
+
+        let query = query_by_id(session.id, &server).await;
+
+        assert_eq!(query.exertion, Some(8));
+        assert_eq!(query.readiness, Some(6));
+
+        for invalid in [json!({"exertion": 0}), json!({"readiness": 11})] {
+            server
+                .patch(&format!("/api/session/{}", session.id))
+                .json(&invalid)
+                .await
+                .assert_status_bad_request();
+        }
+
+        server
+            .patch(&format!("/api/session/{}", session.id))
+            .json(&json!({"exertion": null}))
+            .await
+            .assert_status_success();
+
+        assert!(query_by_id(session.id, &server).await.exertion.is_none());
+    }
+
     #[sqlx::test]
     async fn finish(pool: PgPool) {
         let (server, _, _, _, session, _, _) = create_test_scenario(&pool).await;
@@ -351,6 +616,146 @@ mod tests {
             .assert_status_success();
 
         assert!(query_by_id(session.id, &server).await.finished.is_some());
+
+        // Already finished
+        server
+            .patch(&format!("/api/session/{}/finish", session.id))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+    }
+
+    #[sqlx::test]
+    async fn lifecycle(pool: PgPool) {
+        let (server, _, _, _, _, exercise_instance, set) = create_test_scenario(&pool).await;
+
+        let planned_start = Utc::now() + Duration::days(1);
+
+        let planned = server
+            .post("/api/session")
+            .json(&json!({
+                "name": "Tomorrow",
+                "planned_start": planned_start
+            }))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        assert_eq!(planned.state, SessionState::Planned);
+
+        server
+            .patch(&format!("/api/session/{}/finish", planned.id))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        server
+            .patch(&format!("/api/session/{}/start", planned.id))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!(
+                "/api/session/{}/finish",
+                exercise_instance.session_id
+            ))
+            .await
+            .assert_status_success();
+
+        // Only the finished session is locked
+        assert_eq!(
+            query_by_id(planned.id, &server).await.state,
+            SessionState::InProgress
+        );
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({ "reps": 5 }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        server
+            .post(&format!(
+                "/api/exercise_instance/{}/comment",
+                exercise_instance.id
+            ))
+            .json(&json!({ "new_comment": "Too late" }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        server
+            .patch(&format!(
+                "/api/session/{}/reopen",
+                exercise_instance.session_id
+            ))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({ "reps": 5 }))
+            .await
+            .assert_status_success();
+    }
+
+    #[sqlx::test]
+    async fn rest_timer(pool: PgPool) {
+        let (server, _, _, _, _, _, set) = create_test_scenario(&pool).await;
+
+        server
+            .get("/api/session/rest")
+            .await
+            .assert_status_not_found();
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({ "weight": 100, "reps": 5, "completed": true }))
+            .await
+            .assert_status_success();
+
+        let timer = server
+            .get("/api/session/rest")
+            .await
+            .json::<RouteSuccess<RestTimer>>()
+            .data;
+
+        assert_eq!(timer.set_id, set.id);
+        assert_eq!(timer.rest_seconds, 120);
+    }
+
+    #[sqlx::test]
+    async fn correct_times(pool: PgPool) {
+        let (server, _, _, _, session, _, _) = create_test_scenario(&pool).await;
+
+        let started = session.started - Duration::hours(2);
+        let finished = started + Duration::hours(1);
+
+        // Not finished yet
+        server
+            .patch(&format!("/api/session/{}", session.id))
+            .json(&json!({ "finished": finished }))
+            .await
+            .assert_status(StatusCode::CONFLICT);
+
+        server
+            .patch(&format!("/api/session/{}/finish", session.id))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/session/{}", session.id))
+            .json(&json!({ "started": finished + Duration::minutes(1), "finished": finished }))
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        server
+            .patch(&format!("/api/session/{}", session.id))
+            .json(&json!({ "started": started, "finished": finished }))
+            .await
+            .assert_status_success();
+
+        let query = query_by_id(session.id, &server).await;
+
+        assert_eq!(query.started, started);
+        assert_eq!(query.finished, Some(finished));
     }
 
     // Get all sessions of an users
@@ -367,9 +772,90 @@ mod tests {
         let all_sessions = server
             .get("/api/session")
             .await
-            .json::<RouteSuccess<Vec<Session>>>()
+            .json::<RouteSuccess<SessionPage>>()
             .data;
 
-        assert_eq!(test_sessions.len(), all_sessions.len());
+        assert_eq!(test_sessions.len(), all_sessions.sessions.len());
+        assert!(all_sessions.next_cursor.is_none());
+    }
+
+    #[sqlx::test]
+    async fn get_pages_filtered(pool: PgPool) {
+        let (server, _, _, exercise, session, _, _) = create_test_scenario(&pool).await;
+
+        let mut test_sessions = vec![session];
+
+        for _ in 1..5 {
+            test_sessions.push(create_test_session(&server).await);
+        }
+
+        // Newest first by default, two pages of 3 and 2
+        let first_page = server
+            .get("/api/session")
+            .add_query_param("limit", 3)
+            .add_query_param("summary", true)
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data;
+
+        assert_eq!(first_page.sessions.len(), 3);
+        assert_eq!(first_page.sessions[0].id, test_sessions[4].id);
+
+        let second_page = server
+            .get("/api/session")
+            .add_query_param("limit", 3)
+            .add_query_param("cursor", first_page.next_cursor.unwrap())
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data;
+
+        assert_eq!(second_page.sessions.len(), 2);
+        assert_eq!(second_page.sessions[1].id, test_sessions[0].id);
+        assert!(second_page.next_cursor.is_none());
+
+        // Only the first session has the exercise, and exercise instances are left out
+        // in summaries
+        let with_exercise = server
+            .get("/api/session")
+            .add_query_param("exercise_id", exercise.id)
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data;
+
+        assert_eq!(with_exercise.sessions.len(), 1);
+        assert_eq!(with_exercise.sessions[0].exercise_instances.len(), 1);
+
+        let summary = server
+            .get("/api/session")
+            .add_query_param("exercise_id", exercise.id)
+            .add_query_param("summary", true)
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data;
+
+        assert!(summary.sessions[0].exercise_instances.is_empty());
+
+        // None are finished
+        let finished = server
+            .get("/api/session")
+            .add_query_param("finished", true)
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data;
+
+        assert!(finished.sessions.is_empty());
+
+        // Invalid limit and cursor
+        server
+            .get("/api/session")
+            .add_query_param("limit", 0)
+            .await
+            .assert_status_bad_request();
+
+        server
+            .get("/api/session")
+            .add_query_param("cursor", "invalid")
+            .await
+            .assert_status_bad_request();
     }
 }
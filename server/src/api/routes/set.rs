@@ -1,28 +1,64 @@
+use std::borrow::Cow;
+
 use axum::{extract::State, http::StatusCode};
 use serde::Deserialize;
 use sqlx::PgPool;
 use utoipa::ToSchema;
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 use crate::{
     api::{
-        extractors::{json::ValidatedJson, path::Path},
-        response::{RouteResponse, RouteSuccess},
+        extractors::{
+            json::ValidatedJson,
+            path::Path,
+            query::ValidatedQuery,
+            scope::{Scoped, SetsRead, SetsWrite},
+        },
+        response::{RouteError, RouteResponse, RouteSuccess},
     },
-    models::{set::Set, user::User},
+    models::set::{self, ConvertWeights, Set, SetKind},
 };
 
-use super::deserialize_optional_option;
+use super::{deserialize_optional_option, UnitQuery};
+
+fn validate_rpe(rpe: f32) -> Result<(), ValidationError> {
+    if set::is_valid_rpe(rpe) {
+        return Ok(());
+    }
+
+    let mut error = ValidationError::new("rpe");
+    error.message = Some(Cow::from("must be between 6 and 10 in steps of 0.5"));
+
+    Err(error)
+}
+
+fn validate_optional_rpe(rpe: &Option<f32>) -> Result<(), ValidationError> {
+    rpe.map_or(Ok(()), validate_rpe)
+}
+
+fn validate_nullable_rpe(rpe: &Option<Option<f32>>) -> Result<(), ValidationError> {
+    validate_optional_rpe(&rpe.flatten())
+}
 
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct CreateSetInput {
     exercise_instance_id: Uuid,
+    // Effort as RPE or reps in reserve, not both
+    #[validate(custom(function = "validate_optional_rpe"))]
+    #[schema(example = 8.5)]
+    rpe: Option<f32>,
+    #[validate(range(min = 0, max = 10, message = "must be between 0 and 10"))]
+    rir: Option<i32>,
+    // Inserted before the set at the position, after the last one if not given
+    #[validate(range(min = 0, message = "can't be negative"))]
+    position: Option<i32>,
 }
 
 #[utoipa::path(
     post,
     path = "/api/set",
+    params(UnitQuery),
     request_body = CreateSetInput,
     security(
         ("access_token"= [])
@@ -35,22 +71,49 @@ pub struct CreateSetInput {
     )
 )]
 pub async fn create_set(
-    user: User,
+    Scoped(user, _): Scoped<SetsWrite>,
     State(pool): State<PgPool>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     ValidatedJson(body): ValidatedJson<CreateSetInput>,
 ) -> RouteResponse<Set> {
+    ensure_rpe_or_rir(body.rpe, body.rir)?;
+
+    let mut set = Set::new(user.id, body.exercise_instance_id, body.position, &pool).await?;
+
+    if body.rpe.is_some() {
+        set.set_rpe(body.rpe, &pool).await?;
+    }
+
+    if body.rir.is_some() {
+        set.set_rir(body.rir, &pool).await?;
+    }
+
     Ok(RouteSuccess::new(
         "New set created without reps or weight.",
-        Set::new(user.id, body.exercise_instance_id, &pool).await?,
+        set.in_unit(unit_query.or_preference(&user)),
         StatusCode::CREATED,
     ))
 }
 
+// Both describe the same effort, so only one can be given
+fn ensure_rpe_or_rir(rpe: Option<f32>, rir: Option<i32>) -> Result<(), RouteError> {
+    if rpe.is_some() && rir.is_some() {
+        return Err(RouteError::new(
+            "Invalid input in rir field: only one of rpe and rir can be set",
+            Some("rir"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    Ok(())
+}
+
 #[utoipa::path(
     get,
     path = "/api/set/{set_id}",
     params(
-        ("set_id" = Uuid, Path, description = "The ID of the set")
+        ("set_id" = Uuid, Path, description = "The ID of the set"),
+        UnitQuery
     ),
     security(
         ("access_token"= [])
@@ -63,13 +126,16 @@ pub async fn create_set(
     )
 )]
 pub async fn get_set_by_id(
-    user: User,
+    Scoped(user, _): Scoped<SetsRead>,
     State(pool): State<PgPool>,
     Path(set_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
 ) -> RouteResponse<Set> {
     Ok(RouteSuccess::new(
         "Found set from ID.",
-        Set::from_id(user.id, set_id, &pool).await?,
+        Set::from_id(user.id, set_id, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -91,7 +157,7 @@ pub async fn get_set_by_id(
     )
 )]
 pub async fn delete_set(
-    user: User,
+    Scoped(user, _): Scoped<SetsWrite>,
     State(pool): State<PgPool>,
     Path(set_id): Path<Uuid>,
 ) -> RouteResponse<Uuid> {
@@ -111,7 +177,22 @@ pub struct EditSetInput {
     weight: Option<Option<f32>>,
     #[serde(default, deserialize_with = "deserialize_optional_option")]
     reps: Option<Option<i32>>,
+    kind: Option<SetKind>,
+    // Setting one of these removes the other
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(custom(function = "validate_nullable_rpe"))]
+    #[schema(example = 8.5)]
+    rpe: Option<Option<f32>>,
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(range(min = 0, max = 10, message = "must be between 0 and 10"))]
+    rir: Option<Option<i32>>,
+    // Only for drop sets, null removes the link
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    parent_set_id: Option<Option<Uuid>>,
     completed: Option<bool>,
+    // Moves the set, the ones in between move by one
+    #[validate(range(min = 0, message = "can't be negative"))]
+    position: Option<i32>,
 }
 
 #[utoipa::path(
@@ -119,6 +200,7 @@ pub struct EditSetInput {
     path = "/api/set/{set_id}",
     params(
         ("set_id" = Uuid, Path, description = "The ID of the exercise instance being edited"),
+        UnitQuery
     ),
     request_body = EditSetInput,
     security(
@@ -132,17 +214,26 @@ pub struct EditSetInput {
     )
 )]
 pub async fn edit_set(
-    user: User,
+    Scoped(user, _): Scoped<SetsWrite>,
     State(pool): State<PgPool>,
     Path(set_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
     ValidatedJson(body): ValidatedJson<EditSetInput>,
 ) -> RouteResponse<Set> {
+    ensure_rpe_or_rir(body.rpe.flatten(), body.rir.flatten())?;
+
+    let unit = unit_query.or_preference(&user);
+
     let mut set = Set::from_id(user.id, set_id, &pool).await?;
 
     // Weight and reps are edited first, because they have to exist
     // to complete the set
 
     if let Some(weight) = body.weight {
+        let weight = weight
+            .map(|weight| unit.rounded_to_kilograms(weight))
+            .transpose()?;
+
         set.set_weight(weight, &pool).await?
     }
 
@@ -150,6 +241,28 @@ pub async fn edit_set(
         set.set_reps(reps, &pool).await?
     }
 
+    if let Some(rpe) = body.rpe {
+        set.set_rpe(rpe, &pool).await?
+    }
+
+    if let Some(rir) = body.rir {
+        set.set_rir(rir, &pool).await?
+    }
+
+    // Moved before linking, since parents have to be earlier sets
+    if let Some(position) = body.position {
+        set.set_position(position, &pool).await?
+    }
+
+    // Kind before the parent, so a set can be made a drop set and linked at once
+    if let Some(kind) = body.kind {
+        set.set_kind(kind, &pool).await?
+    }
+
+    if let Some(parent_set_id) = body.parent_set_id {
+        set.set_parent(parent_set_id, &pool).await?
+    }
+
     if let Some(completed) = body.completed {
         if completed {
             set.set_complete(&pool).await?;
@@ -160,7 +273,42 @@ pub async fn edit_set(
 
     Ok(RouteSuccess::new(
         "Requseted changes to set made successfully.",
-        set,
+        set.in_unit(unit),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/set/{set_id}/swap/{other_set_id}",
+    params(
+        ("set_id" = Uuid, Path, description = "The ID of the set being moved"),
+        ("other_set_id" = Uuid, Path, description = "The ID of the set it swaps places with"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Sets swapped", body = RouteSuccessSet),
+        (status = NOT_FOUND, description = "Set not found", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Sets aren't in the same exercise instance", body = RouteError),
+    )
+)]
+pub async fn swap_sets(
+    Scoped(user, _): Scoped<SetsWrite>,
+    State(pool): State<PgPool>,
+    Path(path_args): Path<(Uuid, Uuid)>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Set> {
+    let mut set = Set::from_id(user.id, path_args.0, &pool).await?;
+
+    set.swap_position(path_args.1, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Sets swapped.",
+        set.in_unit(unit_query.or_preference(&user)),
         StatusCode::OK,
     ))
 }
@@ -174,7 +322,11 @@ mod tests {
 
     use crate::{
         api::response::RouteSuccess,
-        models::{exercise_instance::ExerciseInstance, set::Set},
+        models::{
+            exercise_instance::ExerciseInstance,
+            set::{Set, SetKind, WeightUnit},
+            user::User,
+        },
         test_utils::api::{create_test_exercise_instance, create_test_scenario, create_test_set},
     };
 
@@ -284,6 +436,195 @@ mod tests {
         assert_eq!(query_negative.weight.unwrap(), -10.0);
     }
 
+    #[sqlx::test]
+    async fn weight_units(pool: PgPool) {
+        let (server, _, _, _, _, _, set) = create_test_scenario(&pool).await;
+
+        let user = server
+            .patch("/api/user/weight_unit")
+            .json(&json!({"weight_unit": "lb"}))
+            .await
+            .json::<RouteSuccess<User>>()
+            .data;
+
+        assert_eq!(user.weight_unit, WeightUnit::Lb);
+
+        // Given and shown in pounds, rounded in pounds
+        let in_pounds = server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"weight": 225.04}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(in_pounds.weight, Some(225.0));
+
+        // Stored in kilograms
+        let stored = Set::from_id(set.user_id, set.id, &pool).await.unwrap();
+
+        assert_eq!(stored.weight, Some(WeightUnit::Lb.to_kilograms(225.0)));
+
+        // The unit can be asked for per request
+        let in_kilograms = server
+            .get(&format!("/api/set/{}", set.id))
+            .add_query_param("unit", "kg")
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        // Only converted weights are rounded
+        assert_eq!(in_kilograms.weight, stored.weight);
+
+        // Switching back doesn't change the history
+        server
+            .patch("/api/user/weight_unit")
+            .json(&json!({"weight_unit": "kg"}))
+            .await
+            .assert_status_ok();
+
+        assert_eq!(query_set(&server, set.id).await.weight, stored.weight);
+
+        let in_pounds = server
+            .get(&format!("/api/set/{}", set.id))
+            .add_query_param("unit", "lb")
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(in_pounds.weight, Some(225.0));
+
+        server
+            .get(&format!("/api/set/{}", set.id))
+            .add_query_param("unit", "stone")
+            .await
+            .assert_status_bad_request();
+
+        server
+            .patch("/api/user/weight_unit")
+            .json(&json!({"weight_unit": "stone"}))
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn rpe_and_rir(pool: PgPool) {
+        let (server, _, _, _, _, exercise_instance, set) = create_test_scenario(&pool).await;
+
+        let created = server
+            .post("/api/set")
+            .json(&json!({"exercise_instance_id": exercise_instance.id, "rpe": 7.5}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(created.rpe, Some(7.5));
+
+        for invalid in [
+            json!({"rpe": 8.25}),
+            json!({"rpe": 5.5}),
+            json!({"rpe": 10.5}),
+            json!({"rir": -1}),
+            json!({"rpe": 8, "rir": 2}),
+        ] {
+            server
+                .patch(&format!("/api/set/{}", set.id))
+                .json(&invalid)
+                .await
+                .assert_status_bad_request();
+        }
+
+        let edited = server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"rpe": 9.5}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(edited.rpe, Some(9.5));
+
+        // Setting one removes the other
+        let edited = server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"rir": 2}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(edited.rir, Some(2));
+        assert!(edited.rpe.is_none());
+
+        let edited = server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"rpe": 8, "rir": null}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(edited.rpe, Some(8.0));
+        assert!(edited.rir.is_none());
+    }
+
+    #[sqlx::test]
+    async fn kinds_and_drop_sets(pool: PgPool) {
+        let (server, _, _, exercise, session, exercise_instance, set) =
+            create_test_scenario(&pool).await;
+
+        let drop_set = create_test_set(&server, exercise_instance.id).await;
+
+        assert_eq!(drop_set.kind, SetKind::Working);
+
+        // Only drop sets can have a parent
+        server
+            .patch(&format!("/api/set/{}", drop_set.id))
+            .json(&json!({"parent_set_id": set.id}))
+            .await
+            .assert_status_bad_request();
+
+        let edited = server
+            .patch(&format!("/api/set/{}", drop_set.id))
+            .json(&json!({"kind": "drop", "parent_set_id": set.id}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(edited.kind, SetKind::Drop);
+        assert_eq!(edited.parent_set_id, Some(set.id));
+
+        // The parent has to be an earlier set of the same instance
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"kind": "drop", "parent_set_id": drop_set.id}))
+            .await
+            .assert_status_bad_request();
+
+        let another_instance =
+            create_test_exercise_instance(&server, session.id, exercise.id).await;
+        let another_set = create_test_set(&server, another_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}", another_set.id))
+            .json(&json!({"kind": "drop", "parent_set_id": set.id}))
+            .await
+            .assert_status_bad_request();
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"kind": "superset"}))
+            .await
+            .assert_status_failure();
+
+        // Changing the kind removes the parent
+        let edited = server
+            .patch(&format!("/api/set/{}", drop_set.id))
+            .json(&json!({"kind": "amrap"}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(edited.kind, SetKind::Amrap);
+        assert!(edited.parent_set_id.is_none());
+    }
+
     #[sqlx::test]
     async fn get_all_for_exercise_instance(pool: PgPool) {
         let (server, _, _, exercise, session, exercise_instance, _) =
@@ -310,4 +651,80 @@ mod tests {
 
         assert_eq!(query_all.sets.len(), 4);
     }
+
+    #[sqlx::test]
+    async fn reorder(pool: PgPool) {
+        let (server, _, _, exercise, session, exercise_instance, first) =
+            create_test_scenario(&pool).await;
+
+        let last = create_test_set(&server, exercise_instance.id).await;
+
+        // Forgotten set before the others
+        let inserted = server
+            .post("/api/set")
+            .json(&json!({"exercise_instance_id": exercise_instance.id, "position": 0}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(inserted.position, 0);
+        assert_eq!(query_set(&server, first.id).await.position, 1);
+        assert_eq!(query_set(&server, last.id).await.position, 2);
+
+        server
+            .post("/api/set")
+            .json(&json!({"exercise_instance_id": exercise_instance.id, "position": 4}))
+            .await
+            .assert_status_bad_request();
+
+        // Drop set of the first one
+        server
+            .patch(&format!("/api/set/{}", last.id))
+            .json(&json!({"kind": "drop", "parent_set_id": first.id}))
+            .await
+            .assert_status_ok();
+
+        let moved = server
+            .patch(&format!("/api/set/{}", inserted.id))
+            .json(&json!({"position": 2}))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(moved.position, 2);
+        assert_eq!(query_set(&server, first.id).await.position, 0);
+        assert_eq!(query_set(&server, last.id).await.position, 1);
+
+        // Drop sets moved before their parents are unlinked
+        let swapped = server
+            .patch(&format!("/api/set/{}/swap/{}", last.id, first.id))
+            .await
+            .json::<RouteSuccess<Set>>()
+            .data;
+
+        assert_eq!(swapped.position, 0);
+        assert!(swapped.parent_set_id.is_none());
+        assert_eq!(query_set(&server, first.id).await.position, 1);
+
+        // Only within the same exercise instance
+        let other_instance = create_test_exercise_instance(&server, session.id, exercise.id).await;
+        let other = create_test_set(&server, other_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}/swap/{}", first.id, other.id))
+            .await
+            .assert_status_bad_request();
+
+        let sets = server
+            .get(&format!("/api/exercise_instance/{}", exercise_instance.id))
+            .await
+            .json::<RouteSuccess<ExerciseInstance>>()
+            .data
+            .sets;
+
+        assert_eq!(
+            sets.iter().map(|set| set.id).collect::<Vec<_>>(),
+            vec![last.id, first.id, inserted.id]
+        );
+    }
 }
@@ -0,0 +1,483 @@
+use std::borrow::Cow;
+
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{
+    api::{
+        extractors::{
+            json::ValidatedJson,
+            path::Path,
+            query::ValidatedQuery,
+            scope::{Scoped, SessionsWrite, TemplatesRead, TemplatesWrite},
+        },
+        response::{RouteError, RouteResponse, RouteSuccess},
+    },
+    models::{
+        session::Session,
+        set::{ConvertWeights, WeightUnit},
+        template::{all_user_templates, PlannedExercise, Template},
+    },
+};
+
+use super::{deserialize_optional_option, UnitQuery};
+
+// Checks the planned exercises of a template, because nested
+// validation errors would not show up as field errors
+fn validate_planned_exercises(exercises: &[PlannedExercise]) -> Result<(), ValidationError> {
+    let error = |message: &'static str| {
+        let mut error = ValidationError::new("planned_exercises");
+        error.message = Some(Cow::from(message));
+        error
+    };
+
+    if exercises.len() > 50 {
+        return Err(error("a template can have at most 50 exercises"));
+    }
+
+    for planned in exercises {
+        if !(1..=50).contains(&planned.sets) {
+            return Err(error("planned sets must be between 1 and 50"));
+        }
+
+        if let Some(reps) = planned.reps {
+            if !(0..=1000).contains(&reps) {
+                return Err(error("planned reps must be between 0 and 1000"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Planned weights are given in the unit of the request
+fn planned_in_kilograms(
+    exercises: Vec<PlannedExercise>,
+    unit: WeightUnit,
+) -> Result<Vec<PlannedExercise>, RouteError> {
+    exercises
+        .into_iter()
+        .map(|planned| planned.in_kilograms(unit))
+        .collect()
+}
+
+// Same as above, but only if the exercises are being changed
+fn validate_optional_planned_exercises(
+    exercises: &Option<Vec<PlannedExercise>>,
+) -> Result<(), ValidationError> {
+    match exercises {
+        Some(exercises) => validate_planned_exercises(exercises),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct CreateTemplateInput {
+    #[validate(length(min = 1, max = 30, message = "must be between 1 and 30 characters"))]
+    name: String,
+    #[validate(length(
+        min = 1,
+        max = 10000,
+        message = "must be between 1 and 10000 characters"
+    ))]
+    description: Option<String>,
+    // Planned exercises in order
+    #[serde(default)]
+    #[validate(custom(function = "validate_planned_exercises"))]
+    exercises: Vec<PlannedExercise>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/template",
+    params(UnitQuery),
+    request_body = CreateTemplateInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "New template created", body = RouteSuccessTemplate),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid input for template", body = RouteError),
+    )
+)]
+pub async fn create_template(
+    Scoped(user, _): Scoped<TemplatesWrite>,
+    State(pool): State<PgPool>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+    ValidatedJson(body): ValidatedJson<CreateTemplateInput>,
+) -> RouteResponse<Template> {
+    let unit = unit_query.or_preference(&user);
+    let exercises = planned_in_kilograms(body.exercises, unit)?;
+
+    let new_template =
+        Template::new(user.id, body.name, body.description, exercises, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        format!("New template '{}' created.", &new_template.name),
+        new_template.in_unit(unit),
+        StatusCode::CREATED,
+    ))
+}
+
+// All changes are optional, and if they do not exist in the input JSON
+// they are not changed, meaning nulls can overwrite set values
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct EditTemplateInput {
+    #[validate(length(min = 1, max = 30, message = "must be between 1 and 30 characters"))]
+    name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_option")]
+    #[validate(length(
+        min = 1,
+        max = 10000,
+        message = "must be between 1 and 10000 characters"
+    ))]
+    description: Option<Option<String>>,
+    // Replaces all planned exercises if set
+    #[validate(custom(function = "validate_optional_planned_exercises"))]
+    exercises: Option<Vec<PlannedExercise>>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/template/{template_id}",
+    params(
+        ("template_id" = Uuid, Path, description = "The ID of the edited template"),
+        UnitQuery
+    ),
+    request_body = EditTemplateInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Template modified", body = RouteSuccessTemplate),
+        (status = NOT_FOUND, description = "Invalid template ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid input for template", body = RouteError),
+    )
+)]
+pub async fn edit_template(
+    Scoped(user, _): Scoped<TemplatesWrite>,
+    State(pool): State<PgPool>,
+    Path(template_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+    ValidatedJson(body): ValidatedJson<EditTemplateInput>,
+) -> RouteResponse<Template> {
+    let unit = unit_query.or_preference(&user);
+
+    let mut template = Template::from_id(user.id, template_id, &pool).await?;
+
+    if let Some(new_name) = body.name {
+        template.set_name(new_name, &pool).await?;
+    }
+
+    if let Some(new_description) = body.description {
+        template.set_description(new_description, &pool).await?;
+    }
+
+    if let Some(new_exercises) = body.exercises {
+        template
+            .set_exercises(planned_in_kilograms(new_exercises, unit)?, &pool)
+            .await?;
+    }
+
+    Ok(RouteSuccess::new(
+        "Template modified if changes were requested.",
+        template.in_unit(unit),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/template/{template_id}",
+    params(
+        ("template_id" = Uuid, Path, description = "The ID of the template requested"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Template found", body = RouteSuccessTemplate),
+        (status = NOT_FOUND, description = "Invalid template ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+    )
+)]
+pub async fn get_template_by_id(
+    Scoped(user, _): Scoped<TemplatesRead>,
+    State(pool): State<PgPool>,
+    Path(template_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Template> {
+    Ok(RouteSuccess::new(
+        "Template found.",
+        Template::from_id(user.id, template_id, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/template/{template_id}",
+    params(
+        ("template_id" = Uuid, Path, description = "The ID of the template being deleted")
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Template found and deleted", body = RouteSuccessUuid),
+        (status = NOT_FOUND, description = "Invalid template ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+    )
+)]
+pub async fn delete_template_by_id(
+    Scoped(user, _): Scoped<TemplatesWrite>,
+    State(pool): State<PgPool>,
+    Path(template_id): Path<Uuid>,
+) -> RouteResponse<Uuid> {
+    Ok(RouteSuccess::new(
+        "Template deleted, sessions created from it were not affected.",
+        Template::from_id(user.id, template_id, &pool)
+            .await?
+            .delete(&pool)
+            .await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/template",
+    params(UnitQuery),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Returned all user's templates (zero or more)", body = RouteSuccessTemplateVec),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+    )
+)]
+pub async fn get_all_user_templates(
+    Scoped(user, _): Scoped<TemplatesRead>,
+    State(pool): State<PgPool>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Vec<Template>> {
+    Ok(RouteSuccess::new(
+        "Returned all user's templates.",
+        all_user_templates(user.id, &pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/template/{template_id}/session",
+    params(
+        ("template_id" = Uuid, Path, description = "The ID of the template used for the session"),
+        UnitQuery
+    ),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "New session created from the template", body = RouteSuccessSession),
+        (status = NOT_FOUND, description = "Invalid template ID", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid ID format", body = RouteError),
+    )
+)]
+pub async fn create_session_from_template(
+    Scoped(user, _): Scoped<SessionsWrite>,
+    State(pool): State<PgPool>,
+    Path(template_id): Path<Uuid>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> RouteResponse<Session> {
+    Ok(RouteSuccess::new(
+        "New session created from template.",
+        Template::from_id(user.id, template_id, &pool)
+            .await?
+            .start_session(&pool)
+            .await?
+            .in_unit(unit_query.or_preference(&user)),
+        StatusCode::CREATED,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_test::TestServer;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use crate::{
+        api::response::RouteSuccess,
+        models::{session::Session, template::Template},
+        test_utils::api::{create_test_exercise, create_test_scenario},
+    };
+
+    async fn create_test_template(server: &TestServer, exercise_id: Uuid) -> Template {
+        server
+            .post("/api/template")
+            .json(&json!({
+                "name": "Push",
+                "description": "Chest, shoulders and triceps",
+                "exercises": [
+                    {"exercise_id": exercise_id, "sets": 3, "reps": 8, "weight": 80.0},
+                    {"exercise_id": exercise_id, "sets": 2, "reps": null, "weight": null}
+                ]
+            }))
+            .await
+            .json::<RouteSuccess<Template>>()
+            .data
+    }
+
+    #[sqlx::test]
+    async fn create_and_query(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        let template = create_test_template(&server, exercise.id).await;
+
+        assert_eq!(template.exercises.len(), 2);
+
+        let query = server
+            .get(&format!("/api/template/{}", template.id))
+            .await
+            .json::<RouteSuccess<Template>>()
+            .data;
+
+        assert_eq!(query, template);
+
+        // Invalid ID
+        server
+            .get(&format!("/api/template/{}", Uuid::new_v4()))
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn invalid_planned_exercises(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        // Zero sets
+        server
+            .post("/api/template")
+            .json(&json!({
+                "name": "Push",
+                "exercises": [{"exercise_id": exercise.id, "sets": 0}]
+            }))
+            .await
+            .assert_status_failure();
+
+        // Exercise not owned by the user
+        server
+            .post("/api/template")
+            .json(&json!({
+                "name": "Push",
+                "exercises": [{"exercise_id": Uuid::new_v4(), "sets": 3}]
+            }))
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn edit(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        let template = create_test_template(&server, exercise.id).await;
+        let another_exercise = create_test_exercise(&server).await;
+
+        let edited = server
+            .patch(&format!("/api/template/{}", template.id))
+            .json(&json!({
+                "name": "Pull",
+                "description": null,
+                "exercises": [{"exercise_id": another_exercise.id, "sets": 4}]
+            }))
+            .await
+            .json::<RouteSuccess<Template>>()
+            .data;
+
+        assert_eq!(edited.name, "Pull");
+        assert!(edited.description.is_none());
+        assert_eq!(edited.exercises.len(), 1);
+        assert_eq!(edited.exercises[0].exercise_id, another_exercise.id);
+        assert_eq!(edited.exercises[0].sets, 4);
+    }
+
+    #[sqlx::test]
+    async fn delete(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        let template = create_test_template(&server, exercise.id).await;
+
+        server
+            .delete(&format!("/api/template/{}", template.id))
+            .await
+            .assert_status_success();
+
+        server
+            .get(&format!("/api/template/{}", template.id))
+            .await
+            .assert_status_failure();
+    }
+
+    #[sqlx::test]
+    async fn get_all(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        for _ in 0..3 {
+            create_test_template(&server, exercise.id).await;
+        }
+
+        let all = server
+            .get("/api/template")
+            .await
+            .json::<RouteSuccess<Vec<Template>>>()
+            .data;
+
+        assert_eq!(all.len(), 3);
+    }
+
+    #[sqlx::test]
+    async fn create_session(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        let template = create_test_template(&server, exercise.id).await;
+
+        let response = server
+            .post(&format!("/api/template/{}/session", template.id))
+            .await;
+
+        response.assert_status(axum::http::StatusCode::CREATED);
+
+        let session = response.json::<RouteSuccess<Session>>().data;
+
+        assert_eq!(session.name, template.name);
+        assert_eq!(session.exercise_instances.len(), 2);
+        assert_eq!(session.exercise_instances[0].sets.len(), 3);
+        assert_eq!(session.exercise_instances[0].sets[0].weight, Some(80.0));
+        assert_eq!(session.exercise_instances[1].sets.len(), 2);
+
+        // Should be queryable as a normal session
+        let query = server
+            .get(&format!("/api/session/{}", session.id))
+            .await
+            .json::<RouteSuccess<Session>>()
+            .data;
+
+        assert_eq!(query, session);
+    }
+}
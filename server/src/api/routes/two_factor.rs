@@ -0,0 +1,201 @@
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    api::{
+        extractors::json::ValidatedJson,
+        response::{RouteResponse, RouteSuccess},
+    },
+    models::{
+        totp::{self, TotpEnrollment},
+        user::User,
+    },
+};
+
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct TwoFactorCodeInput {
+    // Code from the authenticator app, or a recovery code when disabling
+    #[validate(length(min = 1, max = 20, message = "must be between 1 and 20 characters"))]
+    #[schema(example = "123456")]
+    code: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/2fa",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "Secret for the authenticator app, enabled after verifying a code", body = RouteSuccessTotpEnrollment),
+        (status = CONFLICT, description = "Already enabled", body = RouteError),
+        (status = UNAUTHORIZED, description = "Invalid authorization token", body = RouteError),
+    )
+)]
+pub async fn start_two_factor(
+    user: User,
+    State(pool): State<PgPool>,
+) -> RouteResponse<TotpEnrollment> {
+    Ok(RouteSuccess::new(
+        "Add the secret to an authenticator app and verify a code to enable two-factor authentication.",
+        totp::start_enrollment(user.id, &user.username, &pool).await?,
+        StatusCode::CREATED,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/2fa/verify",
+    request_body = TwoFactorCodeInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Enabled, returns the recovery codes which are not shown again", body = RouteSuccessStringVec),
+        (status = UNAUTHORIZED, description = "Invalid code or authorization token", body = RouteError),
+        (status = NOT_FOUND, description = "Enrollment not started", body = RouteError),
+        (status = CONFLICT, description = "Already enabled", body = RouteError),
+    )
+)]
+pub async fn verify_two_factor(
+    user: User,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<TwoFactorCodeInput>,
+) -> RouteResponse<Vec<String>> {
+    Ok(RouteSuccess::new(
+        "Two-factor authentication enabled, store the recovery codes somewhere safe.",
+        totp::verify_enrollment(user.id, &body.code, &pool).await?,
+        StatusCode::OK,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/user/2fa",
+    request_body = TwoFactorCodeInput,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Disabled", body = RouteSuccessUuid),
+        (status = UNAUTHORIZED, description = "Invalid code or authorization token", body = RouteError),
+        (status = NOT_FOUND, description = "Not enabled", body = RouteError),
+    )
+)]
+pub async fn disable_two_factor(
+    user: User,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<TwoFactorCodeInput>,
+) -> RouteResponse<Uuid> {
+    totp::disable(user.id, &body.code, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Two-factor authentication disabled.",
+        user.id,
+        StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use chrono::Utc;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        api::response::RouteSuccess,
+        models::{
+            access_token::AccessToken,
+            totp::{totp_at, TotpEnrollment},
+        },
+        test_utils::api::create_test_app,
+    };
+
+    #[sqlx::test]
+    async fn login_requires_code(pool: PgPool) {
+        let (server, _, _) = create_test_app(&pool).await;
+
+        server
+            .post("/api/user/2fa")
+            .await
+            .assert_status(StatusCode::CREATED);
+
+        // Verifying a wrong code keeps it disabled
+        server
+            .post("/api/user/2fa/verify")
+            .json(&json!({ "code": "000000x" }))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let enrollment = server
+            .post("/api/user/2fa")
+            .await
+            .json::<RouteSuccess<TotpEnrollment>>()
+            .data;
+
+        assert!(enrollment
+            .otpauth_uri
+            .starts_with("otpauth://totp/LiftLog:test?"));
+
+        let secret = sqlx::query_scalar!("SELECT secret FROM totp_credentials")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let code = totp_at(&secret, Utc::now().timestamp());
+
+        let recovery_codes = server
+            .post("/api/user/2fa/verify")
+            .json(&json!({ "code": code }))
+            .await
+            .json::<RouteSuccess<Vec<String>>>()
+            .data;
+
+        let login = json!({
+            "username": "test",
+            "password": "testuserpassword",
+            "validity_in_seconds": 60
+        });
+
+        // Second step is needed
+        server
+            .post("/api/access_token")
+            .json(&login)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        let mut with_code = login.clone();
+        with_code["code"] = json!(recovery_codes[0]);
+
+        let response = server.post("/api/access_token").json(&with_code).await;
+
+        response.assert_status_success();
+
+        let access_token = response.json::<RouteSuccess<AccessToken>>().data;
+
+        assert!(access_token.is_valid());
+
+        // Used recovery code
+        server
+            .post("/api/access_token")
+            .json(&with_code)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        server
+            .delete("/api/user/2fa")
+            .json(&json!({ "code": recovery_codes[1] }))
+            .await
+            .assert_status_success();
+
+        server
+            .post("/api/access_token")
+            .json(&login)
+            .await
+            .assert_status_success();
+    }
+}
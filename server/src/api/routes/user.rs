@@ -1,17 +1,42 @@
-use axum::{extract::State, http::StatusCode};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::StreamExt;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
 use sqlx::PgPool;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
-    api::{response::{RouteResponse, RouteSuccess}, extractors::json::ValidatedJson},
-    models::user::User,
+    api::{
+        extractors::{
+            json::ValidatedJson,
+            query::ValidatedQuery,
+            scope::{Export, Scoped},
+        },
+        response::{RouteError, RouteResponse, RouteSuccess},
+    },
+    mailer::SharedMailer,
+    models::{
+        access_token::AccessToken,
+        account_deletion::{self, AccountDeletion, DeletionGracePeriod},
+        export::{self, ExportFormat, UserExport},
+        import::{self, ImportSummary},
+        set::{self, SetKind, WeightUnit},
+        user::User,
+    },
+    settings::Registration,
 };
 
+use super::{default_as_false, deserialize_comma_separated, UnitQuery};
+
 lazy_static! {
     pub static ref REGEX_USERNAME: Regex = Regex::new(r"^[a-zA-Z0-9_-]{1,20}$").unwrap();
 }
@@ -19,7 +44,7 @@ lazy_static! {
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct CreateUserInput {
     #[validate(regex(
-        path = "REGEX_USERNAME",
+        path = *REGEX_USERNAME,
         message = "only letters a-z, A-Z, numbers, - and _ are allowed"
     ))]
     #[schema(example = "some_username")]
@@ -27,6 +52,9 @@ pub struct CreateUserInput {
     #[validate(length(min = 10, max = 200, message = "must be between 10 and 200 characters"))]
     #[schema(example = "strong_password_with_at_least_10_characters")]
     password: String,
+    // Needed when registration is invite only
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    invite_code: Option<String>,
 }
 
 #[utoipa::path(
@@ -36,18 +64,27 @@ pub struct CreateUserInput {
     responses(
         (status = OK, description = "New user created", body = RouteSuccessUser),
         (status = CONFLICT, description = "Username already taken", body = RouteError),
+        (status = FORBIDDEN, description = "Signing up is closed, or the invite code is missing or invalid", body = RouteError),
         (status = INTERNAL_SERVER_ERROR, description = "Password hashing failed", body = RouteError)
     ),
     
 )]
 // New user from the username and password provided
 pub async fn create_user(
+    Extension(registration): Extension<Registration>,
     State(pool): State<PgPool>,
     ValidatedJson(body): ValidatedJson<CreateUserInput>,
 ) -> RouteResponse<User> {
     Ok(RouteSuccess::new(
         "New user created.",
-        User::new(body.username, body.password, &pool).await?,
+        User::register(
+            body.username,
+            body.password,
+            registration,
+            body.invite_code,
+            &pool,
+        )
+        .await?,
         StatusCode::OK,
     ))
 }
@@ -80,23 +117,34 @@ pub async fn get_self(user: User) -> RouteResponse<User> {
         ("access_token"= [])
     ),
     responses(
-        (status = OK, description = "User has been deleted", body = RouteSuccessUuid),
+        (status = OK, description = "Deletion scheduled and logged out everywhere, the export token downloads the data until then", body = RouteSuccessAccountDeletion),
         (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
         (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
     ),
 )]
-pub async fn delete_user(user: User, State(pool): State<PgPool>) -> RouteResponse<Uuid> {
-    let user_id = user.id;
-
-    user.delete(&pool).await?;
+// Everything is deleted after the grace period, unless the user logs in before that
+pub async fn delete_user(
+    user: User,
+    Extension(DeletionGracePeriod(grace_period)): Extension<DeletionGracePeriod>,
+    Extension(mailer): Extension<SharedMailer>,
+    State(pool): State<PgPool>,
+) -> RouteResponse<AccountDeletion> {
+    let deletion = account_deletion::schedule(&user, grace_period, mailer.as_ref(), &pool).await?;
 
-    Ok(RouteSuccess::new("User has been deleted.", user_id, StatusCode::OK))
+    Ok(RouteSuccess::new(
+        format!(
+            "User will be deleted on {}, log in before that to cancel.",
+            deletion.purge_after.format("%Y-%m-%d")
+        ),
+        deletion,
+        StatusCode::OK,
+    ))
 }
 
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct ChangeUsernameInput {
     #[validate(regex(
-        path = "REGEX_USERNAME",
+        path = *REGEX_USERNAME,
         message = "only letters a-z, A-Z, numbers, - and _ are allowed"
     ))]
     #[schema(example = "some_username")]
@@ -115,17 +163,56 @@ pub struct ChangeUsernameInput {
         (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
     ),
 )]
-pub async fn change_username(mut user: User, State(pool): State<PgPool>, ValidatedJson(body): ValidatedJson<ChangeUsernameInput>) -> RouteResponse<User> {
+pub async fn change_username(
+    mut user: User,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<ChangeUsernameInput>,
+) -> RouteResponse<User> {
     user.change_username(body.new_username, &pool).await?;
 
     Ok(RouteSuccess::new("Username changed.", user, StatusCode::OK))
 }
 
+#[derive(Debug, Validate, Deserialize, ToSchema)]
+pub struct ChangeWeightUnitInput {
+    // Weights are stored in kilograms, so changing this doesn't change them
+    weight_unit: WeightUnit,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/user/weight_unit",
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Weight unit has been changed", body = RouteSuccessUser),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Access token missing or malformed, or invalid unit", body = RouteError)
+    ),
+)]
+pub async fn change_weight_unit(
+    mut user: User,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<ChangeWeightUnitInput>,
+) -> RouteResponse<User> {
+    user.set_weight_unit(body.weight_unit, &pool).await?;
+
+    Ok(RouteSuccess::new(
+        "Weight unit changed.",
+        user,
+        StatusCode::OK,
+    ))
+}
+
 #[derive(Debug, Validate, Deserialize, ToSchema)]
 pub struct ChangePasswordInput {
     #[validate(length(min = 10, max = 200, message = "must be between 10 and 200 characters"))]
     #[schema(example = "strong_password_with_at_least_10_characters")]
     new_password: String,
+    // Log out everywhere else
+    #[serde(default = "default_as_false")]
+    revoke_other_tokens: bool,
 }
 
 #[utoipa::path(
@@ -140,23 +227,291 @@ pub struct ChangePasswordInput {
         (status = BAD_REQUEST, description = "Access token missing or malformed", body = RouteError)
     ),
 )]
-pub async fn change_password(mut user: User, State(pool): State<PgPool>, ValidatedJson(body): ValidatedJson<ChangePasswordInput>) -> RouteResponse<User> {
-    user.change_password(body.new_password, &pool).await?;
+pub async fn change_password(
+    mut user: User,
+    access_token: AccessToken,
+    State(pool): State<PgPool>,
+    ValidatedJson(body): ValidatedJson<ChangePasswordInput>,
+) -> RouteResponse<User> {
+    let revoke_tokens_except = body.revoke_other_tokens.then_some(access_token.id);
+
+    user.change_password(body.new_password, revoke_tokens_except, &pool)
+        .await?;
 
     Ok(RouteSuccess::new("Password changed.", user, StatusCode::OK))
 }
 
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    // JSON by default
+    #[serde(default)]
+    format: ExportFormat,
+    // Only include these kinds of sets, comma separated
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "working,drop")]
+    kinds: Option<Vec<SetKind>>,
+    // Leave out these kinds of sets, comma separated. Everything is included by default.
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "warm_up")]
+    exclude_kinds: Option<Vec<SetKind>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/export",
+    params(ExportQuery, UnitQuery),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = OK, description = "Training history as a JSON document or CSV", body = UserExport, content_type = ["application/json", "text/csv"]),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Access token missing or malformed, or invalid format", body = RouteError)
+    ),
+)]
+// Stream all of the user's training history as a download
+pub async fn export_user_data(
+    Scoped(user, _): Scoped<Export>,
+    State(pool): State<PgPool>,
+    ValidatedQuery(query): ValidatedQuery<ExportQuery>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> Result<Response, RouteError> {
+    let set_kinds =
+        set::included_kinds(query.kinds.as_deref(), query.exclude_kinds.as_deref(), &[]);
+
+    Ok(export_response(
+        user.id,
+        query.format,
+        set_kinds,
+        unit_query.or_preference(&user),
+        pool,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeletionExportQuery {
+    // Returned when the deletion was requested
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    token: String,
+    // JSON by default
+    #[serde(default)]
+    format: ExportFormat,
+    // Only include these kinds of sets, comma separated
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "working,drop")]
+    kinds: Option<Vec<SetKind>>,
+    // Leave out these kinds of sets, comma separated. Everything is included by default.
+    #[serde(default, deserialize_with = "deserialize_comma_separated")]
+    #[param(value_type = Option<String>, example = "warm_up")]
+    exclude_kinds: Option<Vec<SetKind>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/deletion/export",
+    params(DeletionExportQuery, UnitQuery),
+    responses(
+        (status = OK, description = "Training history as a JSON document or CSV", body = UserExport, content_type = ["application/json", "text/csv"]),
+        (status = UNAUTHORIZED, description = "Invalid export token or the user has been deleted", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid format", body = RouteError)
+    ),
+)]
+// Export of an user who is being deleted, they can't log in without cancelling it
+pub async fn export_deleted_user_data(
+    State(pool): State<PgPool>,
+    ValidatedQuery(query): ValidatedQuery<DeletionExportQuery>,
+    ValidatedQuery(unit_query): ValidatedQuery<UnitQuery>,
+) -> Result<Response, RouteError> {
+    let user = account_deletion::user_from_export_token(&query.token, &pool).await?;
+
+    let set_kinds =
+        set::included_kinds(query.kinds.as_deref(), query.exclude_kinds.as_deref(), &[]);
+
+    Ok(export_response(
+        user.id,
+        query.format,
+        set_kinds,
+        unit_query.or_preference(&user),
+        pool,
+    ))
+}
+
+fn export_response(
+    user_id: Uuid,
+    format: ExportFormat,
+    set_kinds: Vec<SetKind>,
+    unit: WeightUnit,
+    pool: PgPool,
+) -> Response {
+    let (stream, content_type, file_name) = match format {
+        ExportFormat::Json => (
+            export::json_stream(user_id, set_kinds, unit, pool).boxed(),
+            "application/json",
+            "liftlog-export.json",
+        ),
+        ExportFormat::Csv => (
+            export::csv_stream(user_id, set_kinds, unit, pool).boxed(),
+            "text/csv",
+            "liftlog-export.csv",
+        ),
+    };
+
+    // Errors after the response has started can only abort the body
+    let body = Body::from_stream(stream.map(|chunk| {
+        chunk.map_err(|_| std::io::Error::other("Export failed. Check logs for details."))
+    }));
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file_name),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CsvImportQuery {
+    // Unit of the weights in the file, kilograms by default.
+    // Not used if the file has the unit.
+    #[serde(default)]
+    weight_unit: WeightUnit,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/import/csv",
+    params(CsvImportQuery),
+    request_body(content = String, description = "CSV export from Strong or Hevy", content_type = "text/csv"),
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "Training history imported, rows which could not be imported are listed", body = RouteSuccessImportSummary),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Unrecognized CSV format or no rows could be imported", body = RouteError)
+    ),
+)]
+// Import sessions from other apps' CSV exports
+pub async fn import_csv(
+    user: User,
+    State(pool): State<PgPool>,
+    ValidatedQuery(query): ValidatedQuery<CsvImportQuery>,
+    body: Bytes,
+) -> RouteResponse<ImportSummary> {
+    let input = std::str::from_utf8(&body).map_err(|_| {
+        RouteError::new(
+            "CSV input is not valid UTF-8.",
+            None::<&str>,
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+
+    let (source, sessions, skipped) = import::parse_csv(input, query.weight_unit)?;
+
+    // Nothing to import, so the errors are the response
+    if sessions.is_empty() && !skipped.is_empty() {
+        let mut error = RouteError::empty(StatusCode::BAD_REQUEST);
+
+        for skipped_row in skipped {
+            error.add(skipped_row.msg, skipped_row.field);
+        }
+
+        return Err(error);
+    }
+
+    let mut summary = import::import_sessions(user.id, sessions, &pool).await?;
+    summary.skipped = skipped;
+
+    Ok(RouteSuccess::new(
+        format!("Imported {} sessions from {:?}.", summary.sessions, source),
+        summary,
+        StatusCode::CREATED,
+    ))
+}
+
+#[derive(Debug, Validate, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    // Only report what would be imported
+    #[serde(default = "default_as_false")]
+    dry_run: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/import",
+    params(ImportQuery),
+    request_body = UserExport,
+    security(
+        ("access_token"= [])
+    ),
+    responses(
+        (status = CREATED, description = "Export imported", body = RouteSuccessImportSummary),
+        (status = OK, description = "Dry run, nothing was imported", body = RouteSuccessImportSummary),
+        (status = FORBIDDEN, description = "Invalid access token", body = RouteError),
+        (status = BAD_REQUEST, description = "Invalid or unsupported export", body = RouteError)
+    ),
+)]
+// Restore a LiftLog export, for example from another instance
+pub async fn import_export(
+    user: User,
+    State(pool): State<PgPool>,
+    ValidatedQuery(query): ValidatedQuery<ImportQuery>,
+    ValidatedJson(body): ValidatedJson<UserExport>,
+) -> RouteResponse<ImportSummary> {
+    let summary = import::import_export(user.id, body, query.dry_run, &pool).await?;
+
+    if summary.dry_run {
+        Ok(RouteSuccess::new(
+            format!("Dry run, {} sessions would be imported.", summary.sessions),
+            summary,
+            StatusCode::OK,
+        ))
+    } else {
+        Ok(RouteSuccess::new(
+            format!("Imported {} sessions.", summary.sessions),
+            summary,
+            StatusCode::CREATED,
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use axum::http::{HeaderName, HeaderValue, StatusCode};
     use axum_test::TestServer;
+    use chrono::Duration;
     use serde_json::json;
     use sqlx::PgPool;
+    use uuid::Uuid;
 
     use crate::{
         api::response::RouteSuccess,
-        models::{access_token::AccessToken, user::User},
-        test_utils::api::test_server,
+        models::{
+            access_token::{AccessToken, TokenMetadata},
+            account_deletion::{self, AccountDeletion},
+            exercise::{Exercise, ExerciseKind},
+            exercise_group::ExerciseGroupKind,
+            export::{UserExport, EXPORT_VERSION},
+            import::ImportSummary,
+            personal_record::ExerciseRecords,
+            session::SessionPage,
+            set::{Set, SetKind, WeightUnit},
+            user::User,
+        },
+        test_utils::api::{
+            create_test_app, create_test_exercise_instance, create_test_scenario,
+            create_test_session, create_test_set, get_auth_header, test_server,
+        },
     };
 
     const PASSWORD: &str = "testuserpassword";
@@ -255,21 +610,46 @@ mod tests {
             )
             .await;
 
-        // Should be successfully deleted
+        // Should be successfully scheduled
         delete_response.assert_status_ok();
 
-        // Make sure is deleted
-        let access_token_invalid = server
-            .post("/api/access_token")
-            .json(&json!({
-                "username": USERNAME,
-                "password": PASSWORD,
-                "validity_in_seconds": 60
-            }))
-            .await;
+        let deletion = delete_response.json::<RouteSuccess<AccountDeletion>>().data;
 
-        // "Login" should not work anymore
-        access_token_invalid.assert_status_failure();
+        // Logged out immediately
+        server
+            .get("/api/user")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_bytes(format!("Bearer {}", access_token.token).as_bytes())
+                    .unwrap(),
+            )
+            .await
+            .assert_status_failure();
+
+        // Export is still available without logging in
+        let export = server
+            .get("/api/user/deletion/export")
+            .add_query_param("token", deletion.export_token.unwrap())
+            .await
+            .json::<UserExport>();
+
+        assert_eq!(export.version, EXPORT_VERSION);
+
+        server
+            .get("/api/user/deletion/export")
+            .add_query_param("token", "invalid")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        // Logging in cancels the deletion
+        access_token_from_api(&server).await;
+
+        assert_eq!(account_deletion::purge_due(&pool).await.unwrap(), 0);
+        assert!(sqlx::query!("SELECT user_id FROM account_deletions")
+            .fetch_optional(&pool)
+            .await
+            .unwrap()
+            .is_none());
     }
 
     #[sqlx::test]
@@ -320,6 +700,42 @@ mod tests {
         access_token_valid.assert_status_ok();
     }
 
+    #[sqlx::test]
+    async fn change_password_revoke_other_tokens(pool: PgPool) {
+        let server = test_server(&pool);
+
+        test_user_from_api(&server).await;
+        let current_token = access_token_from_api(&server).await;
+        let other_token = access_token_from_api(&server).await;
+
+        server
+            .patch("/api/user/password")
+            .json(&json!({
+                "new_password": "w2EFovWuLT5qFGFCtbKykpMUnEFVpGEfNaTtjWnz",
+                "revoke_other_tokens": true
+            }))
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_bytes(format!("Bearer {}", current_token.token).as_bytes())
+                    .unwrap(),
+            )
+            .await
+            .assert_status_ok();
+
+        // Only the token used to change the password still works
+        for (token, works) in [(current_token, true), (other_token, false)] {
+            let response = server
+                .get("/api/user")
+                .add_header(
+                    HeaderName::from_static("authorization"),
+                    HeaderValue::from_bytes(format!("Bearer {}", token.token).as_bytes()).unwrap(),
+                )
+                .await;
+
+            assert_eq!(response.status_code().is_success(), works);
+        }
+    }
+
     #[sqlx::test]
     async fn change_username(pool: PgPool) {
         let server = test_server(&pool);
@@ -354,9 +770,445 @@ mod tests {
 
         get_self.assert_status_success();
 
-        let get_self_body = get_self.json::<RouteSuccess<User>>()
-        .data;
+        let get_self_body = get_self.json::<RouteSuccess<User>>().data;
 
         assert_eq!(get_self_body.username, new_username);
     }
+
+    #[sqlx::test]
+    async fn export(pool: PgPool) {
+        let (server, _, _, exercise, session, exercise_instance, set) =
+            create_test_scenario(&pool).await;
+
+        server
+            .post(&format!(
+                "/api/exercise_instance/{}/comment",
+                exercise_instance.id
+            ))
+            .json(&json!({"new_comment": "Felt heavy"}))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"weight": 100.0, "reps": 5, "completed": true}))
+            .await
+            .assert_status_success();
+
+        let another_session = create_test_session(&server).await;
+
+        let json_export = server.get("/api/user/export").await;
+
+        json_export.assert_status_ok();
+        assert_eq!(json_export.header("content-type"), "application/json");
+
+        let json_export = json_export.json::<UserExport>();
+
+        assert_eq!(json_export.version, EXPORT_VERSION);
+        assert_eq!(json_export.exercises, vec![exercise.clone()]);
+        assert_eq!(json_export.sessions.len(), 2);
+        assert_eq!(json_export.sessions[0].id, session.id);
+        assert_eq!(json_export.sessions[1].id, another_session.id);
+
+        let exported_instance = &json_export.sessions[0].exercise_instances[0];
+
+        assert_eq!(exported_instance.id, exercise_instance.id);
+        assert_eq!(exported_instance.comments, vec!["Felt heavy".to_string()]);
+        assert_eq!(exported_instance.sets[0].weight, Some(100.0));
+
+        let csv_export = server
+            .get("/api/user/export")
+            .add_query_param("format", "csv")
+            .await;
+
+        csv_export.assert_status_ok();
+        assert_eq!(csv_export.header("content-type"), "text/csv");
+
+        let csv_text = csv_export.text();
+        let lines: Vec<&str> = csv_text.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "session_name,date,exercise_name,kind,set_kind,weight,weight_unit,reps,rpe,rir,completed"
+        );
+        assert!(lines[1].starts_with(&session.name));
+        assert!(lines[1].ends_with(&format!(
+            "{},barbell,working,100.0,kg,5,,,true",
+            exercise.name
+        )));
+
+        // Kinds of sets can be left out
+        let warm_up = create_test_set(&server, exercise_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}", warm_up.id))
+            .json(&json!({"kind": "warm_up"}))
+            .await
+            .assert_status_success();
+
+        let csv_text = server
+            .get("/api/user/export")
+            .add_query_param("format", "csv")
+            .await
+            .text();
+
+        assert_eq!(csv_text.lines().count(), 3);
+
+        let csv_text = server
+            .get("/api/user/export")
+            .add_query_param("format", "csv")
+            .add_query_param("exclude_kinds", "warm_up")
+            .await
+            .text();
+
+        assert_eq!(csv_text.lines().count(), 2);
+
+        let json_export = server
+            .get("/api/user/export")
+            .add_query_param("kinds", "warm_up,drop")
+            .await
+            .json::<UserExport>();
+        let exported_sets = &json_export.sessions[0].exercise_instances[0].sets;
+
+        assert_eq!(exported_sets.len(), 1);
+        assert_eq!(exported_sets[0].id, warm_up.id);
+
+        // Weights are labeled with the unit they're in
+        let json_export = server
+            .get("/api/user/export")
+            .add_query_param("unit", "lb")
+            .await
+            .json::<UserExport>();
+
+        assert_eq!(json_export.weight_unit, WeightUnit::Lb);
+        assert_eq!(
+            json_export.sessions[0].exercise_instances[0].sets[0].weight,
+            Some(220.5)
+        );
+
+        let csv_text = server
+            .get("/api/user/export")
+            .add_query_param("format", "csv")
+            .add_query_param("unit", "lb")
+            .await
+            .text();
+
+        assert!(csv_text.contains(",working,220.5,lb,5,"));
+
+        server
+            .get("/api/user/export")
+            .add_query_param("kinds", "warm_up,unknown")
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[sqlx::test]
+    async fn export_empty(pool: PgPool) {
+        let (server, _, _) = create_test_app(&pool).await;
+
+        let json_export = server.get("/api/user/export").await.json::<UserExport>();
+
+        assert!(json_export.exercises.is_empty());
+        assert!(json_export.sessions.is_empty());
+
+        let csv_export = server
+            .get("/api/user/export")
+            .add_query_param("format", "csv")
+            .await
+            .text();
+
+        assert_eq!(csv_export.lines().count(), 1);
+
+        server
+            .get("/api/user/export")
+            .add_query_param("format", "xml")
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[sqlx::test]
+    async fn import_csv(pool: PgPool) {
+        let (server, _, _, exercise, _, _, _) = create_test_scenario(&pool).await;
+
+        // Same name and kind as the existing test exercise
+        let strong_csv = format!(
+            "Date,Workout Name,Duration,Exercise Name,Set Order,Weight,Reps,Distance,Seconds,Notes,Workout Notes,RPE
+2024-01-15 10:30:00,Push,1h,{} (Barbell),1,100,5,0,0,,,
+2024-01-15 10:30:00,Push,1h,{} (Barbell),2,225,3,0,0,,,
+2024-01-15 10:30:00,Push,1h,Lateral Raise (Dumbbell),1,10,15,0,0,,,
+2024-01-15 10:30:00,Push,1h,Lateral Raise (Dumbbell),2,10,-1,0,0,,,
+",
+            exercise.name, exercise.name
+        );
+
+        let import = server
+            .post("/api/user/import/csv")
+            .add_query_param("weight_unit", "lb")
+            .text(&strong_csv)
+            .await;
+
+        import.assert_status(StatusCode::CREATED);
+
+        let summary = import.json::<RouteSuccess<ImportSummary>>().data;
+
+        assert_eq!(summary.sessions, 1);
+        assert_eq!(summary.exercises_created, 1);
+        assert_eq!(summary.exercise_instances, 2);
+        assert_eq!(summary.sets, 3);
+        assert_eq!(summary.skipped.len(), 1);
+        assert!(summary.skipped[0].msg.starts_with("Line 5:"));
+
+        let exercises = server
+            .get("/api/exercise/all")
+            .await
+            .json::<RouteSuccess<Vec<Exercise>>>()
+            .data;
+
+        assert!(exercises
+            .iter()
+            .any(|e| e.name == "Lateral Raise" && e.kind == ExerciseKind::Dumbbell));
+
+        // Imported sets are completed and count towards records, converted from pounds
+        let records = server
+            .get(&format!("/api/exercise/{}/records", exercise.id))
+            .await
+            .json::<RouteSuccess<ExerciseRecords>>()
+            .data;
+
+        assert_eq!(
+            records.heaviest_weight.unwrap().value,
+            WeightUnit::Lb.to_kilograms(225.0)
+        );
+
+        // Importing again doesn't duplicate sessions
+        let summary = server
+            .post("/api/user/import/csv")
+            .add_query_param("weight_unit", "lb")
+            .text(&strong_csv)
+            .await
+            .json::<RouteSuccess<ImportSummary>>()
+            .data;
+
+        assert_eq!(summary.sessions, 0);
+        assert_eq!(summary.duplicate_sessions, 1);
+
+        // Nothing could be imported
+        server
+            .post("/api/user/import/csv")
+            .text("a,b\n1,2\n")
+            .await
+            .assert_status_bad_request();
+    }
+
+    #[sqlx::test]
+    async fn import_json_round_trip(pool: PgPool) {
+        let (server, _, _, exercise, session, exercise_instance, set) =
+            create_test_scenario(&pool).await;
+
+        server
+            .post(&format!(
+                "/api/exercise_instance/{}/comment",
+                exercise_instance.id
+            ))
+            .json(&json!({"new_comment": "Felt heavy"}))
+            .await
+            .assert_status_success();
+
+        server
+            .patch(&format!("/api/set/{}", set.id))
+            .json(&json!({"weight": 100.0, "reps": 5, "completed": true}))
+            .await
+            .assert_status_success();
+
+        let drop_set = create_test_set(&server, exercise_instance.id).await;
+
+        server
+            .patch(&format!("/api/set/{}", drop_set.id))
+            .json(&json!({"kind": "drop", "parent_set_id": set.id, "weight": 80.0, "reps": 4}))
+            .await
+            .assert_status_success();
+
+        let second_instance = create_test_exercise_instance(&server, session.id, exercise.id).await;
+
+        server
+            .post("/api/exercise_group")
+            .json(&json!({
+                "session_id": session.id,
+                "kind": "circuit",
+                "exercise_instance_ids": [exercise_instance.id, second_instance.id]
+            }))
+            .await
+            .assert_status_success();
+
+        // Weights are converted back to kilograms when importing
+        let export = server
+            .get("/api/user/export")
+            .add_query_param("unit", "lb")
+            .await
+            .json::<UserExport>();
+
+        // Another user on the same instance
+        let other_user = User::new("other", PASSWORD, &pool).await.unwrap();
+        let other_token = AccessToken::new(
+            other_user.id,
+            Duration::days(1),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let mut other_server = test_server(&pool);
+        let (header_name, header_value) = get_auth_header(&other_token);
+        other_server.add_header(header_name, header_value);
+
+        let get_sessions = || async {
+            other_server
+                .get("/api/session")
+                .await
+                .json::<RouteSuccess<SessionPage>>()
+                .data
+                .sessions
+        };
+
+        // Nothing is saved in a dry run
+        let dry_run = other_server
+            .post("/api/user/import")
+            .add_query_param("dry_run", true)
+            .json(&export)
+            .await;
+
+        dry_run.assert_status_ok();
+
+        let summary = dry_run.json::<RouteSuccess<ImportSummary>>().data;
+
+        assert!(summary.dry_run);
+        assert_eq!(summary.exercises_created, 1);
+        assert_eq!(summary.sessions, 1);
+        assert_eq!(summary.sets, 2);
+        assert!(get_sessions().await.is_empty());
+
+        let import = other_server.post("/api/user/import").json(&export).await;
+
+        import.assert_status(StatusCode::CREATED);
+
+        let imported = get_sessions().await;
+
+        assert_eq!(imported.len(), 1);
+        assert_ne!(imported[0].id, session.id);
+        assert_eq!(imported[0].name, session.name);
+        assert_eq!(imported[0].started, session.started);
+
+        let imported_instance = &imported[0].exercise_instances[0];
+
+        assert_ne!(imported_instance.exercise_id, exercise.id);
+        assert_eq!(imported_instance.comments, vec!["Felt heavy".to_string()]);
+        // Exported as 220.5 lb
+        assert_eq!(
+            imported_instance.sets[0].weight,
+            Some(WeightUnit::Lb.to_kilograms(220.5))
+        );
+        assert!(imported_instance.sets[0].completed);
+
+        // Drop sets are linked to the imported parents
+        assert_eq!(imported_instance.sets[1].kind, SetKind::Drop);
+        assert_eq!(
+            imported_instance.sets[1].parent_set_id,
+            Some(imported_instance.sets[0].id)
+        );
+
+        // Groups are recreated with the imported instances
+        let imported_group = &imported[0].exercise_groups[0];
+
+        assert_eq!(imported_group.kind, ExerciseGroupKind::Circuit);
+        assert_eq!(
+            imported_group.exercise_instance_ids,
+            vec![imported_instance.id, imported[0].exercise_instances[1].id]
+        );
+
+        // Importing again reports the existing exercise and session as conflicts
+        let summary = other_server
+            .post("/api/user/import")
+            .add_query_param("dry_run", true)
+            .json(&export)
+            .await
+            .json::<RouteSuccess<ImportSummary>>()
+            .data;
+
+        assert_eq!(summary.exercises_created, 0);
+        assert_eq!(summary.exercises_reused, 1);
+        assert_eq!(summary.duplicate_sessions, 1);
+        assert_eq!(summary.conflicts.len(), 2);
+
+        // Unsupported version and instances of exercises not in the export
+        let mut invalid_export = export.clone();
+        invalid_export.version += 1;
+        invalid_export.exercises.clear();
+
+        let error = other_server
+            .post("/api/user/import")
+            .json(&invalid_export)
+            .await;
+
+        error.assert_status_bad_request();
+        assert_eq!(
+            error.json::<serde_json::Value>()["errors"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // Exercises repeated in the export are reported separately, and weights are rounded
+        let mut repeated_export = export.clone();
+        let mut repeated_exercise = repeated_export.exercises[0].clone();
+        repeated_exercise.id = Uuid::new_v4();
+        repeated_export.exercises.push(repeated_exercise);
+        repeated_export.sessions[0].exercise_instances[0].sets[0].weight = Some(220.54);
+
+        let third_user = User::new("third", PASSWORD, &pool).await.unwrap();
+        let third_token = AccessToken::new(
+            third_user.id,
+            Duration::days(1),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let mut third_server = test_server(&pool);
+        let (header_name, header_value) = get_auth_header(&third_token);
+        third_server.add_header(header_name, header_value);
+
+        let summary = third_server
+            .post("/api/user/import")
+            .json(&repeated_export)
+            .await
+            .json::<RouteSuccess<ImportSummary>>()
+            .data;
+
+        assert_eq!(summary.exercises_created, 1);
+        assert_eq!(summary.exercises_reused, 0);
+        assert_eq!(summary.conflicts.len(), 1);
+        assert!(summary.conflicts[0].msg.contains("more than once"));
+
+        let imported_set = &third_server
+            .get("/api/session")
+            .add_query_param("unit", "lb")
+            .await
+            .json::<RouteSuccess<SessionPage>>()
+            .data
+            .sessions[0]
+            .exercise_instances[0]
+            .sets[0];
+
+        assert_eq!(imported_set.weight, Some(220.5));
+
+        let stored_set = Set::from_id(third_user.id, imported_set.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(stored_set.weight, Some(WeightUnit::Lb.to_kilograms(220.5)));
+    }
 }
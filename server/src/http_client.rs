@@ -0,0 +1,19 @@
+use std::{sync::OnceLock, time::Duration};
+
+use reqwest::{redirect::Policy, Client};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Shared client for the few outgoing requests, like exchanging OIDC codes,
+// so connections are reused. Redirects aren't followed, the endpoints are configured.
+pub fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .timeout(TIMEOUT)
+            .redirect(Policy::none())
+            .build()
+            .expect("HTTP client configuration is valid")
+    })
+}
@@ -5,11 +5,11 @@ use std::{
 };
 use tracing::{error, info, instrument};
 
-use crate::api::routes;
+use crate::api::routes::{self, RouterOptions};
 
 // Initialize and start HTTP server
-#[instrument]
-pub async fn start(addr: &str, pg_pool: PgPool) {
+#[instrument(skip(options))]
+pub async fn start(addr: &str, pg_pool: PgPool, options: RouterOptions) {
     info!("Starting HTTP server on http://{}", addr);
 
     // Parse listen address
@@ -27,12 +27,18 @@ pub async fn start(addr: &str, pg_pool: PgPool) {
     };
 
     // build our application with a single route
-    let app_router = routes::build_router(pg_pool);
+    let app_router = routes::build_router(pg_pool, options);
 
     info!("Starting HTTP server on: http://{}", addr);
 
     // run it with hyper on localhost:3000
     let listener = tokio::net::TcpListener::bind(&parsed_addr).await.unwrap();
 
-    axum::serve(listener, app_router).await.unwrap();
+    // Client addresses are stored with access tokens
+    axum::serve(
+        listener,
+        app_router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
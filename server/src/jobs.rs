@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tracing::{error, info};
+
+use crate::models::{account_deletion, oidc};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Start the background jobs, they run until the program exits
+pub fn start(pool: PgPool) {
+    info!("Starting background jobs");
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(purge_error) = account_deletion::purge_due(&pool).await {
+                error!("Failed to purge deleted users: {:?}", purge_error);
+            }
+
+            if let Err(purge_error) = oidc::purge_expired_states(&pool).await {
+                error!("Failed to purge expired OIDC logins: {:?}", purge_error);
+            }
+        }
+    });
+}
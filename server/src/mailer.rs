@@ -0,0 +1,234 @@
+use std::{fmt::Debug, fs::OpenOptions, io::Write, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{error, info, instrument};
+
+use crate::settings::{SmtpSecurity, SmtpSettings};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Plain text email sent to a single address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Sends emails, like verification codes and password reset tokens
+#[async_trait]
+pub trait Mailer: Debug + Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), String>;
+}
+
+// Shared with the routes as an extension
+pub type SharedMailer = Arc<dyn Mailer>;
+
+// SMTP if configured, otherwise emails are only logged
+pub fn from_settings(smtp: Option<SmtpSettings>, mail_file: Option<String>) -> SharedMailer {
+    match smtp {
+        Some(settings) => Arc::new(SmtpMailer { settings }),
+        None => Arc::new(LogMailer {
+            file: mail_file.map(PathBuf::from),
+        }),
+    }
+}
+
+// Doesn't send emails, for development and tests. Only the recipient and the subject
+// are logged, because the body has codes in it. Whole emails are appended to the file
+// if one is given.
+#[derive(Debug, Clone, Default)]
+pub struct LogMailer {
+    pub file: Option<PathBuf>,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    #[instrument(skip(self, email))]
+    async fn send(&self, email: Email) -> Result<(), String> {
+        info!(
+            "Not sending an email to {} with subject '{}', SMTP isn't configured",
+            email.to, email.subject
+        );
+
+        if let Some(path) = &self.file {
+            let message = format!(
+                "To: {}\nSubject: {}\n\n{}\n\n",
+                email.to, email.subject, email.body
+            );
+
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(message.as_bytes()))
+                .map_err(|error| {
+                    error!("Failed to write an email to {}: {}", path.display(), error);
+                    error.to_string()
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpMailer {
+    pub settings: SmtpSettings,
+}
+
+impl SmtpMailer {
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let settings = &self.settings;
+
+        let builder = match settings.security {
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.host),
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)
+            }
+            SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &settings.host,
+            )),
+        }
+        .map_err(|error| error.to_string())?
+        .port(settings.port)
+        .timeout(Some(TIMEOUT));
+
+        Ok(match (&settings.username, &settings.password) {
+            (Some(username), Some(password)) => builder
+                .credentials(Credentials::new(username.clone(), password.clone()))
+                .build(),
+            _ => builder.build(),
+        })
+    }
+
+    fn message(&self, email: Email) -> Result<Message, String> {
+        let from: Mailbox = self
+            .settings
+            .from
+            .parse()
+            .map_err(|_| format!("Invalid sender {}", self.settings.from))?;
+        let to: Mailbox = email
+            .to
+            .parse()
+            .map_err(|_| format!("Invalid recipient {}", email.to))?;
+
+        Message::builder()
+            .from(from)
+            .to(to)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .map_err(|error| error.to_string())
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    #[instrument(skip(self, email))]
+    async fn send(&self, email: Email) -> Result<(), String> {
+        info!("Sending an email with SMTP");
+
+        let message = self.message(email)?;
+
+        self.transport()?
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|error| {
+                error!("Failed to send an email: {}", error);
+                error.to_string()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Replies to every command and returns what was received
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = Vec::new();
+            let mut in_data = false;
+
+            stream.write_all(b"220 localhost\r\n").unwrap();
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        received.push(line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-localhost\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    received.push(line);
+                    return received;
+                } else {
+                    b"250 ok\r\n"
+                };
+
+                received.push(line);
+                stream.write_all(reply).unwrap();
+            }
+        });
+
+        let mailer = SmtpMailer {
+            settings: SmtpSettings {
+                host: "127.0.0.1".to_string(),
+                port,
+                security: SmtpSecurity::None,
+                username: Some("user".to_string()),
+                password: Some("password".to_string()),
+                from: "LiftLog <liftlog@example.com>".to_string(),
+            },
+        };
+
+        mailer
+            .send(Email {
+                to: "someone@example.com".to_string(),
+                subject: "Test".to_string(),
+                body: "First line\n.starts with a dot".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let received = server.join().unwrap();
+
+        assert!(received.contains(&"MAIL FROM:<liftlog@example.com>".to_string()));
+        assert!(received.contains(&"RCPT TO:<someone@example.com>".to_string()));
+        assert!(received.contains(&"Subject: Test".to_string()));
+        assert!(received.contains(&"..starts with a dot".to_string()));
+        assert_eq!(received.last().unwrap(), "QUIT");
+    }
+}
@@ -2,12 +2,17 @@
 #![allow(dead_code)]
 
 mod api;
+mod http_client;
 mod http_server;
+mod jobs;
+mod mailer;
 mod models;
 mod pg;
 mod settings;
 mod test_utils;
 
+use api::routes::RouterOptions;
+use chrono::Duration;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
@@ -19,9 +24,21 @@ async fn main() {
 
     info!("Starting LiftLog ...");
 
-    let pool = pg::create_pool(&current_settings.database_url).await;
+    models::access_token::set_token_key(current_settings.token_key.as_bytes());
 
-    http_server::start(&current_settings.listen_address, pool).await;
+    let pool = pg::create_pool(&current_settings.database_url, &current_settings.token_key).await;
+
+    jobs::start(pool.clone());
+
+    let options = RouterOptions {
+        registration: current_settings.registration,
+        trusted_proxies: current_settings.trusted_proxies,
+        oidc_providers: current_settings.oidc_providers,
+        mailer: mailer::from_settings(current_settings.smtp, current_settings.mail_file),
+        deletion_grace_period: Duration::days(current_settings.deletion_grace_period_days),
+    };
+
+    http_server::start(&current_settings.listen_address, pool, options).await;
 }
 
 // Initialize tracing library for logging
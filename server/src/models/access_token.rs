@@ -1,25 +1,84 @@
-use std::fmt::{Debug, Display};
-
-use axum::http::StatusCode;
+use std::{
+    fmt::{Debug, Display},
+    sync::OnceLock,
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+    RequestPartsExt,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
 use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
-use tracing::{error, info, instrument, warn};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, info, instrument, warn};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::api::response::RouteError;
 
-use super::user::User;
+use super::{refresh_token, user::User};
+
+// Length of the start of a token which is stored in plaintext to find it
+pub const TOKEN_PREFIX_LENGTH: usize = 8;
+
+// Key for hashing tokens, set from the settings on startup
+static TOKEN_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+// Used instead in tests
+pub const TEST_TOKEN_KEY: &str = "liftlog test key which is not used anywhere else";
 
 // Used to authenticate single API requests to a specific user
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
 pub struct AccessToken {
+    // Used to list and revoke tokens without knowing their value
+    pub id: Uuid,
+    // Only a hash is stored, so this is only known when creating the token
+    // or when it's given in a request. Queries return it empty.
     pub token: String,
     pub created: DateTime<Utc>,
     pub expires: DateTime<Utc>,
     pub user_id: Uuid,
+    // Set if issued with a refresh token, see RefreshToken
+    pub family_id: Option<Uuid>,
+    // Given by the client when logging in
+    pub device_name: Option<String>,
+    // From the request which created the token
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    // Updated on every authenticated request
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+// Details about the device a token is created for
+#[derive(Debug, Clone, Default)]
+pub struct TokenMetadata {
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+// Listed to the user, so the token value itself is left out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AccessTokenInfo {
+    pub id: Uuid,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    // The token used to make the request
+    pub current: bool,
 }
 
 impl AccessToken {
@@ -28,30 +87,39 @@ impl AccessToken {
     pub async fn new(
         user_id: Uuid,
         validity: Duration,
+        metadata: TokenMetadata,
         pool: &PgPool,
     ) -> Result<AccessToken, RouteError> {
         info!("Creating new access token for {}", user_id);
 
-        let token: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(50)
-            .map(char::from)
-            .collect();
+        let token = generate_token();
 
         let expiration_time = Utc::now() + validity;
 
-        Ok(sqlx::query_as!(
+        let mut access_token = sqlx::query_as!(
             AccessToken,
-            "INSERT INTO access_tokens (token, expires, user_id) VALUES ($1, $2, $3) RETURNING *",
-            token,
+            r#"
+            INSERT INTO access_tokens (token_prefix, token_hash, expires, user_id, device_name, user_agent, ip)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, '' AS "token!", created, expires, user_id, family_id, device_name, user_agent, ip, last_used
+            "#,
+            token_prefix(&token),
+            hash_token(&token),
             expiration_time,
-            user_id
+            user_id,
+            metadata.device_name,
+            metadata.user_agent,
+            metadata.ip
         )
         .fetch_one(pool)
-        .await?)
+        .await?;
+
+        access_token.token = token;
+
+        Ok(access_token)
     }
 
-    // Returns an access token if non-expired is found
+    // Returns an access token if non-expired is found and marks it used
     #[instrument(skip(token))]
     pub async fn from_token(
         token: impl ToString + Display + Debug,
@@ -59,16 +127,42 @@ impl AccessToken {
     ) -> Result<AccessToken, RouteError> {
         info!("Querying potential access token");
 
-        let potential_token = sqlx::query_as!(
-            AccessToken,
-            "SELECT * FROM access_tokens WHERE token = $1 AND expires > NOW()",
-            token.to_string()
+        let token = token.to_string();
+
+        let candidates = sqlx::query!(
+            "SELECT id, token_hash FROM access_tokens WHERE token_prefix = $1 AND expires > NOW()",
+            token_prefix(&token)
         )
-        .fetch_optional(pool)
+        .fetch_all(pool)
         .await?;
 
+        let matching_id = candidates
+            .into_iter()
+            .find(|candidate| token_matches(&token, &candidate.token_hash))
+            .map(|candidate| candidate.id);
+
+        let potential_token = match matching_id {
+            Some(id) => {
+                sqlx::query_as!(
+                    AccessToken,
+                    r#"
+                    UPDATE access_tokens SET last_used = NOW() WHERE id = $1 AND expires > NOW()
+                    RETURNING id, '' AS "token!", created, expires, user_id, family_id, device_name, user_agent, ip, last_used
+                    "#,
+                    id
+                )
+                .fetch_optional(pool)
+                .await?
+            }
+            None => None,
+        };
+
         match potential_token {
-            Some(access_token) => Ok(access_token),
+            Some(mut access_token) => {
+                access_token.token = token;
+
+                Ok(access_token)
+            }
             None => {
                 warn!("Invalid access token provided");
 
@@ -81,22 +175,18 @@ impl AccessToken {
         }
     }
 
-    #[instrument]
+    #[instrument(skip(self))]
     pub fn is_valid(&self) -> bool {
-        if self.expires >= Utc::now() {
-            true
-        } else {
-            false
-        }
+        self.expires >= Utc::now()
     }
 
     // Validity check which returns an error
-    #[instrument]
+    #[instrument(skip(self))]
     pub fn is_valid_error(&self) -> Result<(), RouteError> {
         if self.is_valid() {
             Ok(())
         } else {
-            warn!("Access token {} expired after is was queried", self.token);
+            warn!("Access token {} expired after is was queried", self.id);
 
             Err(RouteError::new(
                 "Session has expired.",
@@ -107,21 +197,17 @@ impl AccessToken {
     }
 
     // Returns the user bound to the access token
-    #[instrument]
+    #[instrument(skip(self))]
     pub async fn get_user(&self, pool: &PgPool) -> Result<User, RouteError> {
         // Just making sure token is still valid
         self.is_valid_error()?;
 
-        Ok(
-            sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", self.user_id)
-                .fetch_one(pool)
-                .await?,
-        )
+        User::from_id(self.user_id, pool).await
     }
 
-    #[instrument]
+    #[instrument(skip(self))]
     pub async fn delete(self, pool: &PgPool) -> Result<(), RouteError> {
-        match sqlx::query!("DELETE FROM access_tokens WHERE token = $1", &self.token)
+        match sqlx::query!("DELETE FROM access_tokens WHERE id = $1", self.id)
             .execute(pool)
             .await
         {
@@ -129,7 +215,7 @@ impl AccessToken {
             Err(error) => {
                 error!(
                     "Failed to delete an existing access token {} which should not happen: {}",
-                    &self.token, error
+                    self.id, error
                 );
 
                 Err(RouteError::new(
@@ -142,11 +228,203 @@ impl AccessToken {
     }
 }
 
+// Derive the access token used in the 'Authorization' header of a request
+#[async_trait]
+impl<S> FromRequestParts<S> for AccessToken
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RouteError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        debug!("Trying to get an access token from the 'Authorization' header");
+
+        let TypedHeader(Authorization(bearer_token)) = parts
+            .extract::<TypedHeader<Authorization<Bearer>>>()
+            .await
+            .map_err(|_| {
+                RouteError::new(
+                    "Authorization header is missing.",
+                    None::<&str>,
+                    StatusCode::BAD_REQUEST,
+                )
+            })?;
+
+        AccessToken::from_token(bearer_token.token(), &PgPool::from_ref(state)).await
+    }
+}
+
+// Unexpired tokens of the user, most recently used first
+#[instrument]
+pub async fn all_user_tokens(
+    user_id: Uuid,
+    current_token_id: Uuid,
+    pool: &PgPool,
+) -> Result<Vec<AccessTokenInfo>, RouteError> {
+    info!("Querying all access tokens of an user");
+
+    Ok(sqlx::query_as!(
+        AccessTokenInfo,
+        r#"
+        SELECT id, created, expires, last_used, device_name, user_agent, ip, id = $2 AS "current!"
+        FROM access_tokens WHERE user_id = $1 AND expires > NOW()
+        ORDER BY COALESCE(last_used, created) DESC
+        "#,
+        user_id,
+        current_token_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+// Revoke a token of the user by its ID. Refresh tokens of the same login are
+// revoked too, so the token can't be renewed.
+#[instrument]
+pub async fn revoke_by_id(
+    user_id: Uuid,
+    token_id: Uuid,
+    pool: &PgPool,
+) -> Result<Uuid, RouteError> {
+    info!("Revoking an access token by ID");
+
+    let mut tx = pool.begin().await?;
+
+    let family_id = sqlx::query_scalar!(
+        "DELETE FROM access_tokens WHERE user_id = $1 AND id = $2 RETURNING family_id",
+        user_id,
+        token_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match family_id {
+        Some(family_id) => {
+            if let Some(family_id) = family_id {
+                refresh_token::revoke_family(family_id, &mut tx).await?;
+            }
+
+            tx.commit().await?;
+
+            Ok(token_id)
+        }
+        None => Err(RouteError::new(
+            "Access token not found.",
+            Some("token_id"),
+            StatusCode::NOT_FOUND,
+        )),
+    }
+}
+
+// Log out everywhere, optionally except with the given token.
+// Returns how many access tokens were revoked.
+#[instrument]
+pub async fn revoke_all(
+    user_id: Uuid,
+    except_token_id: Option<Uuid>,
+    pool: &PgPool,
+) -> Result<u64, RouteError> {
+    let mut tx = pool.begin().await?;
+
+    let revoked = revoke_user_tokens(user_id, except_token_id, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(revoked)
+}
+
+// Same as revoke_all, as part of a bigger change
+pub async fn revoke_user_tokens(
+    user_id: Uuid,
+    except_token_id: Option<Uuid>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<u64, RouteError> {
+    info!("Revoking all access tokens of an user");
+
+    // The refresh tokens of the kept token have to be kept too
+    let kept_family_id = match except_token_id {
+        Some(token_id) => sqlx::query_scalar!(
+            "SELECT family_id FROM access_tokens WHERE user_id = $1 AND id = $2",
+            user_id,
+            token_id
+        )
+        .fetch_optional(&mut **tx)
+        .await?
+        .flatten(),
+        None => None,
+    };
+
+    let revoked = sqlx::query!(
+        "DELETE FROM access_tokens WHERE user_id = $1 AND id IS DISTINCT FROM $2",
+        user_id,
+        except_token_id
+    )
+    .execute(&mut **tx)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        "DELETE FROM refresh_tokens WHERE user_id = $1 AND family_id IS DISTINCT FROM $2",
+        user_id,
+        kept_family_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(revoked)
+}
+
+// Set the key used to hash tokens, has to be called before handling any
+pub fn set_token_key(key: impl Into<Vec<u8>>) {
+    if TOKEN_KEY.set(key.into()).is_err() {
+        warn!("Token key was already set");
+    }
+}
+
+fn token_key() -> &'static [u8] {
+    TOKEN_KEY.get_or_init(|| {
+        if cfg!(test) {
+            TEST_TOKEN_KEY.as_bytes().to_vec()
+        } else {
+            panic!("Token key has to be set before handling tokens")
+        }
+    })
+}
+
+// Non-secret start of the token, shorter if the token is invalid
+pub fn token_prefix(token: &str) -> &str {
+    token.get(..TOKEN_PREFIX_LENGTH).unwrap_or(token)
+}
+
+// Keyed hash of the whole token
+pub fn hash_token(token: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token_key()).expect("HMAC accepts keys of any length");
+
+    mac.update(token.as_bytes());
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Compare in constant time, so the hash can't be guessed from response times
+pub fn token_matches(token: &str, hash: &[u8]) -> bool {
+    hash_token(token).ct_eq(hash).into()
+}
+
+// Random token used as a bearer token
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(50)
+        .map(char::from)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use sqlx::PgPool;
+    use sqlx::{Executor, PgPool};
 
-    use crate::test_utils::api::create_test_user;
+    use crate::{pg::TOKEN_KEY_SETTING, test_utils::api::create_test_user};
 
     use super::*;
 
@@ -155,9 +433,14 @@ mod tests {
         let user = create_test_user(&pool).await;
 
         // Token with 1 minute validity
-        let access_token: AccessToken = AccessToken::new(user.id, Duration::seconds(60), &pool)
-            .await
-            .unwrap();
+        let access_token: AccessToken = AccessToken::new(
+            user.id,
+            Duration::seconds(60),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
 
         // Should still be valid
         assert!(access_token.is_valid());
@@ -174,9 +457,14 @@ mod tests {
         let user = create_test_user(&pool).await;
 
         // Token with 1 second validity
-        let access_token: AccessToken = AccessToken::new(user.id, Duration::seconds(1), &pool)
-            .await
-            .unwrap();
+        let access_token: AccessToken = AccessToken::new(
+            user.id,
+            Duration::seconds(1),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
 
         tokio::time::sleep(std::time::Duration::from_secs(2)).await;
 
@@ -194,9 +482,14 @@ mod tests {
     async fn deleted_user(pool: PgPool) {
         let user = create_test_user(&pool).await;
 
-        let access_token: AccessToken = AccessToken::new(user.id, Duration::seconds(60), &pool)
-            .await
-            .unwrap();
+        let access_token: AccessToken = AccessToken::new(
+            user.id,
+            Duration::seconds(60),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
 
         // Delete the user
         user.delete(&pool).await.unwrap();
@@ -211,9 +504,14 @@ mod tests {
     async fn get_user(pool: PgPool) {
         let user = create_test_user(&pool).await;
 
-        let access_token: AccessToken = AccessToken::new(user.id, Duration::seconds(60), &pool)
-            .await
-            .unwrap();
+        let access_token: AccessToken = AccessToken::new(
+            user.id,
+            Duration::seconds(60),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
 
         // Returned user should be the same which was used to create the access token
         assert_eq!(user, access_token.get_user(&pool).await.unwrap());
@@ -223,9 +521,14 @@ mod tests {
     async fn delete(pool: PgPool) {
         let user = create_test_user(&pool).await;
 
-        let access_token: AccessToken = AccessToken::new(user.id, Duration::seconds(60), &pool)
-            .await
-            .unwrap();
+        let access_token: AccessToken = AccessToken::new(
+            user.id,
+            Duration::seconds(60),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
 
         let token_string = access_token.token.clone();
 
@@ -235,4 +538,84 @@ mod tests {
         // Should not exist anymore
         assert!(AccessToken::from_token(token_string, &pool).await.is_err());
     }
+
+    #[sqlx::test]
+    async fn stored_hashed(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let access_token = AccessToken::new(
+            user.id,
+            Duration::seconds(60),
+            TokenMetadata::default(),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let stored = sqlx::query!(
+            "SELECT token_prefix, token_hash FROM access_tokens WHERE id = $1",
+            access_token.id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            stored.token_prefix,
+            access_token.token[..TOKEN_PREFIX_LENGTH]
+        );
+        assert!(token_matches(&access_token.token, &stored.token_hash));
+
+        // Same prefix but a different secret part
+        let mut wrong_token = access_token.token.clone();
+        wrong_token.replace_range(TOKEN_PREFIX_LENGTH.., &"a".repeat(42));
+
+        assert!(AccessToken::from_token(wrong_token, &pool).await.is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn migration_hashes_existing_tokens(pool: PgPool) {
+        let mut connection = pool.acquire().await.unwrap();
+
+        sqlx::query("SELECT set_config($1, $2, false)")
+            .bind(TOKEN_KEY_SETTING)
+            .bind(TEST_TOKEN_KEY)
+            .execute(&mut *connection)
+            .await
+            .unwrap();
+
+        let mut user_id = None;
+
+        for migration in sqlx::migrate!().iter() {
+            // Created before tokens were hashed
+            if migration.description == "hashed tokens" {
+                let id: Uuid = sqlx::query_scalar(
+                    "INSERT INTO users (username, password_hash) VALUES ('test', '') RETURNING id",
+                )
+                .fetch_one(&mut *connection)
+                .await
+                .unwrap();
+
+                sqlx::query(
+                    "INSERT INTO access_tokens (token, expires, user_id) VALUES ($1, NOW() + INTERVAL '1 day', $2)",
+                )
+                .bind("plaintext_token_from_an_older_version_0123456789")
+                .bind(id)
+                .execute(&mut *connection)
+                .await
+                .unwrap();
+
+                user_id = Some(id);
+            }
+
+            connection.execute(&*migration.sql).await.unwrap();
+        }
+
+        let access_token =
+            AccessToken::from_token("plaintext_token_from_an_older_version_0123456789", &pool)
+                .await
+                .unwrap();
+
+        assert_eq!(Some(access_token.user_id), user_id);
+    }
 }
@@ -0,0 +1,192 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{error, info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::response::RouteError,
+    mailer::{Email, Mailer},
+};
+
+use super::{
+    access_token::{self, generate_token, hash_token},
+    set::WeightUnit,
+    user::{User, UserRole},
+};
+
+// How long deleted users can cancel by logging in, shared with the routes as an extension
+#[derive(Debug, Clone, Copy)]
+pub struct DeletionGracePeriod(pub Duration);
+
+// Scheduled deletion of an user and all of their data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AccountDeletion {
+    pub requested: DateTime<Utc>,
+    // Everything is deleted after this unless the user logs in
+    pub purge_after: DateTime<Utc>,
+    // Downloads the export until the purge without logging in,
+    // only returned when the deletion is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_token: Option<String>,
+}
+
+// Schedule the deletion and log the user out everywhere. API keys are deleted,
+// because they would keep working. The user is told by email if it's verified.
+#[instrument(skip(user, mailer))]
+pub async fn schedule(
+    user: &User,
+    grace_period: Duration,
+    mailer: &dyn Mailer,
+    pool: &PgPool,
+) -> Result<AccountDeletion, RouteError> {
+    info!("Scheduling the deletion of user {}", user.id);
+
+    let export_token = generate_token();
+
+    let mut tx = pool.begin().await?;
+
+    let scheduled = sqlx::query!(
+        r#"
+        INSERT INTO account_deletions (user_id, purge_after, export_token_hash)
+        VALUES ($1, NOW() + $2::interval, $3)
+        ON CONFLICT (user_id) DO UPDATE
+        SET requested = NOW(), purge_after = EXCLUDED.purge_after, export_token_hash = EXCLUDED.export_token_hash
+        RETURNING requested, purge_after
+        "#,
+        user.id,
+        grace_period as _,
+        hash_token(&export_token)
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    access_token::revoke_user_tokens(user.id, None, &mut tx).await?;
+
+    sqlx::query!("DELETE FROM api_keys WHERE user_id = $1", user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    if let (Some(email), true) = (&user.email, user.email_verified) {
+        let notification = Email {
+            to: email.clone(),
+            subject: "Your LiftLog account will be deleted".to_string(),
+            body: format!(
+                "Hi {},\n\nYour account and training history will be deleted on {}. Log in before that to keep your account.\n\nUntil then, your data can be downloaded with this export code:\n\n{}",
+                user.username,
+                scheduled.purge_after.format("%Y-%m-%d %H:%M UTC"),
+                export_token
+            ),
+        };
+
+        // The deletion has been scheduled already, so this isn't fatal
+        if let Err(send_error) = mailer.send(notification).await {
+            error!("Failed to send a deletion notification: {}", send_error);
+        }
+    }
+
+    Ok(AccountDeletion {
+        requested: scheduled.requested,
+        purge_after: scheduled.purge_after,
+        export_token: Some(export_token),
+    })
+}
+
+// Cancel a scheduled deletion, done when the user logs in
+#[instrument]
+pub async fn cancel(user_id: Uuid, pool: &PgPool) -> Result<(), RouteError> {
+    let cancelled = sqlx::query!("DELETE FROM account_deletions WHERE user_id = $1", user_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if cancelled > 0 {
+        info!("Deletion of user {} cancelled by logging in", user_id);
+    }
+
+    Ok(())
+}
+
+// User whose data the export token allows downloading
+#[instrument(skip(export_token))]
+pub async fn user_from_export_token(export_token: &str, pool: &PgPool) -> Result<User, RouteError> {
+    // Hashes are keyed, so they can be compared in the database
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT users.id, users.created, users.changed, users.username, users.password_hash,
+            users.role AS "role: UserRole", users.disabled, users.email, users.email_verified,
+            users.weight_unit AS "weight_unit: WeightUnit"
+        FROM account_deletions
+        JOIN users ON users.id = account_deletions.user_id
+        WHERE account_deletions.export_token_hash = $1 AND account_deletions.purge_after > NOW()
+        "#,
+        hash_token(export_token)
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| {
+        warn!("Invalid export token");
+
+        RouteError::new(
+            "Export token is invalid or the account has been deleted.",
+            Some("token"),
+            StatusCode::UNAUTHORIZED,
+        )
+    })
+}
+
+// Delete the users whose grace period has ended, their data cascades.
+// Returns how many users were deleted.
+#[instrument(skip(pool))]
+pub async fn purge_due(pool: &PgPool) -> Result<u64, RouteError> {
+    let purged = sqlx::query!(
+        "DELETE FROM users WHERE id IN (SELECT user_id FROM account_deletions WHERE purge_after <= NOW())"
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {} deleted users", purged);
+    }
+
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mailer::LogMailer, test_utils::api::create_test_user};
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn purge_after_grace_period(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+        let mailer = LogMailer::default();
+
+        schedule(&user, Duration::days(1), &mailer, &pool)
+            .await
+            .unwrap();
+
+        // Still in the grace period
+        assert_eq!(purge_due(&pool).await.unwrap(), 0);
+
+        schedule(&user, Duration::zero(), &mailer, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(purge_due(&pool).await.unwrap(), 1);
+
+        let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(users, Some(0));
+    }
+}
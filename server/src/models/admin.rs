@@ -0,0 +1,157 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::{
+    access_token,
+    set::WeightUnit,
+    user::{User, UserRole},
+};
+
+// Basic usage of the whole instance, shown to admins
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct UsageStats {
+    pub users: i64,
+    pub admins: i64,
+    pub disabled_users: i64,
+    // Users who have used an access token in the last 30 days
+    pub active_users: i64,
+    pub exercises: i64,
+    pub sessions: i64,
+    pub sets: i64,
+}
+
+// Every user of the instance, including disabled ones, oldest first
+#[instrument]
+pub async fn all_users(pool: &PgPool) -> Result<Vec<User>, RouteError> {
+    info!("Querying all users");
+
+    Ok(sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        FROM users ORDER BY created, id
+        "#
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+// Any user by the ID, including disabled ones
+#[instrument]
+pub async fn find_user(user_id: Uuid, pool: &PgPool) -> Result<User, RouteError> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        FROM users WHERE id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| RouteError::new("User not found.", Some("user_id"), StatusCode::NOT_FOUND))
+}
+
+// Change the role or disable an user. Disabling logs the user out everywhere.
+// Admins can't change themselves, so there's always an admin left.
+#[instrument]
+pub async fn update_user(
+    admin_id: Uuid,
+    user_id: Uuid,
+    role: Option<UserRole>,
+    disabled: Option<bool>,
+    pool: &PgPool,
+) -> Result<User, RouteError> {
+    if admin_id == user_id {
+        return Err(RouteError::new(
+            "Admins can't change their own role or disable themselves.",
+            Some("user_id"),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    info!("Updating user {}", user_id);
+
+    let mut tx = pool.begin().await?;
+
+    let user = sqlx::query_as!(
+        User,
+        r#"
+        UPDATE users SET role = COALESCE($1, role), disabled = COALESCE($2, disabled), changed = NOW()
+        WHERE id = $3
+        RETURNING id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        "#,
+        role as Option<UserRole>,
+        disabled,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| RouteError::new("User not found.", Some("user_id"), StatusCode::NOT_FOUND))?;
+
+    if user.disabled {
+        access_token::revoke_user_tokens(user.id, None, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+// Set a new password for an user who has forgotten theirs, logging them out everywhere.
+// API keys are deleted too, the same as when resetting with an email.
+#[instrument(skip(new_password))]
+pub async fn reset_password(
+    user_id: Uuid,
+    new_password: impl ToString,
+    pool: &PgPool,
+) -> Result<User, RouteError> {
+    info!("Resetting the password of user {}", user_id);
+
+    let mut user = find_user(user_id, pool).await?;
+
+    let mut tx = pool.begin().await?;
+
+    user.set_password(new_password, &mut tx).await?;
+    access_token::revoke_user_tokens(user.id, None, &mut tx).await?;
+    sqlx::query!("DELETE FROM api_keys WHERE user_id = $1", user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+#[instrument]
+pub async fn usage_stats(pool: &PgPool) -> Result<UsageStats, RouteError> {
+    info!("Querying usage statistics");
+
+    Ok(sqlx::query_as!(
+        UsageStats,
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM users) AS "users!",
+            (SELECT COUNT(*) FROM users WHERE role = 'ADMIN') AS "admins!",
+            (SELECT COUNT(*) FROM users WHERE disabled) AS "disabled_users!",
+            (
+                SELECT COUNT(DISTINCT user_id) FROM access_tokens
+                WHERE last_used > NOW() - INTERVAL '30 days'
+            ) AS "active_users!",
+            (SELECT COUNT(*) FROM exercises) AS "exercises!",
+            (SELECT COUNT(*) FROM sessions) AS "sessions!",
+            (SELECT COUNT(*) FROM sets) AS "sets!"
+        "#
+    )
+    .fetch_one(pool)
+    .await?)
+}
@@ -0,0 +1,398 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::{
+    personal_record::CompletedSet,
+    set::{ConvertWeights, SetKind, WeightUnit},
+};
+
+// Formulas for estimating a one-rep-max from a set of multiple reps
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OneRepMaxFormula {
+    // weight * (1 + reps / 30)
+    #[default]
+    Epley,
+    // weight * 36 / (37 - reps), only defined below 37 reps
+    Brzycki,
+    // weight * reps ^ 0.1
+    Lombardi,
+}
+
+impl OneRepMaxFormula {
+    // Estimated one-rep-max, a single rep is the weight itself.
+    // Sets without reps or with zero or negative (assisted) weight have no estimate.
+    pub fn estimate(self, weight: f32, reps: i32) -> Option<f32> {
+        self.estimate_to_failure(weight, reps, 0.0)
+    }
+
+    // Estimate as if the set had been taken to failure, so 5 reps at RPE 8
+    // (2 reps in reserve) are estimated like 7 reps to failure
+    pub fn estimate_to_failure(self, weight: f32, reps: i32, reps_in_reserve: f32) -> Option<f32> {
+        if reps < 1 || weight <= 0.0 {
+            return None;
+        }
+
+        let reps = reps as f32 + reps_in_reserve.max(0.0);
+
+        if reps == 1.0 {
+            return Some(weight);
+        }
+
+        match self {
+            OneRepMaxFormula::Epley => Some(weight * (1.0 + reps / 30.0)),
+            OneRepMaxFormula::Brzycki if reps < 37.0 => Some(weight * 36.0 / (37.0 - reps)),
+            OneRepMaxFormula::Brzycki => None,
+            OneRepMaxFormula::Lombardi => Some(weight * reps.powf(0.1)),
+        }
+    }
+}
+
+// Length of the time period data points are grouped by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    #[default]
+    Day,
+    // Weeks start on monday
+    Week,
+    Month,
+}
+
+impl Grouping {
+    // First day of the period the date belongs to
+    pub fn period_start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Grouping::Day => date,
+            Grouping::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            Grouping::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+}
+
+// The best single set of a period, heaviest weight and then most reps
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TopSet {
+    pub set_id: Uuid,
+    pub session_id: Uuid,
+    pub weight: f32,
+    pub reps: i32,
+}
+
+// Statistics of all completed sets of an exercise in one period
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AnalyticsPoint {
+    // First day of the period, by the start date (UTC) of the sessions
+    pub period: NaiveDate,
+    // Best estimated one-rep-max of a single set with the selected formula
+    pub estimated_one_rep_max: Option<f32>,
+    // Sum of weight * reps of the sets with a positive weight
+    pub volume: f32,
+    pub top_set: TopSet,
+    // Amount of sessions and completed sets in the period
+    pub sessions: usize,
+    pub sets: usize,
+}
+
+// Which completed sets the analytics are computed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyticsFilter {
+    // Range of session start times, inclusive
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub set_kinds: Vec<SetKind>,
+}
+
+// Time series of an exercise, only periods with completed sets are included
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ExerciseAnalytics {
+    pub exercise_id: Uuid,
+    pub formula: OneRepMaxFormula,
+    pub grouping: Grouping,
+    // Whether the estimates use the RPE or RIR of the sets
+    pub use_rpe: bool,
+    // Range of session start times the data is from, inclusive
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // Kinds of the sets the data is from
+    pub set_kinds: Vec<SetKind>,
+    // Ordered by period
+    pub points: Vec<AnalyticsPoint>,
+}
+
+impl ConvertWeights for ExerciseAnalytics {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        for point in &mut self.points {
+            point.estimated_one_rep_max = point
+                .estimated_one_rep_max
+                .map(|estimate| unit.convert_kilograms_exact(estimate));
+            point.volume = unit.convert_kilograms_exact(point.volume);
+            point.top_set.weight = unit.convert_kilograms(point.top_set.weight);
+        }
+    }
+}
+
+impl ExerciseAnalytics {
+    // Query the completed sets of an exercise matching the filter and group them
+    #[instrument]
+    pub async fn from_exercise_id(
+        user_id: Uuid,
+        exercise_id: Uuid,
+        formula: OneRepMaxFormula,
+        grouping: Grouping,
+        use_rpe: bool,
+        filter: AnalyticsFilter,
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Querying analytics of an exercise");
+
+        let sets: Vec<CompletedSet> = sqlx::query_as(
+            r#"
+            SELECT sets.id AS set_id, sessions.id AS session_id, sessions.started, sets.weight, sets.reps,
+                COALESCE(sets.rir::real, 10::real - sets.rpe) AS reps_in_reserve
+            FROM sets
+            JOIN exercise_instances ON exercise_instances.id = sets.exercise_instance_id
+            JOIN sessions ON sessions.id = exercise_instances.session_id
+            WHERE sets.user_id = $1 AND exercise_instances.exercise_id = $2 AND sets.completed
+                AND sets.weight IS NOT NULL AND sets.reps IS NOT NULL
+                AND ($3::timestamptz IS NULL OR sessions.started >= $3)
+                AND ($4::timestamptz IS NULL OR sessions.started <= $4)
+                AND sets.kind = ANY($5)
+            ORDER BY sessions.started, sessions.id, exercise_instances.position, sets.position
+            "#,
+        )
+        .bind(user_id)
+        .bind(exercise_id)
+        .bind(filter.from)
+        .bind(filter.to)
+        .bind(&filter.set_kinds)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(ExerciseAnalytics {
+            exercise_id,
+            formula,
+            grouping,
+            use_rpe,
+            from: filter.from,
+            to: filter.to,
+            set_kinds: filter.set_kinds,
+            points: compute_points(&sets, formula, grouping, use_rpe),
+        })
+    }
+}
+
+// Groups the completed sets, which have to be ordered by session start time,
+// to periods. Sets without any reps are ignored. With use_rpe the estimates
+// count the reps in reserve of the sets which have them.
+pub fn compute_points(
+    sets: &[CompletedSet],
+    formula: OneRepMaxFormula,
+    grouping: Grouping,
+    use_rpe: bool,
+) -> Vec<AnalyticsPoint> {
+    let mut points: Vec<AnalyticsPoint> = Vec::new();
+    let mut last_session_id: Option<Uuid> = None;
+
+    for set in sets.iter().filter(|set| set.reps > 0) {
+        let period = grouping.period_start(set.started.date_naive());
+
+        let top_set = TopSet {
+            set_id: set.set_id,
+            session_id: set.session_id,
+            weight: set.weight,
+            reps: set.reps,
+        };
+
+        let point = match points.last_mut() {
+            Some(point) if point.period == period => point,
+            _ => {
+                points.push(AnalyticsPoint {
+                    period,
+                    estimated_one_rep_max: None,
+                    volume: 0.0,
+                    top_set: top_set.clone(),
+                    sessions: 0,
+                    sets: 0,
+                });
+                last_session_id = None;
+
+                points.last_mut().unwrap()
+            }
+        };
+
+        if last_session_id != Some(set.session_id) {
+            point.sessions += 1;
+            last_session_id = Some(set.session_id);
+        }
+
+        point.sets += 1;
+
+        if set.weight > 0.0 {
+            point.volume += set.weight * set.reps as f32;
+        }
+
+        let reps_in_reserve = set.reps_in_reserve.filter(|_| use_rpe).unwrap_or(0.0);
+
+        if let Some(one_rep_max) =
+            formula.estimate_to_failure(set.weight, set.reps, reps_in_reserve)
+        {
+            if point
+                .estimated_one_rep_max
+                .is_none_or(|best| one_rep_max > best)
+            {
+                point.estimated_one_rep_max = Some(one_rep_max);
+            }
+        }
+
+        if (set.weight, set.reps) > (point.top_set.weight, point.top_set.reps) {
+            point.top_set = top_set;
+        }
+    }
+
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn completed_set(
+        session_id: Uuid,
+        started: DateTime<Utc>,
+        weight: f32,
+        reps: i32,
+    ) -> CompletedSet {
+        CompletedSet {
+            set_id: Uuid::new_v4(),
+            session_id,
+            started,
+            weight,
+            reps,
+            reps_in_reserve: None,
+        }
+    }
+
+    #[test]
+    fn formulas() {
+        for formula in [
+            OneRepMaxFormula::Epley,
+            OneRepMaxFormula::Brzycki,
+            OneRepMaxFormula::Lombardi,
+        ] {
+            assert_eq!(formula.estimate(100.0, 1), Some(100.0));
+            assert_eq!(formula.estimate(100.0, 0), None);
+            assert_eq!(formula.estimate(-10.0, 5), None);
+        }
+
+        assert_eq!(OneRepMaxFormula::Epley.estimate(90.0, 30), Some(180.0));
+        assert_eq!(
+            OneRepMaxFormula::Brzycki.estimate(100.0, 10),
+            Some(100.0 * 36.0 / 27.0)
+        );
+        assert_eq!(OneRepMaxFormula::Brzycki.estimate(100.0, 37), None);
+        assert_eq!(
+            OneRepMaxFormula::Lombardi.estimate(100.0, 10),
+            Some(100.0 * 10f32.powf(0.1))
+        );
+    }
+
+    #[test]
+    fn estimates_with_reps_in_reserve() {
+        let epley = OneRepMaxFormula::Epley;
+
+        assert_eq!(
+            epley.estimate_to_failure(100.0, 5, 2.0),
+            epley.estimate(100.0, 7)
+        );
+        assert_eq!(epley.estimate_to_failure(100.0, 1, 0.0), Some(100.0));
+        assert_eq!(epley.estimate_to_failure(100.0, 0, 2.0), None);
+
+        let monday = Utc.with_ymd_and_hms(2024, 1, 15, 10, 0, 0).unwrap();
+        let mut sets = vec![completed_set(Uuid::new_v4(), monday, 100.0, 5)];
+        // RPE 8.5
+        sets[0].reps_in_reserve = Some(1.5);
+
+        let without_rpe = compute_points(&sets, epley, Grouping::Day, false);
+        let with_rpe = compute_points(&sets, epley, Grouping::Day, true);
+
+        assert_eq!(
+            without_rpe[0].estimated_one_rep_max,
+            epley.estimate(100.0, 5)
+        );
+        assert_eq!(
+            with_rpe[0].estimated_one_rep_max,
+            Some(100.0 * (1.0 + 6.5 / 30.0))
+        );
+    }
+
+    #[test]
+    fn period_starts() {
+        // A wednesday
+        let date = NaiveDate::from_ymd_opt(2024, 1, 17).unwrap();
+
+        assert_eq!(Grouping::Day.period_start(date), date);
+        assert_eq!(
+            Grouping::Week.period_start(date),
+            NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()
+        );
+        assert_eq!(
+            Grouping::Month.period_start(date),
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn grouped_points() {
+        let monday = Utc.with_ymd_and_hms(2024, 1, 15, 10, 0, 0).unwrap();
+        let thursday = monday + Duration::days(3);
+        let next_monday = monday + Duration::days(7);
+
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let sets = vec![
+            completed_set(first, monday, 100.0, 5),
+            completed_set(first, monday, 100.0, 3),
+            completed_set(second, thursday, 110.0, 1),
+            completed_set(second, thursday, 60.0, 0),
+            completed_set(third, next_monday, 105.0, 5),
+        ];
+
+        let daily = compute_points(&sets, OneRepMaxFormula::Epley, Grouping::Day, false);
+
+        assert_eq!(daily.len(), 3);
+        assert_eq!(daily[0].volume, 800.0);
+        assert_eq!(daily[0].sets, 2);
+        assert_eq!(daily[0].top_set.set_id, sets[0].set_id);
+        assert_eq!(daily[1].sets, 1);
+
+        let weekly = compute_points(&sets, OneRepMaxFormula::Epley, Grouping::Week, false);
+
+        assert_eq!(weekly.len(), 2);
+        assert_eq!(weekly[0].period, monday.date_naive());
+        assert_eq!(weekly[0].sessions, 2);
+        assert_eq!(weekly[0].sets, 3);
+        assert_eq!(weekly[0].volume, 910.0);
+        assert_eq!(weekly[0].top_set.set_id, sets[2].set_id);
+        // 100kg for 5 reps is more than 110kg for a single
+        assert_eq!(
+            weekly[0].estimated_one_rep_max,
+            Some(100.0 * (1.0 + 5.0 / 30.0))
+        );
+        assert_eq!(weekly[1].sessions, 1);
+
+        let monthly = compute_points(&sets, OneRepMaxFormula::Epley, Grouping::Month, false);
+
+        assert_eq!(monthly.len(), 1);
+        assert_eq!(monthly[0].sessions, 3);
+        assert_eq!(monthly[0].volume, 910.0 + 525.0);
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt::{Debug, Display};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    PgPool,
+};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::access_token::{generate_token, hash_token, token_matches, token_prefix};
+
+// What an API key is allowed to do, access tokens can do everything
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    ToSchema,
+    sqlx::Type,
+)]
+#[sqlx(type_name = "api_scope", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ApiScope {
+    #[serde(rename = "exercises:read")]
+    ExercisesRead,
+    #[serde(rename = "exercises:write")]
+    ExercisesWrite,
+    // Also covers exercise instances and the rest timer
+    #[serde(rename = "sessions:read")]
+    SessionsRead,
+    #[serde(rename = "sessions:write")]
+    SessionsWrite,
+    #[serde(rename = "sets:read")]
+    SetsRead,
+    #[serde(rename = "sets:write")]
+    SetsWrite,
+    #[serde(rename = "templates:read")]
+    TemplatesRead,
+    #[serde(rename = "templates:write")]
+    TemplatesWrite,
+    #[serde(rename = "export")]
+    Export,
+}
+
+impl Display for ApiScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = match self {
+            ApiScope::ExercisesRead => "exercises:read",
+            ApiScope::ExercisesWrite => "exercises:write",
+            ApiScope::SessionsRead => "sessions:read",
+            ApiScope::SessionsWrite => "sessions:write",
+            ApiScope::SetsRead => "sets:read",
+            ApiScope::SetsWrite => "sets:write",
+            ApiScope::TemplatesRead => "templates:read",
+            ApiScope::TemplatesWrite => "templates:write",
+            ApiScope::Export => "export",
+        };
+
+        write!(f, "{}", scope)
+    }
+}
+
+impl PgHasArrayType for ApiScope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_api_scope")
+    }
+}
+
+// Named key which doesn't expire, used by scripts and other integrations.
+// Only has access to the routes its scopes allow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    // Only returned when the key is created, queries return null
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub user_id: Uuid,
+}
+
+impl ApiKey {
+    #[instrument]
+    pub async fn new(
+        user_id: Uuid,
+        name: impl ToString + Display + Debug,
+        scopes: Vec<ApiScope>,
+        pool: &PgPool,
+    ) -> Result<ApiKey, RouteError> {
+        info!("Creating a new API key '{}' for user {}", name, user_id);
+
+        let key = generate_token();
+
+        let mut scopes = scopes;
+        scopes.sort();
+        scopes.dedup();
+
+        let mut api_key = sqlx::query_as!(
+            ApiKey,
+            r#"
+            INSERT INTO api_keys (name, scopes, token_prefix, token_hash, user_id) VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, name, scopes AS "scopes: Vec<ApiScope>", NULL AS "key?", created, last_used, user_id
+            "#,
+            name.to_string(),
+            scopes as Vec<ApiScope>,
+            token_prefix(&key),
+            hash_token(&key),
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        api_key.key = Some(key);
+
+        Ok(api_key)
+    }
+
+    // Find the API key matching the bearer token and mark it used
+    #[instrument(skip(key))]
+    pub async fn from_key(
+        key: impl ToString + Display + Debug,
+        pool: &PgPool,
+    ) -> Result<ApiKey, RouteError> {
+        info!("Querying potential API key");
+
+        let key = key.to_string();
+
+        let candidates = sqlx::query!(
+            "SELECT id, token_hash FROM api_keys WHERE token_prefix = $1",
+            token_prefix(&key)
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let matching_id = candidates
+            .into_iter()
+            .find(|candidate| token_matches(&key, &candidate.token_hash))
+            .map(|candidate| candidate.id);
+
+        let potential_key = match matching_id {
+            Some(id) => {
+                sqlx::query_as!(
+                    ApiKey,
+                    r#"
+                    UPDATE api_keys SET last_used = NOW() WHERE id = $1
+                    RETURNING id, name, scopes AS "scopes: Vec<ApiScope>", NULL AS "key?", created, last_used, user_id
+                    "#,
+                    id
+                )
+                .fetch_optional(pool)
+                .await?
+            }
+            None => None,
+        };
+
+        match potential_key {
+            Some(api_key) => Ok(api_key),
+            None => {
+                warn!("Invalid API key provided");
+
+                Err(RouteError::new(
+                    "No valid API key found.",
+                    None::<&str>,
+                    StatusCode::UNAUTHORIZED,
+                ))
+            }
+        }
+    }
+
+    // Returns an error if the key doesn't have the scope
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), RouteError> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            info!("API key {} is missing the scope {}", self.id, scope);
+
+            Err(RouteError::new(
+                format!("API key is missing the scope {}.", scope),
+                None::<&str>,
+                StatusCode::FORBIDDEN,
+            ))
+        }
+    }
+}
+
+// API keys of the user, newest first
+#[instrument]
+pub async fn all_user_api_keys(user_id: Uuid, pool: &PgPool) -> Result<Vec<ApiKey>, RouteError> {
+    info!("Querying all API keys of an user");
+
+    Ok(sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT id, name, scopes AS "scopes: Vec<ApiScope>", NULL AS "key?", created, last_used, user_id
+        FROM api_keys WHERE user_id = $1 ORDER BY created DESC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+#[instrument]
+pub async fn revoke_api_key(
+    user_id: Uuid,
+    api_key_id: Uuid,
+    pool: &PgPool,
+) -> Result<Uuid, RouteError> {
+    info!("Revoking an API key");
+
+    let deleted = sqlx::query!(
+        "DELETE FROM api_keys WHERE user_id = $1 AND id = $2",
+        user_id,
+        api_key_id
+    )
+    .execute(pool)
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(RouteError::new(
+            "API key not found.",
+            Some("api_key_id"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    Ok(api_key_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::test_utils::api::create_test_user;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn create_and_find(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let api_key = ApiKey::new(
+            user.id,
+            "Dashboard",
+            vec![
+                ApiScope::SetsRead,
+                ApiScope::SessionsRead,
+                ApiScope::SetsRead,
+            ],
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            api_key.scopes,
+            vec![ApiScope::SessionsRead, ApiScope::SetsRead]
+        );
+
+        let found = ApiKey::from_key(api_key.key.unwrap(), &pool).await.unwrap();
+
+        assert_eq!(found.id, api_key.id);
+        assert!(found.key.is_none());
+        assert!(found.last_used.is_some());
+        assert!(found.require_scope(ApiScope::SetsRead).is_ok());
+        assert!(found.require_scope(ApiScope::SetsWrite).is_err());
+    }
+
+    #[sqlx::test]
+    async fn revoke(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let api_key = ApiKey::new(user.id, "Script", vec![ApiScope::Export], &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(all_user_api_keys(user.id, &pool).await.unwrap().len(), 1);
+
+        revoke_api_key(user.id, api_key.id, &pool).await.unwrap();
+
+        assert!(ApiKey::from_key(api_key.key.unwrap(), &pool).await.is_err());
+        assert!(revoke_api_key(user.id, api_key.id, &pool).await.is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use axum::http::StatusCode;
+use chrono::Duration;
+use sqlx::{PgExecutor, PgPool};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use crate::{
+    api::response::RouteError,
+    mailer::{Email, Mailer},
+};
+
+use super::{
+    access_token::{self, generate_token, hash_token},
+    set::WeightUnit,
+    user::{User, UserRole},
+};
+
+// How long the tokens sent by email can be used
+const VERIFY_EMAIL_VALIDITY: Duration = Duration::hours(24);
+const RESET_PASSWORD_VALIDITY: Duration = Duration::hours(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_token_purpose", rename_all = "SCREAMING_SNAKE_CASE")]
+enum EmailTokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+// Set or remove the email of the user, which needs the current password.
+// A new email is unverified until the token sent to it is used.
+#[instrument(skip(user, password, mailer))]
+pub async fn change_email(
+    user: &mut User,
+    email: Option<String>,
+    password: &str,
+    mailer: &dyn Mailer,
+    pool: &PgPool,
+) -> Result<(), RouteError> {
+    user.require_password(password)?;
+
+    info!("Changing the email of user {}", user.id);
+
+    let email = email.map(|email| email.trim().to_lowercase());
+
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM users WHERE email = $1 AND id != $2) AS "taken!""#,
+        email,
+        user.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if taken {
+        return Err(RouteError::new(
+            "Email is already used by another user.",
+            Some("email"),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE users SET email = $1, email_verified = FALSE, changed = NOW() WHERE id = $2 RETURNING changed",
+        email,
+        user.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    user.email = email;
+    user.email_verified = false;
+    user.changed = updated.changed;
+
+    // Tokens sent to the previous email can't be used anymore
+    sqlx::query!("DELETE FROM email_tokens WHERE user_id = $1", user.id)
+        .execute(pool)
+        .await?;
+
+    if let Some(email) = &user.email {
+        let token = create_token(
+            user.id,
+            email,
+            EmailTokenPurpose::VerifyEmail,
+            VERIFY_EMAIL_VALIDITY,
+            pool,
+        )
+        .await?;
+
+        send(
+            mailer,
+            Email {
+                to: email.clone(),
+                subject: "Verify your email for LiftLog".to_string(),
+                body: format!(
+                    "Hi {},\n\nUse this code to verify your email in LiftLog:\n\n{}\n\nThe code expires in 24 hours.",
+                    user.username, token
+                ),
+            },
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Mark the email verified with the token sent to it
+#[instrument(skip(token))]
+pub async fn verify_email(token: &str, pool: &PgPool) -> Result<User, RouteError> {
+    let (user_id, email) = use_token(token, EmailTokenPurpose::VerifyEmail, pool).await?;
+
+    info!("Verifying the email of user {}", user_id);
+
+    sqlx::query_as!(
+        User,
+        r#"
+        UPDATE users SET email_verified = TRUE, changed = NOW()
+        WHERE id = $1 AND email = $2
+        RETURNING id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        "#,
+        user_id,
+        email
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(invalid_token)
+}
+
+// Send a password reset token if an user has the verified email.
+// Doesn't tell whether the email was found.
+#[instrument(skip(email, mailer))]
+pub async fn request_password_reset(
+    email: &str,
+    mailer: &dyn Mailer,
+    pool: &PgPool,
+) -> Result<(), RouteError> {
+    let email = email.trim().to_lowercase();
+
+    let user = sqlx::query!(
+        "SELECT id, username FROM users WHERE email = $1 AND email_verified AND NOT disabled",
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(user) = user else {
+        info!("Password reset requested for an unknown email");
+        return Ok(());
+    };
+
+    info!("Sending a password reset token to user {}", user.id);
+
+    let token = create_token(
+        user.id,
+        &email,
+        EmailTokenPurpose::ResetPassword,
+        RESET_PASSWORD_VALIDITY,
+        pool,
+    )
+    .await?;
+
+    send(
+        mailer,
+        Email {
+            to: email,
+            subject: "Reset your LiftLog password".to_string(),
+            body: format!(
+                "Hi {},\n\nUse this code to set a new password for LiftLog:\n\n{}\n\nThe code expires in an hour. If you didn't ask for it, ignore this email.",
+                user.username, token
+            ),
+        },
+    )
+    .await
+}
+
+// Set a new password with the token sent by email and log out everywhere,
+// API keys included
+#[instrument(skip(token, new_password))]
+pub async fn reset_password(
+    token: &str,
+    new_password: impl ToString,
+    pool: &PgPool,
+) -> Result<User, RouteError> {
+    let mut tx = pool.begin().await?;
+
+    let (user_id, email) = use_token(token, EmailTokenPurpose::ResetPassword, &mut *tx).await?;
+
+    let mut user = sqlx::query_as!(
+        User,
+        r#"
+        SELECT id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        FROM users WHERE id = $1 FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    user.require_enabled()?;
+
+    // Email has changed after sending the token
+    if user.email.as_deref() != Some(email.as_str()) {
+        return Err(invalid_token());
+    }
+
+    info!("Resetting the password of user {} with a token", user.id);
+
+    user.set_password(new_password, &mut tx).await?;
+    access_token::revoke_user_tokens(user.id, None, &mut tx).await?;
+    sqlx::query!("DELETE FROM api_keys WHERE user_id = $1", user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+async fn create_token(
+    user_id: Uuid,
+    email: &str,
+    purpose: EmailTokenPurpose,
+    validity: Duration,
+    pool: &PgPool,
+) -> Result<String, RouteError> {
+    let token = generate_token();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_tokens (purpose, token_hash, email, expires, user_id)
+        VALUES ($1, $2, $3, NOW() + $4::interval, $5)
+        "#,
+        purpose as EmailTokenPurpose,
+        hash_token(&token),
+        email,
+        validity as _,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+// Delete the token so it can be used only once, returns the user and the email it was sent to
+async fn use_token(
+    token: &str,
+    purpose: EmailTokenPurpose,
+    executor: impl PgExecutor<'_>,
+) -> Result<(Uuid, String), RouteError> {
+    // Hashes are keyed, so they can be compared in the database
+    let used = sqlx::query!(
+        r#"
+        DELETE FROM email_tokens
+        WHERE token_hash = $1 AND purpose = $2 AND expires > NOW()
+        RETURNING user_id, email
+        "#,
+        hash_token(token),
+        purpose as EmailTokenPurpose
+    )
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(invalid_token)?;
+
+    Ok((used.user_id, used.email))
+}
+
+fn invalid_token() -> RouteError {
+    RouteError::new(
+        "Token is invalid, expired or already used.",
+        Some("token"),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+async fn send(mailer: &dyn Mailer, email: Email) -> Result<(), RouteError> {
+    mailer.send(email).await.map_err(|send_error| {
+        error!("Failed to send an email: {}", send_error);
+
+        RouteError::new(
+            "Failed to send the email.",
+            None::<&str>,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })
+}
@@ -29,10 +29,18 @@ pub struct Exercise {
     pub notes: Option<String>,
     // Kind is just visual and any additional weight is not calculated at the set level
     pub kind: ExerciseKind,
+    // How long to rest after completing a set, can be overridden per instance
+    #[serde(default = "default_rest_seconds")]
+    pub rest_seconds: i32,
+}
+
+// Same as the database default, used for exports without rest times
+pub fn default_rest_seconds() -> i32 {
+    120
 }
 
 // Used to categorize exercises
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, sqlx::Type)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema, sqlx::Type)]
 #[sqlx(type_name = "exercise_kind", rename_all = "UPPERCASE")]
 #[serde(rename_all = "lowercase")]
 pub enum ExerciseKind {
@@ -62,7 +70,7 @@ impl Exercise {
             r#"
             INSERT INTO exercises (user_id, name, description, favourite, notes, kind)
             VALUES ($1, $2, $3, $4, $5, $6)
-            RETURNING id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind"
+            RETURNING id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind", rest_seconds
             "#,
             user_id,
             name.to_string(),
@@ -87,7 +95,7 @@ impl Exercise {
         Ok(sqlx::query_as!(
             Exercise,
             r#"
-            SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind"
+            SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind", rest_seconds
             FROM exercises WHERE user_id = $1 AND id = $2 LIMIT 1
             "#,
             user_id,
@@ -238,6 +246,28 @@ impl Exercise {
 
         Ok(())
     }
+
+    // Change the rest time between sets
+    #[instrument]
+    pub async fn set_rest_seconds(
+        &mut self,
+        rest_seconds: i32,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating exercise rest time");
+
+        self.rest_seconds = sqlx::query!(
+            "UPDATE exercises SET rest_seconds = $1 WHERE id = $2 AND user_id = $3 RETURNING rest_seconds",
+            rest_seconds,
+            self.id,
+            self.user_id
+        )
+        .fetch_one(pool)
+        .await?
+        .rest_seconds;
+
+        Ok(())
+    }
 }
 
 // Get all the users exercises, if Kind is specified it's included as a filter
@@ -253,7 +283,7 @@ pub async fn all_user_exercises(
     match optional_kind {
         Some(kind) => Ok(sqlx::query_as!(
             Exercise,
-            r#"SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind" FROM exercises WHERE user_id = $1 AND kind = $2"#,
+            r#"SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind", rest_seconds FROM exercises WHERE user_id = $1 AND kind = $2"#,
             user_id,
             kind as _
         )
@@ -261,7 +291,7 @@ pub async fn all_user_exercises(
         .await?),
         None => Ok(sqlx::query_as!(
             Exercise,
-            r#"SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind" FROM exercises WHERE user_id = $1"#,
+            r#"SELECT id, user_id, name, description, favourite, notes, kind AS "kind: ExerciseKind", rest_seconds FROM exercises WHERE user_id = $1"#,
             user_id
         )
         .fetch_all(pool).await?)
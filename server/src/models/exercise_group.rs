@@ -0,0 +1,433 @@
+use std::collections::HashSet;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool, Postgres, Transaction};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{api::response::RouteError, models::session};
+
+use super::exercise_instance::{self, ExerciseInstance};
+
+// Exercise instances of a session done in rounds, one set of each in turn.
+// Instances can be in one group at a time and groups have at least two of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
+pub struct ExerciseGroup {
+    // Primary key
+    pub id: Uuid,
+    // These are user specific
+    pub user_id: Uuid,
+    // A group is bound to a session like its exercise instances
+    pub session_id: Uuid,
+    pub kind: ExerciseGroupKind,
+    // Rest after each round, the rest times of the instances are used if not set.
+    // There's no rest between the exercises of a round.
+    #[serde(default)]
+    pub rest_seconds: Option<i32>,
+    pub created: DateTime<Utc>,
+    // In the order they are done in a round, which is their order in the session
+    #[sqlx(skip)]
+    pub exercise_instance_ids: Vec<Uuid>,
+    // IDs of the sets in the order they are done, a set of each instance per round
+    // until the instance runs out of sets
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub rounds: Vec<Vec<Uuid>>,
+}
+
+// Only describes the group, all kinds work the same way
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "exercise_group_kind", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum ExerciseGroupKind {
+    // Usually two exercises
+    #[default]
+    Superset,
+    Circuit,
+    // Usually three or more exercises for the same muscle group
+    GiantSet,
+}
+
+impl ExerciseGroup {
+    // Group exercise instances of the session, which can't be in other groups
+    #[instrument]
+    pub async fn new(
+        user_id: Uuid,
+        session_id: Uuid,
+        kind: ExerciseGroupKind,
+        rest_seconds: Option<i32>,
+        exercise_instance_ids: &[Uuid],
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Creating a new exercise group");
+
+        let session_owner = sqlx::query("SELECT id FROM sessions WHERE id = $1 AND user_id = $2")
+            .bind(session_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        if session_owner.is_none() {
+            return Err(RouteError::new(
+                "Invalid session ID",
+                Some("session_id"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(session_id, &mut tx).await?;
+        ensure_groupable(user_id, session_id, None, exercise_instance_ids, &mut tx).await?;
+
+        let group_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO exercise_groups (user_id, session_id, kind, rest_seconds) VALUES ($1, $2, $3, $4) RETURNING id",
+        )
+        .bind(user_id)
+        .bind(session_id)
+        .bind(kind)
+        .bind(rest_seconds)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query("UPDATE exercise_instances SET group_id = $1 WHERE id = ANY($2)")
+            .bind(group_id)
+            .bind(exercise_instance_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Self::from_id(user_id, group_id, pool).await
+    }
+
+    // Get from ID and user with the instances and rounds filled
+    #[instrument]
+    pub async fn from_id(user_id: Uuid, id: Uuid, pool: &PgPool) -> Result<Self, RouteError> {
+        info!("Querying an exercise group based on ID");
+
+        let mut group: Self =
+            sqlx::query_as("SELECT * FROM exercise_groups WHERE user_id = $1 AND id = $2")
+                .bind(user_id)
+                .bind(id)
+                .fetch_one(pool)
+                .await?;
+
+        let instances =
+            exercise_instance::all_from_session_id(user_id, group.session_id, pool).await?;
+
+        fill_rounds(std::slice::from_mut(&mut group), &instances);
+
+        Ok(group)
+    }
+
+    #[instrument]
+    pub async fn set_kind(
+        &mut self,
+        kind: ExerciseGroupKind,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating exercise group kind");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        sqlx::query("UPDATE exercise_groups SET kind = $1 WHERE id = $2")
+            .bind(kind)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.kind = kind;
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub async fn set_rest_seconds(
+        &mut self,
+        rest_seconds: Option<i32>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating exercise group rest time");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        sqlx::query("UPDATE exercise_groups SET rest_seconds = $1 WHERE id = $2")
+            .bind(rest_seconds)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.rest_seconds = rest_seconds;
+
+        Ok(())
+    }
+
+    // Replace the instances of the group, the removed ones are ungrouped
+    #[instrument]
+    pub async fn set_exercise_instances(
+        &mut self,
+        exercise_instance_ids: &[Uuid],
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating exercise group instances");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+        ensure_groupable(
+            self.user_id,
+            self.session_id,
+            Some(self.id),
+            exercise_instance_ids,
+            &mut tx,
+        )
+        .await?;
+
+        sqlx::query("UPDATE exercise_instances SET group_id = NULL WHERE group_id = $1")
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("UPDATE exercise_instances SET group_id = $1 WHERE id = ANY($2)")
+            .bind(self.id)
+            .bind(exercise_instance_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        *self = Self::from_id(self.user_id, self.id, pool).await?;
+
+        Ok(())
+    }
+
+    // Delete the group, the exercise instances and their sets stay in the session
+    #[instrument]
+    pub async fn dissolve(self, pool: &PgPool) -> Result<Uuid, RouteError> {
+        info!("Dissolving exercise group");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        // Instances are ungrouped by the database
+        sqlx::query("DELETE FROM exercise_groups WHERE id = $1")
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(self.id)
+    }
+}
+
+// The instances have to be at least two different ones of the session
+// and not in any other group than the one being edited. They stay locked until
+// the transaction ends, so they can't be grouped at the same time.
+async fn ensure_groupable(
+    user_id: Uuid,
+    session_id: Uuid,
+    group_id: Option<Uuid>,
+    exercise_instance_ids: &[Uuid],
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    let unique: HashSet<&Uuid> = exercise_instance_ids.iter().collect();
+
+    if unique.len() < 2 || unique.len() != exercise_instance_ids.len() {
+        return Err(RouteError::new(
+            "A group needs at least two different exercise instances.",
+            Some("exercise_instance_ids"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    // Rows grouped by a concurrent transaction are checked again after it's done
+    let groupable: Vec<Uuid> = sqlx::query_scalar(
+        r#"
+        SELECT id FROM exercise_instances
+        WHERE user_id = $1 AND session_id = $2 AND id = ANY($3)
+            AND (group_id IS NULL OR group_id IS NOT DISTINCT FROM $4)
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .bind(session_id)
+    .bind(exercise_instance_ids)
+    .bind(group_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    if groupable.len() != exercise_instance_ids.len() {
+        return Err(RouteError::new(
+            "Exercise instances have to be in the same session and not in another group.",
+            Some("exercise_instance_ids"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    Ok(())
+}
+
+// Fill the instances and rounds of the groups from the instances of their sessions,
+// which have to be in their order with their sets
+pub fn fill_rounds(groups: &mut [ExerciseGroup], exercise_instances: &[ExerciseInstance]) {
+    for group in groups {
+        let members: Vec<&ExerciseInstance> = exercise_instances
+            .iter()
+            .filter(|instance| instance.group_id == Some(group.id))
+            .collect();
+
+        let round_count = members
+            .iter()
+            .map(|instance| instance.sets.len())
+            .max()
+            .unwrap_or(0);
+
+        group.exercise_instance_ids = members.iter().map(|instance| instance.id).collect();
+        group.rounds = (0..round_count)
+            .map(|round| {
+                members
+                    .iter()
+                    .filter_map(|instance| instance.sets.get(round).map(|set| set.id))
+                    .collect()
+            })
+            .collect();
+    }
+}
+
+// Groups of multiple sessions without their instances, ordered by creation
+//
+// WARNING: User ownership of session IS NOT CHECKED
+#[instrument]
+pub async fn all_from_session_ids(
+    user_id: Uuid,
+    session_ids: &[Uuid],
+    pool: &PgPool,
+) -> Result<Vec<ExerciseGroup>, RouteError> {
+    info!("Querying all exercise groups of multiple sessions");
+
+    Ok(sqlx::query_as(
+        "SELECT * FROM exercise_groups WHERE user_id = $1 AND session_id = ANY($2) ORDER BY created",
+    )
+    .bind(user_id)
+    .bind(session_ids)
+    .fetch_all(pool)
+    .await?)
+}
+
+// A group left with a single instance isn't a group anymore
+#[instrument]
+pub async fn dissolve_if_too_small(group_id: Uuid, pool: &PgPool) -> Result<(), RouteError> {
+    sqlx::query(
+        "DELETE FROM exercise_groups WHERE id = $1 AND (SELECT COUNT(*) FROM exercise_instances WHERE group_id = $1) < 2",
+    )
+    .bind(group_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        models::{session::Session, set::Set},
+        test_utils::api::create_test_scenario,
+    };
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn rounds_and_dissolving(pool: PgPool) {
+        let (_, user, _, exercise, session, first, first_set) = create_test_scenario(&pool).await;
+
+        let second = ExerciseInstance::new(user.id, session.id, exercise.id, None, &pool)
+            .await
+            .unwrap();
+        let second_sets = [
+            Set::new(user.id, second.id, None, &pool).await.unwrap(),
+            Set::new(user.id, second.id, None, &pool).await.unwrap(),
+        ];
+
+        let group = ExerciseGroup::new(
+            user.id,
+            session.id,
+            ExerciseGroupKind::Superset,
+            Some(90),
+            &[first.id, second.id],
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(group.exercise_instance_ids, vec![first.id, second.id]);
+        // The first instance has only one set
+        assert_eq!(
+            group.rounds,
+            vec![
+                vec![first_set.id, second_sets[0].id],
+                vec![second_sets[1].id]
+            ]
+        );
+
+        let session = Session::from_id(user.id, session.id, &pool).await.unwrap();
+
+        assert_eq!(session.exercise_groups, vec![group.clone()]);
+        assert_eq!(session.exercise_instances[0].group_id, Some(group.id));
+
+        // Deleting an instance leaves only one, which isn't a group
+        first.delete(&pool).await.unwrap();
+
+        assert!(ExerciseGroup::from_id(user.id, group.id, &pool)
+            .await
+            .is_err());
+
+        let session = Session::from_id(user.id, session.id, &pool).await.unwrap();
+
+        assert!(session.exercise_groups.is_empty());
+        assert!(session.exercise_instances[0].group_id.is_none());
+    }
+
+    #[sqlx::test]
+    async fn concurrent_groups(pool: PgPool) {
+        let (_, user, _, exercise, session, first, _) = create_test_scenario(&pool).await;
+
+        let second = ExerciseInstance::new(user.id, session.id, exercise.id, None, &pool)
+            .await
+            .unwrap();
+        let instance_ids = [first.id, second.id];
+
+        let groups = (0..3).map(|_| {
+            ExerciseGroup::new(
+                user.id,
+                session.id,
+                ExerciseGroupKind::Superset,
+                None,
+                &instance_ids,
+                &pool,
+            )
+        });
+
+        let results = futures::future::join_all(groups).await;
+
+        // The instances can only be in one of them
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    }
+}
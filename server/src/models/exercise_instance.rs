@@ -1,4 +1,7 @@
-use std::fmt::{Debug, Display};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+};
 
 use axum::http::StatusCode;
 use chrono::{DateTime, Utc};
@@ -8,9 +11,19 @@ use tracing::{error, info, instrument};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::{api::response::RouteError, models::set};
+use crate::{
+    api::response::RouteError,
+    models::{
+        exercise_group, personal_record,
+        position::{self, Ordered},
+        session, set,
+    },
+};
 
-use super::{exercise::Exercise, set::Set};
+use super::{
+    exercise::Exercise,
+    set::{ConvertWeights, Set, WeightUnit},
+};
 
 // One instance of an existing exercise, containing:
 // - Comments tied to the session
@@ -19,8 +32,8 @@ use super::{exercise::Exercise, set::Set};
 //   - Reps
 //   - Completed or not
 //
-// The sets are ordered, new ones are always added at the end and any of them
-// can be deleted.
+// Instances are ordered in their session and sets in their instance by position.
+// New ones are added at the end unless a position is given, and both can be moved.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
 pub struct ExerciseInstance {
     // Primary key
@@ -29,24 +42,40 @@ pub struct ExerciseInstance {
     pub user_id: Uuid,
     // An exercise instance is bound to a session
     pub session_id: Uuid,
-    // Mainly for ordering of instances and can't be changed
+    // When the instance was added, can't be changed
     pub created: DateTime<Utc>,
+    // Order in the session starting from 0, older exports are ordered by the list
+    #[serde(default)]
+    pub position: i32,
     // The predefined exercise this one is an instance of
     pub exercise_id: Uuid,
     // Comments tied to this instance, and this way also the session
     pub comments: Vec<String>,
-    // The sets included in the instance (order sensitive and immutable without deleting or adding)
+    // Overrides the rest time of the exercise if set
+    #[serde(default)]
+    pub rest_seconds: Option<i32>,
+    // Superset, circuit or giant set the instance is done in if any
+    #[serde(default)]
+    pub group_id: Option<Uuid>,
+    // The sets included in the instance in order
     #[sqlx(skip)]
     pub sets: Vec<Set>,
 }
 
+impl ConvertWeights for ExerciseInstance {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        self.sets.convert_weights(unit);
+    }
+}
+
 impl ExerciseInstance {
-    // Create a new one with no comments or sets
+    // Create a new one with no comments or sets at the position, or at the end if not given
     #[instrument]
     pub async fn new(
         user_id: Uuid,
         session_id: Uuid,
         exercise_id: Uuid,
+        position: Option<i32>,
         pool: &PgPool,
     ) -> Result<Self, RouteError> {
         // Make sure session and exercise are owned by the user
@@ -78,7 +107,18 @@ impl ExerciseInstance {
             ));
         }
 
-        Ok(sqlx::query_as("INSERT INTO exercise_instances (user_id, session_id, exercise_id) VALUES ($1, $2, $3) RETURNING *").bind(user_id).bind(session_id).bind(exercise_id).fetch_one(pool).await?)
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(session_id, &mut tx).await?;
+
+        let position =
+            position::make_room(Ordered::ExerciseInstances, session_id, position, &mut tx).await?;
+
+        let created = sqlx::query_as("INSERT INTO exercise_instances (user_id, session_id, exercise_id, position) VALUES ($1, $2, $3, $4) RETURNING *").bind(user_id).bind(session_id).bind(exercise_id).bind(position).fetch_one(&mut *tx).await?;
+
+        tx.commit().await?;
+
+        Ok(created)
     }
 
     // Get existing one by ID and user
@@ -104,9 +144,34 @@ impl ExerciseInstance {
     // Delete the instance and the sets related to it
     #[instrument]
     pub async fn delete(self, pool: &PgPool) -> Result<Uuid, RouteError> {
-        sqlx::query!("DELETE FROM exercise_instances WHERE id = $1", self.id)
-            .execute(pool)
-            .await?;
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        // The group might have changed after this instance was queried
+        let group_id = sqlx::query_scalar!(
+            "SELECT group_id FROM exercise_instances WHERE id = $1",
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        position::delete(
+            Ordered::ExerciseInstances,
+            self.id,
+            self.session_id,
+            &mut tx,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        if let Some(group_id) = group_id {
+            exercise_group::dissolve_if_too_small(group_id, pool).await?;
+        }
+
+        // Completed sets of the instance might have been records
+        personal_record::recompute(self.user_id, self.exercise_id, pool).await?;
 
         Ok(self.id)
     }
@@ -118,14 +183,20 @@ impl ExerciseInstance {
         comment: impl ToString + Display + Debug,
         pool: &PgPool,
     ) -> Result<(), RouteError> {
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
         self.comments = sqlx::query!(
             "UPDATE exercise_instances SET comments = comments || $1 WHERE id = $2 RETURNING comments",
             &[comment.to_string()],
             self.id
         )
-        .fetch_one(pool)
+        .fetch_one(&mut *tx)
         .await?.comments;
 
+        tx.commit().await?;
+
         Ok(())
     }
 
@@ -137,6 +208,10 @@ impl ExerciseInstance {
         comment: impl ToString + Display + Debug,
         pool: &PgPool,
     ) -> Result<(), RouteError> {
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
         // Check that the index exists and return a clearer error if it doesn't
         if convert_index_type(index)? + 1 > self.comments.len() {
             Err(RouteError::new(
@@ -151,10 +226,12 @@ impl ExerciseInstance {
                 comment.to_string(),
                 self.id
             )
-            .fetch_one(pool)
+            .fetch_one(&mut *tx)
             .await?
             .comments;
 
+            tx.commit().await?;
+
             Ok(())
         }
     }
@@ -166,6 +243,10 @@ impl ExerciseInstance {
         index: usize,
         pool: &PgPool,
     ) -> Result<usize, RouteError> {
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
         match self.comments.get(index) {
             Some(value) => {
                 self.comments = sqlx::query!(
@@ -173,9 +254,11 @@ impl ExerciseInstance {
                 value,
                 self.id
             )
-                .fetch_one(pool)
+                .fetch_one(&mut *tx)
                 .await?.comments;
 
+                tx.commit().await?;
+
                 Ok(index)
             }
             None => Err(RouteError::new(
@@ -196,16 +279,102 @@ impl ExerciseInstance {
         // Validate ownership of exercise
         let exercise = Exercise::from_id(self.user_id, exercise_id, pool).await?;
 
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
         sqlx::query!(
             "UPDATE exercise_instances SET exercise_id = $1 WHERE id = $2",
             exercise.id,
             self.id
         )
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
 
+        tx.commit().await?;
+
+        // Sets are moved from one exercise to another, so both are affected
+        let previous_exercise_id = self.exercise_id;
         self.exercise_id = exercise_id;
 
+        personal_record::recompute(self.user_id, previous_exercise_id, pool).await?;
+        personal_record::recompute(self.user_id, self.exercise_id, pool).await?;
+
+        Ok(())
+    }
+
+    // Override the rest time of the exercise for this instance, None uses the exercise's
+    #[instrument]
+    pub async fn set_rest_seconds(
+        &mut self,
+        rest_seconds: Option<i32>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        self.rest_seconds = sqlx::query!(
+            "UPDATE exercise_instances SET rest_seconds = $1 WHERE id = $2 RETURNING rest_seconds",
+            rest_seconds,
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .rest_seconds;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Move to the position in the session, the instances in between move by one
+    #[instrument]
+    pub async fn set_position(&mut self, position: i32, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Moving exercise instance");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        self.position = position::move_to(
+            Ordered::ExerciseInstances,
+            self.id,
+            self.session_id,
+            position,
+            &mut tx,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Swap places with another instance of the same session
+    #[instrument]
+    pub async fn swap_position(
+        &mut self,
+        other_exercise_instance_id: Uuid,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Swapping exercise instances");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked(self.session_id, &mut tx).await?;
+
+        self.position = position::swap(
+            Ordered::ExerciseInstances,
+            self.id,
+            other_exercise_instance_id,
+            self.session_id,
+            &mut tx,
+        )
+        .await?;
+
+        tx.commit().await?;
+
         Ok(())
     }
 }
@@ -240,17 +409,41 @@ pub async fn all_from_session_id(
 ) -> Result<Vec<ExerciseInstance>, RouteError> {
     info!("Querying all exercise instances in one session");
 
+    all_from_session_ids(user_id, &[session_id], pool).await
+}
+
+// Get all exercise instances of multiple sessions with their sets.
+// Uses two queries no matter how many sessions, instances or sets there are.
+#[instrument]
+pub async fn all_from_session_ids(
+    user_id: Uuid,
+    session_ids: &[Uuid],
+    pool: &PgPool,
+) -> Result<Vec<ExerciseInstance>, RouteError> {
+    info!("Querying all exercise instances of multiple sessions");
+
     let mut all: Vec<ExerciseInstance> = sqlx::query_as(
-        "SELECT * FROM exercise_instances WHERE user_id = $1 AND session_id = $2 ORDER BY created",
+        "SELECT * FROM exercise_instances WHERE user_id = $1 AND session_id = ANY($2) ORDER BY position",
     )
     .bind(user_id)
-    .bind(session_id)
+    .bind(session_ids)
     .fetch_all(pool)
     .await?;
 
-    // Query all sets contained in each instance
+    let instance_ids: Vec<Uuid> = all.iter().map(|instance| instance.id).collect();
+
+    // Sets are ordered, so they stay that way when grouped to their instances
+    let mut sets_by_instance: HashMap<Uuid, Vec<Set>> = HashMap::new();
+
+    for set in set::all_from_exercise_instance_ids(user_id, &instance_ids, pool).await? {
+        sets_by_instance
+            .entry(set.exercise_instance_id)
+            .or_default()
+            .push(set);
+    }
+
     for instance in &mut all {
-        instance.sets = set::all_from_exercise_instance_id(user_id, instance.id, pool).await?;
+        instance.sets = sets_by_instance.remove(&instance.id).unwrap_or_default();
     }
 
     Ok(all)
@@ -285,7 +478,10 @@ mod tests {
         // Query exercise instances from a session
         let query: Session = Session::from_id(user.id, session.id, &pool).await.unwrap();
 
-        assert_eq!(query.exercise_instances.get(0).unwrap(), &exercise_instance);
+        assert_eq!(
+            query.exercise_instances.first().unwrap(),
+            &exercise_instance
+        );
         assert_eq!(query.exercise_instances.len(), 1);
     }
 
@@ -330,7 +526,7 @@ mod tests {
 
         assert_eq!(exercise_instance_query.comments.len(), 1);
         assert_eq!(
-            exercise_instance_query.comments.get(0).unwrap(),
+            exercise_instance_query.comments.first().unwrap(),
             &"Comment 3".to_string()
         );
     }
@@ -370,7 +566,7 @@ mod tests {
 
         // Add some instances
         for _ in 1..10 {
-            ExerciseInstance::new(user.id, session.id, exercise.id, &pool)
+            ExerciseInstance::new(user.id, session.id, exercise.id, None, &pool)
                 .await
                 .unwrap();
         }
@@ -381,4 +577,35 @@ mod tests {
 
         assert_eq!(instances.len(), 10);
     }
+
+    // Adding and moving at the same time in one session neither deadlocks nor leaves gaps
+    #[sqlx::test]
+    async fn concurrent_positions(pool: PgPool) {
+        let (_, user, _, exercise, session, exercise_instance, _) =
+            create_test_scenario(&pool).await;
+
+        let added = futures::future::join_all(
+            (0..5).map(|_| ExerciseInstance::new(user.id, session.id, exercise.id, Some(0), &pool)),
+        );
+        let moved = futures::future::join_all((0..5).map(|_| {
+            let mut exercise_instance = exercise_instance.clone();
+            let pool = &pool;
+
+            async move { exercise_instance.set_position(0, pool).await }
+        }));
+
+        let (added, moved) = tokio::join!(added, moved);
+
+        assert!(added.iter().all(|instance| instance.is_ok()));
+        assert!(moved.iter().all(|result| result.is_ok()));
+
+        let positions: Vec<i32> = all_from_session_id(user.id, session.id, &pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|instance| instance.position)
+            .collect();
+
+        assert_eq!(positions, (0..6).collect::<Vec<i32>>());
+    }
 }
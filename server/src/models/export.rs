@@ -0,0 +1,254 @@
+use axum::{body::Bytes, http::StatusCode};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool};
+use tokio::sync::mpsc;
+use tracing::{error, info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::api::response::RouteError;
+
+use super::{
+    exercise::{all_user_exercises, Exercise, ExerciseKind},
+    exercise_group,
+    session::{self, Session, SessionCursor, SessionFilter, SessionOrder},
+    set::{ConvertWeights, SetKind, WeightUnit},
+};
+
+// Increased when the format of the JSON export changes
+pub const EXPORT_VERSION: u32 = 2;
+
+// How many sessions are loaded to memory at once when exporting
+const SESSIONS_PER_CHUNK: i64 = 100;
+
+// Full training history of an user. Sessions contain their exercise instances,
+// which contain their comments and sets.
+// Also the input for importing, which is validated when importing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, Validate)]
+pub struct UserExport {
+    #[validate(range(min = 1, message = "must be at least 1"))]
+    pub version: u32,
+    pub exported: DateTime<Utc>,
+    // Unit of all weights in the export, older exports are in kilograms
+    #[serde(default)]
+    pub weight_unit: WeightUnit,
+    pub exercises: Vec<Exercise>,
+    // Oldest first
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    // Versioned document with everything, see UserExport
+    #[default]
+    Json,
+    // Flat list of sets
+    Csv,
+}
+
+// One row of the CSV export, which has one row per set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, FromRow)]
+pub struct CsvExportRow {
+    pub session_name: String,
+    // When the session was started
+    pub date: DateTime<Utc>,
+    pub exercise_name: String,
+    pub kind: ExerciseKind,
+    pub set_kind: SetKind,
+    pub weight: Option<f32>,
+    // Set for every row, so the weights can't be mistaken for another unit
+    #[sqlx(default)]
+    pub weight_unit: WeightUnit,
+    pub reps: Option<i32>,
+    pub rpe: Option<f32>,
+    pub rir: Option<i32>,
+    pub completed: bool,
+}
+
+type ExportChunk = Result<Bytes, RouteError>;
+
+// Stream the JSON export with the sets of the given kinds, weights in the unit.
+// Sessions are written in chunks so the whole history doesn't have to be in memory at once.
+#[instrument]
+pub fn json_stream(
+    user_id: Uuid,
+    set_kinds: Vec<SetKind>,
+    unit: WeightUnit,
+    pool: PgPool,
+) -> impl Stream<Item = ExportChunk> {
+    info!("Exporting user's training history as JSON");
+
+    spawn_export(move |sender| async move {
+        // Everything except the sessions is written at once
+        let mut head = serde_json::to_vec(&UserExport {
+            version: EXPORT_VERSION,
+            exported: Utc::now(),
+            weight_unit: unit,
+            exercises: all_user_exercises(user_id, None, &pool).await?,
+            sessions: Vec::new(),
+        })
+        .map_err(serialization_error)?;
+
+        // Remove the closing "]}" of the empty session list to continue it
+        head.truncate(head.len() - 2);
+        send(&sender, head).await?;
+
+        let filter = SessionFilter::default();
+        let mut cursor: Option<SessionCursor> = None;
+        let mut first = true;
+
+        loop {
+            let mut page = session::user_sessions_page(
+                user_id,
+                &filter,
+                SessionOrder::Oldest,
+                cursor,
+                SESSIONS_PER_CHUNK,
+                true,
+                &pool,
+            )
+            .await?;
+
+            let mut chunk = Vec::new();
+
+            for session in &mut page.sessions {
+                for exercise_instance in &mut session.exercise_instances {
+                    exercise_instance
+                        .sets
+                        .retain(|set| set_kinds.contains(&set.kind));
+                }
+
+                // Rounds only have the exported sets
+                exercise_group::fill_rounds(
+                    &mut session.exercise_groups,
+                    &session.exercise_instances,
+                );
+
+                session.convert_weights(unit);
+
+                if !first {
+                    chunk.push(b',');
+                }
+                first = false;
+
+                serde_json::to_writer(&mut chunk, session).map_err(serialization_error)?;
+            }
+
+            send(&sender, chunk).await?;
+
+            match page.next_cursor.and_then(|next| next.parse().ok()) {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        send(&sender, b"]}".to_vec()).await
+    })
+}
+
+// Stream the CSV export of the sets of the given kinds row by row straight from the database.
+// Every row has the unit of its weight.
+#[instrument]
+pub fn csv_stream(
+    user_id: Uuid,
+    set_kinds: Vec<SetKind>,
+    unit: WeightUnit,
+    pool: PgPool,
+) -> impl Stream<Item = ExportChunk> {
+    info!("Exporting user's training history as CSV");
+
+    spawn_export(move |sender| async move {
+        let mut rows = sqlx::query_as::<_, CsvExportRow>(
+            r#"
+            SELECT sessions.name AS session_name, sessions.started AS date, exercises.name AS exercise_name,
+                exercises.kind, sets.kind AS set_kind, sets.weight, sets.reps, sets.rpe, sets.rir,
+                sets.completed
+            FROM sets
+            JOIN exercise_instances ON exercise_instances.id = sets.exercise_instance_id
+            JOIN sessions ON sessions.id = exercise_instances.session_id
+            JOIN exercises ON exercises.id = exercise_instances.exercise_id
+            WHERE sets.user_id = $1 AND sets.kind = ANY($2)
+            ORDER BY sessions.started, sessions.id, exercise_instances.position, sets.position
+            "#,
+        )
+        .bind(user_id)
+        .bind(&set_kinds)
+        .fetch(&pool);
+
+        let mut header = true;
+
+        while let Some(row) = rows.next().await {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(header)
+                .from_writer(Vec::new());
+
+            let mut row = row?;
+            row.weight = row.weight.map(|weight| unit.convert_kilograms(weight));
+            row.weight_unit = unit;
+
+            writer.serialize(row).map_err(serialization_error)?;
+            header = false;
+
+            send(&sender, writer.into_inner().map_err(serialization_error)?).await?;
+        }
+
+        // Only the header for an empty history
+        if header {
+            send(
+                &sender,
+                b"session_name,date,exercise_name,kind,set_kind,weight,weight_unit,reps,rpe,rir,completed\n"
+                    .to_vec(),
+            )
+            .await?;
+        }
+
+        Ok(())
+    })
+}
+
+// Runs the export in its own task and returns a stream of what it sends.
+// If the export fails the error is the last item.
+fn spawn_export<F, Fut>(export: F) -> impl Stream<Item = ExportChunk>
+where
+    F: FnOnce(mpsc::Sender<ExportChunk>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<(), RouteError>> + Send,
+{
+    let (sender, receiver) = mpsc::channel::<ExportChunk>(16);
+
+    tokio::spawn(async move {
+        if let Err(export_error) = export(sender.clone()).await {
+            error!("Export failed: {:?}", export_error);
+
+            let _ = sender.send(Err(export_error)).await;
+        }
+    });
+
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+}
+
+// Sending only fails if the client has disconnected, which also stops the export
+async fn send(sender: &mpsc::Sender<ExportChunk>, chunk: Vec<u8>) -> Result<(), RouteError> {
+    sender.send(Ok(Bytes::from(chunk))).await.map_err(|_| {
+        RouteError::new(
+            "Export was cancelled.",
+            None::<&str>,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+    })
+}
+
+fn serialization_error(error: impl std::fmt::Display) -> RouteError {
+    error!("Failed to serialize export: {}", error);
+
+    RouteError::new(
+        "Failed to serialize export.",
+        None::<&str>,
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
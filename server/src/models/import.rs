@@ -0,0 +1,1068 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::{RouteError, SingleRouteError};
+
+use super::{
+    exercise::{all_user_exercises, ExerciseKind},
+    export::{UserExport, EXPORT_VERSION},
+    personal_record,
+    session::SessionState,
+    set::{is_valid_rpe, SetKind, WeightUnit},
+};
+
+// Apps whose CSV exports can be imported, detected from the header
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvSource {
+    Strong,
+    Hevy,
+}
+
+// What was created by an import, or would have been in a dry run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, ToSchema)]
+pub struct ImportSummary {
+    // Nothing was saved
+    pub dry_run: bool,
+    pub exercises_created: usize,
+    // Existing exercises with the same name and kind used instead of creating new ones
+    pub exercises_reused: usize,
+    pub sessions: usize,
+    pub exercise_instances: usize,
+    pub sets: usize,
+    // Sessions which already existed with the same name and start time
+    pub duplicate_sessions: usize,
+    // Exercises and sessions which already existed, and exercises repeated in the export
+    pub conflicts: Vec<SingleRouteError>,
+    // Rows which could not be imported, with their line numbers
+    pub skipped: Vec<SingleRouteError>,
+}
+
+impl ImportSummary {
+    fn add_duplicate_session(&mut self, name: &str, started: DateTime<Utc>) {
+        self.duplicate_sessions += 1;
+        self.conflicts.push(SingleRouteError {
+            msg: format!(
+                "Session '{}' started at {} already exists and was skipped.",
+                name,
+                started.to_rfc3339()
+            ),
+            field: Some("sessions".to_string()),
+        });
+    }
+}
+
+// A session parsed from another app's export
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedSession {
+    pub name: String,
+    pub description: Option<String>,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub exercises: Vec<ImportedExercise>,
+}
+
+// Consecutive sets of the same exercise in a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedExercise {
+    pub name: String,
+    pub kind: ExerciseKind,
+    pub comments: Vec<String>,
+    pub sets: Vec<ImportedSet>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedSet {
+    // Kilograms
+    pub weight: Option<f32>,
+    pub reps: Option<i32>,
+    pub rpe: Option<f32>,
+}
+
+// Columns of one export format, optional ones might not exist in every version
+struct Columns {
+    source: CsvSource,
+    started: usize,
+    finished: Option<usize>,
+    duration: Option<usize>,
+    session_name: usize,
+    description: Option<usize>,
+    exercise_name: usize,
+    notes: Option<usize>,
+    set_order: Option<usize>,
+    weight: usize,
+    weight_unit: Option<usize>,
+    reps: usize,
+    rpe: Option<usize>,
+}
+
+impl Columns {
+    fn from_header(header: &csv::StringRecord, unit: WeightUnit) -> Option<(Self, WeightUnit)> {
+        let find = |name: &str| header.iter().position(|column| column.trim() == name);
+
+        if let (Some(started), Some(session_name), Some(exercise_name), Some(weight), Some(reps)) = (
+            find("Date"),
+            find("Workout Name"),
+            find("Exercise Name"),
+            find("Weight"),
+            find("Reps"),
+        ) {
+            return Some((
+                Columns {
+                    source: CsvSource::Strong,
+                    started,
+                    finished: None,
+                    duration: find("Duration").or(find("Workout Duration")),
+                    session_name,
+                    description: find("Workout Notes"),
+                    exercise_name,
+                    notes: find("Notes"),
+                    set_order: find("Set Order"),
+                    weight,
+                    weight_unit: find("Weight Unit"),
+                    reps,
+                    rpe: find("RPE"),
+                },
+                unit,
+            ));
+        }
+
+        // Hevy has the unit in the name of the weight column
+        let (weight, unit) = match (find("weight_kg"), find("weight_lbs")) {
+            (Some(weight), _) => (weight, WeightUnit::Kg),
+            (None, Some(weight)) => (weight, WeightUnit::Lb),
+            (None, None) => return None,
+        };
+
+        Some((
+            Columns {
+                source: CsvSource::Hevy,
+                started: find("start_time")?,
+                finished: find("end_time"),
+                duration: None,
+                session_name: find("title")?,
+                description: find("description"),
+                exercise_name: find("exercise_title")?,
+                notes: find("exercise_notes"),
+                set_order: None,
+                weight,
+                weight_unit: None,
+                reps: find("reps")?,
+                rpe: find("rpe"),
+            },
+            unit,
+        ))
+    }
+}
+
+// Parse a Strong or Hevy CSV export to sessions. Rows which can't be mapped are
+// returned as errors with their line numbers, and the rest are still imported.
+// Weights are converted to kilograms from the given unit, unless the file has it.
+pub fn parse_csv(
+    input: &str,
+    unit: WeightUnit,
+) -> Result<(CsvSource, Vec<ImportedSession>, Vec<SingleRouteError>), RouteError> {
+    // Some Strong versions use semicolons
+    let first_line = input.lines().next().unwrap_or_default();
+    let delimiter = if first_line.contains(';') && !first_line.contains(',') {
+        b';'
+    } else {
+        b','
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(input.as_bytes());
+
+    let header = reader.headers().map_err(|error| {
+        RouteError::new(
+            format!("Failed to read CSV header: {}.", error),
+            None::<&str>,
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+
+    let Some((columns, unit)) = Columns::from_header(header, unit) else {
+        return Err(RouteError::new(
+            "Unrecognized CSV format, only Strong and Hevy exports are supported.",
+            None::<&str>,
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let column_names = header.clone();
+
+    let mut sessions: Vec<ImportedSession> = Vec::new();
+    // Index of each session by start time and name
+    let mut session_indexes: HashMap<(DateTime<Utc>, String), usize> = HashMap::new();
+    let mut errors: Vec<SingleRouteError> = Vec::new();
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                let line = error.position().map_or(0, |position| position.line());
+
+                errors.push(SingleRouteError {
+                    msg: format!("Line {}: invalid CSV: {}", line, error),
+                    field: None,
+                });
+
+                continue;
+            }
+        };
+
+        let line = record.position().map_or(0, |position| position.line());
+
+        let get = |index: usize| record.get(index).map(str::trim).unwrap_or_default();
+        let optional = |index: Option<usize>| index.map(get).filter(|value| !value.is_empty());
+
+        // Strong has rows for rest timers and notes which are not sets
+        if let Some(set_order) = optional(columns.set_order) {
+            if set_order.parse::<i32>().is_err() && set_order.chars().count() > 1 {
+                continue;
+            }
+        }
+
+        let mut error = |index: usize, msg: String| {
+            errors.push(SingleRouteError {
+                msg: format!("Line {}: {}", line, msg),
+                field: column_names.get(index).map(|name| name.trim().to_string()),
+            })
+        };
+
+        let Some(started) = parse_timestamp(get(columns.started)) else {
+            error(
+                columns.started,
+                format!("invalid date '{}'", get(columns.started)),
+            );
+            continue;
+        };
+
+        let session_name = get(columns.session_name);
+
+        if session_name.is_empty() {
+            error(columns.session_name, "missing workout name".to_string());
+            continue;
+        }
+
+        let (exercise_name, kind) = infer_exercise_kind(get(columns.exercise_name));
+
+        if exercise_name.is_empty() {
+            error(columns.exercise_name, "missing exercise name".to_string());
+            continue;
+        }
+
+        if exercise_name.chars().count() > 30 {
+            error(
+                columns.exercise_name,
+                format!(
+                    "exercise name '{}' is longer than 30 characters",
+                    exercise_name
+                ),
+            );
+            continue;
+        }
+
+        let row_unit = match optional(columns.weight_unit) {
+            Some(row_unit) if row_unit.starts_with("lb") => WeightUnit::Lb,
+            Some(_) => WeightUnit::Kg,
+            None => unit,
+        };
+
+        let weight = match optional(Some(columns.weight)).map(str::parse::<f32>) {
+            None => None,
+            Some(Ok(weight)) if weight.is_finite() => match row_unit.rounded_to_kilograms(weight) {
+                Ok(weight) => Some(weight),
+                Err(_) => {
+                    error(columns.weight, format!("invalid weight '{}'", weight));
+                    continue;
+                }
+            },
+            Some(_) => {
+                error(
+                    columns.weight,
+                    format!("invalid weight '{}'", get(columns.weight)),
+                );
+                continue;
+            }
+        };
+
+        // Some apps write reps as decimals
+        let reps = match optional(Some(columns.reps)).map(str::parse::<f32>) {
+            None => None,
+            Some(Ok(reps)) if reps >= 0.0 && reps.fract() == 0.0 && reps <= i32::MAX as f32 => {
+                Some(reps as i32)
+            }
+            Some(_) => {
+                error(
+                    columns.reps,
+                    format!("invalid reps '{}'", get(columns.reps)),
+                );
+                continue;
+            }
+        };
+
+        // An RPE out of the supported range is dropped rather than the whole row
+        let rpe = optional(columns.rpe)
+            .and_then(|rpe| rpe.parse::<f32>().ok())
+            .filter(|rpe| is_valid_rpe(*rpe));
+
+        let finished = optional(columns.finished)
+            .and_then(parse_timestamp)
+            .or_else(|| {
+                optional(columns.duration)
+                    .and_then(parse_duration)
+                    .map(|d| started + d)
+            })
+            // A broken end time is dropped rather than the whole row
+            .filter(|finished| *finished >= started);
+
+        let session_index = *session_indexes
+            .entry((started, session_name.to_string()))
+            .or_insert_with(|| {
+                sessions.push(ImportedSession {
+                    // Limit of the database
+                    name: session_name.chars().take(100).collect(),
+                    description: optional(columns.description).map(str::to_string),
+                    started,
+                    finished,
+                    exercises: Vec::new(),
+                });
+
+                sessions.len() - 1
+            });
+
+        let session = &mut sessions[session_index];
+
+        // A new exercise instance is started when the exercise changes
+        let exercise = match session.exercises.last_mut() {
+            Some(exercise) if exercise.name == exercise_name && exercise.kind == kind => exercise,
+            _ => {
+                session.exercises.push(ImportedExercise {
+                    name: exercise_name,
+                    kind,
+                    comments: Vec::new(),
+                    sets: Vec::new(),
+                });
+
+                session.exercises.last_mut().unwrap()
+            }
+        };
+
+        if let Some(notes) = optional(columns.notes) {
+            if !exercise.comments.iter().any(|comment| comment == notes) {
+                exercise.comments.push(notes.to_string());
+            }
+        }
+
+        exercise.sets.push(ImportedSet { weight, reps, rpe });
+    }
+
+    Ok((columns.source, sessions, errors))
+}
+
+// Formats used by Strong and Hevy, which are in local time without the offset,
+// so they are treated as UTC
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%d %b %Y, %H:%M",
+        "%d %b %Y %H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .map(|timestamp| timestamp.and_utc())
+}
+
+// Durations like "1h 5m", "45m" or "30s"
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+
+    for part in value.split_whitespace() {
+        let (amount, unit) = part.split_at(part.find(|c: char| !c.is_ascii_digit())?);
+        let amount: i64 = amount.parse().ok()?;
+
+        total += match unit {
+            "h" => Duration::hours(amount),
+            "m" | "min" => Duration::minutes(amount),
+            "s" => Duration::seconds(amount),
+            _ => return None,
+        };
+    }
+
+    Some(total)
+}
+
+// Both apps put the equipment in parentheses after the name, like "Bench Press (Barbell)".
+// Returns the name without it and the kind, which is guessed from the name if missing.
+pub fn infer_exercise_kind(name: &str) -> (String, ExerciseKind) {
+    let name = name.trim();
+
+    if let Some((base, equipment)) = name
+        .strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once('('))
+    {
+        if let Some(kind) = kind_from_keywords(equipment) {
+            return (base.trim().to_string(), kind);
+        }
+    }
+
+    let kind = kind_from_keywords(name).unwrap_or_else(|| {
+        let lowercase = name.to_lowercase();
+
+        let is_bodyweight = [
+            "pull up",
+            "pull-up",
+            "pullup",
+            "chin up",
+            "chin-up",
+            "push up",
+            "push-up",
+            "pushup",
+            "dip",
+            "plank",
+            "sit up",
+            "sit-up",
+            "crunch",
+            "burpee",
+            "muscle up",
+        ]
+        .iter()
+        .any(|keyword| lowercase.contains(keyword));
+
+        if is_bodyweight {
+            ExerciseKind::Bodyweight
+        } else {
+            ExerciseKind::Barbell
+        }
+    });
+
+    (name.to_string(), kind)
+}
+
+fn kind_from_keywords(value: &str) -> Option<ExerciseKind> {
+    let lowercase = value.to_lowercase();
+
+    // Checked in order, so "Smith Machine" is a machine and not a barbell
+    [
+        ("machine", ExerciseKind::Machine),
+        ("cable", ExerciseKind::Cable),
+        ("band", ExerciseKind::Cable),
+        ("dumbbell", ExerciseKind::Dumbbell),
+        ("kettlebell", ExerciseKind::Dumbbell),
+        ("barbell", ExerciseKind::Barbell),
+        ("ez bar", ExerciseKind::Barbell),
+        ("trap bar", ExerciseKind::Barbell),
+        ("bodyweight", ExerciseKind::Bodyweight),
+        ("assisted", ExerciseKind::Bodyweight),
+        ("weighted", ExerciseKind::Bodyweight),
+    ]
+    .into_iter()
+    .find(|(keyword, _)| lowercase.contains(keyword))
+    .map(|(_, kind)| kind)
+}
+
+// Create the parsed sessions with their exercise instances and sets in one transaction.
+// Exercises are matched by name and kind to the user's existing ones, or created.
+// Sessions with the same name and start time as an existing one are skipped, so the
+// same file can be imported again without duplicating anything.
+#[instrument(skip(sessions))]
+pub async fn import_sessions(
+    user_id: Uuid,
+    sessions: Vec<ImportedSession>,
+    pool: &PgPool,
+) -> Result<ImportSummary, RouteError> {
+    info!("Importing {} sessions", sessions.len());
+
+    let mut summary = ImportSummary::default();
+
+    let mut exercise_ids = existing_exercise_ids(user_id, pool).await?;
+    let existing_ids: HashSet<Uuid> = exercise_ids.values().copied().collect();
+
+    let mut affected_exercise_ids: HashSet<Uuid> = HashSet::new();
+
+    let mut tx = pool.begin().await?;
+
+    for session in sessions {
+        if session_exists(user_id, &session.name, session.started, &mut tx).await? {
+            summary.add_duplicate_session(&session.name, session.started);
+            continue;
+        }
+
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO sessions (user_id, name, description, started, finished, state) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        )
+        .bind(user_id)
+        .bind(&session.name)
+        .bind(&session.description)
+        .bind(session.started)
+        .bind(session.finished)
+        .bind(if session.finished.is_some() {
+            SessionState::Finished
+        } else {
+            SessionState::InProgress
+        })
+        .fetch_one(&mut *tx)
+        .await?;
+
+        summary.sessions += 1;
+
+        for (position, exercise) in session.exercises.into_iter().enumerate() {
+            let key = (exercise.name.to_lowercase(), exercise.kind);
+
+            let exercise_id = match exercise_ids.get(&key) {
+                Some(exercise_id) => *exercise_id,
+                None => {
+                    let exercise_id: Uuid = sqlx::query_scalar(
+                        "INSERT INTO exercises (user_id, name, kind) VALUES ($1, $2, $3) RETURNING id",
+                    )
+                    .bind(user_id)
+                    .bind(&exercise.name)
+                    .bind(exercise.kind)
+                    .fetch_one(&mut *tx)
+                    .await?;
+
+                    summary.exercises_created += 1;
+                    exercise_ids.insert(key, exercise_id);
+
+                    exercise_id
+                }
+            };
+
+            affected_exercise_ids.insert(exercise_id);
+
+            // Instances and sets are in the order of the file
+            let exercise_instance_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO exercise_instances (user_id, session_id, exercise_id, comments, position) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            )
+            .bind(user_id)
+            .bind(session_id)
+            .bind(exercise_id)
+            .bind(&exercise.comments)
+            .bind(position as i32)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            summary.exercise_instances += 1;
+
+            for (set_position, set) in exercise.sets.into_iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO sets (user_id, exercise_instance_id, weight, reps, rpe, completed, position) VALUES ($1, $2, $3, $4, $5, true, $6)",
+                )
+                .bind(user_id)
+                .bind(exercise_instance_id)
+                .bind(set.weight)
+                .bind(set.reps)
+                .bind(set.rpe)
+                .bind(set_position as i32)
+                .execute(&mut *tx)
+                .await?;
+
+                summary.sets += 1;
+            }
+        }
+    }
+
+    tx.commit().await?;
+
+    summary.exercises_reused = affected_exercise_ids.intersection(&existing_ids).count();
+
+    // All imported sets are completed
+    for exercise_id in affected_exercise_ids {
+        personal_record::recompute(user_id, exercise_id, pool).await?;
+    }
+
+    Ok(summary)
+}
+
+// Recreate everything in a LiftLog export for the user in one transaction. Everything
+// gets new IDs, and existing exercises with the same name and kind are used instead of
+// creating duplicates. Sessions which already exist are skipped like in CSV imports.
+// A dry run does everything but rolls the transaction back, so the summary is exact.
+#[instrument(skip(export))]
+pub async fn import_export(
+    user_id: Uuid,
+    export: UserExport,
+    dry_run: bool,
+    pool: &PgPool,
+) -> Result<ImportSummary, RouteError> {
+    info!(
+        "Importing a LiftLog export with {} sessions",
+        export.sessions.len()
+    );
+
+    validate_export(&export)?;
+
+    let mut summary = ImportSummary {
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut exercise_ids = existing_exercise_ids(user_id, pool).await?;
+
+    // IDs in the export mapped to the new or existing ones
+    let mut remapped_exercise_ids: HashMap<Uuid, Uuid> = HashMap::new();
+    // Exercises created by this import, so repeats in the export aren't reported as existing
+    let mut created_keys: HashSet<(String, ExerciseKind)> = HashSet::new();
+
+    let mut tx = pool.begin().await?;
+
+    for exercise in &export.exercises {
+        let key = (exercise.name.to_lowercase(), exercise.kind);
+
+        let exercise_id = match exercise_ids.get(&key) {
+            Some(created_id) if created_keys.contains(&key) => {
+                summary.conflicts.push(SingleRouteError {
+                    msg: format!(
+                        "Exercise '{}' is in the export more than once, its sessions will use the first one.",
+                        exercise.name
+                    ),
+                    field: Some("exercises".to_string()),
+                });
+
+                *created_id
+            }
+            Some(existing_id) => {
+                summary.exercises_reused += 1;
+                summary.conflicts.push(SingleRouteError {
+                    msg: format!(
+                        "Exercise '{}' already exists, its sessions will use the existing one.",
+                        exercise.name
+                    ),
+                    field: Some("exercises".to_string()),
+                });
+
+                *existing_id
+            }
+            None => {
+                let exercise_id: Uuid = sqlx::query_scalar(
+                    "INSERT INTO exercises (user_id, name, description, favourite, notes, kind, rest_seconds) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+                )
+                .bind(user_id)
+                .bind(&exercise.name)
+                .bind(&exercise.description)
+                .bind(exercise.favourite)
+                .bind(&exercise.notes)
+                .bind(exercise.kind)
+                .bind(exercise.rest_seconds)
+                .fetch_one(&mut *tx)
+                .await?;
+
+                summary.exercises_created += 1;
+                exercise_ids.insert(key.clone(), exercise_id);
+                created_keys.insert(key);
+
+                exercise_id
+            }
+        };
+
+        remapped_exercise_ids.insert(exercise.id, exercise_id);
+    }
+
+    // Weights are stored in kilograms
+    let unit = export.weight_unit;
+
+    for session in export.sessions {
+        if session_exists(user_id, &session.name, session.started, &mut tx).await? {
+            summary.add_duplicate_session(&session.name, session.started);
+            continue;
+        }
+
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO sessions (user_id, name, description, started, finished, state, exertion, readiness) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+        )
+        .bind(user_id)
+        .bind(&session.name)
+        .bind(&session.description)
+        .bind(session.started)
+        .bind(session.finished)
+        // Older exports don't have states, the finish time decides those
+        .bind(match (session.state, session.finished) {
+            (SessionState::Finished, None) => SessionState::InProgress,
+            (_, Some(_)) => SessionState::Finished,
+            (state, None) => state,
+        })
+        .bind(session.exertion)
+        .bind(session.readiness)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        summary.sessions += 1;
+
+        // Groups are created first, so the instances can be placed in them
+        let mut remapped_group_ids: HashMap<Uuid, Uuid> = HashMap::new();
+
+        for exercise_group in &session.exercise_groups {
+            let group_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO exercise_groups (user_id, session_id, kind, rest_seconds, created) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            )
+            .bind(user_id)
+            .bind(session_id)
+            .bind(exercise_group.kind)
+            .bind(exercise_group.rest_seconds)
+            .bind(exercise_group.created)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            remapped_group_ids.insert(exercise_group.id, group_id);
+        }
+
+        // Instances and sets are in the order of the export, older exports don't have
+        // positions but are ordered the same way
+        for (position, exercise_instance) in session.exercise_instances.into_iter().enumerate() {
+            let exercise_instance_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO exercise_instances (user_id, session_id, exercise_id, comments, rest_seconds, group_id, created, position) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+            )
+            .bind(user_id)
+            .bind(session_id)
+            .bind(remapped_exercise_ids[&exercise_instance.exercise_id])
+            .bind(&exercise_instance.comments)
+            .bind(exercise_instance.rest_seconds)
+            .bind(
+                exercise_instance
+                    .group_id
+                    .and_then(|group_id| remapped_group_ids.get(&group_id).copied()),
+            )
+            .bind(exercise_instance.created)
+            .bind(position as i32)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            summary.exercise_instances += 1;
+
+            // Parents are earlier sets of the same instance, so they are imported first
+            let mut remapped_set_ids: HashMap<Uuid, Uuid> = HashMap::new();
+
+            for (set_position, set) in exercise_instance.sets.into_iter().enumerate() {
+                let set_id: Uuid = sqlx::query_scalar(
+                    "INSERT INTO sets (user_id, exercise_instance_id, weight, reps, rpe, rir, kind, parent_set_id, completed, completed_at, created, position) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING id",
+                )
+                .bind(user_id)
+                .bind(exercise_instance_id)
+                .bind(
+                    set.weight
+                        .map(|weight| unit.rounded_to_kilograms(weight))
+                        .transpose()?,
+                )
+                .bind(set.reps)
+                .bind(set.rpe)
+                // Only one of them is kept
+                .bind(set.rir.filter(|_| set.rpe.is_none()))
+                .bind(set.kind)
+                .bind(
+                    set.parent_set_id
+                        .filter(|_| set.kind == SetKind::Drop)
+                        .and_then(|parent_set_id| remapped_set_ids.get(&parent_set_id).copied()),
+                )
+                .bind(set.completed)
+                .bind(set.completed_at.filter(|_| set.completed))
+                .bind(set.created)
+                .bind(set_position as i32)
+                .fetch_one(&mut *tx)
+                .await?;
+
+                remapped_set_ids.insert(set.id, set_id);
+
+                summary.sets += 1;
+            }
+        }
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+
+        return Ok(summary);
+    }
+
+    tx.commit().await?;
+
+    let mut affected_exercise_ids: Vec<Uuid> = remapped_exercise_ids.into_values().collect();
+    affected_exercise_ids.sort();
+    affected_exercise_ids.dedup();
+
+    for exercise_id in affected_exercise_ids {
+        personal_record::recompute(user_id, exercise_id, pool).await?;
+    }
+
+    Ok(summary)
+}
+
+// Check what the database or the API would not accept, so the document
+// is rejected as a whole with all of the problems listed
+fn validate_export(export: &UserExport) -> Result<(), RouteError> {
+    let mut error = RouteError::empty(StatusCode::BAD_REQUEST);
+    let mut invalid = false;
+
+    let mut add = |msg: String, field: String| {
+        error.add(msg, Some(field));
+        invalid = true;
+    };
+
+    if export.version > EXPORT_VERSION {
+        add(
+            format!(
+                "Export version {} is newer than the supported version {}.",
+                export.version, EXPORT_VERSION
+            ),
+            "version".to_string(),
+        );
+    }
+
+    let exercise_ids: HashSet<Uuid> = export.exercises.iter().map(|e| e.id).collect();
+
+    for (i, exercise) in export.exercises.iter().enumerate() {
+        if exercise.name.is_empty() || exercise.name.chars().count() > 30 {
+            add(
+                "Name must be between 1 and 30 characters.".to_string(),
+                format!("exercises[{}].name", i),
+            );
+        }
+
+        if exercise.rest_seconds < 0 {
+            add(
+                "Rest time can't be negative.".to_string(),
+                format!("exercises[{}].rest_seconds", i),
+            );
+        }
+    }
+
+    for (i, session) in export.sessions.iter().enumerate() {
+        if session.name.is_empty() || session.name.chars().count() > 100 {
+            add(
+                "Name must be between 1 and 100 characters.".to_string(),
+                format!("sessions[{}].name", i),
+            );
+        }
+
+        if session
+            .finished
+            .is_some_and(|finished| finished < session.started)
+        {
+            add(
+                "Session can't be finished before it was started.".to_string(),
+                format!("sessions[{}].finished", i),
+            );
+        }
+
+        for (rating, field) in [
+            (session.exertion, "exertion"),
+            (session.readiness, "readiness"),
+        ] {
+            if rating.is_some_and(|rating| !(1..=10).contains(&rating)) {
+                add(
+                    "Rating must be between 1 and 10.".to_string(),
+                    format!("sessions[{}].{}", i, field),
+                );
+            }
+        }
+
+        for (j, exercise_group) in session.exercise_groups.iter().enumerate() {
+            let members = session
+                .exercise_instances
+                .iter()
+                .filter(|instance| instance.group_id == Some(exercise_group.id))
+                .count();
+
+            if members < 2 {
+                add(
+                    "A group needs at least two exercise instances.".to_string(),
+                    format!("sessions[{}].exercise_groups[{}]", i, j),
+                );
+            }
+
+            if exercise_group.rest_seconds.is_some_and(|rest| rest < 0) {
+                add(
+                    "Rest time can't be negative.".to_string(),
+                    format!("sessions[{}].exercise_groups[{}].rest_seconds", i, j),
+                );
+            }
+        }
+
+        for (j, exercise_instance) in session.exercise_instances.iter().enumerate() {
+            if !exercise_ids.contains(&exercise_instance.exercise_id) {
+                add(
+                    "Exercise is not in the export.".to_string(),
+                    format!("sessions[{}].exercise_instances[{}].exercise_id", i, j),
+                );
+            }
+
+            if exercise_instance.rest_seconds.is_some_and(|rest| rest < 0) {
+                add(
+                    "Rest time can't be negative.".to_string(),
+                    format!("sessions[{}].exercise_instances[{}].rest_seconds", i, j),
+                );
+            }
+
+            for (k, set) in exercise_instance.sets.iter().enumerate() {
+                if set.reps.is_some_and(|reps| reps < 0) {
+                    add(
+                        "Reps can't be negative.".to_string(),
+                        format!("sessions[{}].exercise_instances[{}].sets[{}].reps", i, j, k),
+                    );
+                }
+
+                if set.rpe.is_some_and(|rpe| !is_valid_rpe(rpe)) {
+                    add(
+                        "RPE must be between 6 and 10 in steps of 0.5.".to_string(),
+                        format!("sessions[{}].exercise_instances[{}].sets[{}].rpe", i, j, k),
+                    );
+                }
+
+                if set.rir.is_some_and(|rir| rir < 0) {
+                    add(
+                        "Reps in reserve can't be negative.".to_string(),
+                        format!("sessions[{}].exercise_instances[{}].sets[{}].rir", i, j, k),
+                    );
+                }
+            }
+        }
+    }
+
+    if invalid {
+        Err(error)
+    } else {
+        Ok(())
+    }
+}
+
+// The user's exercises by lowercase name and kind
+async fn existing_exercise_ids(
+    user_id: Uuid,
+    pool: &PgPool,
+) -> Result<HashMap<(String, ExerciseKind), Uuid>, RouteError> {
+    let mut exercise_ids: HashMap<(String, ExerciseKind), Uuid> = HashMap::new();
+
+    for exercise in all_user_exercises(user_id, None, pool).await? {
+        exercise_ids
+            .entry((exercise.name.to_lowercase(), exercise.kind))
+            .or_insert(exercise.id);
+    }
+
+    Ok(exercise_ids)
+}
+
+async fn session_exists(
+    user_id: Uuid,
+    name: &str,
+    started: DateTime<Utc>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<bool, RouteError> {
+    Ok(sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM sessions WHERE user_id = $1 AND name = $2 AND started = $3",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(started)
+    .fetch_optional(&mut **tx)
+    .await?
+    .is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRONG: &str = "Date,Workout Name,Duration,Exercise Name,Set Order,Weight,Reps,Distance,Seconds,Notes,Workout Notes,RPE
+2024-01-15 10:30:00,Push,1h 5m,Bench Press (Barbell),1,100,5,0,0,Felt good,,
+2024-01-15 10:30:00,Push,1h 5m,Bench Press (Barbell),Rest Timer,,,,90,,,
+2024-01-15 10:30:00,Push,1h 5m,Bench Press (Barbell),2,100,4,0,0,,,
+2024-01-15 10:30:00,Push,1h 5m,Triceps Pushdown (Cable - Straight Bar),1,30,12,0,0,,,
+2024-01-15 10:30:00,Push,1h 5m,Pull Up,1,,10,0,0,,,
+invalid date,Push,1h 5m,Bench Press (Barbell),3,100,5,0,0,,,
+2024-01-17 18:00:00,Legs,45m,Squat (Barbell),1,120,five,0,0,,,
+2024-01-17 18:00:00,Legs,45m,Squat (Barbell),2,120,5,0,0,,,
+";
+
+    const HEVY: &str = r#""title","start_time","end_time","description","exercise_title","superset_id","exercise_notes","set_index","set_type","weight_lbs","reps","distance_miles","duration_seconds","rpe"
+"Upper","15 Jan 2024, 10:30","15 Jan 2024, 11:30","","Bicep Curl (Dumbbell)",,"",0,"normal",50,10,,,11
+"Upper","15 Jan 2024, 10:30","15 Jan 2024, 11:30","","Bicep Curl (Dumbbell)",,"",1,"normal",55,8,,,8.5
+"#;
+
+    #[test]
+    fn strong() {
+        let (source, sessions, errors) = parse_csv(STRONG, WeightUnit::Kg).unwrap();
+
+        assert_eq!(source, CsvSource::Strong);
+        assert_eq!(sessions.len(), 2);
+
+        let push = &sessions[0];
+
+        assert_eq!(push.name, "Push");
+        assert_eq!(push.finished.unwrap() - push.started, Duration::minutes(65));
+        assert_eq!(push.exercises.len(), 3);
+        assert_eq!(push.exercises[0].name, "Bench Press");
+        assert_eq!(push.exercises[0].kind, ExerciseKind::Barbell);
+        assert_eq!(push.exercises[0].comments, vec!["Felt good".to_string()]);
+        assert_eq!(push.exercises[0].sets.len(), 2);
+        assert_eq!(push.exercises[1].name, "Triceps Pushdown");
+        assert_eq!(push.exercises[1].kind, ExerciseKind::Cable);
+        assert_eq!(push.exercises[2].kind, ExerciseKind::Bodyweight);
+        assert_eq!(
+            push.exercises[2].sets[0],
+            ImportedSet {
+                weight: None,
+                reps: Some(10),
+                rpe: None,
+            }
+        );
+
+        assert_eq!(sessions[1].exercises[0].sets.len(), 1);
+
+        // Invalid date and reps, with their line numbers
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].msg.starts_with("Line 7:"));
+        assert_eq!(errors[0].field.as_deref(), Some("Date"));
+        assert!(errors[1].msg.starts_with("Line 8:"));
+        assert_eq!(errors[1].field.as_deref(), Some("Reps"));
+    }
+
+    #[test]
+    fn hevy() {
+        let (source, sessions, errors) = parse_csv(HEVY, WeightUnit::Kg).unwrap();
+
+        assert_eq!(source, CsvSource::Hevy);
+        assert!(errors.is_empty());
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(
+            sessions[0].finished.unwrap() - sessions[0].started,
+            Duration::hours(1)
+        );
+
+        let curls = &sessions[0].exercises[0];
+
+        assert_eq!(curls.name, "Bicep Curl");
+        assert_eq!(curls.kind, ExerciseKind::Dumbbell);
+        // Converted from pounds, and still the same in pounds
+        assert_eq!(
+            curls.sets[0].weight,
+            Some(WeightUnit::Lb.to_kilograms(50.0))
+        );
+        assert_eq!(
+            WeightUnit::Lb.convert_kilograms(curls.sets[0].weight.unwrap()),
+            50.0
+        );
+        assert_eq!(curls.sets[1].reps, Some(8));
+        // Unsupported RPEs are dropped
+        assert_eq!(curls.sets[0].rpe, None);
+        assert_eq!(curls.sets[1].rpe, Some(8.5));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(parse_csv("a,b,c\n1,2,3\n", WeightUnit::Kg).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool, Postgres, Transaction};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::access_token::{generate_token, hash_token};
+
+// Single use code created by an admin, needed to sign up when registration is invite only
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
+pub struct InviteCode {
+    pub id: Uuid,
+    // Only returned when the code is created
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub created: DateTime<Utc>,
+    // Admin who created it, unset if deleted
+    pub created_by: Option<Uuid>,
+    pub used: Option<DateTime<Utc>>,
+    pub used_by: Option<Uuid>,
+}
+
+impl InviteCode {
+    #[instrument]
+    pub async fn new(created_by: Uuid, pool: &PgPool) -> Result<InviteCode, RouteError> {
+        info!("Creating a new invite code");
+
+        let code = generate_token();
+
+        let mut invite_code: InviteCode = sqlx::query_as(
+            "INSERT INTO invite_codes (code_hash, created_by) VALUES ($1, $2) RETURNING *",
+        )
+        .bind(hash_token(&code))
+        .bind(created_by)
+        .fetch_one(pool)
+        .await?;
+
+        invite_code.code = Some(code);
+
+        Ok(invite_code)
+    }
+}
+
+// Invite codes of the instance, newest first
+#[instrument]
+pub async fn all_invite_codes(pool: &PgPool) -> Result<Vec<InviteCode>, RouteError> {
+    info!("Querying all invite codes");
+
+    Ok(
+        sqlx::query_as("SELECT * FROM invite_codes ORDER BY created DESC")
+            .fetch_all(pool)
+            .await?,
+    )
+}
+
+// Mark an unused code used, so it can't be used twice at the same time.
+// It's usable again if the transaction creating the user is rolled back.
+// Returns the ID of the code.
+#[instrument(skip(code, tx))]
+pub async fn claim(code: &str, tx: &mut Transaction<'_, Postgres>) -> Result<Uuid, RouteError> {
+    // Hashes are keyed, so they can be compared in the database
+    let claimed = sqlx::query_scalar!(
+        "UPDATE invite_codes SET used = NOW() WHERE code_hash = $1 AND used IS NULL RETURNING id",
+        hash_token(code)
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    claimed.ok_or_else(|| {
+        warn!("Invalid or used invite code");
+
+        RouteError::new(
+            "Invite code is invalid or already used.",
+            Some("invite_code"),
+            StatusCode::FORBIDDEN,
+        )
+    })
+}
+
+// Store who used a claimed code
+#[instrument(skip(tx))]
+pub async fn set_used_by(
+    id: Uuid,
+    user_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    sqlx::query!(
+        "UPDATE invite_codes SET used_by = $1 WHERE id = $2",
+        user_id,
+        id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
@@ -1,6 +1,22 @@
 pub mod access_token;
+pub mod account_deletion;
+pub mod admin;
+pub mod analytics;
+pub mod api_key;
+pub mod email;
 pub mod exercise;
+pub mod exercise_group;
 pub mod exercise_instance;
+pub mod export;
+pub mod import;
+pub mod invite_code;
+pub mod oidc;
+pub mod personal_record;
+pub mod position;
+pub mod refresh_token;
+pub mod rest_timer;
 pub mod session;
 pub mod set;
+pub mod template;
+pub mod totp;
 pub mod user;
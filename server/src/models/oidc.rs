@@ -0,0 +1,551 @@
+use axum::http::{header, StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, PgPool};
+use tracing::{error, info, instrument, warn};
+use url::Url;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    api::response::RouteError,
+    http_client,
+    settings::{OidcProvider, Registration},
+};
+
+use super::{
+    access_token::generate_token,
+    set::WeightUnit,
+    user::{User, UserRole},
+};
+
+// How long the user has to log in at the provider
+const LOGIN_STATE_VALIDITY_MINUTES: i64 = 10;
+
+// Where to send the user to log in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct OidcAuthorization {
+    pub authorization_url: String,
+    // Returned by the provider with the code, has to be given when finishing
+    pub state: String,
+}
+
+// External identity linked to an user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct OidcIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub user_id: Uuid,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Audience can be a single client or many
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+// Start the authorization code flow with PKCE
+#[instrument(skip(provider))]
+pub async fn start_login(
+    provider: &OidcProvider,
+    pool: &PgPool,
+) -> Result<OidcAuthorization, RouteError> {
+    info!("Starting an OIDC login with {}", provider.name);
+
+    let state = generate_token();
+    let nonce = generate_token();
+    let code_verifier = generate_token();
+
+    sqlx::query!(
+        "INSERT INTO oidc_login_states (state, provider, code_verifier, nonce, expires) VALUES ($1, $2, $3, $4, $5)",
+        state,
+        provider.name,
+        code_verifier,
+        nonce,
+        Utc::now() + Duration::minutes(LOGIN_STATE_VALIDITY_MINUTES)
+    )
+    .execute(pool)
+    .await?;
+
+    let mut authorization_url = Url::parse(&provider.authorization_endpoint).map_err(|error| {
+        error!(
+            "Invalid authorization endpoint for {}: {}",
+            provider.name, error
+        );
+
+        provider_error()
+    })?;
+
+    authorization_url
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &provider.redirect_uri)
+        .append_pair("scope", &provider.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge(&code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(OidcAuthorization {
+        authorization_url: authorization_url.to_string(),
+        state,
+    })
+}
+
+// Delete logins which were never finished, run in the background
+#[instrument(skip(pool))]
+pub async fn purge_expired_states(pool: &PgPool) -> Result<u64, RouteError> {
+    let purged = sqlx::query!("DELETE FROM oidc_login_states WHERE expires <= NOW()")
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if purged > 0 {
+        info!("Purged {} expired OIDC login states", purged);
+    }
+
+    Ok(purged)
+}
+
+// Finish the flow and return the user linked to the identity.
+// A new user is created if the provider and the registration setting allow signing up.
+#[instrument(skip(provider, code, state, invite_code))]
+pub async fn finish_login(
+    provider: &OidcProvider,
+    code: &str,
+    state: &str,
+    registration: Registration,
+    invite_code: Option<String>,
+    pool: &PgPool,
+) -> Result<User, RouteError> {
+    let claims = exchange_code(provider, code, state, pool).await?;
+
+    if let Some(user) = linked_user(provider, &claims.sub, pool).await? {
+        user.require_enabled()?;
+
+        return Ok(user);
+    }
+
+    if !provider.allow_signup {
+        info!("Unlinked identity tried to log in with {}", provider.name);
+
+        return Err(RouteError::new(
+            "No user is linked to the identity, log in and link it first.",
+            None::<&str>,
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    match signup(provider, &claims, registration, invite_code, pool).await {
+        Ok(user) => Ok(user),
+        // Another login with the same identity created its user first
+        Err(error) => match linked_user(provider, &claims.sub, pool).await? {
+            Some(user) => {
+                user.require_enabled()?;
+
+                Ok(user)
+            }
+            None => Err(error),
+        },
+    }
+}
+
+// Create an user and link the identity to it, both or neither
+async fn signup(
+    provider: &OidcProvider,
+    claims: &IdTokenClaims,
+    registration: Registration,
+    invite_code: Option<String>,
+    pool: &PgPool,
+) -> Result<User, RouteError> {
+    info!("Creating a new user from an identity of {}", provider.name);
+
+    let username = available_username(claims, pool).await?;
+
+    let mut tx = pool.begin().await?;
+
+    // Nobody knows the password, it can be changed later to log in without the provider
+    let user = User::register_in(
+        username,
+        generate_token(),
+        registration,
+        invite_code,
+        &mut tx,
+    )
+    .await?;
+
+    link(provider, &claims.sub, user.id, &mut *tx).await?;
+
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+async fn linked_user(
+    provider: &OidcProvider,
+    subject: &str,
+    pool: &PgPool,
+) -> Result<Option<User>, RouteError> {
+    Ok(sqlx::query_as!(
+        User,
+        r#"
+        SELECT users.id, users.created, users.changed, users.username, users.password_hash,
+            users.role AS "role: UserRole", users.disabled, users.email, users.email_verified,
+            users.weight_unit AS "weight_unit: WeightUnit"
+        FROM users
+        JOIN oidc_identities ON oidc_identities.user_id = users.id
+        WHERE oidc_identities.provider = $1 AND oidc_identities.subject = $2
+        "#,
+        provider.name,
+        subject
+    )
+    .fetch_optional(pool)
+    .await?)
+}
+
+// Finish the flow by linking the identity to an user who is already logged in
+#[instrument(skip(provider, code, state))]
+pub async fn finish_link(
+    provider: &OidcProvider,
+    code: &str,
+    state: &str,
+    user_id: Uuid,
+    pool: &PgPool,
+) -> Result<OidcIdentity, RouteError> {
+    let claims = exchange_code(provider, code, state, pool).await?;
+
+    link(provider, &claims.sub, user_id, pool).await
+}
+
+async fn link(
+    provider: &OidcProvider,
+    subject: &str,
+    user_id: Uuid,
+    executor: impl PgExecutor<'_>,
+) -> Result<OidcIdentity, RouteError> {
+    sqlx::query_as!(
+        OidcIdentity,
+        r#"
+        INSERT INTO oidc_identities (provider, subject, user_id) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        RETURNING *
+        "#,
+        provider.name,
+        subject,
+        user_id
+    )
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(|| {
+        RouteError::new(
+            "The identity is already linked to an user.",
+            None::<&str>,
+            StatusCode::CONFLICT,
+        )
+    })
+}
+
+// Consume the state, exchange the code and validate the ID token.
+// The token comes straight from the provider's token endpoint, so the
+// claims are validated without checking its signature (OIDC Core 3.1.3.7).
+async fn exchange_code(
+    provider: &OidcProvider,
+    code: &str,
+    state: &str,
+    pool: &PgPool,
+) -> Result<IdTokenClaims, RouteError> {
+    let login_state = sqlx::query!(
+        "DELETE FROM oidc_login_states WHERE state = $1 AND provider = $2 AND expires > NOW() RETURNING code_verifier, nonce",
+        state,
+        provider.name
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| {
+        RouteError::new(
+            "Login has expired or was already finished, start again.",
+            Some("state"),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &provider.redirect_uri),
+        ("client_id", &provider.client_id),
+        ("code_verifier", &login_state.code_verifier),
+    ];
+
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret));
+    }
+
+    let response = http_client::client()
+        .post(&provider.token_endpoint)
+        .header(header::ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await
+        .map_err(|error| {
+            error!("Token request to {} failed: {}", provider.name, error);
+
+            provider_error()
+        })?;
+
+    if response.status() != reqwest::StatusCode::OK {
+        warn!(
+            "Token endpoint of {} returned {}",
+            provider.name,
+            response.status()
+        );
+
+        return Err(RouteError::new(
+            "Identity provider rejected the code.",
+            Some("code"),
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    let token_response: TokenResponse = response.json().await.map_err(|error| {
+        error!("Invalid token response from {}: {}", provider.name, error);
+
+        provider_error()
+    })?;
+
+    let claims = decode_claims(&token_response.id_token).ok_or_else(|| {
+        error!("Invalid ID token from {}", provider.name);
+
+        provider_error()
+    })?;
+
+    validate_claims(
+        &claims,
+        provider,
+        &login_state.nonce,
+        Utc::now().timestamp(),
+    )?;
+
+    Ok(claims)
+}
+
+fn decode_claims(id_token: &str) -> Option<IdTokenClaims> {
+    let payload = id_token.split('.').nth(1)?;
+
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+fn validate_claims(
+    claims: &IdTokenClaims,
+    provider: &OidcProvider,
+    nonce: &str,
+    now: i64,
+) -> Result<(), RouteError> {
+    let audience_matches = match &claims.aud {
+        Audience::Single(audience) => *audience == provider.client_id,
+        Audience::Many(audiences) => audiences.contains(&provider.client_id),
+    };
+
+    if claims.iss != provider.issuer
+        || !audience_matches
+        || claims.exp <= now
+        || claims.nonce.as_deref() != Some(nonce)
+    {
+        warn!("ID token from {} failed validation", provider.name);
+
+        return Err(RouteError::new(
+            "Invalid ID token from the identity provider.",
+            None::<&str>,
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    Ok(())
+}
+
+// S256 challenge sent to the provider, which then requires the verifier
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+// Username from the identity, with a random suffix if it's taken
+async fn available_username(claims: &IdTokenClaims, pool: &PgPool) -> Result<String, RouteError> {
+    let wanted = claims
+        .preferred_username
+        .as_deref()
+        .or(claims
+            .email
+            .as_deref()
+            .and_then(|email| email.split('@').next()))
+        .unwrap_or_default();
+
+    let mut base: String = wanted
+        .chars()
+        .filter(|character| {
+            character.is_ascii_alphanumeric() || *character == '_' || *character == '-'
+        })
+        .take(20)
+        .collect();
+
+    if base.is_empty() {
+        base = "user".to_string();
+    }
+
+    let mut username = base.clone();
+
+    // Each attempt is very likely to be free
+    for _ in 0..10 {
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE username = $1) AS "taken!""#,
+            username
+        )
+        .fetch_one(pool)
+        .await?;
+
+        if !taken {
+            return Ok(username);
+        }
+
+        username = format!(
+            "{}-{:04}",
+            base.chars().take(15).collect::<String>(),
+            rand::random::<u16>() % 10000
+        );
+    }
+
+    Err(RouteError::new(
+        "Failed to find a free username.",
+        None::<&str>,
+        StatusCode::CONFLICT,
+    ))
+}
+
+fn provider_error() -> RouteError {
+    RouteError::new(
+        "Identity provider could not be reached.",
+        None::<&str>,
+        StatusCode::BAD_GATEWAY,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_provider() -> OidcProvider {
+        OidcProvider {
+            name: "test".to_string(),
+            issuer: "https://id.example.com".to_string(),
+            client_id: "liftlog".to_string(),
+            client_secret: None,
+            authorization_endpoint: "https://id.example.com/authorize".to_string(),
+            token_endpoint: "https://id.example.com/token".to_string(),
+            redirect_uri: "https://liftlog.example.com/callback".to_string(),
+            scopes: "openid".to_string(),
+            allow_signup: false,
+        }
+    }
+
+    fn claims(aud: Audience, exp: i64, nonce: Option<&str>) -> IdTokenClaims {
+        IdTokenClaims {
+            iss: "https://id.example.com".to_string(),
+            sub: "subject".to_string(),
+            aud,
+            exp,
+            nonce: nonce.map(str::to_string),
+            preferred_username: None,
+            email: None,
+        }
+    }
+
+    #[test]
+    fn token_endpoint_uses_tls() {
+        let mut provider = test_provider();
+
+        assert!(provider.check_token_endpoint().is_ok());
+
+        provider.token_endpoint = "http://id.example.com/token".to_string();
+        assert!(provider.check_token_endpoint().is_err());
+
+        // Allowed for providers running on the same machine
+        provider.token_endpoint = "http://127.0.0.1:8080/token".to_string();
+        assert!(provider.check_token_endpoint().is_ok());
+        provider.token_endpoint = "http://localhost/token".to_string();
+        assert!(provider.check_token_endpoint().is_ok());
+    }
+
+    #[sqlx::test]
+    async fn purge_expired(pool: PgPool) {
+        start_login(&test_provider(), &pool).await.unwrap();
+
+        sqlx::query!(
+            "INSERT INTO oidc_login_states (state, provider, code_verifier, nonce, expires) VALUES ('old', 'test', '', '', NOW())"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(purge_expired_states(&pool).await.unwrap(), 1);
+        assert_eq!(purge_expired_states(&pool).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn pkce_challenge() {
+        // From RFC 7636 appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn claim_validation() {
+        let provider = test_provider();
+        let now = 1700000000;
+
+        let valid = claims(Audience::Single("liftlog".into()), now + 60, Some("nonce"));
+        assert!(validate_claims(&valid, &provider, "nonce", now).is_ok());
+
+        let many = claims(
+            Audience::Many(vec!["other".into(), "liftlog".into()]),
+            now + 60,
+            Some("nonce"),
+        );
+        assert!(validate_claims(&many, &provider, "nonce", now).is_ok());
+
+        let expired = claims(Audience::Single("liftlog".into()), now, Some("nonce"));
+        assert!(validate_claims(&expired, &provider, "nonce", now).is_err());
+
+        let other_audience = claims(Audience::Single("other".into()), now + 60, Some("nonce"));
+        assert!(validate_claims(&other_audience, &provider, "nonce", now).is_err());
+
+        let wrong_nonce = claims(Audience::Single("liftlog".into()), now + 60, None);
+        assert!(validate_claims(&wrong_nonce, &provider, "nonce", now).is_err());
+
+        let mut other_issuer = claims(Audience::Single("liftlog".into()), now + 60, Some("nonce"));
+        other_issuer.iss = "https://evil.example.com".into();
+        assert!(validate_claims(&other_issuer, &provider, "nonce", now).is_err());
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgExecutor, PgPool};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::{
+    analytics::OneRepMaxFormula,
+    set::{self, ConvertWeights, SetKind, WeightUnit},
+};
+
+// Warm-up sets aren't records, unless other kinds are asked for
+pub const EXCLUDED_SET_KINDS: [SetKind; 1] = [SetKind::WarmUp];
+
+// One personal best for an exercise. Records are derived from completed sets,
+// and every time a previous best is beaten a new one is stored, so the records
+// of an exercise are also its PR history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
+pub struct PersonalRecord {
+    // Primary key, changes when records are recomputed
+    pub id: Uuid,
+    // These are user specific
+    pub user_id: Uuid,
+    // The exercise the record is for
+    pub exercise_id: Uuid,
+    pub kind: RecordKind,
+    // The value being compared, depends on the kind:
+    // weight for weight, 1RM and volume (kilograms when stored), reps for most reps
+    pub value: f32,
+    // Weight of the set, for most reps this is the weight the reps were done with
+    pub weight: Option<f32>,
+    // Reps of the set, not set for session volume
+    pub reps: Option<i32>,
+    // The set the record was achieved in, not set for session volume
+    pub set_id: Option<Uuid>,
+    // The session the record was achieved in
+    pub session_id: Uuid,
+    // When the session was started
+    pub achieved: DateTime<Utc>,
+}
+
+impl ConvertWeights for PersonalRecord {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        self.value = match self.kind {
+            RecordKind::HeaviestWeight => unit.convert_kilograms(self.value),
+            RecordKind::EstimatedOneRepMax | RecordKind::SessionVolume => {
+                unit.convert_kilograms_exact(self.value)
+            }
+            RecordKind::MostReps => self.value,
+        };
+
+        self.weight = self.weight.map(|weight| unit.convert_kilograms(weight));
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "record_kind", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    // Heaviest weight in a single completed set
+    HeaviestWeight,
+    // Most reps at a specific weight
+    MostReps,
+    // Best estimated one-rep-max (Epley formula) from a single set
+    EstimatedOneRepMax,
+    // Most weight * reps in a single session
+    SessionVolume,
+}
+
+// Current records of an exercise and the history of how they were reached
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ExerciseRecords {
+    pub exercise_id: Uuid,
+    pub heaviest_weight: Option<PersonalRecord>,
+    pub estimated_one_rep_max: Option<PersonalRecord>,
+    pub session_volume: Option<PersonalRecord>,
+    // Best reps for each weight used, ordered by weight
+    pub most_reps: Vec<PersonalRecord>,
+    // All records in the order they were achieved
+    pub history: Vec<PersonalRecord>,
+}
+
+impl ConvertWeights for ExerciseRecords {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        for record in [
+            &mut self.heaviest_weight,
+            &mut self.estimated_one_rep_max,
+            &mut self.session_volume,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            record.convert_weights(unit);
+        }
+
+        self.most_reps.convert_weights(unit);
+        self.history.convert_weights(unit);
+    }
+}
+
+// A completed set with the details needed for computing records
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct CompletedSet {
+    pub set_id: Uuid,
+    pub session_id: Uuid,
+    pub started: DateTime<Utc>,
+    pub weight: f32,
+    pub reps: i32,
+    // From the RPE or RIR of the set, only queried for analytics
+    #[sqlx(default)]
+    pub reps_in_reserve: Option<f32>,
+}
+
+impl ExerciseRecords {
+    // Get the stored records of an exercise
+    #[instrument]
+    pub async fn from_exercise_id(
+        user_id: Uuid,
+        exercise_id: Uuid,
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Querying personal records of an exercise");
+
+        let history: Vec<PersonalRecord> = sqlx::query_as(
+            "SELECT * FROM personal_records WHERE user_id = $1 AND exercise_id = $2 ORDER BY achieved, kind, value",
+        )
+        .bind(user_id)
+        .bind(exercise_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(Self::from_history(exercise_id, history))
+    }
+
+    // Records counting only the given kinds of sets. The stored records are used
+    // for the default kinds, others are computed from the sets without storing them.
+    #[instrument]
+    pub async fn from_set_kinds(
+        user_id: Uuid,
+        exercise_id: Uuid,
+        set_kinds: &[SetKind],
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        let stored_kinds = set::included_kinds(None, None, &EXCLUDED_SET_KINDS);
+
+        if stored_kinds.iter().all(|kind| set_kinds.contains(kind))
+            && set_kinds.iter().all(|kind| stored_kinds.contains(kind))
+        {
+            return Self::from_exercise_id(user_id, exercise_id, pool).await;
+        }
+
+        info!("Computing personal records of an exercise for other set kinds");
+
+        let sets = completed_sets(user_id, exercise_id, set_kinds, pool).await?;
+
+        Ok(Self::from_history(
+            exercise_id,
+            compute_records(user_id, exercise_id, &sets),
+        ))
+    }
+
+    // The latest record of each kind is the current one
+    pub fn from_history(exercise_id: Uuid, history: Vec<PersonalRecord>) -> Self {
+        let latest = |kind: RecordKind| history.iter().rev().find(|r| r.kind == kind).cloned();
+
+        let mut most_reps_by_weight: HashMap<u32, PersonalRecord> = HashMap::new();
+
+        for record in history.iter().filter(|r| r.kind == RecordKind::MostReps) {
+            if let Some(weight) = record.weight {
+                most_reps_by_weight.insert(weight.to_bits(), record.clone());
+            }
+        }
+
+        let mut most_reps: Vec<PersonalRecord> = most_reps_by_weight.into_values().collect();
+        most_reps.sort_by(|a, b| a.weight.partial_cmp(&b.weight).unwrap());
+
+        ExerciseRecords {
+            exercise_id,
+            heaviest_weight: latest(RecordKind::HeaviestWeight),
+            estimated_one_rep_max: latest(RecordKind::EstimatedOneRepMax),
+            session_volume: latest(RecordKind::SessionVolume),
+            most_reps,
+            history,
+        }
+    }
+}
+
+// Estimated one-rep-max with the Epley formula, a single rep is the weight itself
+pub fn estimate_one_rep_max(weight: f32, reps: i32) -> Option<f32> {
+    OneRepMaxFormula::Epley.estimate(weight, reps)
+}
+
+// Goes through the completed sets in the order they were done and returns
+// every record which beat the previous best of the same kind.
+// Sets without any reps are ignored, and negative (assisted) weights only
+// count towards the heaviest weight and most reps.
+pub fn compute_records(
+    user_id: Uuid,
+    exercise_id: Uuid,
+    sets: &[CompletedSet],
+) -> Vec<PersonalRecord> {
+    let mut records = Vec::new();
+
+    let record =
+        |kind: RecordKind, value: f32, set: &CompletedSet, single_set: bool| PersonalRecord {
+            id: Uuid::new_v4(),
+            user_id,
+            exercise_id,
+            kind,
+            value,
+            weight: single_set.then_some(set.weight),
+            reps: single_set.then_some(set.reps),
+            set_id: single_set.then_some(set.set_id),
+            session_id: set.session_id,
+            achieved: set.started,
+        };
+
+    let mut heaviest: Option<f32> = None;
+    let mut best_one_rep_max: Option<f32> = None;
+    let mut best_volume: Option<f32> = None;
+    let mut most_reps: HashMap<u32, i32> = HashMap::new();
+
+    let mut index = 0;
+
+    // Sets are grouped by session for the volume
+    while index < sets.len() {
+        let session_id = sets[index].session_id;
+        let mut session_volume = 0.0;
+        let first_of_session = index;
+
+        while index < sets.len() && sets[index].session_id == session_id {
+            let set = &sets[index];
+            index += 1;
+
+            if set.reps < 1 {
+                continue;
+            }
+
+            if heaviest.is_none_or(|best| set.weight > best) {
+                heaviest = Some(set.weight);
+                records.push(record(RecordKind::HeaviestWeight, set.weight, set, true));
+            }
+
+            let best_reps = most_reps.get(&set.weight.to_bits());
+
+            if best_reps.is_none_or(|best| set.reps > *best) {
+                most_reps.insert(set.weight.to_bits(), set.reps);
+                records.push(record(RecordKind::MostReps, set.reps as f32, set, true));
+            }
+
+            if let Some(one_rep_max) = estimate_one_rep_max(set.weight, set.reps) {
+                if best_one_rep_max.is_none_or(|best| one_rep_max > best) {
+                    best_one_rep_max = Some(one_rep_max);
+                    records.push(record(
+                        RecordKind::EstimatedOneRepMax,
+                        one_rep_max,
+                        set,
+                        true,
+                    ));
+                }
+            }
+
+            if set.weight > 0.0 {
+                session_volume += set.weight * set.reps as f32;
+            }
+        }
+
+        if session_volume > 0.0 && best_volume.is_none_or(|best| session_volume > best) {
+            best_volume = Some(session_volume);
+            records.push(record(
+                RecordKind::SessionVolume,
+                session_volume,
+                &sets[first_of_session],
+                false,
+            ));
+        }
+    }
+
+    records
+}
+
+// Completed sets of the given kinds in the order they were done
+async fn completed_sets(
+    user_id: Uuid,
+    exercise_id: Uuid,
+    set_kinds: &[SetKind],
+    executor: impl PgExecutor<'_>,
+) -> Result<Vec<CompletedSet>, RouteError> {
+    Ok(sqlx::query_as(
+        r#"
+        SELECT sets.id AS set_id, sessions.id AS session_id, sessions.started, sets.weight, sets.reps
+        FROM sets
+        JOIN exercise_instances ON exercise_instances.id = sets.exercise_instance_id
+        JOIN sessions ON sessions.id = exercise_instances.session_id
+        WHERE sets.user_id = $1 AND exercise_instances.exercise_id = $2 AND sets.completed
+            AND sets.weight IS NOT NULL AND sets.reps IS NOT NULL AND sets.kind = ANY($3)
+        ORDER BY sessions.started, sessions.id, exercise_instances.position, sets.position
+        "#,
+    )
+    .bind(user_id)
+    .bind(exercise_id)
+    .bind(set_kinds)
+    .fetch_all(executor)
+    .await?)
+}
+
+// Recompute and store all records of an exercise from its completed sets.
+// Called whenever a completed set is changed in any way, so the history stays
+// correct when sets are edited or deleted and not only when new ones are completed.
+// Only the default kinds of sets are stored.
+#[instrument]
+pub async fn recompute(user_id: Uuid, exercise_id: Uuid, pool: &PgPool) -> Result<(), RouteError> {
+    info!("Recomputing personal records of an exercise");
+
+    let mut tx = pool.begin().await?;
+
+    // Lock the exercise so concurrent recomputes for it don't interleave
+    sqlx::query("SELECT id FROM exercises WHERE id = $1 AND user_id = $2 FOR UPDATE")
+        .bind(exercise_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let sets = completed_sets(
+        user_id,
+        exercise_id,
+        &set::included_kinds(None, None, &EXCLUDED_SET_KINDS),
+        &mut *tx,
+    )
+    .await?;
+
+    sqlx::query("DELETE FROM personal_records WHERE user_id = $1 AND exercise_id = $2")
+        .bind(user_id)
+        .bind(exercise_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for record in compute_records(user_id, exercise_id, &sets) {
+        sqlx::query(
+            "INSERT INTO personal_records (id, user_id, exercise_id, kind, value, weight, reps, set_id, session_id, achieved) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .bind(record.id)
+        .bind(record.user_id)
+        .bind(record.exercise_id)
+        .bind(record.kind)
+        .bind(record.value)
+        .bind(record.weight)
+        .bind(record.reps)
+        .bind(record.set_id)
+        .bind(record.session_id)
+        .bind(record.achieved)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use crate::{
+        models::{
+            exercise_instance::ExerciseInstance,
+            session::Session,
+            set::{Set, SetKind},
+        },
+        test_utils::api::create_test_scenario,
+    };
+
+    use super::*;
+
+    fn completed_set(
+        session_id: Uuid,
+        started: DateTime<Utc>,
+        weight: f32,
+        reps: i32,
+    ) -> CompletedSet {
+        CompletedSet {
+            set_id: Uuid::new_v4(),
+            session_id,
+            started,
+            weight,
+            reps,
+            reps_in_reserve: None,
+        }
+    }
+
+    #[test]
+    fn one_rep_max() {
+        assert_eq!(estimate_one_rep_max(100.0, 1), Some(100.0));
+        assert_eq!(estimate_one_rep_max(100.0, 0), None);
+        assert_eq!(estimate_one_rep_max(-10.0, 5), None);
+        assert_eq!(estimate_one_rep_max(90.0, 30), Some(180.0));
+    }
+
+    #[test]
+    fn records_are_only_added_when_beaten() {
+        let first_session = Uuid::new_v4();
+        let second_session = Uuid::new_v4();
+        let now = Utc::now();
+
+        let sets = vec![
+            completed_set(first_session, now, 100.0, 5),
+            completed_set(first_session, now, 100.0, 3),
+            completed_set(second_session, now + Duration::days(1), 90.0, 10),
+            completed_set(second_session, now + Duration::days(1), 105.0, 1),
+        ];
+
+        let records = compute_records(Uuid::new_v4(), Uuid::new_v4(), &sets);
+        let current = ExerciseRecords::from_history(Uuid::new_v4(), records.clone());
+
+        let heaviest: Vec<&PersonalRecord> = records
+            .iter()
+            .filter(|r| r.kind == RecordKind::HeaviestWeight)
+            .collect();
+
+        // 100kg and then 105kg
+        assert_eq!(heaviest.len(), 2);
+        assert_eq!(current.heaviest_weight.unwrap().value, 105.0);
+
+        // 90kg for 10 (120kg) beats 100kg for 5 (~117kg)
+        assert_eq!(current.estimated_one_rep_max.unwrap().weight, Some(90.0));
+
+        // 1000 + 105 vs 800
+        assert_eq!(current.session_volume.unwrap().value, 1005.0);
+
+        // One for each weight
+        assert_eq!(current.most_reps.len(), 3);
+        assert_eq!(current.most_reps[1].value, 5.0);
+    }
+
+    #[test]
+    fn zero_rep_sets_are_ignored() {
+        let sets = vec![completed_set(Uuid::new_v4(), Utc::now(), 200.0, 0)];
+
+        assert!(compute_records(Uuid::new_v4(), Uuid::new_v4(), &sets).is_empty());
+    }
+
+    #[sqlx::test]
+    async fn recomputed_on_session_delete(pool: PgPool) {
+        let (_, user, _, exercise, session, exercise_instance, mut set) =
+            create_test_scenario(&pool).await;
+
+        set.set_weight(Some(100.0), &pool).await.unwrap();
+        set.set_reps(Some(3), &pool).await.unwrap();
+        set.set_complete(&pool).await.unwrap();
+
+        // A lighter set in another session
+        let another_session = Session::new(user.id, "Another", None::<&str>, &pool)
+            .await
+            .unwrap();
+        let another_instance =
+            ExerciseInstance::new(user.id, another_session.id, exercise.id, None, &pool)
+                .await
+                .unwrap();
+        let mut another_set = Set::new(user.id, another_instance.id, None, &pool)
+            .await
+            .unwrap();
+
+        another_set.set_weight(Some(80.0), &pool).await.unwrap();
+        another_set.set_reps(Some(3), &pool).await.unwrap();
+        another_set.set_complete(&pool).await.unwrap();
+
+        let records = ExerciseRecords::from_exercise_id(user.id, exercise.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(records.heaviest_weight.unwrap().set_id, Some(set.id));
+
+        // Without the first session the lighter set is the record
+        Session::from_id(user.id, session.id, &pool)
+            .await
+            .unwrap()
+            .delete(&pool)
+            .await
+            .unwrap();
+
+        let records = ExerciseRecords::from_exercise_id(user.id, exercise.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            records.heaviest_weight.unwrap().set_id,
+            Some(another_set.id)
+        );
+        assert!(records
+            .history
+            .iter()
+            .all(|record| record.session_id != exercise_instance.session_id));
+    }
+
+    #[sqlx::test]
+    async fn warm_ups_are_not_records(pool: PgPool) {
+        let (_, user, _, exercise, _, _, mut set) = create_test_scenario(&pool).await;
+
+        set.set_weight(Some(60.0), &pool).await.unwrap();
+        set.set_reps(Some(5), &pool).await.unwrap();
+        set.set_complete(&pool).await.unwrap();
+
+        let records = ExerciseRecords::from_exercise_id(user.id, exercise.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(records.heaviest_weight.unwrap().set_id, Some(set.id));
+
+        // Changing the kind of a completed set recomputes the records
+        set.set_kind(SetKind::WarmUp, &pool).await.unwrap();
+
+        let records = ExerciseRecords::from_exercise_id(user.id, exercise.id, &pool)
+            .await
+            .unwrap();
+
+        assert!(records.heaviest_weight.is_none());
+        assert!(records.history.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn records_of_other_set_kinds(pool: PgPool) {
+        let (_, user, _, exercise, _, _, mut set) = create_test_scenario(&pool).await;
+
+        set.set_weight(Some(60.0), &pool).await.unwrap();
+        set.set_reps(Some(5), &pool).await.unwrap();
+        set.set_kind(SetKind::WarmUp, &pool).await.unwrap();
+        set.set_complete(&pool).await.unwrap();
+
+        let records = ExerciseRecords::from_set_kinds(
+            user.id,
+            exercise.id,
+            &set::included_kinds(None, None, &EXCLUDED_SET_KINDS),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert!(records.heaviest_weight.is_none());
+
+        let records = ExerciseRecords::from_set_kinds(user.id, exercise.id, &SetKind::ALL, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(records.heaviest_weight.unwrap().set_id, Some(set.id));
+    }
+}
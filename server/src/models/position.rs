@@ -0,0 +1,236 @@
+use axum::http::StatusCode;
+use sqlx::{Postgres, Transaction};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::session;
+
+// Exercise instances are ordered in their session and sets in their exercise instance by
+// a position column going from 0 without gaps. Changes lock the parent row first, so
+// concurrent changes to the same session or instance are done one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ordered {
+    ExerciseInstances,
+    Sets,
+}
+
+impl Ordered {
+    fn table(self) -> &'static str {
+        match self {
+            Ordered::ExerciseInstances => "exercise_instances",
+            Ordered::Sets => "sets",
+        }
+    }
+
+    fn parent_column(self) -> &'static str {
+        match self {
+            Ordered::ExerciseInstances => "session_id",
+            Ordered::Sets => "exercise_instance_id",
+        }
+    }
+
+    fn parent_name(self) -> &'static str {
+        match self {
+            Ordered::ExerciseInstances => "session",
+            Ordered::Sets => "exercise instance",
+        }
+    }
+}
+
+// Lock the parent for the rest of the transaction and count its rows.
+// Sessions are locked the same way as when checking that they aren't finished.
+async fn lock(
+    ordered: Ordered,
+    parent_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<i32, RouteError> {
+    match ordered {
+        Ordered::ExerciseInstances => {
+            session::lock_state(parent_id, tx).await?;
+        }
+        Ordered::Sets => {
+            sqlx::query("SELECT id FROM exercise_instances WHERE id = $1 FOR UPDATE")
+                .bind(parent_id)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+
+    let count: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM {} WHERE {} = $1",
+        ordered.table(),
+        ordered.parent_column()
+    ))
+    .bind(parent_id)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    Ok(count as i32)
+}
+
+fn invalid_position(last: i32) -> RouteError {
+    RouteError::new(
+        format!("Position must be between 0 and {}.", last),
+        Some("position"),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+// Make room for a new row at the position by moving the later ones back,
+// the position is after the last row if not given
+#[instrument(skip(tx))]
+pub async fn make_room(
+    ordered: Ordered,
+    parent_id: Uuid,
+    position: Option<i32>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<i32, RouteError> {
+    let count = lock(ordered, parent_id, tx).await?;
+    let position = position.unwrap_or(count);
+
+    if !(0..=count).contains(&position) {
+        return Err(invalid_position(count));
+    }
+
+    sqlx::query(&format!(
+        "UPDATE {} SET position = position + 1 WHERE {} = $1 AND position >= $2",
+        ordered.table(),
+        ordered.parent_column()
+    ))
+    .bind(parent_id)
+    .bind(position)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(position)
+}
+
+// Delete a row and move the later ones forward to fill its place
+#[instrument(skip(tx))]
+pub async fn delete(
+    ordered: Ordered,
+    id: Uuid,
+    parent_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    lock(ordered, parent_id, tx).await?;
+
+    let position: i32 = sqlx::query_scalar(&format!(
+        "DELETE FROM {} WHERE id = $1 RETURNING position",
+        ordered.table()
+    ))
+    .bind(id)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    sqlx::query(&format!(
+        "UPDATE {} SET position = position - 1 WHERE {} = $1 AND position > $2",
+        ordered.table(),
+        ordered.parent_column()
+    ))
+    .bind(parent_id)
+    .bind(position)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+// Move a row to the position, the ones in between move by one to make room.
+// Returns the new position.
+#[instrument(skip(tx))]
+pub async fn move_to(
+    ordered: Ordered,
+    id: Uuid,
+    parent_id: Uuid,
+    position: i32,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<i32, RouteError> {
+    let count = lock(ordered, parent_id, tx).await?;
+
+    if !(0..count).contains(&position) {
+        return Err(invalid_position(count - 1));
+    }
+
+    sqlx::query(&format!(
+        r#"
+        UPDATE {table} SET position = CASE
+            WHEN {table}.id = $1 THEN $3
+            WHEN $3 < moved.position THEN {table}.position + 1
+            ELSE {table}.position - 1
+        END
+        FROM (SELECT position FROM {table} WHERE id = $1) AS moved
+        WHERE {table}.{parent_column} = $2
+            AND {table}.position BETWEEN LEAST(moved.position, $3) AND GREATEST(moved.position, $3)
+        "#,
+        table = ordered.table(),
+        parent_column = ordered.parent_column()
+    ))
+    .bind(id)
+    .bind(parent_id)
+    .bind(position)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(position)
+}
+
+// Swap the positions of two rows of the same parent, returns the new position of the first
+#[instrument(skip(tx))]
+pub async fn swap(
+    ordered: Ordered,
+    id: Uuid,
+    other_id: Uuid,
+    parent_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<i32, RouteError> {
+    lock(ordered, parent_id, tx).await?;
+
+    let same_parent: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM {} WHERE id IN ($1, $2) AND {} = $3",
+        ordered.table(),
+        ordered.parent_column()
+    ))
+    .bind(id)
+    .bind(other_id)
+    .bind(parent_id)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if same_parent != 2 {
+        return Err(RouteError::new(
+            format!(
+                "Can only be swapped with another one of the same {}.",
+                ordered.parent_name()
+            ),
+            None::<&str>,
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    // The subqueries see the positions from before the update
+    sqlx::query(&format!(
+        r#"
+        UPDATE {table} SET position = CASE
+            WHEN id = $1 THEN (SELECT position FROM {table} WHERE id = $2)
+            ELSE (SELECT position FROM {table} WHERE id = $1)
+        END
+        WHERE id IN ($1, $2)
+        "#,
+        table = ordered.table()
+    ))
+    .bind(id)
+    .bind(other_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(sqlx::query_scalar(&format!(
+        "SELECT position FROM {} WHERE id = $1",
+        ordered.table()
+    ))
+    .bind(id)
+    .fetch_one(&mut **tx)
+    .await?)
+}
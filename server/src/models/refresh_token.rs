@@ -0,0 +1,334 @@
+use std::fmt::{Debug, Display};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::access_token::{
+    generate_token, hash_token, token_matches, token_prefix, AccessToken, TokenMetadata,
+};
+
+// Access tokens issued with refresh tokens are short-lived, because
+// they can be renewed without the password
+pub const ACCESS_TOKEN_VALIDITY_SECONDS: i64 = 15 * 60;
+pub const REFRESH_TOKEN_VALIDITY_DAYS: i64 = 90;
+
+// Single-use token which is exchanged for a new access and refresh token.
+// Every token rotated from the same login shares a family, which is revoked
+// as a whole if an already used refresh token is presented again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    // Only known when issued, a hash is stored like with access tokens
+    pub token: String,
+    pub family_id: Uuid,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    // When it was exchanged, can't be used after that
+    pub used: Option<DateTime<Utc>>,
+    pub user_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TokenPair {
+    pub access_token: AccessToken,
+    pub refresh_token: RefreshToken,
+}
+
+impl TokenPair {
+    // Starts a new token family, used after logging in with the password
+    #[instrument]
+    pub async fn new(
+        user_id: Uuid,
+        metadata: TokenMetadata,
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Creating a new token family for {}", user_id);
+
+        let mut tx = pool.begin().await?;
+
+        let pair = issue(user_id, Uuid::new_v4(), metadata, &mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(pair)
+    }
+
+    // Exchange a refresh token for a new pair in the same family, the previous
+    // access token of the family stops working. The device name is kept from it
+    // if not given.
+    #[instrument(skip(token))]
+    pub async fn refresh(
+        token: impl ToString + Display + Debug,
+        mut metadata: TokenMetadata,
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Rotating a refresh token");
+
+        let token = token.to_string();
+        let mut tx = pool.begin().await?;
+
+        // Locked so only one request can mark it used
+        let candidates = sqlx::query!(
+            "SELECT id, token_hash FROM refresh_tokens WHERE token_prefix = $1 AND expires > NOW() FOR UPDATE",
+            token_prefix(&token)
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let matching_id = candidates
+            .into_iter()
+            .find(|candidate| token_matches(&token, &candidate.token_hash))
+            .map(|candidate| candidate.id);
+
+        let matching = match matching_id {
+            Some(id) => Some(
+                sqlx::query_as!(
+                    RefreshToken,
+                    r#"
+                    SELECT id, '' AS "token!", family_id, created, expires, used, user_id
+                    FROM refresh_tokens WHERE id = $1
+                    "#,
+                    id
+                )
+                .fetch_one(&mut *tx)
+                .await?,
+            ),
+            None => None,
+        };
+
+        let (rotated, reused_family_id) = match matching {
+            Some(previous) if previous.used.is_none() => {
+                sqlx::query!(
+                    "UPDATE refresh_tokens SET used = NOW() WHERE id = $1",
+                    previous.id
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                (Some(previous), None)
+            }
+            Some(previous) => (None, Some(previous.family_id)),
+            None => (None, None),
+        };
+
+        match rotated {
+            Some(previous) => {
+                let previous_device_name = sqlx::query_scalar!(
+                    "DELETE FROM access_tokens WHERE family_id = $1 RETURNING device_name",
+                    previous.family_id
+                )
+                .fetch_optional(&mut *tx)
+                .await?
+                .flatten();
+
+                if metadata.device_name.is_none() {
+                    metadata.device_name = previous_device_name;
+                }
+
+                let pair = issue(previous.user_id, previous.family_id, metadata, &mut tx).await?;
+
+                tx.commit().await?;
+
+                Ok(pair)
+            }
+            None => {
+                if let Some(family_id) = reused_family_id {
+                    // Either the client or someone who stole the token has the newer one
+                    warn!(
+                        "Used refresh token was presented again, revoking family {}",
+                        family_id
+                    );
+
+                    revoke_family(family_id, &mut tx).await?;
+                    tx.commit().await?;
+
+                    return Err(RouteError::new(
+                        "Refresh token has already been used, all tokens of the login were revoked.",
+                        None::<&str>,
+                        StatusCode::FORBIDDEN,
+                    ));
+                }
+
+                warn!("Invalid refresh token provided");
+
+                Err(RouteError::new(
+                    "No valid refresh token found.",
+                    None::<&str>,
+                    StatusCode::FORBIDDEN,
+                ))
+            }
+        }
+    }
+}
+
+// New access and refresh token to an existing or a new family
+async fn issue(
+    user_id: Uuid,
+    family_id: Uuid,
+    metadata: TokenMetadata,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<TokenPair, RouteError> {
+    let access_token_string = generate_token();
+    let refresh_token_string = generate_token();
+
+    let mut access_token = sqlx::query_as!(
+        AccessToken,
+        r#"
+        INSERT INTO access_tokens (token_prefix, token_hash, expires, user_id, family_id, device_name, user_agent, ip)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id, '' AS "token!", created, expires, user_id, family_id, device_name, user_agent, ip, last_used
+        "#,
+        token_prefix(&access_token_string),
+        hash_token(&access_token_string),
+        Utc::now() + Duration::seconds(ACCESS_TOKEN_VALIDITY_SECONDS),
+        user_id,
+        family_id,
+        metadata.device_name,
+        metadata.user_agent,
+        metadata.ip
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    let mut refresh_token = sqlx::query_as!(
+        RefreshToken,
+        r#"
+        INSERT INTO refresh_tokens (token_prefix, token_hash, family_id, expires, user_id)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, '' AS "token!", family_id, created, expires, used, user_id
+        "#,
+        token_prefix(&refresh_token_string),
+        hash_token(&refresh_token_string),
+        family_id,
+        Utc::now() + Duration::days(REFRESH_TOKEN_VALIDITY_DAYS),
+        user_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    access_token.token = access_token_string;
+    refresh_token.token = refresh_token_string;
+
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+    })
+}
+
+// Delete every access and refresh token of the family
+pub async fn revoke_family(
+    family_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    sqlx::query!("DELETE FROM access_tokens WHERE family_id = $1", family_id)
+        .execute(&mut **tx)
+        .await?;
+
+    sqlx::query!("DELETE FROM refresh_tokens WHERE family_id = $1", family_id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::test_utils::api::create_test_user;
+
+    use super::*;
+
+    #[sqlx::test]
+    async fn rotate(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let first = TokenPair::new(user.id, TokenMetadata::default(), &pool)
+            .await
+            .unwrap();
+        let second =
+            TokenPair::refresh(&first.refresh_token.token, TokenMetadata::default(), &pool)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            first.refresh_token.family_id,
+            second.refresh_token.family_id
+        );
+        assert_eq!(
+            second.access_token.family_id,
+            Some(second.refresh_token.family_id)
+        );
+        assert_ne!(first.refresh_token.token, second.refresh_token.token);
+
+        // The previous access token is replaced
+        assert!(AccessToken::from_token(&first.access_token.token, &pool)
+            .await
+            .is_err());
+        assert!(AccessToken::from_token(&second.access_token.token, &pool)
+            .await
+            .is_ok());
+    }
+
+    #[sqlx::test]
+    async fn reuse_revokes_family(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let first = TokenPair::new(user.id, TokenMetadata::default(), &pool)
+            .await
+            .unwrap();
+        let other_login = TokenPair::new(user.id, TokenMetadata::default(), &pool)
+            .await
+            .unwrap();
+
+        let second =
+            TokenPair::refresh(&first.refresh_token.token, TokenMetadata::default(), &pool)
+                .await
+                .unwrap();
+
+        // Replaying the used token revokes the newer tokens too
+        assert!(
+            TokenPair::refresh(&first.refresh_token.token, TokenMetadata::default(), &pool)
+                .await
+                .is_err()
+        );
+        assert!(
+            TokenPair::refresh(&second.refresh_token.token, TokenMetadata::default(), &pool)
+                .await
+                .is_err()
+        );
+        assert!(AccessToken::from_token(&second.access_token.token, &pool)
+            .await
+            .is_err());
+
+        // Other logins are not affected
+        assert!(
+            AccessToken::from_token(&other_login.access_token.token, &pool)
+                .await
+                .is_ok()
+        );
+        assert!(TokenPair::refresh(
+            &other_login.refresh_token.token,
+            TokenMetadata::default(),
+            &pool
+        )
+        .await
+        .is_ok());
+    }
+
+    #[sqlx::test]
+    async fn invalid(pool: PgPool) {
+        assert!(
+            TokenPair::refresh("not_a_refresh_token", TokenMetadata::default(), &pool)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+// Rest after the latest completed set of the current session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct RestTimer {
+    pub session_id: Uuid,
+    pub exercise_instance_id: Uuid,
+    pub exercise_id: Uuid,
+    // Superset, circuit or giant set the instance is in if any
+    pub exercise_group_id: Option<Uuid>,
+    // The set the rest started from
+    pub set_id: Uuid,
+    // When the set was completed
+    pub started: DateTime<Utc>,
+    // Rest time of the group, the instance or the exercise, whichever is set first.
+    // Zero between the exercises of a round in a group.
+    pub rest_seconds: i32,
+    pub ends: DateTime<Utc>,
+    // Zero when the rest is over
+    pub remaining_seconds: i64,
+}
+
+#[derive(FromRow)]
+struct LatestCompletedSet {
+    session_id: Uuid,
+    exercise_instance_id: Uuid,
+    exercise_id: Uuid,
+    group_id: Option<Uuid>,
+    set_id: Uuid,
+    completed_at: DateTime<Utc>,
+    rest_seconds: i32,
+}
+
+impl RestTimer {
+    // The timer of the most recently started in-progress session,
+    // which has to have at least one completed set
+    #[instrument]
+    pub async fn current(user_id: Uuid, pool: &PgPool) -> Result<Self, RouteError> {
+        info!("Querying the current rest timer");
+
+        let latest: Option<LatestCompletedSet> = sqlx::query_as(
+            r#"
+            SELECT sessions.id AS session_id, exercise_instances.id AS exercise_instance_id,
+                exercise_instances.exercise_id, exercise_instances.group_id, sets.id AS set_id,
+                sets.completed_at,
+                CASE
+                    -- The round goes on if a later instance of the group has a set for it
+                    WHEN EXISTS (
+                        SELECT 1 FROM exercise_instances later
+                        WHERE later.group_id = exercise_instances.group_id
+                            AND later.position > exercise_instances.position
+                            AND (SELECT COUNT(*) FROM sets later_sets WHERE later_sets.exercise_instance_id = later.id)
+                                >= (
+                                    SELECT COUNT(*) FROM sets earlier_sets
+                                    WHERE earlier_sets.exercise_instance_id = sets.exercise_instance_id
+                                        AND earlier_sets.position <= sets.position
+                                )
+                    ) THEN 0
+                    ELSE COALESCE(
+                        exercise_groups.rest_seconds, exercise_instances.rest_seconds, exercises.rest_seconds
+                    )
+                END AS rest_seconds
+            FROM sets
+            JOIN exercise_instances ON exercise_instances.id = sets.exercise_instance_id
+            JOIN sessions ON sessions.id = exercise_instances.session_id
+            JOIN exercises ON exercises.id = exercise_instances.exercise_id
+            LEFT JOIN exercise_groups ON exercise_groups.id = exercise_instances.group_id
+            WHERE sets.completed_at IS NOT NULL AND sessions.id = (
+                SELECT id FROM sessions WHERE user_id = $1 AND state = 'IN_PROGRESS'
+                ORDER BY started DESC LIMIT 1
+            )
+            ORDER BY sets.completed_at DESC LIMIT 1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let Some(latest) = latest else {
+            return Err(RouteError::new(
+                "No rest timer, a set has to be completed in a session in progress.",
+                None::<&str>,
+                StatusCode::NOT_FOUND,
+            ));
+        };
+
+        let ends = latest.completed_at + Duration::seconds(latest.rest_seconds as i64);
+
+        Ok(RestTimer {
+            session_id: latest.session_id,
+            exercise_instance_id: latest.exercise_instance_id,
+            exercise_id: latest.exercise_id,
+            exercise_group_id: latest.group_id,
+            set_id: latest.set_id,
+            started: latest.completed_at,
+            rest_seconds: latest.rest_seconds,
+            ends,
+            remaining_seconds: remaining_seconds(ends, Utc::now()),
+        })
+    }
+}
+
+// Rounded up, so the timer only shows zero once the rest is over
+fn remaining_seconds(ends: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    let remaining_ms = (ends - now).num_milliseconds().max(0);
+
+    (remaining_ms + 999) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        models::{
+            exercise_group::{ExerciseGroup, ExerciseGroupKind},
+            exercise_instance::ExerciseInstance,
+            session::Session,
+            set::Set,
+        },
+        test_utils::api::create_test_scenario,
+    };
+
+    use super::*;
+
+    #[test]
+    fn rounds_up() {
+        let now = Utc::now();
+
+        assert_eq!(
+            remaining_seconds(now + Duration::milliseconds(1500), now),
+            2
+        );
+        assert_eq!(remaining_seconds(now, now), 0);
+        assert_eq!(remaining_seconds(now - Duration::seconds(10), now), 0);
+    }
+
+    #[sqlx::test]
+    async fn latest_set_and_override(pool: PgPool) {
+        let (_, user, _, _, mut session, mut exercise_instance, mut set) =
+            create_test_scenario(&pool).await;
+
+        // Nothing completed yet
+        assert!(RestTimer::current(user.id, &pool).await.is_err());
+
+        set.set_weight(Some(100.0), &pool).await.unwrap();
+        set.set_reps(Some(5), &pool).await.unwrap();
+        set.set_complete(&pool).await.unwrap();
+
+        let timer = RestTimer::current(user.id, &pool).await.unwrap();
+
+        assert_eq!(timer.set_id, set.id);
+        assert_eq!(timer.started, set.completed_at.unwrap());
+        assert_eq!(timer.rest_seconds, 120);
+        assert!(timer.remaining_seconds > 0 && timer.remaining_seconds <= 120);
+
+        exercise_instance
+            .set_rest_seconds(Some(0), &pool)
+            .await
+            .unwrap();
+
+        let mut second_set = Set::new(user.id, exercise_instance.id, None, &pool)
+            .await
+            .unwrap();
+        second_set.set_weight(Some(100.0), &pool).await.unwrap();
+        second_set.set_reps(Some(4), &pool).await.unwrap();
+        second_set.set_complete(&pool).await.unwrap();
+
+        let timer = RestTimer::current(user.id, &pool).await.unwrap();
+
+        assert_eq!(timer.set_id, second_set.id);
+        assert_eq!(timer.rest_seconds, 0);
+        assert_eq!(timer.remaining_seconds, 0);
+
+        // Only sessions in progress have a timer
+        session.mark_finished(&pool).await.unwrap();
+
+        assert!(RestTimer::current(user.id, &pool).await.is_err());
+
+        // A newer session without completed sets hides the older ones
+        session.reopen(&pool).await.unwrap();
+        let newer = Session::new(user.id, "Newer", None::<&str>, &pool)
+            .await
+            .unwrap();
+        ExerciseInstance::new(
+            user.id,
+            newer.id,
+            exercise_instance.exercise_id,
+            None,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert!(RestTimer::current(user.id, &pool).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn rest_after_rounds(pool: PgPool) {
+        let (_, user, _, exercise, session, first, mut first_set) =
+            create_test_scenario(&pool).await;
+
+        let second = ExerciseInstance::new(user.id, session.id, exercise.id, None, &pool)
+            .await
+            .unwrap();
+        let mut second_set = Set::new(user.id, second.id, None, &pool).await.unwrap();
+
+        let group = ExerciseGroup::new(
+            user.id,
+            session.id,
+            ExerciseGroupKind::Superset,
+            Some(60),
+            &[first.id, second.id],
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        for set in [&mut first_set, &mut second_set] {
+            set.set_weight(Some(50.0), &pool).await.unwrap();
+            set.set_reps(Some(10), &pool).await.unwrap();
+        }
+
+        first_set.set_complete(&pool).await.unwrap();
+
+        // Straight to the next exercise of the round
+        let timer = RestTimer::current(user.id, &pool).await.unwrap();
+
+        assert_eq!(timer.exercise_group_id, Some(group.id));
+        assert_eq!(timer.rest_seconds, 0);
+
+        second_set.set_complete(&pool).await.unwrap();
+
+        // The round is over
+        let timer = RestTimer::current(user.id, &pool).await.unwrap();
+
+        assert_eq!(timer.set_id, second_set.id);
+        assert_eq!(timer.rest_seconds, 60);
+    }
+}
@@ -1,17 +1,27 @@
-use std::fmt::{Debug, Display};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    str::FromStr,
+};
 
+use axum::http::StatusCode;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, PgPool};
+use sqlx::{prelude::FromRow, PgPool, Postgres, Transaction};
 use tracing::{info, instrument};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::api::response::RouteError;
 
-use super::exercise_instance::{self, ExerciseInstance};
+use super::{
+    exercise_group::{self, ExerciseGroup},
+    exercise_instance::{self, ExerciseInstance},
+    personal_record,
+    set::{ConvertWeights, WeightUnit},
+};
 
-// A single session, can be in progess or finished.
+// A single session, can be planned, in progess or finished.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
 pub struct Session {
     // Primary key
@@ -22,13 +32,56 @@ pub struct Session {
     pub name: String,
     // Session specific comments or notes
     pub description: Option<String>,
-    // Started, static
+    // Started, or when it's planned to be started. Can be corrected afterwards
     pub started: DateTime<Utc>,
-    // When it was finished
+    // When it was finished, only set when finished
     pub finished: Option<DateTime<Utc>>,
+    // Exports before states existed don't have this
+    #[serde(default)]
+    pub state: SessionState,
+    // Perceived exertion of the whole session, 1-10
+    #[serde(default)]
+    pub exertion: Option<i32>,
+    // How ready for training the user felt before the session, 1-10
+    #[serde(default)]
+    pub readiness: Option<i32>,
     // Instances of predefined exercised, contains the kind, sets, reps, weight and more
     #[sqlx(skip)]
     pub exercise_instances: Vec<ExerciseInstance>,
+    // Supersets, circuits and giant sets the instances are grouped into
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub exercise_groups: Vec<ExerciseGroup>,
+}
+
+// Sessions go from planned to in progress to finished, and finished ones can be reopened.
+// Exercise instances and sets of finished sessions can't be changed.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "session_state", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    Planned,
+    #[default]
+    InProgress,
+    Finished,
+}
+
+impl Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionState::Planned => write!(f, "planned"),
+            SessionState::InProgress => write!(f, "in progress"),
+            SessionState::Finished => write!(f, "finished"),
+        }
+    }
+}
+
+impl ConvertWeights for Session {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        self.exercise_instances.convert_weights(unit);
+    }
 }
 
 impl Session {
@@ -48,7 +101,7 @@ impl Session {
         )
         .bind(user_id)
         .bind(name.to_string())
-        .bind(description.map_or(None, |i| Some(i.to_string())))
+        .bind(description.map(|i| i.to_string()))
         .fetch_one(pool)
         .await?)
     }
@@ -86,7 +139,7 @@ impl Session {
     ) -> Result<(), RouteError> {
         info!("Updating session description");
 
-        let description_string = description.map_or(None, |i| Some(i.to_string()));
+        let description_string = description.map(|i| i.to_string());
 
         sqlx::query!(
             "UPDATE sessions SET description = $1 WHERE id = $2",
@@ -101,28 +154,205 @@ impl Session {
         Ok(())
     }
 
+    // Can be rated also after finishing
+    #[instrument]
+    pub async fn set_exertion(
+        &mut self,
+        exertion: Option<i32>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating session exertion");
+
+        self.exertion = sqlx::query!(
+            "UPDATE sessions SET exertion = $1 WHERE id = $2 RETURNING exertion",
+            exertion,
+            self.id,
+        )
+        .fetch_one(pool)
+        .await?
+        .exertion;
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub async fn set_readiness(
+        &mut self,
+        readiness: Option<i32>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating session readiness");
+
+        self.readiness = sqlx::query!(
+            "UPDATE sessions SET readiness = $1 WHERE id = $2 RETURNING readiness",
+            readiness,
+            self.id,
+        )
+        .fetch_one(pool)
+        .await?
+        .readiness;
+
+        Ok(())
+    }
+
     // Deleted the sessions and the exercise instances related and their sets related to it
     // Exercise is not deleted
     #[instrument]
     pub async fn delete(self, pool: &PgPool) -> Result<Uuid, RouteError> {
         info!("Deleting session (self)");
 
+        // Records of all exercises in the session have to be recomputed after
+        let exercise_ids = sqlx::query_scalar!(
+            "SELECT DISTINCT exercise_id FROM exercise_instances WHERE session_id = $1",
+            self.id
+        )
+        .fetch_all(pool)
+        .await?;
+
         sqlx::query!("DELETE FROM sessions WHERE id = $1", self.id)
             .execute(pool)
             .await?;
 
+        for exercise_id in exercise_ids {
+            personal_record::recompute(self.user_id, exercise_id, pool).await?;
+        }
+
         Ok(self.id)
     }
 
-    // Finish the session, permanent but doesn't lock exercise instances or their sets
+    // Start a planned session now
+    #[instrument]
+    pub async fn start(&mut self, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Starting a planned session");
+
+        self.transition(
+            SessionState::Planned,
+            "UPDATE sessions SET state = 'IN_PROGRESS', started = NOW() WHERE id = $1 AND state = 'PLANNED' RETURNING state, started, finished",
+            pool,
+        )
+        .await
+    }
+
+    // Finish the session, which locks its exercise instances and sets until reopened
     #[instrument]
     pub async fn mark_finished(&mut self, pool: &PgPool) -> Result<(), RouteError> {
-        info!("Marking session finished permanently");
+        info!("Marking session finished");
+
+        self.transition(
+            SessionState::InProgress,
+            "UPDATE sessions SET state = 'FINISHED', finished = GREATEST(NOW(), started) WHERE id = $1 AND state = 'IN_PROGRESS' RETURNING state, started, finished",
+            pool,
+        )
+        .await
+    }
+
+    // Set a finished session back in progress so it can be edited
+    #[instrument]
+    pub async fn reopen(&mut self, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Reopening a finished session");
+
+        self.transition(
+            SessionState::Finished,
+            "UPDATE sessions SET state = 'IN_PROGRESS', finished = NULL WHERE id = $1 AND state = 'FINISHED' RETURNING state, started, finished",
+            pool,
+        )
+        .await
+    }
+
+    // Run the update only if the session is still in the required state, so
+    // concurrent requests can't skip a state
+    async fn transition(
+        &mut self,
+        required: SessionState,
+        query: &str,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        let updated: Option<(SessionState, DateTime<Utc>, Option<DateTime<Utc>>)> =
+            sqlx::query_as(query)
+                .bind(self.id)
+                .fetch_optional(pool)
+                .await?;
+
+        match updated {
+            Some((state, started, finished)) => {
+                self.state = state;
+                self.started = started;
+                self.finished = finished;
+
+                Ok(())
+            }
+            None => Err(RouteError::new(
+                format!(
+                    "Session is {}, only {} sessions can be changed like this.",
+                    self.state, required
+                ),
+                Some("state"),
+                StatusCode::CONFLICT,
+            )),
+        }
+    }
+
+    // Correct when the session was started and finished, a finish time
+    // can only be set for finished sessions
+    #[instrument]
+    pub async fn set_times(
+        &mut self,
+        started: DateTime<Utc>,
+        finished: Option<DateTime<Utc>>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Correcting session start and finish times");
+
+        let mut tx = pool.begin().await?;
+
+        // Checked from the locked row, so the session can't be finished or reopened meanwhile
+        if let Some(state) = lock_state(self.id, &mut tx).await? {
+            self.state = state;
+        }
+
+        if finished.is_some() != (self.state == SessionState::Finished) {
+            return Err(RouteError::new(
+                "Only finished sessions have a finish time.",
+                Some("finished"),
+                StatusCode::CONFLICT,
+            ));
+        }
+
+        if finished.is_some_and(|finished| finished < started) {
+            return Err(RouteError::new(
+                "Invalid input in finished field: must not be before started",
+                Some("finished"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        sqlx::query("UPDATE sessions SET started = $1, finished = $2 WHERE id = $3")
+            .bind(started)
+            .bind(finished)
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        let started_changed = self.started != started;
 
-        self.finished = sqlx::query!("UPDATE sessions SET finished = NOW() RETURNING finished")
-            .fetch_one(pool)
-            .await?
-            .finished;
+        self.started = started;
+        self.finished = finished;
+
+        // Records are ordered by the start time of their sessions
+        if started_changed {
+            let exercise_ids = sqlx::query_scalar!(
+                "SELECT DISTINCT exercise_id FROM exercise_instances WHERE session_id = $1",
+                self.id
+            )
+            .fetch_all(pool)
+            .await?;
+
+            for exercise_id in exercise_ids {
+                personal_record::recompute(self.user_id, exercise_id, pool).await?;
+            }
+        }
 
         Ok(())
     }
@@ -145,14 +375,252 @@ impl Session {
         queried_session.exercise_instances =
             exercise_instance::all_from_session_id(user_id, queried_session.id, pool).await?;
 
+        queried_session.exercise_groups =
+            exercise_group::all_from_session_ids(user_id, &[queried_session.id], pool).await?;
+        exercise_group::fill_rounds(
+            &mut queried_session.exercise_groups,
+            &queried_session.exercise_instances,
+        );
+
         Ok(queried_session)
     }
 
     pub fn is_finished(&self) -> bool {
-        self.finished.is_some()
+        self.state == SessionState::Finished
+    }
+}
+
+// Create a planned session which can be started later
+#[instrument]
+pub async fn new_planned(
+    user_id: Uuid,
+    name: impl ToString + Display + Debug,
+    description: Option<impl ToString + Display + Debug>,
+    planned_start: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<Session, RouteError> {
+    info!("Creating a new planned session '{}'", name);
+
+    Ok(sqlx::query_as(
+        "INSERT INTO sessions (user_id, name, description, started, state) VALUES ($1, $2, $3, $4, 'PLANNED') RETURNING *;",
+    )
+    .bind(user_id)
+    .bind(name.to_string())
+    .bind(description.map(|i| i.to_string()))
+    .bind(planned_start)
+    .fetch_one(pool)
+    .await?)
+}
+
+// Lock the session for the rest of the transaction and return its state.
+// Every write in a session takes this same lock, from here or the position of
+// its exercise instances, so no transaction ever has to upgrade a weaker one.
+#[instrument(skip(tx))]
+pub async fn lock_state(
+    session_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Option<SessionState>, RouteError> {
+    Ok(
+        sqlx::query_scalar("SELECT state FROM sessions WHERE id = $1 FOR UPDATE")
+            .bind(session_id)
+            .fetch_optional(&mut **tx)
+            .await?,
+    )
+}
+
+// Exercise instances and sets of finished sessions can't be changed,
+// returns an error if the session is finished. The session stays locked
+// until the transaction ends, so it can't be finished in the middle of a write.
+#[instrument(skip(tx))]
+pub async fn ensure_unlocked(
+    session_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    check_unlocked(lock_state(session_id, tx).await?)
+}
+
+// Same as above from an exercise instance of the session
+#[instrument(skip(tx))]
+pub async fn ensure_unlocked_by_exercise_instance(
+    exercise_instance_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    let session_id: Option<Uuid> =
+        sqlx::query_scalar("SELECT session_id FROM exercise_instances WHERE id = $1")
+            .bind(exercise_instance_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+    match session_id {
+        Some(session_id) => ensure_unlocked(session_id, tx).await,
+        None => Ok(()),
+    }
+}
+
+fn check_unlocked(state: Option<SessionState>) -> Result<(), RouteError> {
+    if state == Some(SessionState::Finished) {
+        Err(RouteError::new(
+            "Session is finished, reopen it to make changes.",
+            Some("session_id"),
+            StatusCode::CONFLICT,
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Position of a session in the list of sessions, ordered by start time and ID.
+// Formatted as "<start time in microseconds>_<ID>" for use as a pagination cursor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionCursor {
+    pub started: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl From<&Session> for SessionCursor {
+    fn from(session: &Session) -> Self {
+        SessionCursor {
+            started: session.started,
+            id: session.id,
+        }
     }
 }
 
+impl Display for SessionCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.started.timestamp_micros(), self.id)
+    }
+}
+
+impl FromStr for SessionCursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (started, id) = s.split_once('_').ok_or(())?;
+
+        Ok(SessionCursor {
+            started: DateTime::from_timestamp_micros(started.parse().map_err(|_| ())?).ok_or(())?,
+            id: id.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionOrder {
+    // Latest started first
+    #[default]
+    Newest,
+    Oldest,
+}
+
+// Conditions a session has to match to be listed, all are optional
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionFilter {
+    // Range of start times, inclusive
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub finished: Option<bool>,
+    // Case insensitive search from the name
+    pub name: Option<String>,
+    // Has atleast one instance of this exercise
+    pub exercise_id: Option<Uuid>,
+}
+
+// One page of sessions and the cursor for getting the next one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct SessionPage {
+    pub sessions: Vec<Session>,
+    // Not set when this is the last page
+    pub next_cursor: Option<String>,
+}
+
+impl ConvertWeights for SessionPage {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        self.sessions.convert_weights(unit);
+    }
+}
+
+// Get a page of the user's sessions matching the filter, after the cursor if given.
+// Exercise instances and their sets are only queried when requested.
+#[instrument]
+pub async fn user_sessions_page(
+    user_id: Uuid,
+    filter: &SessionFilter,
+    order: SessionOrder,
+    cursor: Option<SessionCursor>,
+    limit: i64,
+    with_exercise_instances: bool,
+    pool: &PgPool,
+) -> Result<SessionPage, RouteError> {
+    info!("Querying a page of sessions of one user");
+
+    // Only the direction is formatted in, everything else is a bound parameter
+    let (comparison, direction) = match order {
+        SessionOrder::Newest => ("<", "DESC"),
+        SessionOrder::Oldest => (">", "ASC"),
+    };
+
+    // Escape wildcards so the name is searched for as is
+    let name_pattern = filter.name.as_ref().map(|name| {
+        format!(
+            "%{}%",
+            name.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        )
+    });
+
+    let mut sessions: Vec<Session> = sqlx::query_as(&format!(
+        r#"
+        SELECT * FROM sessions
+        WHERE user_id = $1
+            AND ($2::timestamptz IS NULL OR started >= $2)
+            AND ($3::timestamptz IS NULL OR started <= $3)
+            AND ($4::boolean IS NULL OR (finished IS NOT NULL) = $4)
+            AND ($5::text IS NULL OR name ILIKE $5)
+            AND ($6::uuid IS NULL OR EXISTS (
+                SELECT 1 FROM exercise_instances
+                WHERE exercise_instances.session_id = sessions.id AND exercise_instances.exercise_id = $6
+            ))
+            AND ($7::timestamptz IS NULL OR (started, id) {comparison} ($7, $8))
+        ORDER BY started {direction}, id {direction}
+        LIMIT $9
+        "#
+    ))
+    .bind(user_id)
+    .bind(filter.from)
+    .bind(filter.to)
+    .bind(filter.finished)
+    .bind(name_pattern)
+    .bind(filter.exercise_id)
+    .bind(cursor.map(|cursor| cursor.started))
+    .bind(cursor.map(|cursor| cursor.id))
+    // One extra to know if there is a next page
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await?;
+
+    let next_cursor = if sessions.len() as i64 > limit {
+        sessions.truncate(limit as usize);
+
+        sessions
+            .last()
+            .map(|session| SessionCursor::from(session).to_string())
+    } else {
+        None
+    };
+
+    if with_exercise_instances {
+        fill_exercise_instances(user_id, &mut sessions, pool).await?;
+    }
+
+    Ok(SessionPage {
+        sessions,
+        next_cursor,
+    })
+}
+
 // Get all sessions of an user with all related exercise instances and their sets
 #[instrument]
 pub async fn all_user_sessions(user_id: Uuid, pool: &PgPool) -> Result<Vec<Session>, RouteError> {
@@ -164,27 +632,68 @@ pub async fn all_user_sessions(user_id: Uuid, pool: &PgPool) -> Result<Vec<Sessi
             .fetch_all(pool)
             .await?;
 
-    for session in &mut queried_sessions {
-        session.exercise_instances =
-            exercise_instance::all_from_session_id(user_id, session.id, pool).await?;
-    }
+    fill_exercise_instances(user_id, &mut queried_sessions, pool).await?;
 
     Ok(queried_sessions)
 }
 
+// Query the exercise instances with their sets and the groups of all the sessions
+// at once and place them in their sessions
+#[instrument(skip(sessions))]
+async fn fill_exercise_instances(
+    user_id: Uuid,
+    sessions: &mut [Session],
+    pool: &PgPool,
+) -> Result<(), RouteError> {
+    let session_ids: Vec<Uuid> = sessions.iter().map(|session| session.id).collect();
+
+    let mut instances_by_session: HashMap<Uuid, Vec<ExerciseInstance>> = HashMap::new();
+
+    for instance in exercise_instance::all_from_session_ids(user_id, &session_ids, pool).await? {
+        instances_by_session
+            .entry(instance.session_id)
+            .or_default()
+            .push(instance);
+    }
+
+    let mut groups_by_session: HashMap<Uuid, Vec<ExerciseGroup>> = HashMap::new();
+
+    for group in exercise_group::all_from_session_ids(user_id, &session_ids, pool).await? {
+        groups_by_session
+            .entry(group.session_id)
+            .or_default()
+            .push(group);
+    }
+
+    for session in sessions {
+        session.exercise_instances = instances_by_session.remove(&session.id).unwrap_or_default();
+        session.exercise_groups = groups_by_session.remove(&session.id).unwrap_or_default();
+        exercise_group::fill_rounds(&mut session.exercise_groups, &session.exercise_instances);
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use sqlx::PgPool;
+    use sqlx::{postgres::PgPoolOptions, PgPool};
 
-    use crate::{api::response::RouteError, models::user::User, test_utils::api::create_test_user};
+    use crate::{
+        api::response::RouteError,
+        models::{exercise_instance::ExerciseInstance, set::Set, user::User},
+        test_utils::{
+            api::{create_test_scenario, create_test_user},
+            queries::count_queries,
+        },
+    };
 
     use super::*;
 
     async fn create_test_session(pool: &PgPool) -> (User, Session) {
-        let user = create_test_user(&pool).await;
+        let user = create_test_user(pool).await;
 
         let new_session: Session =
-            Session::new(user.id, "Test sessions", Some("Test description"), &pool)
+            Session::new(user.id, "Test sessions", Some("Test description"), pool)
                 .await
                 .unwrap();
 
@@ -224,7 +733,7 @@ mod tests {
     async fn delete(pool: PgPool) {
         let (user, session) = create_test_session(&pool).await;
 
-        let session_id = session.id.clone();
+        let session_id = session.id;
 
         session.delete(&pool).await.unwrap();
 
@@ -245,4 +754,261 @@ mod tests {
         assert!(queried_session.is_finished());
         assert!(queried_session.finished.is_some());
     }
+
+    #[sqlx::test]
+    async fn mark_finished_only_affects_one_session(pool: PgPool) {
+        let (user, mut session) = create_test_session(&pool).await;
+        let other = Session::new(user.id, "Other", None::<&str>, &pool)
+            .await
+            .unwrap();
+
+        session.mark_finished(&pool).await.unwrap();
+
+        let queried_other = Session::from_id(user.id, other.id, &pool).await.unwrap();
+
+        assert_eq!(queried_other.state, SessionState::InProgress);
+        assert!(queried_other.finished.is_none());
+    }
+
+    #[sqlx::test]
+    async fn transitions(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+        let planned_start = Utc::now() + chrono::Duration::days(1);
+
+        let mut session = new_planned(user.id, "Planned", None::<&str>, planned_start, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(session.state, SessionState::Planned);
+
+        // Can't be finished or reopened before starting
+        assert!(session.mark_finished(&pool).await.is_err());
+        assert!(session.reopen(&pool).await.is_err());
+
+        session.start(&pool).await.unwrap();
+
+        assert_eq!(session.state, SessionState::InProgress);
+        assert!(session.started < planned_start);
+        assert!(session.start(&pool).await.is_err());
+
+        session.mark_finished(&pool).await.unwrap();
+        assert!(session.mark_finished(&pool).await.is_err());
+
+        session.reopen(&pool).await.unwrap();
+
+        let queried_session = Session::from_id(user.id, session.id, &pool).await.unwrap();
+
+        assert_eq!(queried_session.state, SessionState::InProgress);
+        assert!(queried_session.finished.is_none());
+    }
+
+    #[sqlx::test]
+    async fn finished_sessions_are_locked(pool: PgPool) {
+        let (_, user, _, exercise, mut session, mut exercise_instance, mut set) =
+            create_test_scenario(&pool).await;
+
+        session.mark_finished(&pool).await.unwrap();
+
+        assert!(
+            ExerciseInstance::new(user.id, session.id, exercise.id, None, &pool)
+                .await
+                .is_err()
+        );
+        assert!(exercise_instance
+            .add_comment("Locked", &pool)
+            .await
+            .is_err());
+        assert!(Set::new(user.id, exercise_instance.id, None, &pool)
+            .await
+            .is_err());
+        assert!(set.set_reps(Some(5), &pool).await.is_err());
+        assert!(set.clone().delete(&pool).await.is_err());
+
+        session.reopen(&pool).await.unwrap();
+
+        set.set_reps(Some(5), &pool).await.unwrap();
+        exercise_instance.add_comment("Open", &pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn correct_times(pool: PgPool) {
+        let (user, mut session) = create_test_session(&pool).await;
+        let started = session.started - chrono::Duration::hours(2);
+
+        // Unfinished sessions can't have a finish time
+        assert!(session
+            .set_times(started, Some(session.started), &pool)
+            .await
+            .is_err());
+
+        session.set_times(started, None, &pool).await.unwrap();
+        session.mark_finished(&pool).await.unwrap();
+
+        // Can't finish before starting
+        assert!(session
+            .set_times(started, Some(started - chrono::Duration::minutes(1)), &pool)
+            .await
+            .is_err());
+
+        let finished = started + chrono::Duration::hours(1);
+        session
+            .set_times(started, Some(finished), &pool)
+            .await
+            .unwrap();
+
+        let queried_session = Session::from_id(user.id, session.id, &pool).await.unwrap();
+
+        assert_eq!(queried_session.started, started);
+        assert_eq!(queried_session.finished, Some(finished));
+    }
+
+    #[sqlx::test]
+    async fn pages(pool: PgPool) {
+        let (user, first) = create_test_session(&pool).await;
+
+        let mut sessions = vec![first];
+
+        for i in 1..5 {
+            sessions.push(
+                Session::new(user.id, format!("Session {}", i), None::<&str>, &pool)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let filter = SessionFilter::default();
+        let mut cursor = None;
+        let mut queried: Vec<Session> = Vec::new();
+
+        // Go through all pages, oldest first
+        loop {
+            let page = user_sessions_page(
+                user.id,
+                &filter,
+                SessionOrder::Oldest,
+                cursor,
+                2,
+                false,
+                &pool,
+            )
+            .await
+            .unwrap();
+
+            assert!(page.sessions.len() <= 2);
+
+            queried.extend(page.sessions);
+
+            match page.next_cursor {
+                Some(next) => cursor = Some(next.parse().unwrap()),
+                None => break,
+            }
+        }
+
+        assert_eq!(queried, sessions);
+
+        // Name search doesn't treat wildcards as such
+        let filter = SessionFilter {
+            name: Some("SESSION 3".to_string()),
+            ..Default::default()
+        };
+
+        let page = user_sessions_page(
+            user.id,
+            &filter,
+            SessionOrder::Newest,
+            None,
+            10,
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.sessions, vec![sessions[3].clone()]);
+        assert!(page.next_cursor.is_none());
+
+        let filter = SessionFilter {
+            name: Some("%".to_string()),
+            ..Default::default()
+        };
+
+        let page = user_sessions_page(
+            user.id,
+            &filter,
+            SessionOrder::Newest,
+            None,
+            10,
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert!(page.sessions.is_empty());
+    }
+
+    #[test]
+    fn cursor_format() {
+        let cursor = SessionCursor {
+            started: DateTime::from_timestamp_micros(1705400000123456).unwrap(),
+            id: Uuid::new_v4(),
+        };
+
+        assert_eq!(cursor.to_string().parse::<SessionCursor>(), Ok(cursor));
+        assert!("invalid".parse::<SessionCursor>().is_err());
+        assert!("123_invalid".parse::<SessionCursor>().is_err());
+    }
+
+    // Loading sessions takes the same amount of queries no matter how many
+    // exercise instances and sets they have
+    #[sqlx::test]
+    async fn query_count_does_not_scale(pool: PgPool) {
+        let (_, user, _, exercise, session, _, _) = create_test_scenario(&pool).await;
+
+        // New connections look up the custom types first, so only one is used
+        // and it's warmed up before counting
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect_with((*pool.connect_options()).clone())
+            .await
+            .unwrap();
+        Session::from_id(user.id, session.id, &pool).await.unwrap();
+        all_user_sessions(user.id, &pool).await.unwrap();
+
+        let (_, single_count) = count_queries(Session::from_id(user.id, session.id, &pool)).await;
+        let (_, all_count) = count_queries(all_user_sessions(user.id, &pool)).await;
+
+        for _ in 0..10 {
+            let another_session = Session::new(user.id, "Another", None::<&str>, &pool)
+                .await
+                .unwrap();
+
+            for session_id in [session.id, another_session.id] {
+                let instance = ExerciseInstance::new(user.id, session_id, exercise.id, None, &pool)
+                    .await
+                    .unwrap();
+
+                for _ in 0..3 {
+                    Set::new(user.id, instance.id, None, &pool).await.unwrap();
+                }
+            }
+        }
+
+        let (queried, count) = count_queries(Session::from_id(user.id, session.id, &pool)).await;
+
+        assert_eq!(queried.unwrap().exercise_instances.len(), 11);
+        assert_eq!(count, single_count);
+
+        let (queried, count) = count_queries(all_user_sessions(user.id, &pool)).await;
+        let queried = queried.unwrap();
+
+        assert_eq!(queried.len(), 11);
+        assert!(queried.iter().all(|session| session
+            .exercise_instances
+            .iter()
+            .all(|i| !i.sets.is_empty())));
+        assert_eq!(count, all_count);
+        // Sessions, exercise instances, sets and exercise groups
+        assert_eq!(count, 4);
+    }
 }
@@ -5,12 +5,23 @@ use rust_decimal::{
     Decimal,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, PgPool};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    prelude::FromRow,
+    PgPool, Postgres, Transaction,
+};
 use tracing::{error, info, instrument};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::api::response::RouteError;
+use crate::{
+    api::response::RouteError,
+    models::{
+        personal_record,
+        position::{self, Ordered},
+        session,
+    },
+};
 
 // An ExerciseInstance has zero or more of these..
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
@@ -25,19 +36,92 @@ pub struct Set {
     pub weight: Option<f32>,
     // The reps are 0 or more (checked by db)
     pub reps: Option<i32>,
+    // Working by default, older exports don't have kinds
+    #[serde(default)]
+    pub kind: SetKind,
+    // Set a drop set continues from, only drop sets can have one
+    #[serde(default)]
+    pub parent_set_id: Option<Uuid>,
+    // Effort as rate of perceived exertion (6-10 in half steps) or reps in reserve,
+    // only one of them is set
+    #[serde(default)]
+    pub rpe: Option<f32>,
+    #[serde(default)]
+    pub rir: Option<i32>,
     // When created set is not completed and weight and reps have to be set before
     // marking it as complete
     pub completed: bool,
-    // Mainly for ordering of instances and can't be changed
+    // When the set was last marked completed, the rest timer starts from this
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+    // When the set was added, can't be changed
     pub created: DateTime<Utc>,
+    // Order in the exercise instance starting from 0, older exports are ordered by the list
+    #[serde(default)]
+    pub position: i32,
+}
+
+// What kind of effort a set is, aggregates and exports can include or exclude kinds
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "set_kind", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum SetKind {
+    WarmUp,
+    #[default]
+    Working,
+    Drop,
+    Failure,
+    Amrap,
+}
+
+impl SetKind {
+    pub const ALL: [SetKind; 5] = [
+        SetKind::WarmUp,
+        SetKind::Working,
+        SetKind::Drop,
+        SetKind::Failure,
+        SetKind::Amrap,
+    ];
+}
+
+// Allows binding lists of kinds for `kind = ANY($1)`
+impl PgHasArrayType for SetKind {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_set_kind")
+    }
+}
+
+// Kinds included by an aggregate or export, the given kinds or otherwise all of them
+// without the excluded ones. The defaults of the query are only excluded if neither is given.
+pub fn included_kinds(
+    kinds: Option<&[SetKind]>,
+    exclude_kinds: Option<&[SetKind]>,
+    excluded_by_default: &[SetKind],
+) -> Vec<SetKind> {
+    let excluded = match (kinds, exclude_kinds) {
+        (_, Some(exclude_kinds)) => exclude_kinds,
+        (Some(_), None) => &[],
+        (None, None) => excluded_by_default,
+    };
+
+    kinds
+        .unwrap_or(&SetKind::ALL)
+        .iter()
+        .copied()
+        .filter(|kind| !excluded.contains(kind))
+        .collect()
 }
 
 impl Set {
-    // Create a new set uncompleted withtout weight or reps set
+    // Create a new set uncompleted withtout weight or reps set at the position,
+    // or at the end if not given
     #[instrument]
     pub async fn new(
         user_id: Uuid,
         exercise_instance_id: Uuid,
+        position: Option<i32>,
         pool: &PgPool,
     ) -> Result<Self, RouteError> {
         info!("Creating new set");
@@ -57,14 +141,30 @@ impl Set {
             ));
         }
 
-        Ok(sqlx::query_as!(
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(exercise_instance_id, &mut tx).await?;
+
+        let position =
+            position::make_room(Ordered::Sets, exercise_instance_id, position, &mut tx).await?;
+
+        let created = sqlx::query_as!(
             Set,
-            "INSERT INTO sets (user_id, exercise_instance_id) VALUES ($1, $2) RETURNING *;",
+            r#"
+            INSERT INTO sets (user_id, exercise_instance_id, position) VALUES ($1, $2, $3)
+            RETURNING id, user_id, exercise_instance_id, weight, reps, kind AS "kind: SetKind", parent_set_id,
+                rpe, rir, completed, completed_at, created, position
+            "#,
             user_id,
-            exercise_instance_id
+            exercise_instance_id,
+            position
         )
-        .fetch_one(pool)
-        .await?)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(created)
     }
 
     // Get from ID and user
@@ -74,7 +174,11 @@ impl Set {
 
         Ok(sqlx::query_as!(
             Set,
-            "SELECT * FROM sets WHERE user_id = $1 AND id = $2",
+            r#"
+            SELECT id, user_id, exercise_instance_id, weight, reps, kind AS "kind: SetKind", parent_set_id,
+                rpe, rir, completed, completed_at, created, position
+            FROM sets WHERE user_id = $1 AND id = $2
+            "#,
             user_id,
             set_id
         )
@@ -87,55 +191,49 @@ impl Set {
     pub async fn delete(self, pool: &PgPool) -> Result<Uuid, RouteError> {
         info!("Deleting set (self)");
 
-        sqlx::query!("DELETE FROM sets WHERE id = $1", self.id)
-            .execute(pool)
-            .await?;
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        position::delete(Ordered::Sets, self.id, self.exercise_instance_id, &mut tx).await?;
+
+        tx.commit().await?;
+
+        if self.completed {
+            self.update_records(pool).await?;
+        }
 
         Ok(self.id)
     }
 
+    // Weight in kilograms, rounded in the unit it was given in (see WeightUnit)
     #[instrument]
     pub async fn set_weight(
         &mut self,
-        mut weight: Option<f32>,
+        weight: Option<f32>,
         pool: &PgPool,
     ) -> Result<(), RouteError> {
         info!("Updating set weight");
 
-        // All this just to round it to one decimal reliably...
-        if let Some(not_rounded_weight) = weight {
-            if let Some(decimal) = Decimal::from_f32(not_rounded_weight) {
-                if let Some(rounded) = decimal.round_dp(1).to_f32() {
-                    weight = Some(rounded);
-                } else {
-                    error!("Failed to round Decimal to f32: {}", decimal);
-
-                    return Err(RouteError::new(
-                        "Failed to process decimal number.",
-                        Some("weight"),
-                        StatusCode::BAD_REQUEST,
-                    ));
-                }
-            } else {
-                error!("Failed to convert f32 to Decimal: {}", not_rounded_weight);
+        let mut tx = pool.begin().await?;
 
-                return Err(RouteError::new(
-                    "Failed to process decimal number.",
-                    Some("weight"),
-                    StatusCode::BAD_REQUEST,
-                ));
-            }
-        }
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
 
         self.weight = sqlx::query!(
             "UPDATE sets SET weight = $1 WHERE id = $2 RETURNING weight;",
             weight,
             self.id
         )
-        .fetch_one(pool)
+        .fetch_one(&mut *tx)
         .await?
         .weight;
 
+        tx.commit().await?;
+
+        if self.completed {
+            self.update_records(pool).await?;
+        }
+
         Ok(())
     }
 
@@ -143,15 +241,241 @@ impl Set {
     pub async fn set_reps(&mut self, reps: Option<i32>, pool: &PgPool) -> Result<(), RouteError> {
         info!("Updating set reps");
 
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
         self.reps = sqlx::query!(
             "UPDATE sets SET reps = $1 WHERE id = $2 RETURNING reps;",
             reps,
             self.id
         )
-        .fetch_one(pool)
+        .fetch_one(&mut *tx)
         .await?
         .reps;
 
+        tx.commit().await?;
+
+        if self.completed {
+            self.update_records(pool).await?;
+        }
+
+        Ok(())
+    }
+
+    // Setting the RPE removes the reps in reserve
+    #[instrument]
+    pub async fn set_rpe(&mut self, rpe: Option<f32>, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Updating set RPE");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        let updated = sqlx::query!(
+            "UPDATE sets SET rpe = $1, rir = CASE WHEN $1::real IS NULL THEN rir END WHERE id = $2 RETURNING rpe, rir;",
+            rpe,
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.rpe = updated.rpe;
+        self.rir = updated.rir;
+
+        Ok(())
+    }
+
+    // Setting the reps in reserve removes the RPE
+    #[instrument]
+    pub async fn set_rir(&mut self, rir: Option<i32>, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Updating set reps in reserve");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        let updated = sqlx::query!(
+            "UPDATE sets SET rir = $1, rpe = CASE WHEN $1::integer IS NULL THEN rpe END WHERE id = $2 RETURNING rpe, rir;",
+            rir,
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.rpe = updated.rpe;
+        self.rir = updated.rir;
+
+        Ok(())
+    }
+
+    // Changing from a drop set removes the parent
+    #[instrument]
+    pub async fn set_kind(&mut self, kind: SetKind, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Updating set kind");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        let updated = sqlx::query!(
+            r#"
+            UPDATE sets SET kind = $1, parent_set_id = CASE WHEN $1 = 'DROP'::set_kind THEN parent_set_id END
+            WHERE id = $2 RETURNING kind AS "kind: SetKind", parent_set_id
+            "#,
+            kind as SetKind,
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.kind = updated.kind;
+        self.parent_set_id = updated.parent_set_id;
+
+        if self.completed {
+            self.update_records(pool).await?;
+        }
+
+        Ok(())
+    }
+
+    // Link a drop set to the set it drops from. The parent has to be an earlier set of
+    // the same exercise instance, which also keeps the links from forming cycles.
+    #[instrument]
+    pub async fn set_parent(
+        &mut self,
+        parent_set_id: Option<Uuid>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating set parent");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        if let Some(parent_set_id) = parent_set_id {
+            if self.kind != SetKind::Drop {
+                return Err(RouteError::new(
+                    "Only drop sets can have a parent set.",
+                    Some("parent_set_id"),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+
+            let parent = sqlx::query!(
+                "SELECT id FROM sets WHERE id = $1 AND user_id = $2 AND exercise_instance_id = $3 AND position < (SELECT position FROM sets WHERE id = $4)",
+                parent_set_id,
+                self.user_id,
+                self.exercise_instance_id,
+                self.id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            if parent.is_none() {
+                return Err(RouteError::new(
+                    "Parent has to be an earlier set of the same exercise instance.",
+                    Some("parent_set_id"),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        }
+
+        self.parent_set_id = sqlx::query!(
+            "UPDATE sets SET parent_set_id = $1 WHERE id = $2 RETURNING parent_set_id;",
+            parent_set_id,
+            self.id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .parent_set_id;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Move to the position in the exercise instance, the sets in between move by one
+    #[instrument]
+    pub async fn set_position(&mut self, position: i32, pool: &PgPool) -> Result<(), RouteError> {
+        info!("Moving set");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        self.position = position::move_to(
+            Ordered::Sets,
+            self.id,
+            self.exercise_instance_id,
+            position,
+            &mut tx,
+        )
+        .await?;
+
+        self.unlink_later_parents(&mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Swap places with another set of the same exercise instance
+    #[instrument]
+    pub async fn swap_position(
+        &mut self,
+        other_set_id: Uuid,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Swapping sets");
+
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        self.position = position::swap(
+            Ordered::Sets,
+            self.id,
+            other_set_id,
+            self.exercise_instance_id,
+            &mut tx,
+        )
+        .await?;
+
+        self.unlink_later_parents(&mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Drop sets moved before the sets they drop from aren't linked to them anymore
+    async fn unlink_later_parents(
+        &mut self,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<(), RouteError> {
+        let unlinked: Vec<Uuid> = sqlx::query_scalar(
+            r#"
+            UPDATE sets SET parent_set_id = NULL FROM sets parent
+            WHERE sets.exercise_instance_id = $1 AND parent.id = sets.parent_set_id
+                AND parent.position > sets.position
+            RETURNING sets.id
+            "#,
+        )
+        .bind(self.exercise_instance_id)
+        .fetch_all(&mut **tx)
+        .await?;
+
+        if unlinked.contains(&self.id) {
+            self.parent_set_id = None;
+        }
+
         Ok(())
     }
 
@@ -175,16 +499,149 @@ impl Set {
     async fn set_completed_state(&mut self, state: bool, pool: &PgPool) -> Result<(), RouteError> {
         info!("Updating set completion state");
 
-        self.completed = sqlx::query!(
-            "UPDATE sets SET completed = $1 WHERE id = $2 RETURNING completed;",
+        let mut tx = pool.begin().await?;
+
+        session::ensure_unlocked_by_exercise_instance(self.exercise_instance_id, &mut tx).await?;
+
+        // Completing an already completed set keeps the original time
+        let updated = sqlx::query!(
+            "UPDATE sets SET completed = $1, completed_at = CASE WHEN $1 THEN COALESCE(completed_at, NOW()) END WHERE id = $2 RETURNING completed, completed_at;",
             state,
             self.id
         )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.completed = updated.completed;
+        self.completed_at = updated.completed_at;
+
+        self.update_records(pool).await
+    }
+
+    // Recompute the personal records of the exercise this set is an instance of
+    #[instrument]
+    async fn update_records(&self, pool: &PgPool) -> Result<(), RouteError> {
+        let exercise_id = sqlx::query_scalar!(
+            "SELECT exercise_id FROM exercise_instances WHERE id = $1",
+            self.exercise_instance_id
+        )
         .fetch_one(pool)
-        .await?
-        .completed;
+        .await?;
 
-        Ok(())
+        personal_record::recompute(self.user_id, exercise_id, pool).await
+    }
+}
+
+// Unit of a weight, all weights are stored in kilograms and converted when
+// they're given or shown in pounds
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema, sqlx::Type,
+)]
+#[sqlx(type_name = "weight_unit", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "lowercase")]
+pub enum WeightUnit {
+    #[default]
+    Kg,
+    Lb,
+}
+
+impl WeightUnit {
+    pub const KILOGRAMS_PER_POUND: f32 = 0.453_592_37;
+
+    // Convert a weight in this unit to kilograms
+    pub fn to_kilograms(self, weight: f32) -> f32 {
+        match self {
+            WeightUnit::Kg => weight,
+            WeightUnit::Lb => weight * Self::KILOGRAMS_PER_POUND,
+        }
+    }
+
+    // Round a given weight to 0.1 of this unit before converting it, so the stored
+    // kilograms are shown as the same weight in this unit
+    pub fn rounded_to_kilograms(self, weight: f32) -> Result<f32, RouteError> {
+        Ok(self.to_kilograms(round_weight(weight)?))
+    }
+
+    // Convert a weight in kilograms to this unit. Converted weights are rounded
+    // to 0.1 of the unit, so a weight entered in it is shown the same way.
+    pub fn convert_kilograms(self, weight: f32) -> f32 {
+        match self {
+            WeightUnit::Kg => weight,
+            WeightUnit::Lb => {
+                let converted = self.convert_kilograms_exact(weight);
+
+                round_weight(converted).unwrap_or(converted)
+            }
+        }
+    }
+
+    // Convert a value computed from kilograms, like an estimate or volume, without rounding
+    pub fn convert_kilograms_exact(self, value: f32) -> f32 {
+        match self {
+            WeightUnit::Kg => value,
+            WeightUnit::Lb => value / Self::KILOGRAMS_PER_POUND,
+        }
+    }
+}
+
+// Weights in responses are converted from kilograms to the unit of the user
+pub trait ConvertWeights {
+    fn convert_weights(&mut self, unit: WeightUnit);
+
+    fn in_unit(mut self, unit: WeightUnit) -> Self
+    where
+        Self: Sized,
+    {
+        self.convert_weights(unit);
+        self
+    }
+}
+
+impl<T: ConvertWeights> ConvertWeights for Vec<T> {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        for item in self {
+            item.convert_weights(unit);
+        }
+    }
+}
+
+impl ConvertWeights for Set {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        self.weight = self.weight.map(|weight| unit.convert_kilograms(weight));
+    }
+}
+
+// RPE is from 6 to 10 in half steps, like 8.5 (checked by db)
+pub fn is_valid_rpe(rpe: f32) -> bool {
+    (6.0..=10.0).contains(&rpe) && (rpe * 2.0).fract() == 0.0
+}
+
+// Round a weight to one decimal, so 0.1kg accuracy
+// All this just to round it to one decimal reliably...
+#[instrument]
+pub fn round_weight(weight: f32) -> Result<f32, RouteError> {
+    if let Some(decimal) = Decimal::from_f32(weight) {
+        if let Some(rounded) = decimal.round_dp(1).to_f32() {
+            Ok(rounded)
+        } else {
+            error!("Failed to round Decimal to f32: {}", decimal);
+
+            Err(RouteError::new(
+                "Failed to process decimal number.",
+                Some("weight"),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    } else {
+        error!("Failed to convert f32 to Decimal: {}", weight);
+
+        Err(RouteError::new(
+            "Failed to process decimal number.",
+            Some("weight"),
+            StatusCode::BAD_REQUEST,
+        ))
     }
 }
 
@@ -200,7 +657,7 @@ pub async fn all_from_exercise_instance_id(
     info!("Querying all sets of one exercise instance");
 
     Ok(sqlx::query_as(
-        "SELECT * FROM sets WHERE user_id = $1 AND exercise_instance_id = $2 ORDER BY created",
+        "SELECT * FROM sets WHERE user_id = $1 AND exercise_instance_id = $2 ORDER BY position",
     )
     .bind(user_id)
     .bind(exercise_instance_id)
@@ -208,6 +665,24 @@ pub async fn all_from_exercise_instance_id(
     .await?)
 }
 
+// Get all sets of multiple exercise instances in one query
+#[instrument]
+pub async fn all_from_exercise_instance_ids(
+    user_id: Uuid,
+    exercise_instance_ids: &[Uuid],
+    pool: &PgPool,
+) -> Result<Vec<Set>, RouteError> {
+    info!("Querying all sets of multiple exercise instances");
+
+    Ok(sqlx::query_as(
+        "SELECT * FROM sets WHERE user_id = $1 AND exercise_instance_id = ANY($2) ORDER BY position",
+    )
+    .bind(user_id)
+    .bind(exercise_instance_ids)
+    .fetch_all(pool)
+    .await?)
+}
+
 #[cfg(test)]
 mod tests {
     use sqlx::PgPool;
@@ -225,7 +700,7 @@ mod tests {
     use super::*;
 
     async fn create_test_set(pool: &PgPool) -> (User, Exercise, Session, ExerciseInstance, Set) {
-        let user = create_test_user(&pool).await;
+        let user = create_test_user(pool).await;
 
         let new_exercise = Exercise::new(
             user.id,
@@ -240,16 +715,16 @@ mod tests {
         .unwrap();
 
         let new_session: Session =
-            Session::new(user.id, "Test sessions", Some("Test description"), &pool)
+            Session::new(user.id, "Test sessions", Some("Test description"), pool)
                 .await
                 .unwrap();
 
         let new_exercise_instance: ExerciseInstance =
-            ExerciseInstance::new(user.id, new_session.id, new_exercise.id, &pool)
+            ExerciseInstance::new(user.id, new_session.id, new_exercise.id, None, pool)
                 .await
                 .unwrap();
 
-        let new_set: Set = Set::new(user.id, new_exercise_instance.id, &pool)
+        let new_set: Set = Set::new(user.id, new_exercise_instance.id, None, pool)
             .await
             .unwrap();
 
@@ -276,6 +751,30 @@ mod tests {
         query.sets
     }
 
+    #[test]
+    fn included_set_kinds() {
+        assert_eq!(included_kinds(None, None, &[]), SetKind::ALL.to_vec());
+        assert!(!included_kinds(None, None, &[SetKind::WarmUp]).contains(&SetKind::WarmUp));
+
+        // Excluding anything replaces the defaults
+        assert_eq!(
+            included_kinds(None, Some(&[SetKind::Drop]), &[SetKind::WarmUp]).len(),
+            4
+        );
+        assert_eq!(
+            included_kinds(
+                Some(&[SetKind::Working, SetKind::Drop]),
+                Some(&[SetKind::Drop]),
+                &[]
+            ),
+            vec![SetKind::Working]
+        );
+        assert_eq!(
+            included_kinds(Some(&[SetKind::WarmUp]), None, &[SetKind::WarmUp]),
+            vec![SetKind::WarmUp]
+        );
+    }
+
     #[sqlx::test]
     async fn create_and_query(pool: PgPool) {
         let (user, _, _, exercise_instance, set) = create_test_set(&pool).await;
@@ -287,7 +786,7 @@ mod tests {
         // Queried by an instance, because they are included in it
         let query_sets = query_test_sets(&user, &exercise_instance, &pool).await;
 
-        assert_eq!(query_sets.get(0).unwrap().to_owned(), set);
+        assert_eq!(query_sets.first().unwrap().to_owned(), set);
         assert_eq!(query_sets.len(), 1);
     }
 
@@ -314,7 +813,7 @@ mod tests {
             weight,
             query_test_sets(&user, &exercise_instance, &pool)
                 .await
-                .get(0)
+                .first()
                 .unwrap()
                 .weight
         );
@@ -332,7 +831,7 @@ mod tests {
             reps,
             query_test_sets(&user, &exercise_instance, &pool)
                 .await
-                .get(0)
+                .first()
                 .unwrap()
                 .reps
         );
@@ -354,7 +853,7 @@ mod tests {
         assert!(
             query_test_sets(&user, &exercise_instance, &pool)
                 .await
-                .get(0)
+                .first()
                 .unwrap()
                 .completed
         );
@@ -369,9 +868,40 @@ mod tests {
         assert!(
             !query_test_sets(&user, &exercise_instance, &pool)
                 .await
-                .get(0)
+                .first()
                 .unwrap()
                 .completed
         );
     }
+
+    // Sets created at the same time still get their own positions
+    #[sqlx::test]
+    async fn concurrent_positions(pool: PgPool) {
+        let (user, _, _, exercise_instance, set) = create_test_set(&pool).await;
+
+        let created = futures::future::join_all(
+            (0..10).map(|_| Set::new(user.id, exercise_instance.id, Some(0), &pool)),
+        )
+        .await;
+
+        assert!(created.iter().all(|set| set.is_ok()));
+
+        let sets = query_test_sets(&user, &exercise_instance, &pool).await;
+        let positions: Vec<i32> = sets.iter().map(|set| set.position).collect();
+
+        assert_eq!(positions, (0..11).collect::<Vec<i32>>());
+        // Each one was inserted first
+        assert_eq!(sets.last().unwrap().id, set.id);
+
+        // Deleting closes the gap
+        sets[0].clone().delete(&pool).await.unwrap();
+
+        let positions: Vec<i32> = query_test_sets(&user, &exercise_instance, &pool)
+            .await
+            .iter()
+            .map(|set| set.position)
+            .collect();
+
+        assert_eq!(positions, (0..10).collect::<Vec<i32>>());
+    }
 }
@@ -0,0 +1,525 @@
+use std::fmt::{Debug, Display};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::{
+    session::Session,
+    set::{ConvertWeights, WeightUnit},
+};
+
+// A reusable routine, which can be turned into a new session with
+// all the exercise instances and sets already in place
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
+pub struct Template {
+    // Primary key
+    pub id: Uuid,
+    // These are user specific
+    pub user_id: Uuid,
+    // Used as the name of sessions created from the template
+    pub name: String,
+    // Copied to sessions created from the template
+    pub description: Option<String>,
+    // Static once created
+    pub created: DateTime<Utc>,
+    // The planned exercises, ordered by their position
+    #[sqlx(skip)]
+    pub exercises: Vec<TemplateExercise>,
+}
+
+// One planned exercise in a template, which becomes an exercise instance
+// with the planned amount of sets
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema, FromRow)]
+pub struct TemplateExercise {
+    // Primary key
+    pub id: Uuid,
+    // These are user specific
+    pub user_id: Uuid,
+    // The template this belongs to
+    pub template_id: Uuid,
+    // The predefined exercise being planned
+    pub exercise_id: Uuid,
+    // Index in the template, starting from 0
+    pub position: i32,
+    // How many sets are created in the exercise instance
+    pub sets: i32,
+    // Target reps prefilled to every set
+    pub reps: Option<i32>,
+    // Target weight in kilograms prefilled to every set
+    pub weight: Option<f32>,
+}
+
+// Input for one planned exercise, the position is based on the order they are given in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct PlannedExercise {
+    pub exercise_id: Uuid,
+    #[schema(example = 3)]
+    pub sets: i32,
+    #[schema(example = 8)]
+    pub reps: Option<i32>,
+    // In the unit of the request, stored in kilograms
+    #[schema(example = 60.0)]
+    pub weight: Option<f32>,
+}
+
+impl PlannedExercise {
+    // The weight is given in the unit of the user, but stored in kilograms
+    pub fn in_kilograms(mut self, unit: WeightUnit) -> Result<Self, RouteError> {
+        self.weight = self
+            .weight
+            .map(|weight| unit.rounded_to_kilograms(weight))
+            .transpose()?;
+
+        Ok(self)
+    }
+}
+
+impl ConvertWeights for Template {
+    fn convert_weights(&mut self, unit: WeightUnit) {
+        for exercise in &mut self.exercises {
+            exercise.weight = exercise.weight.map(|weight| unit.convert_kilograms(weight));
+        }
+    }
+}
+
+impl Template {
+    // Create a new template with the given exercises in order
+    #[instrument]
+    pub async fn new(
+        user_id: Uuid,
+        name: impl ToString + Display + Debug,
+        description: Option<impl ToString + Display + Debug>,
+        exercises: Vec<PlannedExercise>,
+        pool: &PgPool,
+    ) -> Result<Self, RouteError> {
+        info!("Creating a new template '{}'", name);
+
+        // Checked before anything is created, so an empty template isn't left behind
+        let exercises = prepare_planned_exercises(user_id, exercises, pool).await?;
+
+        let mut template: Template = sqlx::query_as(
+            "INSERT INTO templates (user_id, name, description) VALUES ($1, $2, $3) RETURNING *",
+        )
+        .bind(user_id)
+        .bind(name.to_string())
+        .bind(description.map(|i| i.to_string()))
+        .fetch_one(pool)
+        .await?;
+
+        template.replace_exercises(exercises, pool).await?;
+
+        Ok(template)
+    }
+
+    // Get a template from IDs, also fills the planned exercises
+    #[instrument]
+    pub async fn from_id(user_id: Uuid, id: Uuid, pool: &PgPool) -> Result<Self, RouteError> {
+        info!("Querying template from ID");
+
+        let mut template: Template =
+            sqlx::query_as("SELECT * FROM templates WHERE user_id = $1 AND id = $2")
+                .bind(user_id)
+                .bind(id)
+                .fetch_one(pool)
+                .await?;
+
+        template.exercises = all_from_template_id(user_id, template.id, pool).await?;
+
+        Ok(template)
+    }
+
+    // Overwrites the name
+    #[instrument]
+    pub async fn set_name(
+        &mut self,
+        name: impl ToString + Display + Debug,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Renaming template from '{}' to '{}'", self.name, name);
+
+        self.name = sqlx::query!(
+            "UPDATE templates SET name = $1 WHERE id = $2 RETURNING name",
+            name.to_string(),
+            self.id
+        )
+        .fetch_one(pool)
+        .await?
+        .name;
+
+        Ok(())
+    }
+
+    // Overwrites the description
+    #[instrument]
+    pub async fn set_description(
+        &mut self,
+        description: Option<impl ToString + Display + Debug>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Updating template description");
+
+        self.description = sqlx::query!(
+            "UPDATE templates SET description = $1 WHERE id = $2 RETURNING description",
+            description.map(|i| i.to_string()),
+            self.id
+        )
+        .fetch_one(pool)
+        .await?
+        .description;
+
+        Ok(())
+    }
+
+    // Replaces all planned exercises with the given ones, in the given order
+    #[instrument]
+    pub async fn set_exercises(
+        &mut self,
+        exercises: Vec<PlannedExercise>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!("Replacing the exercises of a template");
+
+        let exercises = prepare_planned_exercises(self.user_id, exercises, pool).await?;
+
+        self.replace_exercises(exercises, pool).await
+    }
+
+    // Helper to overwrite the planned exercises in one transaction,
+    // input has to be checked with prepare_planned_exercises first
+    #[instrument]
+    async fn replace_exercises(
+        &mut self,
+        exercises: Vec<PlannedExercise>,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        let mut tx = pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM template_exercises WHERE template_id = $1",
+            self.id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let mut inserted = Vec::with_capacity(exercises.len());
+
+        for (position, planned) in exercises.into_iter().enumerate() {
+            inserted.push(
+                sqlx::query_as(
+                    "INSERT INTO template_exercises (user_id, template_id, exercise_id, position, sets, reps, weight) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
+                )
+                .bind(self.user_id)
+                .bind(self.id)
+                .bind(planned.exercise_id)
+                .bind(position as i32)
+                .bind(planned.sets)
+                .bind(planned.reps)
+                .bind(planned.weight)
+                .fetch_one(&mut *tx)
+                .await?,
+            );
+        }
+
+        tx.commit().await?;
+
+        self.exercises = inserted;
+
+        Ok(())
+    }
+
+    // Deletes the template and the planned exercises,
+    // sessions created from it are not affected
+    #[instrument]
+    pub async fn delete(self, pool: &PgPool) -> Result<Uuid, RouteError> {
+        info!("Deleting template (self)");
+
+        sqlx::query!("DELETE FROM templates WHERE id = $1", self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(self.id)
+    }
+
+    // Creates a new started session with an exercise instance for each planned exercise,
+    // which have the planned amount of uncompleted sets with the target reps and weight.
+    // Everything is created in one transaction, so a partial session is never left behind.
+    #[instrument]
+    pub async fn start_session(&self, pool: &PgPool) -> Result<Session, RouteError> {
+        info!("Creating a new session from template '{}'", self.name);
+
+        let mut tx = pool.begin().await?;
+
+        let session_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO sessions (user_id, name, description) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(self.user_id)
+        .bind(&self.name)
+        .bind(&self.description)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Instances and sets are in the order of the template
+        for (position, planned) in self.exercises.iter().enumerate() {
+            let exercise_instance_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO exercise_instances (user_id, session_id, exercise_id, position) VALUES ($1, $2, $3, $4) RETURNING id",
+            )
+            .bind(self.user_id)
+            .bind(session_id)
+            .bind(planned.exercise_id)
+            .bind(position as i32)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            for set_position in 0..planned.sets {
+                sqlx::query(
+                    "INSERT INTO sets (user_id, exercise_instance_id, weight, reps, position) VALUES ($1, $2, $3, $4, $5)",
+                )
+                .bind(self.user_id)
+                .bind(exercise_instance_id)
+                .bind(planned.weight)
+                .bind(planned.reps)
+                .bind(set_position)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Session::from_id(self.user_id, session_id, pool).await
+    }
+}
+
+// Helper to check that all planned exercises are owned by the user
+#[instrument]
+async fn prepare_planned_exercises(
+    user_id: Uuid,
+    exercises: Vec<PlannedExercise>,
+    pool: &PgPool,
+) -> Result<Vec<PlannedExercise>, RouteError> {
+    let mut exercise_ids: Vec<Uuid> = exercises.iter().map(|e| e.exercise_id).collect();
+    exercise_ids.sort();
+    exercise_ids.dedup();
+
+    let owned_count = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM exercises WHERE user_id = $1 AND id = ANY($2)",
+        user_id,
+        &exercise_ids
+    )
+    .fetch_one(pool)
+    .await?
+    .unwrap_or(0);
+
+    if owned_count != exercise_ids.len() as i64 {
+        return Err(RouteError::new(
+            "Invalid exercise ID",
+            Some("exercises"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    Ok(exercises)
+}
+
+// Helper to get the planned exercises of a template in order
+//
+// WARNING: User ownership of template IS NOT CHECKED
+#[instrument]
+pub async fn all_from_template_id(
+    user_id: Uuid,
+    template_id: Uuid,
+    pool: &PgPool,
+) -> Result<Vec<TemplateExercise>, RouteError> {
+    info!("Querying all exercises of one template");
+
+    Ok(sqlx::query_as(
+        "SELECT * FROM template_exercises WHERE user_id = $1 AND template_id = $2 ORDER BY position",
+    )
+    .bind(user_id)
+    .bind(template_id)
+    .fetch_all(pool)
+    .await?)
+}
+
+// Get all templates of an user with their planned exercises
+#[instrument]
+pub async fn all_user_templates(user_id: Uuid, pool: &PgPool) -> Result<Vec<Template>, RouteError> {
+    info!("Querying all templates of one user");
+
+    let mut templates: Vec<Template> =
+        sqlx::query_as("SELECT * FROM templates WHERE user_id = $1 ORDER BY name")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+    for template in &mut templates {
+        template.exercises = all_from_template_id(user_id, template.id, pool).await?;
+    }
+
+    Ok(templates)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use crate::{
+        models::{
+            exercise::{Exercise, ExerciseKind},
+            user::User,
+        },
+        test_utils::api::create_test_user,
+    };
+
+    use super::*;
+
+    async fn create_test_template(pool: &PgPool) -> (User, Exercise, Template) {
+        let user = create_test_user(pool).await;
+
+        let exercise = Exercise::new(
+            user.id,
+            "Bench press",
+            None::<&str>,
+            false,
+            None::<&str>,
+            ExerciseKind::Barbell,
+            pool,
+        )
+        .await
+        .unwrap();
+
+        let template = Template::new(
+            user.id,
+            "Push",
+            Some("Push day"),
+            vec![
+                PlannedExercise {
+                    exercise_id: exercise.id,
+                    sets: 3,
+                    reps: Some(8),
+                    weight: Some(60.0),
+                },
+                PlannedExercise {
+                    exercise_id: exercise.id,
+                    sets: 2,
+                    reps: None,
+                    weight: None,
+                },
+            ],
+            pool,
+        )
+        .await
+        .unwrap();
+
+        (user, exercise, template)
+    }
+
+    #[sqlx::test]
+    async fn create_and_query(pool: PgPool) {
+        let (user, _, template) = create_test_template(&pool).await;
+
+        let queried = Template::from_id(user.id, template.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(queried, template);
+        assert_eq!(queried.exercises.len(), 2);
+        assert_eq!(queried.exercises[0].position, 0);
+        assert_eq!(queried.exercises[1].position, 1);
+        assert_eq!(queried.exercises[0].weight, Some(60.0));
+    }
+
+    #[sqlx::test]
+    async fn invalid_exercise(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        let result = Template::new(
+            user.id,
+            "Push",
+            None::<&str>,
+            vec![PlannedExercise {
+                exercise_id: Uuid::new_v4(),
+                sets: 3,
+                reps: None,
+                weight: None,
+            }],
+            &pool,
+        )
+        .await;
+
+        assert!(result.is_err());
+
+        // Nothing should be left behind
+        assert!(all_user_templates(user.id, &pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn replace_exercises(pool: PgPool) {
+        let (user, exercise, mut template) = create_test_template(&pool).await;
+
+        template
+            .set_exercises(
+                vec![PlannedExercise {
+                    exercise_id: exercise.id,
+                    sets: 5,
+                    reps: Some(5),
+                    weight: Some(100.0),
+                }],
+                &pool,
+            )
+            .await
+            .unwrap();
+
+        let queried = Template::from_id(user.id, template.id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(queried.exercises.len(), 1);
+        assert_eq!(queried.exercises[0].sets, 5);
+    }
+
+    #[sqlx::test]
+    async fn start_session(pool: PgPool) {
+        let (_, exercise, template) = create_test_template(&pool).await;
+
+        let session = template.start_session(&pool).await.unwrap();
+
+        assert_eq!(session.name, template.name);
+        assert_eq!(session.description, template.description);
+        assert!(!session.is_finished());
+        assert_eq!(session.exercise_instances.len(), 2);
+
+        let first = &session.exercise_instances[0];
+
+        assert_eq!(first.exercise_id, exercise.id);
+        assert_eq!(first.sets.len(), 3);
+
+        for set in &first.sets {
+            assert!(!set.completed);
+            assert_eq!(set.reps, Some(8));
+            assert_eq!(set.weight, Some(60.0));
+        }
+
+        assert_eq!(session.exercise_instances[1].sets.len(), 2);
+    }
+
+    #[sqlx::test]
+    async fn delete(pool: PgPool) {
+        let (user, _, template) = create_test_template(&pool).await;
+
+        let template_id = template.id;
+
+        template.delete(&pool).await.unwrap();
+
+        assert!(Template::from_id(user.id, template_id, &pool)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,594 @@
+use std::fmt::Debug;
+
+use axum::http::StatusCode;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::{PgPool, Postgres, Transaction};
+use subtle::ConstantTimeEq;
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::api::response::RouteError;
+
+use super::access_token::{hash_token, token_matches};
+
+const ISSUER: &str = "LiftLog";
+const SECRET_LENGTH: usize = 20;
+const DIGITS: u32 = 6;
+const STEP_SECONDS: i64 = 30;
+// Codes of the previous and the next step are accepted for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+// Invalid codes allowed per user before the codes are locked for a while
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINUTES: i32 = 15;
+
+// Returned when starting to enable two-factor authentication.
+// The secret is also in the URI, for apps which can't read QR codes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct TotpEnrollment {
+    // Base32 without padding
+    pub secret: String,
+    #[schema(example = "otpauth://totp/LiftLog:some_username?secret=...&issuer=LiftLog")]
+    pub otpauth_uri: String,
+}
+
+// Start enabling, replacing an earlier unfinished attempt.
+// Not enabled until a code is verified with verify_enrollment.
+#[instrument]
+pub async fn start_enrollment(
+    user_id: Uuid,
+    username: &str,
+    pool: &PgPool,
+) -> Result<TotpEnrollment, RouteError> {
+    info!("Starting two-factor authentication enrollment");
+
+    let secret: Vec<u8> = (0..SECRET_LENGTH).map(|_| rand::random::<u8>()).collect();
+
+    let replaced = sqlx::query!(
+        r#"
+        INSERT INTO totp_credentials (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET secret = $2, created = NOW(), last_used_step = NULL
+        WHERE totp_credentials.enabled = FALSE
+        "#,
+        user_id,
+        secret
+    )
+    .execute(pool)
+    .await?;
+
+    if replaced.rows_affected() == 0 {
+        return Err(RouteError::new(
+            "Two-factor authentication is already enabled.",
+            None::<&str>,
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let encoded_secret = base32(&secret);
+
+    Ok(TotpEnrollment {
+        otpauth_uri: format!(
+            "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+            issuer = ISSUER,
+            username = urlencoding::encode(username),
+            secret = encoded_secret,
+        ),
+        secret: encoded_secret,
+    })
+}
+
+// Enable with the first code from the app, returns the recovery codes
+// which are only shown this once
+#[instrument(skip(code))]
+pub async fn verify_enrollment(
+    user_id: Uuid,
+    code: &str,
+    pool: &PgPool,
+) -> Result<Vec<String>, RouteError> {
+    info!("Verifying two-factor authentication enrollment");
+
+    let mut tx = pool.begin().await?;
+
+    let credential = sqlx::query!(
+        r#"
+        SELECT secret, enabled, COALESCE(locked_until > NOW(), FALSE) AS "locked!"
+        FROM totp_credentials WHERE user_id = $1 FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let secret = match credential {
+        Some(credential) if credential.locked => return Err(locked_error()),
+        Some(credential) if !credential.enabled => credential.secret,
+        Some(_) => {
+            return Err(RouteError::new(
+                "Two-factor authentication is already enabled.",
+                None::<&str>,
+                StatusCode::CONFLICT,
+            ))
+        }
+        None => {
+            return Err(RouteError::new(
+                "Two-factor authentication enrollment has not been started.",
+                None::<&str>,
+                StatusCode::NOT_FOUND,
+            ))
+        }
+    };
+
+    let Some(step) = matching_step(&secret, code, Utc::now().timestamp(), None) else {
+        record_failed_attempt(user_id, &mut tx).await?;
+        tx.commit().await?;
+
+        return Err(invalid_code_error());
+    };
+
+    sqlx::query!(
+        "UPDATE totp_credentials SET enabled = TRUE, last_used_step = $1, failed_attempts = 0 WHERE user_id = $2",
+        step,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    for code in &codes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            user_id,
+            hash_token(code)
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+// Disabling requires a valid code, so a stolen access token isn't enough
+#[instrument(skip(code))]
+pub async fn disable(user_id: Uuid, code: &str, pool: &PgPool) -> Result<(), RouteError> {
+    info!("Disabling two-factor authentication");
+
+    let mut tx = pool.begin().await?;
+
+    if !check_code(user_id, code, &mut tx).await? {
+        tx.commit().await?;
+
+        return Err(invalid_code_error());
+    }
+
+    sqlx::query!("DELETE FROM totp_credentials WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[instrument]
+pub async fn is_enabled(user_id: Uuid, pool: &PgPool) -> Result<bool, RouteError> {
+    Ok(sqlx::query_scalar!(
+        "SELECT enabled FROM totp_credentials WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .unwrap_or(false))
+}
+
+// Second step of logging in, after the password has been checked.
+// Missing code is forbidden instead of unauthorized, so clients can
+// ask for it without it being counted as a failed login.
+#[instrument(skip(code))]
+pub async fn verify_login(
+    user_id: Uuid,
+    code: Option<&str>,
+    pool: &PgPool,
+) -> Result<(), RouteError> {
+    if !is_enabled(user_id, pool).await? {
+        return Ok(());
+    }
+
+    match code {
+        Some(code) => {
+            let mut tx = pool.begin().await?;
+
+            let valid = check_code(user_id, code, &mut tx).await?;
+
+            tx.commit().await?;
+
+            match valid {
+                true => Ok(()),
+                false => Err(invalid_code_error()),
+            }
+        }
+        None => Err(RouteError::new(
+            "Two-factor authentication code is required.",
+            Some("code"),
+            StatusCode::FORBIDDEN,
+        )),
+    }
+}
+
+// Accepts a code from the app or an unused recovery code, which is then used up.
+// Invalid codes are counted in the transaction, which has to be committed either way.
+#[instrument(skip(code, tx))]
+async fn check_code(
+    user_id: Uuid,
+    code: &str,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<bool, RouteError> {
+    let credential = sqlx::query!(
+        r#"
+        SELECT secret, last_used_step, COALESCE(locked_until > NOW(), FALSE) AS "locked!"
+        FROM totp_credentials WHERE user_id = $1 AND enabled = TRUE FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or_else(|| {
+        RouteError::new(
+            "Two-factor authentication is not enabled.",
+            None::<&str>,
+            StatusCode::NOT_FOUND,
+        )
+    })?;
+
+    if credential.locked {
+        return Err(locked_error());
+    }
+
+    let code = code.trim().to_lowercase();
+
+    if let Some(step) = matching_step(
+        &credential.secret,
+        &code,
+        Utc::now().timestamp(),
+        credential.last_used_step,
+    ) {
+        sqlx::query!(
+            "UPDATE totp_credentials SET last_used_step = $1, failed_attempts = 0 WHERE user_id = $2",
+            step,
+            user_id
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        return Ok(true);
+    }
+
+    let recovery_codes = sqlx::query!(
+        "SELECT id, code_hash FROM recovery_codes WHERE user_id = $1 AND used IS NULL FOR UPDATE",
+        user_id
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    match recovery_codes
+        .into_iter()
+        .find(|recovery_code| token_matches(&code, &recovery_code.code_hash))
+    {
+        Some(recovery_code) => {
+            info!("Recovery code used");
+
+            sqlx::query!(
+                "UPDATE recovery_codes SET used = NOW() WHERE id = $1",
+                recovery_code.id
+            )
+            .execute(&mut **tx)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE totp_credentials SET failed_attempts = 0 WHERE user_id = $1",
+                user_id
+            )
+            .execute(&mut **tx)
+            .await?;
+
+            Ok(true)
+        }
+        None => {
+            warn!("Invalid two-factor authentication code");
+
+            record_failed_attempt(user_id, tx).await?;
+
+            Ok(false)
+        }
+    }
+}
+
+// Locks the codes of the user after too many invalid ones,
+// the count starts over after the lockout
+async fn record_failed_attempt(
+    user_id: Uuid,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), RouteError> {
+    sqlx::query!(
+        r#"
+        UPDATE totp_credentials SET
+            failed_attempts = CASE WHEN failed_attempts + 1 >= $1 THEN 0 ELSE failed_attempts + 1 END,
+            locked_until = CASE WHEN failed_attempts + 1 >= $1 THEN NOW() + make_interval(mins => $2) ELSE locked_until END
+        WHERE user_id = $3
+        "#,
+        MAX_FAILED_ATTEMPTS,
+        LOCKOUT_MINUTES,
+        user_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+fn locked_error() -> RouteError {
+    RouteError::new(
+        format!(
+            "Too many invalid two-factor authentication codes, try again in {} minutes.",
+            LOCKOUT_MINUTES
+        ),
+        Some("code"),
+        StatusCode::TOO_MANY_REQUESTS,
+    )
+}
+
+fn invalid_code_error() -> RouteError {
+    RouteError::new(
+        "Invalid two-factor authentication code.",
+        Some("code"),
+        StatusCode::UNAUTHORIZED,
+    )
+}
+
+// The time step the code is valid for, if any. Steps up to the last used one
+// are rejected so a code can't be replayed.
+fn matching_step(
+    secret: &[u8],
+    code: &str,
+    unix_time: i64,
+    last_used_step: Option<i64>,
+) -> Option<i64> {
+    let current_step = unix_time.div_euclid(STEP_SECONDS);
+
+    (current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last_used| *step > last_used))
+        .find(|step| {
+            hotp(secret, *step as u64)
+                .as_bytes()
+                .ct_eq(code.as_bytes())
+                .into()
+        })
+}
+
+// RFC 6238 code for the given time
+pub fn totp_at(secret: &[u8], unix_time: i64) -> String {
+    hotp(secret, unix_time.div_euclid(STEP_SECONDS) as u64)
+}
+
+// RFC 4226 code for the counter, zero padded
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+// RFC 4648 base32 without padding, which authenticator apps expect
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+// Two groups of five, easy to write down
+fn generate_recovery_code() -> String {
+    let characters: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(|character| (character as char).to_ascii_lowercase())
+        .collect();
+
+    format!("{}-{}", &characters[..5], &characters[5..])
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use sqlx::PgPool;
+
+    use crate::test_utils::api::create_test_user;
+
+    use super::*;
+
+    // Secret of the RFC 6238 test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_test_vectors() {
+        // The RFC has 8 digit codes, these are the last 6 digits of them
+        assert_eq!(totp_at(RFC_SECRET, 59), "287082");
+        assert_eq!(totp_at(RFC_SECRET, 1111111109), "081804");
+        assert_eq!(totp_at(RFC_SECRET, 1234567890), "005924");
+        assert_eq!(totp_at(RFC_SECRET, 2000000000), "279037");
+    }
+
+    #[test]
+    fn drift_and_replay() {
+        let now = 1111111109;
+        let step = now / STEP_SECONDS;
+
+        let previous = totp_at(RFC_SECRET, now - STEP_SECONDS);
+        let too_old = totp_at(RFC_SECRET, now - 2 * STEP_SECONDS);
+
+        assert_eq!(
+            matching_step(RFC_SECRET, &previous, now, None),
+            Some(step - 1)
+        );
+        assert_eq!(matching_step(RFC_SECRET, &too_old, now, None), None);
+        // Already used
+        assert_eq!(
+            matching_step(RFC_SECRET, &previous, now, Some(step - 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn base32_encoding() {
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[sqlx::test]
+    async fn enroll_and_login(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        assert!(verify_login(user.id, None, &pool).await.is_ok());
+
+        start_enrollment(user.id, &user.username, &pool)
+            .await
+            .unwrap();
+        let enrollment = start_enrollment(user.id, &user.username, &pool)
+            .await
+            .unwrap();
+
+        assert!(enrollment.otpauth_uri.contains(&enrollment.secret));
+        // Not enabled before verifying
+        assert!(verify_login(user.id, None, &pool).await.is_ok());
+
+        let secret = sqlx::query_scalar!(
+            "SELECT secret FROM totp_credentials WHERE user_id = $1",
+            user.id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert!(verify_enrollment(user.id, "000000x", &pool).await.is_err());
+
+        let codes = verify_enrollment(user.id, &totp_at(&secret, Utc::now().timestamp()), &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(start_enrollment(user.id, &user.username, &pool)
+            .await
+            .is_err());
+
+        assert!(verify_login(user.id, None, &pool).await.is_err());
+        assert!(verify_login(user.id, Some("not a code"), &pool)
+            .await
+            .is_err());
+
+        // Recovery codes work once
+        assert!(verify_login(user.id, Some(&codes[0]), &pool).await.is_ok());
+        assert!(verify_login(user.id, Some(&codes[0]), &pool).await.is_err());
+        assert!(verify_login(user.id, Some(&codes[1].to_uppercase()), &pool)
+            .await
+            .is_ok());
+
+        disable(user.id, &codes[2], &pool).await.unwrap();
+
+        assert!(!is_enabled(user.id, &pool).await.unwrap());
+        assert!(verify_login(user.id, None, &pool).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn lockout_after_invalid_codes(pool: PgPool) {
+        let user = create_test_user(&pool).await;
+
+        start_enrollment(user.id, &user.username, &pool)
+            .await
+            .unwrap();
+
+        let secret = sqlx::query_scalar!(
+            "SELECT secret FROM totp_credentials WHERE user_id = $1",
+            user.id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let codes = verify_enrollment(user.id, &totp_at(&secret, Utc::now().timestamp()), &pool)
+            .await
+            .unwrap();
+
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            let error = verify_login(user.id, Some("000000x"), &pool)
+                .await
+                .unwrap_err();
+
+            assert_eq!(error.into_response().status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Even valid codes are rejected during the lockout
+        let error = disable(user.id, &codes[0], &pool).await.unwrap_err();
+
+        assert_eq!(
+            error.into_response().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        sqlx::query!(
+            "UPDATE totp_credentials SET locked_until = NOW() WHERE user_id = $1",
+            user.id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // The recovery code wasn't used up
+        assert!(verify_login(user.id, Some(&codes[0]), &pool).await.is_ok());
+    }
+}
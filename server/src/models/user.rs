@@ -1,4 +1,4 @@
-use std::fmt::{Debug, Display};
+use std::fmt::{self, Debug, Display};
 
 use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
@@ -10,15 +10,25 @@ use axum::{
     http::{request::Parts, StatusCode},
     RequestPartsExt,
 };
-use axum_extra::{TypedHeader, headers::{Authorization, authorization::Bearer}};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
 use tracing::{debug, error, info, instrument};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::{api::response::RouteError, models::access_token::AccessToken};
+use crate::{
+    api::response::RouteError,
+    models::{
+        access_token::{self, AccessToken},
+        invite_code,
+        set::WeightUnit,
+    },
+    settings::Registration,
+};
+
+// Key of the advisory lock held while creating a user
+const SIGNUP_LOCK: i64 = 0x4c69_6674_4c6f_6701;
 
 // User for authentication and authorization
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
@@ -34,6 +44,33 @@ pub struct User {
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     pub password_hash: String,
+    // The first user of an instance is an admin
+    pub role: UserRole,
+    // Disabled users can't log in and their tokens and API keys stop working
+    pub disabled: bool,
+    // Optional, used to reset a forgotten password once verified
+    pub email: Option<String>,
+    pub email_verified: bool,
+    // Unit weights are shown and given in, they're stored in kilograms
+    pub weight_unit: WeightUnit,
+}
+
+// Admins can manage other users and see usage of the whole instance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "snake_case")]
+pub enum UserRole {
+    Admin,
+    User,
+}
+
+impl Display for UserRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserRole::Admin => write!(f, "admin"),
+            UserRole::User => write!(f, "user"),
+        }
+    }
 }
 
 impl User {
@@ -44,27 +81,79 @@ impl User {
         password: impl ToString + Debug,
         pool: &PgPool,
     ) -> Result<User, RouteError> {
-        info!("Trying to create a new user '{}'", username);
+        let mut tx = pool.begin().await?;
 
-        let username_string = username.to_string();
+        lock_signups(&mut tx).await?;
+        let new_user = create_user(username, password, &mut tx).await?;
 
-        // Check if username is available
-        is_username_available(&username_string, pool).await?;
+        tx.commit().await?;
 
-        // Hash the user's password
-        let hashed_password = hash_password(password.to_string())?;
+        Ok(new_user)
+    }
 
-        // Create the new user
-        let new_user = sqlx::query_as!(
-            User,
-            "INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING *",
-            username_string,
-            hashed_password
-        )
-        .fetch_one(pool)
-        .await?;
+    // Create a new user if the registration setting allows it.
+    // The first user can always sign up, so a fresh instance gets an admin.
+    #[instrument(skip(password, invite_code))]
+    pub async fn register(
+        username: impl ToString + Debug + Display,
+        password: impl ToString + Debug,
+        registration: Registration,
+        invite_code: Option<String>,
+        pool: &PgPool,
+    ) -> Result<User, RouteError> {
+        let mut tx = pool.begin().await?;
 
-        info!("New user '{}' created", new_user.username);
+        let new_user =
+            Self::register_in(username, password, registration, invite_code, &mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(new_user)
+    }
+
+    // Same as register, but in the caller's transaction so more can be created with the user
+    #[instrument(skip(password, invite_code, tx))]
+    pub(crate) async fn register_in(
+        username: impl ToString + Debug + Display,
+        password: impl ToString + Debug,
+        registration: Registration,
+        invite_code: Option<String>,
+        tx: &mut Transaction<'_, Postgres>,
+    ) -> Result<User, RouteError> {
+        // Held until the user is created, so nobody else can be the first user meanwhile
+        lock_signups(tx).await?;
+
+        let first_user =
+            sqlx::query_scalar!("SELECT NOT EXISTS (SELECT 1 FROM users) AS \"first!\"")
+                .fetch_one(&mut **tx)
+                .await?;
+
+        let invite_code_id = match (registration, invite_code) {
+            (Registration::Open, _) => None,
+            _ if first_user => None,
+            (Registration::InviteOnly, Some(code)) => Some(invite_code::claim(&code, tx).await?),
+            (Registration::InviteOnly, None) => {
+                return Err(RouteError::new(
+                    "An invite code is needed to sign up.",
+                    Some("invite_code"),
+                    StatusCode::FORBIDDEN,
+                ))
+            }
+            (Registration::Closed, _) => {
+                return Err(RouteError::new(
+                    "Signing up is closed.",
+                    None::<&str>,
+                    StatusCode::FORBIDDEN,
+                ))
+            }
+        };
+
+        // A claimed code is released by the rollback if this fails
+        let new_user = create_user(username, password, tx).await?;
+
+        if let Some(invite_code_id) = invite_code_id {
+            invite_code::set_used_by(invite_code_id, new_user.id, tx).await?;
+        }
 
         Ok(new_user)
     }
@@ -100,6 +189,32 @@ impl User {
         Ok(())
     }
 
+    // Only changes how weights are shown and given, they're always stored in kilograms
+    #[instrument]
+    pub async fn set_weight_unit(
+        &mut self,
+        weight_unit: WeightUnit,
+        pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        info!(
+            "Changing weight unit of user {} to {:?}",
+            self.id, weight_unit
+        );
+
+        let updated = sqlx::query!(
+            r#"UPDATE users SET weight_unit = $1, changed = NOW() WHERE id = $2 RETURNING weight_unit AS "weight_unit: WeightUnit", changed"#,
+            weight_unit as WeightUnit,
+            self.id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        self.weight_unit = updated.weight_unit;
+        self.changed = updated.changed;
+
+        Ok(())
+    }
+
     #[instrument(skip(password))]
     pub async fn from_credentials(
         username: impl ToString + Debug + Display,
@@ -111,7 +226,11 @@ impl User {
         // Find the user by the username
         let potential_user = sqlx::query_as!(
             User,
-            "SELECT * FROM users WHERE username = $1",
+            r#"
+            SELECT id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+                email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+            FROM users WHERE username = $1
+            "#,
             username.to_string()
         )
         .fetch_optional(pool)
@@ -131,14 +250,96 @@ impl User {
         // Validate that the password matches
         validate_password_hash(password.to_string(), &user.password_hash)?;
 
+        user.require_enabled()?;
+
         Ok(user)
     }
 
+    // Get an user who is allowed to use the API
+    #[instrument]
+    pub async fn from_id(user_id: Uuid, pool: &PgPool) -> Result<User, RouteError> {
+        let user = sqlx::query_as!(
+            User,
+            r#"
+            SELECT id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+                email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+            FROM users WHERE id = $1
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        user.require_enabled()?;
+
+        Ok(user)
+    }
+
+    // Returns an error if the user has been disabled by an admin
+    pub fn require_enabled(&self) -> Result<(), RouteError> {
+        if self.disabled {
+            info!("Disabled user {} tried to use the API", self.id);
+
+            return Err(RouteError::new(
+                "The account has been disabled.",
+                None::<&str>,
+                StatusCode::FORBIDDEN,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Returns an error if the password isn't the user's current one,
+    // for changes which need more than an access token
+    #[instrument(skip(password))]
+    pub fn require_password(&self, password: impl ToString) -> Result<(), RouteError> {
+        validate_password_hash(password.to_string(), &self.password_hash)
+    }
+
+    // Returns an error if the user isn't an admin
+    pub fn require_admin(&self) -> Result<(), RouteError> {
+        if self.role != UserRole::Admin {
+            info!("User {} tried to use an admin route", self.id);
+
+            return Err(RouteError::new(
+                "Only admins are allowed to do that.",
+                None::<&str>,
+                StatusCode::FORBIDDEN,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // If a token ID is given, every other access and refresh token of the
+    // user is revoked in the same transaction, so only that one stays logged in
     #[instrument(skip(new_password))]
     pub async fn change_password(
         &mut self,
         new_password: impl ToString,
+        revoke_tokens_except: Option<Uuid>,
         pool: &PgPool,
+    ) -> Result<(), RouteError> {
+        let mut tx = pool.begin().await?;
+
+        self.set_password(new_password, &mut tx).await?;
+
+        if let Some(kept_token_id) = revoke_tokens_except {
+            access_token::revoke_user_tokens(self.id, Some(kept_token_id), &mut tx).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Only updates the hash, the caller decides what else changes with it
+    #[instrument(skip(new_password, tx))]
+    pub async fn set_password(
+        &mut self,
+        new_password: impl ToString,
+        tx: &mut Transaction<'_, Postgres>,
     ) -> Result<(), RouteError> {
         let new_hashed_password = hash_password(new_password.to_string())?;
 
@@ -147,7 +348,7 @@ impl User {
             new_hashed_password,
             self.id
         )
-        .fetch_one(pool)
+        .fetch_one(&mut **tx)
         .await?;
 
         self.password_hash = updates.password_hash;
@@ -179,33 +380,75 @@ where
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         debug!("Trying to get an user from the 'Authorization' header");
 
-        let TypedHeader(Authorization(bearer_token)) = parts
-            .extract::<TypedHeader<Authorization<Bearer>>>()
-            .await
-            .map_err(|_| {
-                RouteError::new(
-                    "Authorization header is missing.",
-                    None::<&str>,
-                    StatusCode::BAD_REQUEST,
-                )
-            })?;
+        let access_token = parts.extract_with_state::<AccessToken, S>(state).await?;
 
         let pool = &PgPool::from_ref(state);
 
-        let access_token = AccessToken::from_token(bearer_token.token(), pool).await?;
-
         Ok(access_token.get_user(pool).await?)
     }
 }
 
+// Serialize creating users, so the first user and registration checks can't race
+async fn lock_signups(tx: &mut Transaction<'_, Postgres>) -> Result<(), RouteError> {
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(SIGNUP_LOCK)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+// Create the user in a transaction holding the signup lock
+#[instrument(skip(password, tx))]
+async fn create_user(
+    username: impl ToString + Debug + Display,
+    password: impl ToString + Debug,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<User, RouteError> {
+    info!("Trying to create a new user '{}'", username);
+
+    let username_string = username.to_string();
+
+    // Check if username is available
+    is_username_available(&username_string, &mut **tx).await?;
+
+    // Hash the user's password
+    let hashed_password = hash_password(password.to_string())?;
+
+    // Create the new user, an admin if it's the first one
+    let new_user = sqlx::query_as!(
+        User,
+        r#"
+        INSERT INTO users (username, password_hash, role)
+        VALUES ($1, $2, CASE WHEN EXISTS (SELECT 1 FROM users) THEN 'USER' ELSE 'ADMIN' END::user_role)
+        RETURNING id, created, changed, username, password_hash, role AS "role: UserRole", disabled,
+            email, email_verified, weight_unit AS "weight_unit: WeightUnit"
+        "#,
+        username_string,
+        hashed_password
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    info!(
+        "New user '{}' created as {}",
+        new_user.username, new_user.role
+    );
+
+    Ok(new_user)
+}
+
 // Returns an error if username is taken
-#[instrument]
-async fn is_username_available(username: &String, pool: &PgPool) -> Result<(), RouteError> {
+#[instrument(skip(executor))]
+async fn is_username_available(
+    username: &String,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), RouteError> {
     let username_count = sqlx::query_scalar!(
         "SELECT COUNT(*) AS count FROM users WHERE username = $1",
         username
     )
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?;
 
     if username_count.unwrap_or(1) != 0 {
@@ -226,9 +469,9 @@ async fn is_username_available(username: &String, pool: &PgPool) -> Result<(), R
 fn hash_password(password: String) -> Result<String, RouteError> {
     let salt = SaltString::generate(&mut OsRng).to_owned();
 
-    let hash_result = Argon2::default().hash_password(&password.as_bytes(), &salt);
+    let hash_result = Argon2::default().hash_password(password.as_bytes(), &salt);
 
-    return match hash_result {
+    match hash_result {
         Ok(hash) => Ok(hash.to_string()),
         Err(error) => {
             error!("Failed to hash a password: {}", error);
@@ -238,7 +481,7 @@ fn hash_password(password: String) -> Result<String, RouteError> {
                 StatusCode::INTERNAL_SERVER_ERROR,
             ))
         }
-    };
+    }
 }
 
 // Validate that the given password matched the given hash
@@ -297,7 +540,7 @@ mod tests {
     #[sqlx::test]
     async fn changed_column_update(pool: PgPool) {
         let mut user: User = User::new(USERNAME, PASSWORD, &pool).await.unwrap();
-        let initial_changed_column = user.changed.clone();
+        let initial_changed_column = user.changed;
 
         // Change username
         user.change_username("new_test_username", &pool)
@@ -339,20 +582,23 @@ mod tests {
 
     #[sqlx::test]
     async fn password_change(pool: PgPool) {
-        let mut user: User = User::new(USERNAME, PASSWORD, &&pool).await.unwrap();
+        let mut user: User = User::new(USERNAME, PASSWORD, &pool).await.unwrap();
 
         let new_password = "some_other_password_2";
 
         // Change the password of the user
-        assert!(user.change_password(new_password, &&pool).await.is_ok());
+        assert!(user
+            .change_password(new_password, None, &pool)
+            .await
+            .is_ok());
 
         // Try the old password, should not work
-        assert!(User::from_credentials(USERNAME, PASSWORD, &&pool)
+        assert!(User::from_credentials(USERNAME, PASSWORD, &pool)
             .await
             .is_err());
 
         // Try new pasword, should work
-        assert!(User::from_credentials(USERNAME, new_password, &&pool)
+        assert!(User::from_credentials(USERNAME, new_password, &pool)
             .await
             .is_ok());
     }
@@ -369,4 +615,29 @@ mod tests {
             .await
             .is_err());
     }
+
+    #[sqlx::test]
+    async fn concurrent_first_signups(pool: PgPool) {
+        let signups = (0..5).map(|i| {
+            User::register(
+                format!("{}{}", USERNAME, i),
+                PASSWORD,
+                Registration::Closed,
+                None,
+                &pool,
+            )
+        });
+
+        let results = futures::future::join_all(signups).await;
+
+        // Only one of them is the first user
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+
+        let admins = sqlx::query_scalar!("SELECT COUNT(*) FROM users WHERE role = 'ADMIN'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(admins, Some(1));
+    }
 }
@@ -1,13 +1,16 @@
 use std::{process::exit, time::Duration};
 
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use sqlx::{migrate::MigrateError, postgres::PgPoolOptions, Connection, Pool, Postgres};
 use tracing::{error, info, instrument};
 
+// Read by the migration which hashes existing tokens
+pub const TOKEN_KEY_SETTING: &str = "liftlog.token_key";
+
 // Connect to the database and create the database pool,
 // and tests that the connection works.
 // Exits the program on failure.
-#[instrument]
-pub async fn create_pool(url: &str) -> Pool<Postgres> {
+#[instrument(skip(token_key))]
+pub async fn create_pool(url: &str, token_key: &str) -> Pool<Postgres> {
     info!("Creating PostgreSQL database client");
 
     let pool_result = PgPoolOptions::new()
@@ -41,7 +44,7 @@ pub async fn create_pool(url: &str) -> Pool<Postgres> {
     }
 
     info!("Running migrations");
-    if let Err(error) = sqlx::migrate!().run(&pool).await {
+    if let Err(error) = migrate(&pool, token_key).await {
         error!("Failed to apply migratios: {}", error);
         exit(1);
     }
@@ -50,3 +53,21 @@ pub async fn create_pool(url: &str) -> Pool<Postgres> {
 
     pool
 }
+
+// The token key is only set on the connection running the migrations,
+// which is closed afterwards instead of returning it to the pool
+async fn migrate(pool: &Pool<Postgres>, token_key: &str) -> Result<(), MigrateError> {
+    let mut connection = pool.acquire().await?.detach();
+
+    sqlx::query("SELECT set_config($1, $2, false)")
+        .bind(TOKEN_KEY_SETTING)
+        .bind(token_key)
+        .execute(&mut connection)
+        .await?;
+
+    sqlx::migrate!().run(&mut connection).await?;
+
+    connection.close().await?;
+
+    Ok(())
+}
@@ -1,23 +1,155 @@
-use std::process::exit;
+use std::{net::IpAddr, ops::RangeInclusive, process::exit};
+
+use url::{Host, Url};
 
 use config::{Config, ConfigError};
 use serde::Deserialize;
 
+// Short keys could be guessed from a leaked token hash
+const MIN_TOKEN_KEY_LENGTH: usize = 32;
+// Long enough to notice the emails, short enough to actually delete the data
+const DELETION_GRACE_PERIOD_DAYS: RangeInclusive<i64> = 1..=365;
+
 // Holds global configuration values from CLI flags
 #[derive(Debug, Default, Deserialize)]
 pub struct Settings {
     pub database_url: String,
     pub listen_address: String,
     pub debug: bool,
+    // Addresses of reverse proxies in front of the server, only their
+    // X-Forwarded-For headers are used to find the client address.
+    // Separated by commas in the environment.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    // Secret used to hash access and refresh tokens, changing it logs everyone out
+    pub token_key: String,
+    // Who can create new users with the API
+    #[serde(default)]
+    pub registration: Registration,
+    // Deleted users can cancel by logging in during this time
+    #[serde(default = "default_deletion_grace_period_days")]
+    pub deletion_grace_period_days: i64,
+    // Emails are sent with SMTP if configured, otherwise only logged
+    #[serde(default)]
+    pub smtp: Option<SmtpSettings>,
+    // Logged emails are also appended to this file, useful in development
+    #[serde(default)]
+    pub mail_file: Option<String>,
+    // Only configurable in the settings file, because they are lists
+    #[serde(default)]
+    pub oidc_providers: Vec<OidcProvider>,
+}
+
+// Everyone can sign up when open. The first user can always sign up,
+// so a fresh instance gets an admin.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Registration {
+    #[default]
+    Open,
+    // Needs an invite code created by an admin
+    InviteOnly,
+    Closed,
+}
+
+// Only configurable in the settings file, under [smtp]
+#[derive(Debug, Clone, Deserialize)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // For example "LiftLog <liftlog@example.com>"
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    // Upgrade a plain connection to TLS, usually on port 587
+    #[default]
+    StartTls,
+    // TLS from the start, usually on port 465
+    Tls,
+    // Only for local relays
+    None,
+}
+
+fn default_deletion_grace_period_days() -> i64 {
+    30
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+// Identity provider users can log in with, using the authorization code flow
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcProvider {
+    // Used in the routes, for example /api/oidc/{name}/authorize
+    pub name: String,
+    // Has to match the iss claim of ID tokens exactly
+    pub issuer: String,
+    pub client_id: String,
+    // Public clients only use PKCE
+    pub client_secret: Option<String>,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    // Where the provider sends the user back with the code, usually the frontend
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: String,
+    // Create a new user on the first login, otherwise the identity
+    // has to be linked to an existing user first
+    #[serde(default)]
+    pub allow_signup: bool,
+}
+
+fn default_oidc_scopes() -> String {
+    "openid profile".to_string()
 }
 
-// Build config from environment (and maybe other files too, later)
-// Will exit progam on errors
+impl OidcProvider {
+    // The code and the client secret are sent to the token endpoint, so it has to
+    // use TLS. Plain HTTP is only allowed to the same machine, for development.
+    pub fn check_token_endpoint(&self) -> Result<(), String> {
+        let url = Url::parse(&self.token_endpoint)
+            .map_err(|error| format!("Invalid token endpoint: {}", error))?;
+
+        let loopback = match url.host() {
+            Some(Host::Domain(domain)) => domain == "localhost",
+            Some(Host::Ipv4(address)) => address.is_loopback(),
+            Some(Host::Ipv6(address)) => address.is_loopback(),
+            None => false,
+        };
+
+        match url.scheme() {
+            "https" => Ok(()),
+            "http" if loopback => Ok(()),
+            scheme => Err(format!(
+                "Token endpoint has to use https instead of {}",
+                scheme
+            )),
+        }
+    }
+}
+
+// Build config from an optional liftlog.toml file and the environment,
+// which overrides the file. Will exit progam on errors
 pub fn build() -> Settings {
     load_env_file();
 
     let settings_result = Config::builder()
-        .add_source(config::Environment::with_prefix("LIFTLOG").try_parsing(true))
+        .add_source(config::File::with_name("liftlog").required(false))
+        .add_source(
+            config::Environment::with_prefix("LIFTLOG")
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("trusted_proxies"),
+        )
         .build();
 
     let settings = match settings_result {
@@ -30,13 +162,39 @@ pub fn build() -> Settings {
 
     let app_configuration_result: Result<Settings, ConfigError> = settings.try_deserialize();
 
-    match app_configuration_result {
+    let app_configuration = match app_configuration_result {
         Ok(app_configuration) => app_configuration,
         Err(error) => {
             println!("Failed to deserialize settings: {}", error);
             exit(1);
         }
+    };
+
+    if app_configuration.token_key.len() < MIN_TOKEN_KEY_LENGTH {
+        println!(
+            "Token key has to be at least {} characters long",
+            MIN_TOKEN_KEY_LENGTH
+        );
+        exit(1);
     }
+
+    if !DELETION_GRACE_PERIOD_DAYS.contains(&app_configuration.deletion_grace_period_days) {
+        println!(
+            "Deletion grace period has to be between {} and {} days",
+            DELETION_GRACE_PERIOD_DAYS.start(),
+            DELETION_GRACE_PERIOD_DAYS.end()
+        );
+        exit(1);
+    }
+
+    for provider in &app_configuration.oidc_providers {
+        if let Err(error) = provider.check_token_endpoint() {
+            println!("Invalid OIDC provider {}: {}", provider.name, error);
+            exit(1);
+        }
+    }
+
+    app_configuration
 }
 
 // Load .env files to other environment variables
@@ -6,16 +6,24 @@ use sqlx::PgPool;
 use uuid::Uuid;
 
 use crate::{
-    api::{response::RouteSuccess, routes::build_router},
+    api::{
+        response::RouteSuccess,
+        routes::{build_router, RouterOptions},
+    },
     models::{
-        access_token::AccessToken, exercise::Exercise, exercise_instance::ExerciseInstance,
-        session::Session, set::Set, user::User,
+        access_token::{AccessToken, TokenMetadata},
+        exercise::Exercise,
+        exercise_instance::ExerciseInstance,
+        session::Session,
+        set::Set,
+        user::User,
     },
 };
 
 // Shorter way to create a test server in integration tests
 pub fn test_server(pool: &PgPool) -> TestServer {
-    TestServer::new(build_router(pool.to_owned()).into_make_service()).unwrap()
+    TestServer::new(build_router(pool.to_owned(), RouterOptions::default()).into_make_service())
+        .unwrap()
 }
 
 // Create a test server, access token and an user
@@ -59,8 +67,8 @@ pub async fn create_test_scenario(
     let set = create_test_set(&server, exercise_instance.id).await;
 
     // These have to uppdated manually because sets and instances were added after creating these
-    session = Session::from_id(user.id, session.id, &pool).await.unwrap();
-    exercise_instance = ExerciseInstance::from_id(user.id, exercise_instance.id, &pool)
+    session = Session::from_id(user.id, session.id, pool).await.unwrap();
+    exercise_instance = ExerciseInstance::from_id(user.id, exercise_instance.id, pool)
         .await
         .unwrap();
 
@@ -84,7 +92,7 @@ pub async fn create_test_user(pool: &PgPool) -> User {
 pub async fn create_test_access_token(pool: &PgPool) -> (User, AccessToken) {
     let user = create_test_user(pool).await;
 
-    let access_token = AccessToken::new(user.id, Duration::days(1), pool)
+    let access_token = AccessToken::new(user.id, Duration::days(1), TokenMetadata::default(), pool)
         .await
         .unwrap();
 
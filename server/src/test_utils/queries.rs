@@ -0,0 +1,35 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tracing::{Dispatch, Event, Subscriber};
+use tracing_subscriber::{layer::Context, layer::SubscriberExt, Layer, Registry};
+
+// Counts the events sqlx logs for each executed query
+struct QueryCounter(Arc<AtomicUsize>);
+
+impl<S: Subscriber> Layer<S> for QueryCounter {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() == "sqlx::query" {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Run the future and return its output with the amount of database queries it made.
+// The subscriber is thread local, which works because sqlx tests run on a
+// single threaded runtime.
+pub async fn count_queries<F: Future>(future: F) -> (F::Output, usize) {
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let dispatch = Dispatch::new(Registry::default().with(QueryCounter(count.clone())));
+    let _guard = tracing::dispatcher::set_default(&dispatch);
+
+    let output = future.await;
+
+    (output, count.load(Ordering::SeqCst))
+}